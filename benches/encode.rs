@@ -0,0 +1,103 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+// run with `cargo bench`. `nested` is the old encoder, which built each part of the frame as its
+// own Vec and flattened them, kept for comparison
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use improv::{
+    ImprovPacket, RPCCommand, RPCResult, WifiNetwork, WifiSettings, MAX_FRAME_LEN,
+    REQUEST_CURRENT_STATE,
+};
+
+fn nested(p: &ImprovPacket) -> Vec<u8> {
+    let (pkt_type, payload) = match p {
+        ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(w)) => {
+            let mut data = vec![0x01, (2 + w.ssid.len() + w.psk.len()) as u8];
+            let fields = vec![
+                vec![w.ssid.len() as u8],
+                w.ssid.as_bytes().to_vec(),
+                vec![w.psk.len() as u8],
+                w.psk.as_bytes().to_vec(),
+            ];
+            data.extend(fields.into_iter().flatten());
+            (0x03, data)
+        }
+        ImprovPacket::RPCResult(r) => {
+            let fields: Vec<Vec<u8>> = r
+                .data
+                .iter()
+                .map(|f| [vec![f.len() as u8], f.clone()].concat())
+                .collect();
+            let mut data = vec![r.command, fields.iter().map(Vec::len).sum::<usize>() as u8];
+            data.extend(fields.into_iter().flatten());
+            (0x04, data)
+        }
+        _ => unimplemented!(),
+    };
+    let header = String::from("IMPROV");
+    let parts = vec![
+        header.into_bytes(),
+        vec![0x01, pkt_type, payload.len() as u8],
+        payload,
+    ];
+    let mut frame: Vec<u8> = parts.into_iter().flatten().collect();
+    frame.push(frame.iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+    frame
+}
+
+fn encode(c: &mut Criterion) {
+    let settings = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
+        ssid: "anthill".into(),
+        psk: String::from("ants in my pants"),
+    }));
+    let entry = ImprovPacket::RPCResult(RPCResult::scan_entry(&WifiNetwork {
+        ssid: "anthill".into(),
+        rssi: -48,
+        auth: true,
+    }));
+    assert_eq!(nested(&settings), Vec::try_from(&settings).unwrap());
+    assert_eq!(nested(&entry), Vec::try_from(&entry).unwrap());
+
+    for (name, p) in [("wifi_settings", &settings), ("scan_entry", &entry)] {
+        let mut g = c.benchmark_group(name);
+        g.bench_function("nested", |b| b.iter(|| nested(black_box(p))));
+        g.bench_function("vec", |b| {
+            b.iter(|| Vec::<u8>::try_from(black_box(p)).unwrap())
+        });
+        g.bench_function("encode_into", |b| {
+            let mut buf = [0u8; MAX_FRAME_LEN];
+            b.iter(|| black_box(p).encode_into(&mut buf).unwrap())
+        });
+        g.finish();
+    }
+
+    let request = ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState);
+    let mut g = c.benchmark_group("request_current_state");
+    g.bench_function("encode_into", |b| {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        b.iter(|| black_box(&request).encode_into(&mut buf).unwrap())
+    });
+    g.bench_function("const", |b| b.iter(|| black_box(REQUEST_CURRENT_STATE)));
+    g.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let frame = Vec::try_from(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(
+        WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        },
+    )))
+    .unwrap();
+    c.bench_function("decode_wifi_settings", |b| {
+        b.iter(|| ImprovPacket::try_from(black_box(&frame[..])).unwrap())
+    });
+    c.bench_function("decode_wifi_settings_ref", |b| {
+        b.iter(|| improv::PacketRef::try_from(black_box(&frame[..])).unwrap())
+    });
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
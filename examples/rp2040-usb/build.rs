@@ -0,0 +1,16 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// put memory.x where the linker will find it
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("memory.x"), include_bytes!("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+
+    println!("cargo:rustc-link-arg-bins=--nmagic");
+    println!("cargo:rustc-link-arg-bins=-Tlink.x");
+}
@@ -0,0 +1,124 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Improv over the RP2040's native USB, as a CDC-ACM serial port, with `usb-device` and
+//! `usbd-serial`. Wire `connect` up to your Wi-Fi driver, e.g. cyw43 on a Pico W.
+
+#![no_std]
+#![no_main]
+
+use core::mem::MaybeUninit;
+
+use embedded_alloc::LlffHeap as Heap;
+use improv::usb::{CdcServer, PollIo};
+use improv::{DeviceInfo, ErrorState, ImprovServer, WifiNetwork, WifiProvisioner};
+use panic_halt as _;
+use rp2040_hal::{self as hal, pac};
+use usb_device::class_prelude::{UsbBus, UsbBusAllocator};
+use usb_device::prelude::*;
+use usbd_serial::SerialPort;
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[link_section = ".boot2"]
+#[used]
+pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
+
+const XTAL_FREQ_HZ: u32 = 12_000_000;
+
+// `ImprovServer` allocates its buffers and responses
+#[global_allocator]
+static HEAP: Heap = Heap::empty();
+const HEAP_SIZE: usize = 8 * 1024;
+
+struct Provisioner;
+
+impl WifiProvisioner for Provisioner {
+    fn connect(&mut self, _ssid: &[u8], _psk: &str) -> Result<Option<String>, ErrorState> {
+        Err(ErrorState::UnableToConnect)
+    }
+
+    fn scan(&mut self) -> Vec<WifiNetwork> {
+        Vec::new()
+    }
+
+    fn device_info(&mut self) -> DeviceInfo {
+        DeviceInfo {
+            firmware_name: env!("CARGO_PKG_NAME").into(),
+            firmware_version: env!("CARGO_PKG_VERSION").into(),
+            hardware: "RP2040".into(),
+            device_name: "improv-rp2040-usb".into(),
+        }
+    }
+}
+
+// `usbd-serial` reports a full or empty endpoint as an error, where `PollIo` wants 0
+struct Cdc<'a, 'b, B: UsbBus>(&'a mut SerialPort<'b, B>);
+
+impl<B: UsbBus> PollIo for Cdc<'_, '_, B> {
+    type Error = UsbError;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, UsbError> {
+        match self.0.read(buf) {
+            Err(UsbError::WouldBlock) => Ok(0),
+            r => r,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, UsbError> {
+        match self.0.write(buf) {
+            Err(UsbError::WouldBlock) => Ok(0),
+            r => r,
+        }
+    }
+}
+
+#[hal::entry]
+fn main() -> ! {
+    {
+        static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+        unsafe { HEAP.init(&raw mut HEAP_MEM as usize, HEAP_SIZE) }
+    }
+
+    let mut pac = pac::Peripherals::take().unwrap();
+    let mut watchdog = hal::Watchdog::new(pac.WATCHDOG);
+    let Ok(clocks) = hal::clocks::init_clocks_and_plls(
+        XTAL_FREQ_HZ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    ) else {
+        panic!("clocks");
+    };
+
+    let bus = UsbBusAllocator::new(hal::usb::UsbBus::new(
+        pac.USBCTRL_REGS,
+        pac.USBCTRL_DPRAM,
+        clocks.usb_clock,
+        true,
+        &mut pac.RESETS,
+    ));
+    let mut serial = SerialPort::new(&bus);
+    // pid.codes' test VID/PID; get your own before shipping
+    let mut usb = UsbDeviceBuilder::new(&bus, UsbVidPid(0x1209, 0x0001))
+        .strings(&[StringDescriptors::default()
+            .manufacturer("improv-rs")
+            .product("Improv")
+            .serial_number("0001")])
+        .unwrap()
+        .device_class(usbd_serial::USB_CLASS_CDC)
+        .build();
+
+    let mut server = CdcServer::new(ImprovServer::new(Provisioner));
+    loop {
+        usb.poll(&mut [&mut serial]);
+        // responses carry on over the next polls if the endpoint is full, and an error (e.g. the
+        // host going away mid-transfer) isn't fatal
+        let _ = server.poll(&mut Cdc(&mut serial));
+    }
+}
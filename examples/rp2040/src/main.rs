@@ -0,0 +1,65 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Improv over UART0 (GP0 TX, GP1 RX) on an RP2040. Wire `connect` up to your Wi-Fi driver, e.g.
+//! cyw43 on a Pico W.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::UART0;
+use embassy_rp::uart::{BufferedInterruptHandler, BufferedUart, Config};
+use embedded_alloc::LlffHeap as Heap;
+use improv::embedded::{AsyncDeviceServer, AsyncWifiProvisioner, DeviceInfoRef};
+use improv::ErrorState;
+use panic_halt as _;
+use static_cell::StaticCell;
+
+bind_interrupts!(struct Irqs {
+    UART0_IRQ => BufferedInterruptHandler<UART0>;
+});
+
+// the server never allocates, but the crate links `alloc`, which needs an allocator to exist
+#[global_allocator]
+static HEAP: Heap = Heap::empty();
+
+struct Provisioner;
+
+impl AsyncWifiProvisioner for Provisioner {
+    async fn connect(&mut self, _ssid: &[u8], _psk: &str) -> Result<(), ErrorState> {
+        Err(ErrorState::UnableToConnect)
+    }
+
+    fn device_info(&self) -> DeviceInfoRef<'_> {
+        DeviceInfoRef {
+            firmware_name: env!("CARGO_PKG_NAME"),
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            hardware: "RP2040",
+            device_name: "improv-rp2040",
+        }
+    }
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+
+    static TX_BUF: StaticCell<[u8; 256]> = StaticCell::new();
+    static RX_BUF: StaticCell<[u8; 256]> = StaticCell::new();
+    let uart = BufferedUart::new(
+        p.UART0,
+        Irqs,
+        p.PIN_0,
+        p.PIN_1,
+        TX_BUF.init([0; 256]),
+        RX_BUF.init([0; 256]),
+        Config::default(),
+    );
+
+    let mut server = AsyncDeviceServer::new(uart, Provisioner);
+    loop {
+        // a UART error (e.g. a framing error from a baud mismatch) isn't fatal
+        let _ = server.run().await;
+    }
+}
@@ -0,0 +1,993 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+use core::future::Future;
+use core::pin::pin;
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::{self, Either};
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "tokio")]
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+#[cfg(feature = "tokio")]
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+use crate::client::{
+    self, ClientErr, Progress, ProvisionOptions, ProvisionOutcome, Provisioning, ResultAssembler,
+    ResultList, RetryPolicy,
+};
+use crate::demux::next_frame;
+use crate::{
+    decode_frame, discard, scan, CurrentState, CustomCommand, DeviceInfo, DeviceStateTracker,
+    Identify, ImprovErr, ImprovPacket, RPCCommand, RPCResult, Scan, WifiNetwork, WifiSettings,
+    FRAME_TERMINATOR,
+};
+
+/// Sleeps for the client's timeouts and retry delays, so it isn't tied to any one runtime.
+pub trait Timer {
+    type Sleep: Future;
+
+    fn sleep(&self, duration: Duration) -> Self::Sleep;
+}
+
+/// Sleeps with `tokio::time`.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    type Sleep = tokio::time::Sleep;
+
+    fn sleep(&self, duration: Duration) -> tokio::time::Sleep {
+        tokio::time::sleep(duration)
+    }
+}
+
+/// Sleeps with `async_io`, the reactor under smol and async-std.
+#[cfg(feature = "smol")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmolTimer;
+
+#[cfg(feature = "smol")]
+impl Timer for SmolTimer {
+    type Sleep = async_io::Timer;
+
+    fn sleep(&self, duration: Duration) -> async_io::Timer {
+        async_io::Timer::after(duration)
+    }
+}
+
+// `None` if `sleep` finished first
+async fn within<F: Future>(sleep: impl Future, f: F) -> Option<F::Output> {
+    match future::select(pin!(f), pin!(sleep)).await {
+        Either::Left((r, _)) => Some(r),
+        Either::Right(_) => None,
+    }
+}
+
+// an encoded frame, which may hold a PSK. it's wiped however it's dropped, including when the send
+// writing it is cancelled
+#[derive(Default)]
+struct Outgoing(Vec<u8>);
+
+impl Outgoing {
+    // drop the first `n` bytes without leaving a copy of the rest behind, as `drain` would
+    fn consume(&mut self, n: usize) {
+        let len = self.0.len() - n;
+        self.0.copy_within(n.., 0);
+        crate::wipe(&mut self.0[len..]);
+        self.0.truncate(len);
+    }
+}
+
+impl Drop for Outgoing {
+    fn drop(&mut self) {
+        crate::wipe(&mut self.0);
+    }
+}
+
+/// Run `op`, e.g. `client.scan_networks()`, unless `cancel` finishes first, in which case it's
+/// dropped and this returns `ClientErr::Cancelled`. `cancel` can be any future, such as
+/// `CancellationToken::cancelled()` or a oneshot receiver, so a UI can abort a scan or a
+/// provisioning attempt. The client stays usable: see `AsyncImprovClient`.
+pub async fn cancellable<R>(
+    cancel: impl Future,
+    op: impl Future<Output = Result<R, ClientErr>>,
+) -> Result<R, ClientErr> {
+    within(cancel, op).await.ok_or(ClientErr::Cancelled)?
+}
+
+// read until `buf` holds a whole frame, and decode it. nothing is lost if this is cancelled
+async fn next_packet<R: AsyncRead + Unpin>(
+    io: &mut R,
+    buf: &mut Vec<u8>,
+) -> io::Result<Result<ImprovPacket, ImprovErr>> {
+    let mut chunk = [0u8; 256];
+    loop {
+        // a bogus header can't hide a frame behind it, and anything that can't be the start of a
+        // frame is discarded
+        if let Some((start, end)) = next_frame(buf) {
+            discard(buf, start);
+            let p = decode_frame(&buf[start..end]);
+            buf.drain(..end);
+            return Ok(p);
+        }
+        match scan(buf) {
+            Scan::Partial { start } => {
+                discard(buf, start);
+                buf.drain(..start);
+            }
+            _ => {
+                discard(buf, buf.len());
+                buf.clear();
+            }
+        }
+        let n = io.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Reads the packets out of any `futures_io` stream, skipping whatever is between them, for when
+/// the whole client isn't needed. The async counterpart of `PacketReader`.
+pub struct AsyncPacketReader<R, S> {
+    reader: R,
+    timer: S,
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "tokio")]
+impl<R: tokio::io::AsyncRead + Unpin> AsyncPacketReader<Compat<R>, TokioTimer> {
+    pub fn new(reader: R) -> AsyncPacketReader<Compat<R>, TokioTimer> {
+        AsyncPacketReader::with_timer(reader.compat(), TokioTimer)
+    }
+}
+
+impl<R: AsyncRead + Unpin, S: Timer> AsyncPacketReader<R, S> {
+    pub fn with_timer(reader: R, timer: S) -> AsyncPacketReader<R, S> {
+        AsyncPacketReader {
+            reader,
+            timer,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Wait up to `timeout` for the next packet. Frames which can't be decoded come back as
+    /// `ClientErr::Improv`, and the end of the stream as an `UnexpectedEof` error. If this is
+    /// cancelled, the bytes read so far are kept for the next call.
+    pub async fn read_packet(&mut self, timeout: Duration) -> Result<ImprovPacket, ClientErr> {
+        let sleep = self.timer.sleep(timeout);
+        let p = within(sleep, next_packet(&mut self.reader, &mut self.buf))
+            .await
+            .ok_or(ClientErr::Timeout)??;
+        Ok(p?)
+    }
+
+    /// The stream, dropping anything read but not yet decoded.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// An Improv client for any `futures_io` stream. With the `tokio` feature, `new` and `open` take
+/// tokio streams and serial ports; otherwise use `with_timer` and the timer for your runtime.
+///
+/// Every operation can be dropped partway, e.g. by `cancellable` or a `select!`, without
+/// desynchronizing the client: bytes read but not yet decoded are kept for the next read, and a
+/// frame which was partly written is finished before anything else is sent, so the device never
+/// sees half of one. Answers to an abandoned request which arrive later are skipped over.
+pub struct AsyncImprovClient<T, S> {
+    io: T,
+    timer: S,
+    buf: Vec<u8>,
+    // the rest of a frame whose send was cancelled partway
+    torn: Outgoing,
+    terminator: Option<u8>,
+    timeout: Duration,
+    command_timeouts: HashMap<u8, Duration>,
+    inter_frame_timeout: Duration,
+    retry: RetryPolicy,
+    subscribers: Vec<mpsc::Sender<ImprovPacket>>,
+    progress: Vec<mpsc::Sender<Progress>>,
+    tracker: DeviceStateTracker,
+    provision_options: ProvisionOptions,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncImprovClient<Compat<SerialStream>, TokioTimer> {
+    pub fn open(
+        path: &str,
+        baud_rate: u32,
+    ) -> Result<AsyncImprovClient<Compat<SerialStream>, TokioTimer>, ClientErr> {
+        let port = tokio_serial::new(path, baud_rate)
+            .open_native_async()
+            .map_err(io::Error::from)?;
+        Ok(AsyncImprovClient::new(port))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>
+    AsyncImprovClient<Compat<T>, TokioTimer>
+{
+    pub fn new(io: T) -> AsyncImprovClient<Compat<T>, TokioTimer> {
+        AsyncImprovClient::with_timer(io.compat(), TokioTimer)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin, S: Timer> AsyncImprovClient<T, S> {
+    pub fn with_timer(io: T, timer: S) -> AsyncImprovClient<T, S> {
+        AsyncImprovClient {
+            io,
+            timer,
+            buf: Vec::new(),
+            torn: Outgoing::default(),
+            terminator: Some(FRAME_TERMINATOR),
+            timeout: client::DEFAULT_TIMEOUT,
+            command_timeouts: HashMap::new(),
+            inter_frame_timeout: client::DEFAULT_INTER_FRAME_TIMEOUT,
+            retry: RetryPolicy::default(),
+            subscribers: Vec::new(),
+            progress: Vec::new(),
+            tracker: DeviceStateTracker::new(),
+            provision_options: client::polling(),
+        }
+    }
+
+    /// Receive a copy of every packet the device sends. See `ImprovClient::subscribe`.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<ImprovPacket> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Receive progress events: commands sent, state changes, results and errors. See
+    /// `ImprovClient::progress`.
+    pub fn progress(&mut self) -> mpsc::Receiver<Progress> {
+        let (tx, rx) = mpsc::channel();
+        self.progress.push(tx);
+        rx
+    }
+
+    /// The device's state and error as of the last packet read. See `ImprovClient::device_state`.
+    pub fn device_state(&mut self) -> &mut DeviceStateTracker {
+        &mut self.tracker
+    }
+
+    /// Send `terminator` after each frame, as `ImprovClient` does. This is `FRAME_TERMINATOR`
+    /// unless set otherwise; see `IoTransport::set_terminator`.
+    pub fn set_terminator(&mut self, terminator: Option<u8>) {
+        self.terminator = terminator;
+    }
+
+    /// How long to wait for the device to answer each request.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// How long to wait for the device to answer the command with this id, in place of the
+    /// client's timeout. For a list, this is the wait for the first result; see
+    /// `set_inter_frame_timeout` for the rest.
+    pub fn set_command_timeout(&mut self, id: u8, timeout: Duration) {
+        self.command_timeouts.insert(id, timeout);
+    }
+
+    /// How long to wait for the device to answer the command with this id.
+    pub fn command_timeout(&self, id: u8) -> Duration {
+        self.command_timeouts
+            .get(&id)
+            .copied()
+            .unwrap_or(self.timeout)
+    }
+
+    /// How long to wait between the results of a list, such as a scan, once the first has
+    /// arrived.
+    pub fn set_inter_frame_timeout(&mut self, timeout: Duration) {
+        self.inter_frame_timeout = timeout;
+    }
+
+    /// How to retry requests which fail.
+    pub fn set_retry(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// How `send_wifi_settings` waits for the device to join the network. See
+    /// `ImprovClient::set_provision_options`.
+    pub fn set_provision_options(&mut self, options: ProvisionOptions) {
+        self.provision_options = options;
+    }
+
+    /// The stream, dropping anything read but not yet decoded. For tokio streams, this is still
+    /// wrapped in `Compat`.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    pub async fn send(&mut self, packet: ImprovPacket) -> Result<(), ClientErr> {
+        event!(debug, %packet, "send");
+        let id = match &packet {
+            ImprovPacket::RPCCommand(c) => Some(c.id()),
+            _ => None,
+        };
+        // sized up front, as growing it to add the terminator would leave a copy behind
+        let len = packet.encoded_len();
+        let mut frame = Outgoing(vec![0; len + usize::from(self.terminator.is_some())]);
+        packet.encode_into(&mut frame.0)?;
+        if let Some(t) = self.terminator {
+            frame.0[len] = t;
+        }
+        self.finish_torn().await?;
+        // until the first bytes are written, cancelling drops the frame cleanly; after that, the
+        // rest is kept so it can be finished
+        let n = self.io.write(&frame.0).await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+        }
+        frame.consume(n);
+        self.torn = frame;
+        self.finish_torn().await?;
+        self.io.flush().await?;
+        if let Some(id) = id {
+            client::notify(&mut self.progress, &Progress::CommandSent(id));
+        }
+        Ok(())
+    }
+
+    // write out whatever's left of a frame a cancelled send started
+    async fn finish_torn(&mut self) -> io::Result<()> {
+        while !self.torn.0.is_empty() {
+            let n = self.io.write(&self.torn.0).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.torn.consume(n);
+        }
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Result<ImprovPacket, ClientErr> {
+        let p = next_packet(&mut self.io, &mut self.buf)
+            .await?
+            .map_err(ClientErr::Improv)?;
+        event!(debug, packet = %p, "received");
+        client::notify(&mut self.subscribers, &p);
+        if let Some(event) = client::progress(&mut self.tracker, &p) {
+            client::notify(&mut self.progress, &event);
+        }
+        Ok(p)
+    }
+
+    // `response` builds a fresh matcher for each attempt
+    async fn request<R, M: FnMut(ImprovPacket) -> Result<Option<R>, ClientErr>>(
+        &mut self,
+        cmd: RPCCommand,
+        mut response: impl FnMut() -> M,
+    ) -> Result<R, ClientErr> {
+        let mut attempt = 1;
+        loop {
+            match self.attempt(cmd.clone(), response()).await {
+                Err(e) => match self.retry.retry_after(attempt, &e) {
+                    Some(delay) => {
+                        event!(info, attempt, error = %e, ?delay, "retrying");
+                        count!("improv_retries_total");
+                        self.timer.sleep(delay).await;
+                    }
+                    None => return Err(e),
+                },
+                r => return r,
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn attempt<R>(
+        &mut self,
+        cmd: RPCCommand,
+        mut response: impl FnMut(ImprovPacket) -> Result<Option<R>, ClientErr>,
+    ) -> Result<R, ClientErr> {
+        let sleep = self.timer.sleep(self.command_timeout(cmd.id()));
+        self.send(ImprovPacket::RPCCommand(cmd)).await?;
+        let wait = async {
+            loop {
+                if let Some(r) = response(self.recv().await?)? {
+                    return Ok(r);
+                }
+            }
+        };
+        within(sleep, wait).await.ok_or(ClientErr::Timeout)?
+    }
+
+    /// Send a command which the device answers with a list of results, and collect them. See
+    /// `ImprovClient::request_list`.
+    pub async fn request_list(&mut self, cmd: RPCCommand) -> Result<ResultList, ClientErr> {
+        let mut attempt = 1;
+        loop {
+            match self.attempt_list(cmd.clone()).await {
+                ResultList::Partial(results, e) => match self.retry.retry_after(attempt, &e) {
+                    Some(delay) => {
+                        event!(info, attempt, error = %e, ?delay, "retrying");
+                        count!("improv_retries_total");
+                        self.timer.sleep(delay).await;
+                    }
+                    None if results.is_empty() => return Err(e),
+                    None => return Ok(ResultList::Partial(results, e)),
+                },
+                complete => return Ok(complete),
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn attempt_list(&mut self, cmd: RPCCommand) -> ResultList {
+        let mut list = ResultAssembler::new(cmd.id());
+        let timeout = self.command_timeout(cmd.id());
+        if let Err(e) = self.send(ImprovPacket::RPCCommand(cmd)).await {
+            return list.fail(e);
+        }
+        let mut deadline = Instant::now() + timeout;
+        while !list.is_complete() {
+            let sleep = self
+                .timer
+                .sleep(deadline.saturating_duration_since(Instant::now()));
+            let p = within(sleep, self.recv())
+                .await
+                .unwrap_or(Err(ClientErr::Timeout));
+            match p.and_then(|p| list.feed(p)) {
+                Ok(true) => deadline = Instant::now() + self.inter_frame_timeout,
+                Ok(false) => (),
+                Err(e) => return list.fail(e),
+            }
+        }
+        list.finish()
+    }
+
+    pub async fn current_state(&mut self) -> Result<CurrentState, ClientErr> {
+        self.request(RPCCommand::RequestCurrentState, || client::current_state)
+            .await
+    }
+
+    pub async fn device_info(&mut self) -> Result<DeviceInfo, ClientErr> {
+        self.request(RPCCommand::RequestDeviceInformation, || client::device_info)
+            .await
+    }
+
+    pub async fn scan_networks(&mut self) -> Result<Vec<WifiNetwork>, ClientErr> {
+        client::networks(
+            self.request_list(RPCCommand::RequestScannedWifiNetworks)
+                .await?,
+        )
+    }
+
+    /// Send a vendor-specific command and wait for the device's result.
+    pub async fn custom<C: CustomCommand>(&mut self, cmd: &C) -> Result<RPCResult, ClientErr> {
+        self.request(RPCCommand::custom(cmd)?, || client::custom_result(C::ID))
+            .await
+    }
+
+    /// Ask the device to make itself known. See `ImprovClient::identify`.
+    pub async fn identify(&mut self) -> Result<(), ClientErr> {
+        self.custom(&Identify).await.map(|_| ())
+    }
+
+    /// Send credentials and wait for the device to connect, returning its redirect URL, if any.
+    /// See `ImprovClient::send_wifi_settings`.
+    pub async fn send_wifi_settings(
+        &mut self,
+        settings: &WifiSettings,
+    ) -> Result<Option<String>, ClientErr> {
+        let options = self.provision_options.clone();
+        self.provision(settings, &options).await?.into_result()
+    }
+
+    /// Send credentials and follow the device until it either joins the network or fails to.
+    pub async fn provision(
+        &mut self,
+        settings: &WifiSettings,
+        options: &ProvisionOptions,
+    ) -> Result<ProvisionOutcome, ClientErr> {
+        settings.validate()?;
+        if let Some(expected) = &options.expect_firmware {
+            expected.check(self.device_info().await?)?;
+        }
+        self.send(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(
+            settings.clone(),
+        )))
+        .await?;
+        self.follow(options).await
+    }
+
+    /// Try each of `networks` in turn until the device joins one. See
+    /// `ImprovClient::provision_any`.
+    pub async fn provision_any(
+        &mut self,
+        networks: &[WifiSettings],
+        options: &ProvisionOptions,
+    ) -> Result<Option<(usize, Option<String>)>, ClientErr> {
+        let mut options = options.clone();
+        for (i, settings) in networks.iter().enumerate() {
+            if i > 0 {
+                self.wait_for_state(CurrentState::Ready, self.timeout)
+                    .await?;
+            }
+            match self.provision(settings, &options).await? {
+                ProvisionOutcome::Provisioned(url) => return Ok(Some((i, url))),
+                ProvisionOutcome::UnableToConnect => {
+                    event!(info, ssid = %settings.ssid, "unable to connect");
+                }
+                ProvisionOutcome::Rejected(e) => return Err(ClientErr::Device(e)),
+            }
+            options.expect_firmware = None;
+        }
+        Ok(None)
+    }
+
+    /// Wait for a device which is already provisioning, e.g. one set up by another client, to
+    /// either join its network or fail to.
+    pub async fn wait_provisioned(
+        &mut self,
+        options: &ProvisionOptions,
+    ) -> Result<ProvisionOutcome, ClientErr> {
+        self.send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))
+            .await?;
+        self.follow(options).await
+    }
+
+    /// Wait until the device reports `state`. See `ImprovClient::wait_for_state`.
+    pub async fn wait_for_state(
+        &mut self,
+        state: CurrentState,
+        timeout: Duration,
+    ) -> Result<(), ClientErr> {
+        let sleep = self.timer.sleep(timeout);
+        let wait = async {
+            loop {
+                let poll = self.timer.sleep(client::STATE_POLL_INTERVAL);
+                self.send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))
+                    .await?;
+                let answer = async {
+                    loop {
+                        if client::current_state(self.recv().await?)? == Some(state) {
+                            return Ok::<_, ClientErr>(());
+                        }
+                    }
+                };
+                if let Some(r) = within(poll, answer).await {
+                    return r;
+                }
+            }
+        };
+        within(sleep, wait).await.ok_or(ClientErr::Timeout)?
+    }
+
+    /// Wait until the device reports `state` on its own. See `ImprovClient::watch_until`.
+    pub async fn watch_until(
+        &mut self,
+        state: CurrentState,
+        timeout: Duration,
+    ) -> Result<(), ClientErr> {
+        let sleep = self.timer.sleep(timeout);
+        let wait = async {
+            loop {
+                if self.recv().await? == ImprovPacket::CurrentState(state) {
+                    return Ok(());
+                }
+            }
+        };
+        within(sleep, wait).await.ok_or(ClientErr::Timeout)?
+    }
+
+    async fn follow(&mut self, options: &ProvisionOptions) -> Result<ProvisionOutcome, ClientErr> {
+        let mut provisioning = Provisioning::default();
+        let sleep = self.timer.sleep(options.timeout);
+        let wait = async {
+            loop {
+                let p = match options.poll_interval {
+                    Some(poll) => match within(self.timer.sleep(poll), self.recv()).await {
+                        Some(p) => p?,
+                        None => {
+                            if let Some(outcome) = provisioning.idle() {
+                                return Ok(outcome);
+                            }
+                            self.send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))
+                                .await?;
+                            continue;
+                        }
+                    },
+                    None => self.recv().await?,
+                };
+                if let Some(outcome) = provisioning.feed(p)? {
+                    return Ok(outcome);
+                }
+            }
+        };
+        match within(sleep, wait).await {
+            Some(r) => r,
+            None => provisioning.timed_out(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod test {
+    use super::*;
+    use crate::{ErrorState, ImprovCodec, RPCResult};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    // answer each command with the given packets
+    fn device(
+        io: tokio::io::DuplexStream,
+        answers: Vec<Vec<ImprovPacket>>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut framed = Framed::new(io, ImprovCodec);
+            for answer in answers {
+                let _ = framed.next().await;
+                for p in answer {
+                    framed.send(p).await.unwrap();
+                }
+            }
+            // hold the line open like a real port would
+            std::future::pending::<()>().await;
+        })
+    }
+
+    fn network(ssid: &str, rssi: &str) -> ImprovPacket {
+        ImprovPacket::RPCResult(RPCResult {
+            command: 0x04,
+            data: vec![ssid.into(), rssi.into(), b"YES".to_vec()],
+        })
+    }
+
+    #[tokio::test]
+    async fn scan_networks() {
+        let (host, dev) = tokio::io::duplex(1024);
+        device(
+            dev,
+            vec![vec![
+                network("anthill", "-40"),
+                ImprovPacket::CurrentState(CurrentState::Ready),
+                network("beehive", "-70"),
+                ImprovPacket::RPCResult(RPCResult {
+                    command: 0x04,
+                    data: vec![],
+                }),
+            ]],
+        );
+
+        let mut client = AsyncImprovClient::new(host);
+        let networks = client.scan_networks().await.unwrap();
+        assert_eq!(
+            networks
+                .iter()
+                .map(|n| n.ssid.to_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["anthill", "beehive"],
+        );
+    }
+
+    #[tokio::test]
+    async fn list_stalls_midframe() {
+        let (host, mut dev) = tokio::io::duplex(1024);
+        let mut rx = Vec::try_from(network("anthill", "-40")).unwrap();
+        let next = Vec::try_from(network("beehive", "-70")).unwrap();
+        rx.extend(&next[..next.len() / 2]);
+        tokio::io::AsyncWriteExt::write_all(&mut dev, &rx)
+            .await
+            .unwrap();
+
+        let mut client = AsyncImprovClient::new(host);
+        client.set_timeout(Duration::from_secs(30));
+        client.set_inter_frame_timeout(Duration::from_millis(50));
+        let start = Instant::now();
+        match client
+            .request_list(RPCCommand::RequestScannedWifiNetworks)
+            .await
+            .unwrap()
+        {
+            ResultList::Partial(results, ClientErr::Timeout) => assert_eq!(results.len(), 1),
+            r => panic!("unexpected {:?}", r),
+        }
+        // the rest of the list gets the inter-frame timeout, not the request's
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn progress() {
+        let (host, dev) = tokio::io::duplex(1024);
+        device(
+            dev,
+            vec![vec![
+                ImprovPacket::CurrentState(CurrentState::Provisioning),
+                ImprovPacket::CurrentState(CurrentState::Provisioning),
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+                ImprovPacket::RPCResult(RPCResult {
+                    command: 0x01,
+                    data: vec![b"http://192.168.1.2".to_vec()],
+                }),
+            ]],
+        );
+
+        let mut client = AsyncImprovClient::new(host);
+        let progress = client.progress();
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        client
+            .provision(&settings, &ProvisionOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            progress.try_iter().collect::<Vec<_>>(),
+            [
+                Progress::CommandSent(0x01),
+                Progress::StateChanged(CurrentState::Provisioning),
+                Progress::StateChanged(CurrentState::Provisioned),
+                Progress::ResultReceived(0x01),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn send_wifi_settings_fails() {
+        let (host, dev) = tokio::io::duplex(1024);
+        device(
+            dev,
+            vec![vec![
+                ImprovPacket::CurrentState(CurrentState::Provisioning),
+                ImprovPacket::ErrorState(ErrorState::UnableToConnect),
+            ]],
+        );
+
+        let mut client = AsyncImprovClient::new(host);
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        assert!(matches!(
+            client.send_wifi_settings(&settings).await,
+            Err(ClientErr::Device(ErrorState::UnableToConnect)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn provision_without_redirect() {
+        let (host, dev) = tokio::io::duplex(1024);
+        device(
+            dev,
+            vec![vec![
+                ImprovPacket::CurrentState(CurrentState::Provisioning),
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+            ]],
+        );
+
+        let mut client = AsyncImprovClient::new(host);
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        let options = ProvisionOptions {
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        assert_eq!(
+            client.provision(&settings, &options).await.unwrap(),
+            ProvisionOutcome::Provisioned(None),
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let (host, _dev) = tokio::io::duplex(1024);
+        let mut client = AsyncImprovClient::new(host);
+        client.set_timeout(Duration::from_millis(10));
+        assert!(matches!(
+            client.current_state().await,
+            Err(ClientErr::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn wait_for_state() {
+        let (host, dev) = tokio::io::duplex(1024);
+        device(
+            dev,
+            vec![vec![
+                ImprovPacket::CurrentState(CurrentState::Ready),
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+            ]],
+        );
+
+        let mut client = AsyncImprovClient::new(host);
+        client
+            .wait_for_state(CurrentState::Provisioned, Duration::from_secs(1))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn watch_until() {
+        let (host, mut dev) = tokio::io::duplex(1024);
+        for s in [CurrentState::Provisioning, CurrentState::Provisioned] {
+            let frame = Vec::try_from(ImprovPacket::CurrentState(s)).unwrap();
+            tokio::io::AsyncWriteExt::write_all(&mut dev, &frame)
+                .await
+                .unwrap();
+        }
+
+        let mut client = AsyncImprovClient::new(host);
+        client
+            .watch_until(CurrentState::Provisioned, Duration::from_secs(1))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn malformed_packet() {
+        use tokio::io::AsyncWriteExt;
+
+        let (host, mut dev) = tokio::io::duplex(1024);
+        // a state from a version of the protocol this doesn't speak
+        let mut frame = b"IMPROV\x02\x01\x01\x02\x00".to_vec();
+        let n = frame.len();
+        frame[n - 1] = crate::checksum(&frame[..n - 1]);
+        dev.write_all(&frame).await.unwrap();
+
+        let mut client = AsyncImprovClient::new(host);
+        assert!(matches!(
+            client.recv().await,
+            Err(ClientErr::Improv(ImprovErr::UnsupportedVersion(2)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cancel_and_carry_on() {
+        let (host, dev) = tokio::io::duplex(1024);
+        // the scan is never answered, and its late results mustn't confuse what follows
+        device(
+            dev,
+            vec![
+                vec![],
+                vec![
+                    network("anthill", "-40"),
+                    ImprovPacket::CurrentState(CurrentState::Ready),
+                ],
+            ],
+        );
+
+        let mut client = AsyncImprovClient::new(host);
+        let cancel = tokio::time::sleep(Duration::from_millis(10));
+        assert!(matches!(
+            cancellable(cancel, client.scan_networks()).await,
+            Err(ClientErr::Cancelled)
+        ));
+        assert_eq!(client.current_state().await.unwrap(), CurrentState::Ready);
+    }
+
+    #[tokio::test]
+    async fn sends_what_the_sync_client_does() {
+        use tokio::io::AsyncReadExt;
+
+        let (host, mut dev) = tokio::io::duplex(1024);
+        let mut client = AsyncImprovClient::new(host);
+        client
+            .send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))
+            .await
+            .unwrap();
+        drop(client);
+        let mut sent = Vec::new();
+        dev.read_to_end(&mut sent).await.unwrap();
+        assert_eq!(sent, [&crate::REQUEST_CURRENT_STATE[..], b"\n"].concat());
+    }
+
+    #[tokio::test]
+    async fn cancelled_send_is_finished() {
+        // too small for a frame, so the write stalls partway until the device reads
+        let (host, dev) = tokio::io::duplex(4);
+        let mut client = AsyncImprovClient::new(host);
+        let cancel = tokio::time::sleep(Duration::from_millis(10));
+        assert!(matches!(
+            cancellable(cancel, client.identify()).await,
+            Err(ClientErr::Cancelled)
+        ));
+
+        let device = tokio::spawn(async move {
+            let mut framed = Framed::new(dev, ImprovCodec);
+            let first = framed.next().await.unwrap().unwrap();
+            let second = framed.next().await.unwrap().unwrap();
+            (first, second)
+        });
+        client
+            .send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))
+            .await
+            .unwrap();
+        assert_eq!(
+            device.await.unwrap(),
+            (
+                ImprovPacket::RPCCommand(RPCCommand::custom(&Identify).unwrap()),
+                ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState),
+            ),
+        );
+    }
+
+    #[test]
+    fn consume_keeps_the_rest() {
+        let mut frame = Outgoing(b"IMPROV".to_vec());
+        frame.consume(2);
+        assert_eq!(frame.0, b"PROV");
+        frame.consume(4);
+        assert!(frame.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn skips_console_noise() {
+        let (host, mut dev) = tokio::io::duplex(1024);
+        let frame = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        let mut noisy = b"I (312) wifi: started\r\nIMPROV".to_vec();
+        noisy.extend_from_slice(&frame);
+        tokio::io::AsyncWriteExt::write_all(&mut dev, &noisy)
+            .await
+            .unwrap();
+
+        let mut client = AsyncImprovClient::new(host);
+        assert_eq!(
+            client.recv().await.unwrap(),
+            ImprovPacket::CurrentState(CurrentState::Ready)
+        );
+    }
+
+    #[tokio::test]
+    async fn packet_reader() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut dev, host) = tokio::io::duplex(1024);
+        let mut r = AsyncPacketReader::new(host);
+        let frame = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        dev.write_all(b"[I][wifi]: starting\r\n").await.unwrap();
+        dev.write_all(&frame[..5]).await.unwrap();
+        let short = Duration::from_millis(10);
+        assert!(matches!(
+            r.read_packet(short).await,
+            Err(ClientErr::Timeout)
+        ));
+
+        // what was read before the timeout is kept
+        dev.write_all(&frame[5..]).await.unwrap();
+        assert_eq!(
+            r.read_packet(short).await.unwrap(),
+            ImprovPacket::CurrentState(CurrentState::Ready)
+        );
+
+        drop(dev);
+        assert!(matches!(
+            r.read_packet(short).await,
+            Err(ClientErr::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn smol() {
+        use crate::MockImprovDevice;
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = MockImprovDevice::new().serve(stream);
+        });
+
+        async_io::block_on(async {
+            let io = async_io::Async::<TcpStream>::connect(addr).await.unwrap();
+            let mut client = AsyncImprovClient::with_timer(io, SmolTimer);
+            assert_eq!(client.current_state().await.unwrap(), CurrentState::Ready);
+        });
+    }
+}
@@ -0,0 +1,495 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! The BLE transport of Improv.
+//!
+//! Over BLE there is no `IMPROV` framing: each GATT characteristic carries a single value, and RPC
+//! commands and results are written/notified as `[command, len, data..., checksum]`.
+//!
+//! For the device side, `Peripheral` turns writes to the RPC command characteristic into new
+//! values for the others, leaving the GATT server itself (`bluer`, nrf-softdevice, TrouBLE...) to
+//! the firmware.
+
+use core::time::Duration;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::server::{CredentialStore, ImprovServer, WifiProvisioner};
+use crate::{
+    checksum, wipe, Capabilities, ErrorState, ImprovErr, ImprovPacket, Mode, RPCResult,
+    WifiSettings,
+};
+
+pub const SERVICE_UUID: u128 = 0x00467768_6228_2272_4663_277478268000;
+pub const CURRENT_STATE_UUID: u128 = 0x00467768_6228_2272_4663_277478268001;
+pub const ERROR_STATE_UUID: u128 = 0x00467768_6228_2272_4663_277478268002;
+pub const RPC_COMMAND_UUID: u128 = 0x00467768_6228_2272_4663_277478268003;
+pub const RPC_RESULT_UUID: u128 = 0x00467768_6228_2272_4663_277478268004;
+pub const CAPABILITIES_UUID: u128 = 0x00467768_6228_2272_4663_277478268005;
+
+/// The 16-bit UUID under which the service data is advertised.
+pub const SERVICE_DATA_UUID: u16 = 0x4677;
+
+/// Set in the capabilities characteristic when the device supports `RPCCommand::Identify`.
+pub const CAPABILITY_IDENTIFY: u8 = Capabilities::IDENTIFY.bits();
+
+/// The BLE current states. Unlike serial, BLE devices may require the user to authorize
+/// provisioning (e.g. by pressing a button) before accepting credentials.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CurrentState {
+    AuthorizationRequired,
+    Authorized,
+    Provisioning,
+    Provisioned,
+}
+
+impl From<CurrentState> for u8 {
+    fn from(c: CurrentState) -> u8 {
+        match c {
+            CurrentState::AuthorizationRequired => 0x01,
+            CurrentState::Authorized => 0x02,
+            CurrentState::Provisioning => 0x03,
+            CurrentState::Provisioned => 0x04,
+        }
+    }
+}
+
+impl TryFrom<u8> for CurrentState {
+    type Error = ImprovErr;
+
+    fn try_from(b: u8) -> Result<CurrentState, ImprovErr> {
+        match b {
+            0x01 => Ok(CurrentState::AuthorizationRequired),
+            0x02 => Ok(CurrentState::Authorized),
+            0x03 => Ok(CurrentState::Provisioning),
+            0x04 => Ok(CurrentState::Provisioned),
+            b => Err(ImprovErr::InvalidCurrentStateByte(b)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RPCCommand {
+    SendWifiSettings(WifiSettings),
+    /// Ask the device to make itself known, e.g. by blinking an LED.
+    Identify,
+}
+
+impl RPCCommand {
+    /// Encode as a value for the RPC command characteristic.
+    pub fn encode(&self) -> Result<Vec<u8>, ImprovErr> {
+        let mut v = match self {
+            RPCCommand::SendWifiSettings(w) => {
+                let mut data: Vec<u8> = w.clone().try_into()?;
+                let mut v = vec![0x01, data.len() as u8];
+                v.extend_from_slice(&data);
+                wipe(&mut data);
+                v
+            }
+            RPCCommand::Identify => vec![0x02, 0x00],
+        };
+        v.push(checksum(&v));
+        Ok(v)
+    }
+}
+
+impl TryFrom<&[u8]> for RPCCommand {
+    type Error = ImprovErr;
+
+    fn try_from(b: &[u8]) -> Result<RPCCommand, ImprovErr> {
+        let data = unchecksum(b)?;
+        match data[0] {
+            0x01 => Ok(RPCCommand::SendWifiSettings(WifiSettings::decode(
+                &data[2..],
+                Mode::Strict,
+            )?)),
+            0x02 => Ok(RPCCommand::Identify),
+            cmd => Err(ImprovErr::InvalidRPCCommand(cmd)),
+        }
+    }
+}
+
+/// Encode an RPC result as a value for the RPC result characteristic.
+pub fn encode_result(r: &RPCResult) -> Result<Vec<u8>, ImprovErr> {
+    r.validate()?;
+    let mut v = vec![0; r.inner_len()];
+    r.write_inner(&mut v)?;
+    v.push(checksum(&v));
+    Ok(v)
+}
+
+/// Decode a value read from the RPC result characteristic.
+pub fn decode_result(b: &[u8]) -> Result<RPCResult, ImprovErr> {
+    RPCResult::try_from(unchecksum(b)?)
+}
+
+/// Encode capabilities as a value for the capabilities characteristic.
+pub fn encode_capabilities(c: Capabilities) -> [u8; 1] {
+    [c.bits()]
+}
+
+/// Decode a value read from the capabilities characteristic.
+pub fn decode_capabilities(b: &[u8]) -> Result<Capabilities, ImprovErr> {
+    b.first()
+        .map(|&b| Capabilities::from(b))
+        .ok_or(ImprovErr::Truncated)
+}
+
+/// Decode a value read from the current state characteristic.
+pub fn decode_state(b: &[u8]) -> Result<CurrentState, ImprovErr> {
+    CurrentState::try_from(*b.first().ok_or(ImprovErr::Truncated)?)
+}
+
+/// Decode a value read from the error state characteristic.
+pub fn decode_error(b: &[u8]) -> Result<ErrorState, ImprovErr> {
+    ErrorState::try_from(*b.first().ok_or(ImprovErr::Truncated)?)
+}
+
+/// The service data to advertise under `SERVICE_DATA_UUID`: the state, the capabilities, and four
+/// reserved bytes.
+pub fn service_data(state: CurrentState, capabilities: Capabilities) -> [u8; 6] {
+    [state.into(), capabilities.bits(), 0, 0, 0, 0]
+}
+
+/// Decode advertised service data into the device's state and capabilities.
+pub fn decode_service_data(b: &[u8]) -> Result<(CurrentState, Capabilities), ImprovErr> {
+    match b {
+        [state, capabilities, ..] => Ok((
+            CurrentState::try_from(*state)?,
+            Capabilities::from(*capabilities),
+        )),
+        _ => Err(ImprovErr::Truncated),
+    }
+}
+
+/// A new value for one of the characteristics, which the firmware should set and notify
+/// subscribers of.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Update {
+    CurrentState(CurrentState),
+    ErrorState(ErrorState),
+    /// Already encoded, as `encode_result` does.
+    RPCResult(Vec<u8>),
+}
+
+impl Update {
+    /// The characteristic to update.
+    pub fn uuid(&self) -> u128 {
+        match self {
+            Update::CurrentState(_) => CURRENT_STATE_UUID,
+            Update::ErrorState(_) => ERROR_STATE_UUID,
+            Update::RPCResult(_) => RPC_RESULT_UUID,
+        }
+    }
+
+    /// Its new value.
+    pub fn value(&self) -> Vec<u8> {
+        match self {
+            Update::CurrentState(s) => vec![u8::from(*s)],
+            Update::ErrorState(e) => vec![u8::from(*e)],
+            Update::RPCResult(v) => v.clone(),
+        }
+    }
+}
+
+/// The device side of Improv over BLE. Provisioning is handled by an `ImprovServer`, so the same
+/// `WifiProvisioner` (and `CredentialStore`) serve both transports, and it's the server's
+/// authorization gate which the BLE states reflect.
+///
+/// Serve the characteristics with the values `current_state`, `error_state` and `capabilities`
+/// give, advertise `service_data`, and pass every write to the RPC command characteristic to
+/// `write_command`, then apply the updates it returns in order.
+pub struct Peripheral<P, S = ()> {
+    server: ImprovServer<P, S>,
+    capabilities: Capabilities,
+}
+
+impl<P: WifiProvisioner> Peripheral<P> {
+    pub fn new(provisioner: P, capabilities: Capabilities) -> Peripheral<P> {
+        Peripheral {
+            server: ImprovServer::new(provisioner),
+            capabilities,
+        }
+    }
+}
+
+impl<P: WifiProvisioner, S: CredentialStore> Peripheral<P, S> {
+    /// See `ImprovServer::with_store`.
+    pub fn with_store<T: CredentialStore>(self, store: T) -> Peripheral<P, T> {
+        Peripheral {
+            server: self.server.with_store(store),
+            capabilities: self.capabilities,
+        }
+    }
+
+    /// See `ImprovServer::require_authorization`.
+    pub fn require_authorization(&mut self, timeout: Duration) -> Option<Update> {
+        self.changing_state(|s| s.require_authorization(timeout))
+    }
+
+    /// Accept credentials until the authorization timeout elapses, e.g. once the user has pressed
+    /// a button.
+    pub fn authorize(&mut self) -> Option<Update> {
+        self.changing_state(ImprovServer::authorize)
+    }
+
+    /// Let `elapsed` pass on the authorization timeout. Call this periodically when authorization
+    /// is required.
+    pub fn tick(&mut self, elapsed: Duration) -> Option<Update> {
+        self.changing_state(|s| {
+            s.tick(elapsed);
+        })
+    }
+
+    /// A write to the RPC command characteristic.
+    pub fn write_command(&mut self, value: &[u8]) -> Vec<Update> {
+        let packets = match RPCCommand::try_from(value) {
+            Ok(RPCCommand::SendWifiSettings(settings)) => self
+                .server
+                .answer(crate::RPCCommand::SendWifiSettings(settings)),
+            Ok(RPCCommand::Identify) => {
+                let mut out = self.server.clear_error();
+                if let Err(e) = self.server.provisioner().identify() {
+                    out.push(self.server.set_error(e));
+                }
+                out
+            }
+            Err(e) => vec![self.server.set_error(e.into())],
+        };
+        packets.into_iter().filter_map(|p| self.update(p)).collect()
+    }
+
+    pub fn current_state(&self) -> CurrentState {
+        self.server.ble_state()
+    }
+
+    pub fn error_state(&self) -> ErrorState {
+        self.server.error()
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// The service data to advertise, which changes with the state.
+    pub fn service_data(&self) -> [u8; 6] {
+        service_data(self.current_state(), self.capabilities)
+    }
+
+    pub fn server(&mut self) -> &mut ImprovServer<P, S> {
+        &mut self.server
+    }
+
+    // the update for a change to the server's authorization, if it changed the state
+    fn changing_state(&mut self, f: impl FnOnce(&mut ImprovServer<P, S>)) -> Option<Update> {
+        let before = self.current_state();
+        f(&mut self.server);
+        let after = self.current_state();
+        (after != before).then_some(Update::CurrentState(after))
+    }
+
+    fn update(&self, p: ImprovPacket) -> Option<Update> {
+        match p {
+            ImprovPacket::CurrentState(crate::CurrentState::Provisioning) => {
+                Some(Update::CurrentState(CurrentState::Provisioning))
+            }
+            ImprovPacket::CurrentState(crate::CurrentState::Provisioned) => {
+                Some(Update::CurrentState(CurrentState::Provisioned))
+            }
+            // back to waiting, with or without authorization
+            ImprovPacket::CurrentState(_) => {
+                let state = if self.server.is_authorized() {
+                    CurrentState::Authorized
+                } else {
+                    CurrentState::AuthorizationRequired
+                };
+                Some(Update::CurrentState(state))
+            }
+            ImprovPacket::ErrorState(e) => Some(Update::ErrorState(e)),
+            ImprovPacket::RPCResult(r) => encode_result(&r).ok().map(Update::RPCResult),
+            _ => None,
+        }
+    }
+}
+
+// check the trailing checksum and the length byte, returning `[command, len, data...]`
+fn unchecksum(b: &[u8]) -> Result<&[u8], ImprovErr> {
+    let (&sum, data) = b.split_last().ok_or(ImprovErr::Truncated)?;
+    if data.len() < 2 {
+        return Err(ImprovErr::Truncated);
+    }
+    if checksum(data) != sum {
+        return Err(ImprovErr::BadChecksum {
+            expected: checksum(data),
+            found: sum,
+        });
+    }
+    if data[1] as usize != data.len() - 2 {
+        return Err(ImprovErr::BadLength {
+            expected: data[1] as usize,
+            found: data.len() - 2,
+        });
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::rpc_result;
+    use crate::DeviceInfo;
+    use alloc::string::String;
+
+    #[test]
+    fn identify() {
+        assert_eq!(RPCCommand::Identify.encode(), Ok(vec![0x02, 0x00, 0x02]));
+        assert_eq!(
+            RPCCommand::try_from(&[0x02, 0x00, 0x02][..]),
+            Ok(RPCCommand::Identify)
+        );
+        assert_eq!(
+            RPCCommand::try_from(&[0x02, 0x00, 0x03][..]),
+            Err(ImprovErr::BadChecksum {
+                expected: 0x02,
+                found: 0x03
+            })
+        );
+    }
+
+    #[test]
+    fn send_wifi_settings_round_trip() {
+        let cmd = RPCCommand::SendWifiSettings(WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        });
+        let v = cmd.encode().unwrap();
+        assert_eq!(&v[..3], &[0x01, 0x19, 0x07]);
+        assert_eq!(RPCCommand::try_from(&v[..]), Ok(cmd));
+    }
+
+    #[test]
+    fn result_round_trip() {
+        let r = rpc_result(0x01, ["http://192.168.1.2"]);
+        let v = encode_result(&r).unwrap();
+        assert_eq!(decode_result(&v), Ok(r));
+    }
+
+    #[test]
+    fn capabilities() {
+        let c = decode_capabilities(&encode_capabilities(Capabilities::IDENTIFY)).unwrap();
+        assert!(c.supports_identify());
+        assert_eq!(decode_capabilities(&[]), Err(ImprovErr::Truncated));
+    }
+
+    struct Sensor {
+        blinks: usize,
+    }
+
+    impl WifiProvisioner for Sensor {
+        fn connect(&mut self, _ssid: &[u8], psk: &str) -> Result<Option<String>, ErrorState> {
+            match psk {
+                "ants in my pants" => Ok(Some(String::from("http://192.168.1.2"))),
+                _ => Err(ErrorState::UnableToConnect),
+            }
+        }
+
+        fn device_info(&mut self) -> DeviceInfo {
+            DeviceInfo {
+                firmware_name: String::from("improv-rs"),
+                firmware_version: String::from("0.1.0"),
+                hardware: String::from("nRF52840"),
+                device_name: String::from("anthill-sensor"),
+            }
+        }
+
+        fn identify(&mut self) -> Result<(), ErrorState> {
+            self.blinks += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn peripheral() {
+        let mut p = Peripheral::new(Sensor { blinks: 0 }, Capabilities::IDENTIFY);
+        assert_eq!(
+            p.require_authorization(Duration::from_secs(60)),
+            Some(Update::CurrentState(CurrentState::AuthorizationRequired))
+        );
+        assert_eq!(p.service_data(), [0x01, 0x01, 0, 0, 0, 0]);
+        assert_eq!(
+            decode_service_data(&p.service_data()),
+            Ok((CurrentState::AuthorizationRequired, Capabilities::IDENTIFY))
+        );
+
+        let settings = |psk: &str| {
+            RPCCommand::SendWifiSettings(WifiSettings {
+                ssid: "anthill".into(),
+                psk: String::from(psk),
+            })
+            .encode()
+            .unwrap()
+        };
+        assert_eq!(
+            p.write_command(&settings("ants in my pants")),
+            [Update::ErrorState(ErrorState::NotAuthorized)]
+        );
+
+        // identifying doesn't need authorization
+        assert_eq!(
+            p.write_command(&RPCCommand::Identify.encode().unwrap()),
+            [Update::ErrorState(ErrorState::NoError)]
+        );
+        assert_eq!(p.server().provisioner().blinks, 1);
+
+        assert_eq!(
+            p.authorize(),
+            Some(Update::CurrentState(CurrentState::Authorized))
+        );
+        assert_eq!(
+            p.write_command(&settings("hunter2")),
+            [
+                Update::CurrentState(CurrentState::Provisioning),
+                Update::CurrentState(CurrentState::Authorized),
+                Update::ErrorState(ErrorState::UnableToConnect),
+            ]
+        );
+        let updates = p.write_command(&settings("ants in my pants"));
+        assert_eq!(
+            updates[..3],
+            [
+                Update::ErrorState(ErrorState::NoError),
+                Update::CurrentState(CurrentState::Provisioning),
+                Update::CurrentState(CurrentState::Provisioned),
+            ]
+        );
+        assert_eq!(updates[3].uuid(), RPC_RESULT_UUID);
+        assert_eq!(
+            decode_result(&updates[3].value()),
+            Ok(rpc_result(0x01, ["http://192.168.1.2"]))
+        );
+        assert_eq!(decode_state(&updates[2].value()), Ok(p.current_state()));
+        assert_eq!(p.tick(Duration::from_secs(60)), None);
+
+        assert_eq!(
+            p.write_command(&[0x01, 0x00]),
+            [Update::ErrorState(ErrorState::InvalidRPCPacket)]
+        );
+        assert_eq!(
+            decode_error(&Update::ErrorState(ErrorState::InvalidRPCPacket).value()),
+            Ok(p.error_state())
+        );
+    }
+
+    #[test]
+    fn states() {
+        for b in 0x01..=0x04 {
+            assert_eq!(u8::from(CurrentState::try_from(b).unwrap()), b);
+        }
+        assert_eq!(
+            CurrentState::try_from(0x00),
+            Err(ImprovErr::InvalidCurrentStateByte(0x00))
+        );
+    }
+}
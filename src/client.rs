@@ -0,0 +1,1767 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "serial")]
+use crate::serial::{SerialOptions, SerialPort};
+use crate::verify::{self, Verified};
+use crate::{
+    decode_frame, CommandId, CurrentState, CustomCommand, DeviceInfo, DeviceStateTracker,
+    ErrorState, Identify, ImprovErr, ImprovPacket, IoTransport, RPCCommand, RPCResult,
+    Reconnecting, Transport, WifiNetwork, WifiSettings, FRAME_TERMINATOR,
+};
+
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a device may pause between the results of a list before it's considered stalled
+pub(crate) const DEFAULT_INTER_FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+// how often wait_for_state asks again while the device is quiet
+pub(crate) const STATE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum ClientErr {
+    Io(io::Error),
+    Improv(ImprovErr),
+    /// The device reported an error instead of answering.
+    Device(ErrorState),
+    /// The device did not answer in time.
+    Timeout,
+    /// The caller gave up on the request; see `cancellable`.
+    Cancelled,
+    /// The device isn't running the firmware `ProvisionOptions::expect_firmware` asked for, so it
+    /// wasn't sent the credentials.
+    UnexpectedFirmware(DeviceInfo),
+}
+
+impl From<io::Error> for ClientErr {
+    fn from(e: io::Error) -> ClientErr {
+        ClientErr::Io(e)
+    }
+}
+
+impl From<ImprovErr> for ClientErr {
+    fn from(e: ImprovErr) -> ClientErr {
+        ClientErr::Improv(e)
+    }
+}
+
+impl fmt::Display for ClientErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientErr::Io(e) => write!(f, "I/O error: {}", e),
+            ClientErr::Improv(e) => write!(f, "protocol error: {}", e),
+            ClientErr::Device(e) => write!(f, "device reported an error: {}", e),
+            ClientErr::Timeout => write!(f, "timed out waiting for the device"),
+            ClientErr::Cancelled => write!(f, "cancelled"),
+            ClientErr::UnexpectedFirmware(info) => write!(
+                f,
+                "device is running {} {}, not the expected firmware",
+                info.firmware_name, info.firmware_version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientErr {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientErr::Io(e) => Some(e),
+            ClientErr::Improv(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// A request may be answered by several packets, possibly interleaved with unrelated ones. The
+// functions below build matchers which are fed every incoming packet until they produce the
+// answer to their request.
+
+fn check_error(p: &ImprovPacket) -> Result<(), ClientErr> {
+    match p {
+        ImprovPacket::ErrorState(ErrorState::NoError) => Ok(()),
+        ImprovPacket::ErrorState(e) => Err(ClientErr::Device(*e)),
+        _ => Ok(()),
+    }
+}
+
+fn result_for(id: u8, p: ImprovPacket) -> Result<Option<RPCResult>, ClientErr> {
+    check_error(&p)?;
+    match p {
+        ImprovPacket::RPCResult(r) if r.command == id => Ok(Some(r)),
+        _ => Ok(None),
+    }
+}
+
+pub(crate) fn custom_result(
+    id: u8,
+) -> impl FnMut(ImprovPacket) -> Result<Option<RPCResult>, ClientErr> {
+    move |p| result_for(id, p)
+}
+
+pub(crate) fn current_state(p: ImprovPacket) -> Result<Option<CurrentState>, ClientErr> {
+    check_error(&p)?;
+    match p {
+        ImprovPacket::CurrentState(s) => Ok(Some(s)),
+        _ => Ok(None),
+    }
+}
+
+pub(crate) fn device_info(p: ImprovPacket) -> Result<Option<DeviceInfo>, ClientErr> {
+    match result_for(RPCCommand::RequestDeviceInformation.id(), p)? {
+        Some(r) => Ok(Some(DeviceInfo::try_from(r)?)),
+        None => Ok(None),
+    }
+}
+
+// the networks of a complete scan. a partial one fails with whatever cut it short
+pub(crate) fn networks(list: ResultList) -> Result<Vec<WifiNetwork>, ClientErr> {
+    match list {
+        ResultList::Complete(results) => Ok(results
+            .into_iter()
+            .map(WifiNetwork::try_from)
+            .collect::<Result<_, _>>()?),
+        ResultList::Partial(_, e) => Err(e),
+    }
+}
+
+/// The results of a command which the device answers with a list, such as a scan.
+#[derive(Debug)]
+pub enum ResultList {
+    /// Every result, not counting the empty one which ends the list.
+    Complete(Vec<RPCResult>),
+    /// The results received before the device stalled or failed, and why.
+    Partial(Vec<RPCResult>, ClientErr),
+}
+
+/// Collects the results of a list, which ends with an empty result. Packets for other commands
+/// are skipped, and errors the device reports end the list early.
+pub struct ResultAssembler {
+    command: u8,
+    results: Vec<RPCResult>,
+    complete: bool,
+}
+
+impl ResultAssembler {
+    pub fn new(command: u8) -> ResultAssembler {
+        ResultAssembler {
+            command,
+            results: Vec::new(),
+            complete: false,
+        }
+    }
+
+    /// Returns whether the packet was one of the list's results.
+    pub fn feed(&mut self, p: ImprovPacket) -> Result<bool, ClientErr> {
+        match result_for(self.command, p)? {
+            Some(r) if r.data.is_empty() => self.complete = true,
+            Some(r) => self.results.push(r),
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// The results so far, which are only partial if the end of the list hasn't arrived.
+    pub fn finish(self) -> ResultList {
+        if self.complete {
+            ResultList::Complete(self.results)
+        } else {
+            ResultList::Partial(self.results, ClientErr::Timeout)
+        }
+    }
+
+    pub fn fail(self, e: ClientErr) -> ResultList {
+        ResultList::Partial(self.results, e)
+    }
+}
+
+fn provisioned(p: ImprovPacket) -> Result<Option<Option<String>>, ClientErr> {
+    check_error(&p)?;
+    // the device reports Provisioning, then either an error or the result with the redirect URL
+    match p {
+        ImprovPacket::RPCResult(r) if r.command == CommandId::SendWifiSettings as u8 => {
+            Ok(Some(redirect_url(r)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn redirect_url(r: RPCResult) -> Result<Option<String>, ClientErr> {
+    match r.data.into_iter().next() {
+        Some(url) => Ok(Some(
+            String::from_utf8(url).map_err(|_| ImprovErr::InvalidUtf8)?,
+        )),
+        None => Ok(None),
+    }
+}
+
+// the progress event a packet makes, if any, once `tracker` has taken it in
+pub(crate) fn progress(tracker: &mut DeviceStateTracker, p: &ImprovPacket) -> Option<Progress> {
+    let change = tracker.ingest(p);
+    match p {
+        ImprovPacket::CurrentState(s) if change.is_some() => Some(Progress::StateChanged(*s)),
+        ImprovPacket::RPCResult(r) => Some(Progress::ResultReceived(r.command)),
+        ImprovPacket::ErrorState(e) if *e != ErrorState::NoError => {
+            count!("improv_rpc_errors_total", "error" => e.to_string());
+            Some(Progress::Error(*e))
+        }
+        _ => None,
+    }
+}
+
+// forward a packet or event to every subscriber, forgetting the ones that have hung up
+pub(crate) fn notify<E: Clone>(subscribers: &mut Vec<mpsc::Sender<E>>, e: &E) {
+    subscribers.retain(|s| s.send(e.clone()).is_ok());
+}
+
+/// What the client is doing, for showing progress while it works. See `ImprovClient::progress`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Progress {
+    /// A command with this id was sent.
+    CommandSent(u8),
+    /// The device reported a state other than the last one it reported.
+    StateChanged(CurrentState),
+    /// The device sent a result for the command with this id.
+    ResultReceived(u8),
+    /// The device reported an error.
+    Error(ErrorState),
+    /// The device was found on the network after provisioning.
+    Verified(Verified),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backoff {
+    Fixed(Duration),
+    /// Double the delay after each attempt, up to `max`.
+    Exponential {
+        initial: Duration,
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    /// The delay before retrying after `attempt` attempts (counting from 1) have failed.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match *self {
+            Backoff::Fixed(d) => d,
+            Backoff::Exponential { initial, max } => initial
+                .saturating_mul(1 << attempt.saturating_sub(1).min(31))
+                .min(max),
+        }
+    }
+}
+
+/// How a client retries requests which fail. `provision` and `wait_provisioned` follow the device
+/// rather than making a request, and aren't retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first.
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    /// Whether a failure is worth another attempt.
+    pub retryable: fn(&ClientErr) -> bool,
+}
+
+impl RetryPolicy {
+    /// Give up after the first failure. This is the default.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Backoff::Fixed(Duration::ZERO),
+            retryable: RetryPolicy::transient,
+        }
+    }
+
+    /// Failures a noisy serial link can cause: timeouts, garbled frames, and the device not
+    /// understanding what it received.
+    pub fn transient(e: &ClientErr) -> bool {
+        matches!(
+            e,
+            ClientErr::Timeout
+                | ClientErr::Improv(_)
+                | ClientErr::Device(ErrorState::InvalidRPCPacket)
+        )
+    }
+
+    pub(crate) fn retry_after(&self, attempt: u32, e: &ClientErr) -> Option<Duration> {
+        (attempt < self.max_attempts && (self.retryable)(e)).then(|| self.backoff.delay(attempt))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::never()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ProvisionOptions {
+    /// How long to wait for the device to join the network. Connecting usually takes longer than
+    /// answering a request, so this is separate from the client's timeout.
+    pub timeout: Duration,
+    /// Ask the device for its state whenever it's been quiet this long, for devices which don't
+    /// report on their own.
+    pub poll_interval: Option<Duration>,
+    /// Check the device's information first, and refuse to send credentials to the wrong board.
+    pub expect_firmware: Option<ExpectedFirmware>,
+}
+
+// what `send_wifi_settings` uses unless told otherwise
+pub(crate) fn polling() -> ProvisionOptions {
+    ProvisionOptions {
+        poll_interval: Some(STATE_POLL_INTERVAL),
+        ..Default::default()
+    }
+}
+
+impl Default for ProvisionOptions {
+    fn default() -> ProvisionOptions {
+        ProvisionOptions {
+            timeout: Duration::from_secs(30),
+            poll_interval: None,
+            expect_firmware: None,
+        }
+    }
+}
+
+/// The firmware a device has to be running to be provisioned.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExpectedFirmware {
+    /// Compared ignoring case.
+    pub name: Option<String>,
+    /// Versions are compared by their numeric parts, so `2024.6` is older than `2024.10.1`.
+    pub min_version: Option<String>,
+}
+
+impl ExpectedFirmware {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        self.name
+            .as_ref()
+            .is_none_or(|name| name.eq_ignore_ascii_case(&info.firmware_name))
+            && self
+                .min_version
+                .as_ref()
+                .is_none_or(|min| version_parts(&info.firmware_version) >= version_parts(min))
+    }
+
+    pub(crate) fn check(&self, info: DeviceInfo) -> Result<(), ClientErr> {
+        if !self.matches(&info) {
+            return Err(ClientErr::UnexpectedFirmware(info));
+        }
+        Ok(())
+    }
+}
+
+// the leading number of each dotted part, up to the first without one, so `v2024.6.0b1` and
+// `2024.6-dev` are both 2024.6.0. trailing zeros are dropped, making 2024.6 equal to 2024.6.0
+fn version_parts(version: &str) -> Vec<u64> {
+    let mut parts: Vec<u64> = version
+        .trim_start_matches(['v', 'V'])
+        .split(['.', '-', '+'])
+        .map_while(|part| {
+            let digits = part.len() - part.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            part[..digits].parse().ok()
+        })
+        .collect();
+    while parts.last() == Some(&0) {
+        parts.pop();
+    }
+    parts
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProvisionOutcome {
+    /// The device joined the network, and sent its redirect URL if it has one.
+    Provisioned(Option<String>),
+    /// The device couldn't join the network with the given credentials.
+    UnableToConnect,
+    /// The device refused the credentials with some other error.
+    Rejected(ErrorState),
+}
+
+impl ProvisionOutcome {
+    /// The redirect URL if the device joined the network, and otherwise the error it reported.
+    pub fn into_result(self) -> Result<Option<String>, ClientErr> {
+        match self {
+            ProvisionOutcome::Provisioned(url) => Ok(url),
+            ProvisionOutcome::UnableToConnect => {
+                Err(ClientErr::Device(ErrorState::UnableToConnect))
+            }
+            ProvisionOutcome::Rejected(e) => Err(ClientErr::Device(e)),
+        }
+    }
+}
+
+// follows the device through Provisioning to either Provisioned or an error
+#[derive(Default)]
+pub(crate) struct Provisioning {
+    provisioned: bool,
+}
+
+impl Provisioning {
+    pub(crate) fn feed(&mut self, p: ImprovPacket) -> Result<Option<ProvisionOutcome>, ClientErr> {
+        match p {
+            ImprovPacket::ErrorState(ErrorState::NoError) => Ok(None),
+            ImprovPacket::ErrorState(ErrorState::UnableToConnect) => {
+                Ok(Some(ProvisionOutcome::UnableToConnect))
+            }
+            ImprovPacket::ErrorState(e) => Ok(Some(ProvisionOutcome::Rejected(e))),
+            ImprovPacket::CurrentState(CurrentState::Provisioned) => {
+                self.provisioned = true;
+                Ok(None)
+            }
+            // a provisioned device answers a state request with its redirect URL, too
+            ImprovPacket::RPCResult(r)
+                if self.provisioned && r.command == RPCCommand::RequestCurrentState.id() =>
+            {
+                Ok(Some(ProvisionOutcome::Provisioned(redirect_url(r)?)))
+            }
+            p => Ok(provisioned(p)?.map(ProvisionOutcome::Provisioned)),
+        }
+    }
+
+    // the device went quiet for a poll interval; if it's already provisioned it isn't going to
+    // send a URL
+    pub(crate) fn idle(&self) -> Option<ProvisionOutcome> {
+        self.provisioned
+            .then_some(ProvisionOutcome::Provisioned(None))
+    }
+
+    // the redirect URL is optional, so some devices stop talking once they're provisioned
+    pub(crate) fn timed_out(self) -> Result<ProvisionOutcome, ClientErr> {
+        if self.provisioned {
+            Ok(ProvisionOutcome::Provisioned(None))
+        } else {
+            Err(ClientErr::Timeout)
+        }
+    }
+}
+
+/// A blocking Improv client over any `Transport`.
+pub struct ImprovClient<T> {
+    transport: T,
+    timeout: Duration,
+    command_timeouts: HashMap<u8, Duration>,
+    inter_frame_timeout: Duration,
+    retry: RetryPolicy,
+    subscribers: Vec<mpsc::Sender<ImprovPacket>>,
+    progress: Vec<mpsc::Sender<Progress>>,
+    // what the device last reported, so only state changes are reported as progress
+    tracker: DeviceStateTracker,
+    provision_options: ProvisionOptions,
+}
+
+#[cfg(feature = "serial")]
+impl ImprovClient<IoTransport<Box<dyn SerialPort>>> {
+    pub fn open(
+        path: &str,
+        options: &SerialOptions,
+    ) -> Result<ImprovClient<IoTransport<Box<dyn SerialPort>>>, ClientErr> {
+        let port = options.open(path).map_err(io::Error::from)?;
+        let mut transport = IoTransport::new(port);
+        transport.set_terminator(Some(FRAME_TERMINATOR));
+        Ok(ImprovClient::new(transport))
+    }
+}
+
+impl ImprovClient<IoTransport<TcpStream>> {
+    /// Connect to a device behind a serial-to-TCP bridge such as ser2net or esp-link.
+    pub fn connect<A: ToSocketAddrs>(
+        addr: A,
+    ) -> Result<ImprovClient<IoTransport<TcpStream>>, ClientErr> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        // like a serial port, the stream has to time out for the client's timeouts to be checked
+        stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let mut transport = IoTransport::new(stream);
+        transport.set_terminator(Some(FRAME_TERMINATOR));
+        Ok(ImprovClient::new(transport))
+    }
+}
+
+impl ImprovClient<Box<dyn Transport + Send>> {
+    /// Configure a client before opening its port.
+    pub fn builder() -> ImprovClientBuilder {
+        ImprovClientBuilder::default()
+    }
+}
+
+/// Configures a client and opens the port it talks over. Anything not set is left as
+/// `ImprovClient::new` has it.
+#[derive(Clone, Debug)]
+pub struct ImprovClientBuilder {
+    port: Option<String>,
+    #[cfg(feature = "serial")]
+    serial: SerialOptions,
+    terminator: Option<u8>,
+    chunking: (Option<usize>, Duration),
+    frame_gap: Duration,
+    timeout: Duration,
+    command_timeouts: HashMap<u8, Duration>,
+    inter_frame_timeout: Duration,
+    retry: RetryPolicy,
+    provision_options: ProvisionOptions,
+    reconnect: Option<(Backoff, u32)>,
+}
+
+impl Default for ImprovClientBuilder {
+    fn default() -> ImprovClientBuilder {
+        ImprovClientBuilder {
+            port: None,
+            #[cfg(feature = "serial")]
+            serial: SerialOptions::default(),
+            terminator: Some(FRAME_TERMINATOR),
+            chunking: (None, Duration::ZERO),
+            frame_gap: Duration::ZERO,
+            timeout: DEFAULT_TIMEOUT,
+            command_timeouts: HashMap::new(),
+            inter_frame_timeout: DEFAULT_INTER_FRAME_TIMEOUT,
+            retry: RetryPolicy::default(),
+            provision_options: polling(),
+            reconnect: None,
+        }
+    }
+}
+
+impl ImprovClientBuilder {
+    /// The serial port the device is attached to, or `tcp://host:port` for a serial-to-TCP
+    /// bridge.
+    pub fn port(mut self, port: impl Into<String>) -> ImprovClientBuilder {
+        self.port = Some(port.into());
+        self
+    }
+
+    #[cfg(feature = "serial")]
+    pub fn baud(mut self, baud_rate: u32) -> ImprovClientBuilder {
+        self.serial.baud_rate = baud_rate;
+        self
+    }
+
+    /// Line settings for a serial port, replacing any baud rate set before.
+    #[cfg(feature = "serial")]
+    pub fn serial_options(mut self, options: SerialOptions) -> ImprovClientBuilder {
+        self.serial = options;
+        self
+    }
+
+    /// The byte sent after each frame, or none. See `IoTransport::set_terminator`.
+    pub fn terminator(mut self, terminator: Option<u8>) -> ImprovClientBuilder {
+        self.terminator = terminator;
+        self
+    }
+
+    /// Write frames `size` bytes at a time, `delay` apart. See `IoTransport::set_chunking`.
+    pub fn chunking(mut self, size: usize, delay: Duration) -> ImprovClientBuilder {
+        self.chunking = (Some(size), delay);
+        self
+    }
+
+    /// The least time to leave between frames. See `IoTransport::set_frame_gap`.
+    pub fn frame_gap(mut self, gap: Duration) -> ImprovClientBuilder {
+        self.frame_gap = gap;
+        self
+    }
+
+    /// See `ImprovClient::set_timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> ImprovClientBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// See `ImprovClient::set_command_timeout`.
+    pub fn command_timeout(mut self, id: u8, timeout: Duration) -> ImprovClientBuilder {
+        self.command_timeouts.insert(id, timeout);
+        self
+    }
+
+    /// See `ImprovClient::set_inter_frame_timeout`.
+    pub fn inter_frame_timeout(mut self, timeout: Duration) -> ImprovClientBuilder {
+        self.inter_frame_timeout = timeout;
+        self
+    }
+
+    /// See `ImprovClient::set_retry`.
+    pub fn retry(mut self, retry: RetryPolicy) -> ImprovClientBuilder {
+        self.retry = retry;
+        self
+    }
+
+    /// See `ImprovClient::set_provision_options`.
+    pub fn provision_options(mut self, options: ProvisionOptions) -> ImprovClientBuilder {
+        self.provision_options = options;
+        self
+    }
+
+    /// Re-open the port when it drops, e.g. as the device reboots after provisioning, trying up
+    /// to `max_attempts` times with `backoff` between tries, and only if the same device answers
+    /// on it. See `Reconnecting`.
+    pub fn reconnect(mut self, backoff: Backoff, max_attempts: u32) -> ImprovClientBuilder {
+        self.reconnect = Some((backoff, max_attempts));
+        self
+    }
+
+    /// Open the port and return the client. Fails if no port was given.
+    pub fn build(self) -> Result<ImprovClient<Box<dyn Transport + Send>>, ClientErr> {
+        let transport = self.open()?;
+        let transport: Box<dyn Transport + Send> = match self.reconnect {
+            Some((backoff, max_attempts)) => {
+                let builder = self.clone();
+                let mut transport = Reconnecting::new(transport, move || builder.open());
+                transport.set_backoff(backoff, max_attempts);
+                transport.match_device(true);
+                Box::new(transport)
+            }
+            None => transport,
+        };
+        Ok(self.build_with(transport))
+    }
+
+    fn open(&self) -> io::Result<Box<dyn Transport + Send>> {
+        let port = self.port.as_deref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no port to open was given")
+        })?;
+        let transport: Box<dyn Transport + Send> = match port.strip_prefix("tcp://") {
+            Some(addr) => {
+                let mut transport = match ImprovClient::connect(addr) {
+                    Ok(client) => client.into_inner(),
+                    Err(ClientErr::Io(e)) => return Err(e),
+                    Err(e) => return Err(io::Error::other(e.to_string())),
+                };
+                transport.set_terminator(self.terminator);
+                transport.set_chunking(self.chunking.0, self.chunking.1);
+                transport.set_frame_gap(self.frame_gap);
+                Box::new(transport)
+            }
+            #[cfg(feature = "serial")]
+            None => {
+                let port = self.serial.open(port).map_err(io::Error::from)?;
+                let mut transport = IoTransport::new(port);
+                transport.set_terminator(self.terminator);
+                transport.set_chunking(self.chunking.0, self.chunking.1);
+                transport.set_frame_gap(self.frame_gap);
+                Box::new(transport)
+            }
+            #[cfg(not(feature = "serial"))]
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "serial ports need the serial feature",
+                ))
+            }
+        };
+        Ok(transport)
+    }
+
+    /// Use `transport` rather than opening a port. The port settings are ignored.
+    pub fn build_with<T: Transport>(self, transport: T) -> ImprovClient<T> {
+        let mut client = ImprovClient::new(transport);
+        client.timeout = self.timeout;
+        client.command_timeouts = self.command_timeouts;
+        client.inter_frame_timeout = self.inter_frame_timeout;
+        client.retry = self.retry;
+        client.provision_options = self.provision_options;
+        client
+    }
+}
+
+impl<T: Transport> ImprovClient<T> {
+    pub fn new(transport: T) -> ImprovClient<T> {
+        ImprovClient {
+            transport,
+            timeout: DEFAULT_TIMEOUT,
+            command_timeouts: HashMap::new(),
+            inter_frame_timeout: DEFAULT_INTER_FRAME_TIMEOUT,
+            retry: RetryPolicy::default(),
+            subscribers: Vec::new(),
+            progress: Vec::new(),
+            tracker: DeviceStateTracker::new(),
+            provision_options: polling(),
+        }
+    }
+
+    /// Receive a copy of every packet the device sends, including state changes and errors it
+    /// pushes on its own, which requests would otherwise skip over. Packets only arrive while the
+    /// client is reading, so call `recv` when idle to keep them flowing.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<ImprovPacket> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Receive progress events: commands sent, state changes, results, errors and verification.
+    /// Like `subscribe`, these only arrive while the client is working, so read them from another
+    /// thread to drive a spinner or status line during `provision`.
+    pub fn progress(&mut self) -> mpsc::Receiver<Progress> {
+        let (tx, rx) = mpsc::channel();
+        self.progress.push(tx);
+        rx
+    }
+
+    /// The device's state and error as of the last packet the client read. Call `changes` on it
+    /// to be told as they change, which, like `subscribe`, happens while the client is reading.
+    pub fn device_state(&mut self) -> &mut DeviceStateTracker {
+        &mut self.tracker
+    }
+
+    /// How long to wait for the device to answer each request.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// How long to wait for the device to answer the command with this id, in place of the
+    /// client's timeout, e.g. to give scans longer than state queries. For a list, this is the wait
+    /// for the first result; see `set_inter_frame_timeout` for the rest.
+    pub fn set_command_timeout(&mut self, id: u8, timeout: Duration) {
+        self.command_timeouts.insert(id, timeout);
+    }
+
+    /// How long to wait for the device to answer the command with this id.
+    pub fn command_timeout(&self, id: u8) -> Duration {
+        self.command_timeouts
+            .get(&id)
+            .copied()
+            .unwrap_or(self.timeout)
+    }
+
+    /// How long to wait between the results of a list, such as a scan, once the first has
+    /// arrived.
+    pub fn set_inter_frame_timeout(&mut self, timeout: Duration) {
+        self.inter_frame_timeout = timeout;
+    }
+
+    /// How to retry requests which fail.
+    pub fn set_retry(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// How `send_wifi_settings` waits for the device to join the network. By default it waits 30
+    /// seconds, asking for the device's state whenever it's been quiet for a second.
+    pub fn set_provision_options(&mut self, options: ProvisionOptions) {
+        self.provision_options = options;
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    pub fn send(&mut self, packet: ImprovPacket) -> Result<(), ClientErr> {
+        event!(debug, %packet, "send");
+        let id = match &packet {
+            ImprovPacket::RPCCommand(c) => Some(c.id()),
+            _ => None,
+        };
+        let mut frame: Vec<u8> = packet.try_into()?;
+        let sent = self.transport.send_frame(&frame);
+        crate::wipe(&mut frame);
+        sent?;
+        if let Some(id) = id {
+            notify(&mut self.progress, &Progress::CommandSent(id));
+        }
+        Ok(())
+    }
+
+    /// Send bytes as they are, such as a hand-built or deliberately malformed frame.
+    pub fn send_frame(&mut self, frame: &[u8]) -> Result<(), ClientErr> {
+        Ok(self.transport.send_frame(frame)?)
+    }
+
+    /// Wait for the next packet from the device.
+    pub fn recv(&mut self) -> Result<ImprovPacket, ClientErr> {
+        let deadline = Instant::now() + self.timeout;
+        self.recv_until(deadline)
+    }
+
+    pub(crate) fn recv_until(&mut self, deadline: Instant) -> Result<ImprovPacket, ClientErr> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.transport.recv_frame(timeout)? {
+            Some(frame) => {
+                let p = decode_frame(&frame)?;
+                event!(debug, packet = %p, "received");
+                notify(&mut self.subscribers, &p);
+                self.track(&p);
+                Ok(p)
+            }
+            None => {
+                event!(debug, "timed out");
+                Err(ClientErr::Timeout)
+            }
+        }
+    }
+
+    fn track(&mut self, p: &ImprovPacket) {
+        if let Some(event) = progress(&mut self.tracker, p) {
+            notify(&mut self.progress, &event);
+        }
+    }
+
+    // `response` builds a fresh matcher for each attempt
+    fn request<R, M: FnMut(ImprovPacket) -> Result<Option<R>, ClientErr>>(
+        &mut self,
+        cmd: RPCCommand,
+        mut response: impl FnMut() -> M,
+    ) -> Result<R, ClientErr> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("request", command = %cmd).entered();
+        let mut attempt = 1;
+        loop {
+            match self.attempt(cmd.clone(), response()) {
+                Err(e) => match self.retry.retry_after(attempt, &e) {
+                    Some(delay) => {
+                        event!(info, attempt, error = %e, ?delay, "retrying");
+                        count!("improv_retries_total");
+                        std::thread::sleep(delay)
+                    }
+                    None => return Err(e),
+                },
+                r => return r,
+            }
+            attempt += 1;
+        }
+    }
+
+    fn attempt<R>(
+        &mut self,
+        cmd: RPCCommand,
+        mut response: impl FnMut(ImprovPacket) -> Result<Option<R>, ClientErr>,
+    ) -> Result<R, ClientErr> {
+        let timeout = self.command_timeout(cmd.id());
+        self.send(ImprovPacket::RPCCommand(cmd))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(r) = response(self.recv_until(deadline)?)? {
+                return Ok(r);
+            }
+        }
+    }
+
+    /// Send a command which the device answers with a list of results, and collect them. If the
+    /// device stalls or fails partway, and retrying doesn't help, what arrived is returned along
+    /// with the error; an error before any results arrive is returned as is.
+    pub fn request_list(&mut self, cmd: RPCCommand) -> Result<ResultList, ClientErr> {
+        let mut attempt = 1;
+        loop {
+            match self.attempt_list(cmd.clone()) {
+                ResultList::Partial(results, e) => match self.retry.retry_after(attempt, &e) {
+                    Some(delay) => {
+                        event!(info, attempt, error = %e, ?delay, "retrying");
+                        count!("improv_retries_total");
+                        std::thread::sleep(delay)
+                    }
+                    None if results.is_empty() => return Err(e),
+                    None => return Ok(ResultList::Partial(results, e)),
+                },
+                complete => return Ok(complete),
+            }
+            attempt += 1;
+        }
+    }
+
+    fn attempt_list(&mut self, cmd: RPCCommand) -> ResultList {
+        let mut list = ResultAssembler::new(cmd.id());
+        let timeout = self.command_timeout(cmd.id());
+        if let Err(e) = self.send(ImprovPacket::RPCCommand(cmd)) {
+            return list.fail(e);
+        }
+        let mut deadline = Instant::now() + timeout;
+        while !list.is_complete() {
+            match self.recv_until(deadline).and_then(|p| list.feed(p)) {
+                Ok(true) => deadline = Instant::now() + self.inter_frame_timeout,
+                Ok(false) => (),
+                Err(e) => return list.fail(e),
+            }
+        }
+        list.finish()
+    }
+
+    pub fn current_state(&mut self) -> Result<CurrentState, ClientErr> {
+        self.request(RPCCommand::RequestCurrentState, || current_state)
+    }
+
+    pub fn device_info(&mut self) -> Result<DeviceInfo, ClientErr> {
+        self.request(RPCCommand::RequestDeviceInformation, || device_info)
+    }
+
+    pub fn scan_networks(&mut self) -> Result<Vec<WifiNetwork>, ClientErr> {
+        networks(self.request_list(RPCCommand::RequestScannedWifiNetworks)?)
+    }
+
+    /// Check that a device which reported `Provisioned` came online: by fetching its redirect URL
+    /// if it sent one, and otherwise by resolving its name over mDNS. See `verify::verify_online`.
+    pub fn verify_online(
+        &mut self,
+        redirect_url: Option<&str>,
+        timeout: Duration,
+    ) -> Result<Verified, ClientErr> {
+        let name = match redirect_url {
+            Some(_) => None,
+            None => Some(self.device_info()?.device_name),
+        };
+        let verified = verify::verify_online(redirect_url, name.as_deref(), timeout)?;
+        notify(&mut self.progress, &Progress::Verified(verified.clone()));
+        Ok(verified)
+    }
+
+    /// Send a vendor-specific command and wait for the device's result.
+    pub fn custom<C: CustomCommand>(&mut self, cmd: &C) -> Result<RPCResult, ClientErr> {
+        self.request(RPCCommand::custom(cmd)?, || custom_result(C::ID))
+    }
+
+    /// Ask the device to make itself known, e.g. by blinking an LED. Devices without `Identify`
+    /// report `ErrorState::UnknownRPCCommand`.
+    pub fn identify(&mut self) -> Result<(), ClientErr> {
+        self.custom(&Identify).map(|_| ())
+    }
+
+    /// Send credentials and wait for the device to connect, returning its redirect URL, if any.
+    /// Many devices don't report `Provisioned` on their own, so they're polled for their state;
+    /// see `set_provision_options`.
+    pub fn send_wifi_settings(
+        &mut self,
+        settings: &WifiSettings,
+    ) -> Result<Option<String>, ClientErr> {
+        let options = self.provision_options.clone();
+        self.provision(settings, &options)?.into_result()
+    }
+
+    /// Send credentials and follow the device until it either joins the network or fails to.
+    pub fn provision(
+        &mut self,
+        settings: &WifiSettings,
+        options: &ProvisionOptions,
+    ) -> Result<ProvisionOutcome, ClientErr> {
+        settings.validate()?;
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("provision", ssid = %settings.ssid).entered();
+        if let Some(expected) = &options.expect_firmware {
+            expected.check(self.device_info()?)?;
+        }
+        self.send(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(
+            settings.clone(),
+        )))?;
+        self.follow(options)
+    }
+
+    /// Try each of `networks` in turn, e.g. a site's several SSIDs, until the device joins one,
+    /// returning its index and the redirect URL, or `None` if the device couldn't join any. After
+    /// each failure the device is given the client's timeout to go back to `Ready`. Errors other
+    /// than `UnableToConnect`, such as `NotAuthorized`, end the attempt, as other credentials
+    /// won't help. The firmware is checked, if `options` asks for it, before the first attempt.
+    pub fn provision_any(
+        &mut self,
+        networks: &[WifiSettings],
+        options: &ProvisionOptions,
+    ) -> Result<Option<(usize, Option<String>)>, ClientErr> {
+        let mut options = options.clone();
+        for (i, settings) in networks.iter().enumerate() {
+            if i > 0 {
+                self.wait_for_state(CurrentState::Ready, self.timeout)?;
+            }
+            match self.provision(settings, &options)? {
+                ProvisionOutcome::Provisioned(url) => return Ok(Some((i, url))),
+                ProvisionOutcome::UnableToConnect => {
+                    event!(info, ssid = %settings.ssid, "unable to connect");
+                }
+                ProvisionOutcome::Rejected(e) => return Err(ClientErr::Device(e)),
+            }
+            options.expect_firmware = None;
+        }
+        Ok(None)
+    }
+
+    /// Wait for a device which is already provisioning, e.g. one set up by another client, to
+    /// either join its network or fail to.
+    pub fn wait_provisioned(
+        &mut self,
+        options: &ProvisionOptions,
+    ) -> Result<ProvisionOutcome, ClientErr> {
+        self.send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))?;
+        self.follow(options)
+    }
+
+    /// Wait until the device reports `state`, either on its own or when asked, which happens every
+    /// second until `timeout` elapses.
+    pub fn wait_for_state(
+        &mut self,
+        state: CurrentState,
+        timeout: Duration,
+    ) -> Result<(), ClientErr> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))?;
+            let wake = deadline.min(Instant::now() + STATE_POLL_INTERVAL);
+            loop {
+                match self.recv_until(wake) {
+                    Ok(p) => {
+                        if current_state(p)? == Some(state) {
+                            return Ok(());
+                        }
+                    }
+                    Err(ClientErr::Timeout) if Instant::now() < deadline => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// Wait until the device reports `state` on its own, without asking it, e.g. while something
+    /// else provisions it over BLE. Errors it reports along the way are skipped, as it may be
+    /// retried; the wait only ends at `state` or when `timeout` elapses.
+    pub fn watch_until(&mut self, state: CurrentState, timeout: Duration) -> Result<(), ClientErr> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.recv_until(deadline)? == ImprovPacket::CurrentState(state) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn follow(&mut self, options: &ProvisionOptions) -> Result<ProvisionOutcome, ClientErr> {
+        let deadline = Instant::now() + options.timeout;
+        let mut provisioning = Provisioning::default();
+        loop {
+            let wake = match options.poll_interval {
+                Some(poll) => deadline.min(Instant::now() + poll),
+                None => deadline,
+            };
+            match self.recv_until(wake) {
+                Ok(p) => {
+                    if let Some(outcome) = provisioning.feed(p)? {
+                        return Ok(outcome);
+                    }
+                }
+                Err(ClientErr::Timeout)
+                    if options.poll_interval.is_some() && Instant::now() < deadline =>
+                {
+                    if let Some(outcome) = provisioning.idle() {
+                        return Ok(outcome);
+                    }
+                    self.send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))?;
+                }
+                Err(ClientErr::Timeout) => return provisioning.timed_out(),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Provision several devices with the same credentials at once, one thread each. The outcomes are
+/// in the same order as the clients.
+pub fn provision_all<T: Transport + Send>(
+    clients: &mut [ImprovClient<T>],
+    settings: &WifiSettings,
+    options: &ProvisionOptions,
+) -> Vec<Result<ProvisionOutcome, ClientErr>> {
+    std::thread::scope(|s| {
+        let handles: Vec<_> = clients
+            .iter_mut()
+            .map(|client| s.spawn(|| client.provision(settings, options)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("provisioning thread panicked"))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::REQUEST_CURRENT_STATE;
+    use std::collections::VecDeque;
+    use std::io::{Cursor, Read, Write};
+
+    // a fake port which replays canned device output and records what the client wrote
+    struct Pipe {
+        rx: Cursor<Vec<u8>>,
+        tx: Vec<u8>,
+    }
+
+    impl Pipe {
+        fn client(packets: Vec<ImprovPacket>) -> ImprovClient<IoTransport<Pipe>> {
+            let mut rx = b"[D][improv_serial]: hello\r\n".to_vec();
+            for p in packets {
+                rx.extend(Vec::try_from(p).unwrap());
+            }
+            ImprovClient::new(IoTransport::new(Pipe {
+                rx: Cursor::new(rx),
+                tx: Vec::new(),
+            }))
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // dribble the bytes out to exercise reassembly
+            let n = buf.len().min(3);
+            self.rx.read(&mut buf[..n])
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn device_info() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::CurrentState(CurrentState::Ready),
+            ImprovPacket::RPCResult(RPCResult {
+                command: 0x03,
+                data: vec![
+                    b"ESPHome".to_vec(),
+                    b"2024.6.0".to_vec(),
+                    b"ESP32-C3".to_vec(),
+                    b"anthill-sensor".to_vec(),
+                ],
+            }),
+        ]);
+        let info = client.device_info().unwrap();
+        assert_eq!(info.device_name, "anthill-sensor");
+        assert_eq!(
+            client.into_inner().into_inner().tx,
+            Vec::try_from(ImprovPacket::RPCCommand(
+                RPCCommand::RequestDeviceInformation
+            ))
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn identify() {
+        let mut client = Pipe::client(vec![ImprovPacket::RPCResult(RPCResult {
+            command: Identify::ID,
+            data: vec![],
+        })]);
+        client.identify().unwrap();
+
+        let mut client = Pipe::client(vec![ImprovPacket::ErrorState(
+            ErrorState::UnknownRPCCommand,
+        )]);
+        assert!(matches!(
+            client.identify(),
+            Err(ClientErr::Device(ErrorState::UnknownRPCCommand))
+        ));
+    }
+
+    #[test]
+    fn send_wifi_settings() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::CurrentState(CurrentState::Provisioning),
+            ImprovPacket::CurrentState(CurrentState::Provisioned),
+            ImprovPacket::RPCResult(RPCResult {
+                command: 0x01,
+                data: vec![b"http://192.168.1.2".to_vec()],
+            }),
+        ]);
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        assert_eq!(
+            client.send_wifi_settings(&settings).unwrap(),
+            Some(String::from("http://192.168.1.2")),
+        );
+    }
+
+    #[test]
+    fn subscribe() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::ErrorState(ErrorState::NoError),
+            ImprovPacket::CurrentState(CurrentState::Provisioned),
+        ]);
+        let events = client.subscribe();
+        assert_eq!(client.current_state().unwrap(), CurrentState::Provisioned);
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                ImprovPacket::ErrorState(ErrorState::NoError),
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+            ],
+        );
+    }
+
+    #[test]
+    fn device_state() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::ErrorState(ErrorState::UnableToConnect),
+            ImprovPacket::CurrentState(CurrentState::Ready),
+        ]);
+        let changes = client.device_state().changes();
+        client.recv().unwrap();
+        client.recv().unwrap();
+        let tracker = client.device_state();
+        assert_eq!(tracker.last_state(), Some(CurrentState::Ready));
+        assert_eq!(tracker.last_error(), Some(ErrorState::UnableToConnect));
+        assert_eq!(changes.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn progress() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::CurrentState(CurrentState::Provisioning),
+            ImprovPacket::CurrentState(CurrentState::Provisioning),
+            ImprovPacket::CurrentState(CurrentState::Provisioned),
+            ImprovPacket::RPCResult(RPCResult {
+                command: 0x01,
+                data: vec![b"http://192.168.1.2".to_vec()],
+            }),
+        ]);
+        let progress = client.progress();
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        client
+            .provision(&settings, &ProvisionOptions::default())
+            .unwrap();
+        assert_eq!(
+            progress.try_iter().collect::<Vec<_>>(),
+            [
+                Progress::CommandSent(0x01),
+                Progress::StateChanged(CurrentState::Provisioning),
+                Progress::StateChanged(CurrentState::Provisioned),
+                Progress::ResultReceived(0x01),
+            ],
+        );
+    }
+
+    #[test]
+    fn stalled_list() {
+        let network = |ssid: &[u8]| {
+            ImprovPacket::RPCResult(RPCResult {
+                command: 0x04,
+                data: vec![ssid.to_vec(), b"-60".to_vec(), b"YES".to_vec()],
+            })
+        };
+        let mut client = Pipe::client(vec![
+            network(b"anthill"),
+            ImprovPacket::CurrentState(CurrentState::Ready),
+            network(b"beehive"),
+        ]);
+        match client
+            .request_list(RPCCommand::RequestScannedWifiNetworks)
+            .unwrap()
+        {
+            ResultList::Partial(results, ClientErr::Io(_)) => assert_eq!(results.len(), 2),
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let mut client = Pipe::client(vec![]);
+        assert!(matches!(
+            client.request_list(RPCCommand::RequestScannedWifiNetworks),
+            Err(ClientErr::Io(_))
+        ));
+    }
+
+    #[test]
+    fn list_stalls_midframe() {
+        // one network and half of the next, then nothing, with the line still open
+        struct Stalled(Cursor<Vec<u8>>);
+
+        impl Read for Stalled {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.read(buf)? {
+                    0 => Err(io::ErrorKind::TimedOut.into()),
+                    n => Ok(n),
+                }
+            }
+        }
+
+        impl Write for Stalled {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let network = |ssid: &[u8]| {
+            Vec::try_from(ImprovPacket::RPCResult(RPCResult {
+                command: 0x04,
+                data: vec![ssid.to_vec(), b"-60".to_vec(), b"YES".to_vec()],
+            }))
+            .unwrap()
+        };
+        let mut rx = network(b"anthill");
+        let next = network(b"beehive");
+        rx.extend(&next[..next.len() / 2]);
+
+        let mut client = ImprovClient::builder()
+            .timeout(Duration::from_secs(30))
+            .inter_frame_timeout(Duration::from_millis(50))
+            .build_with(IoTransport::new(Stalled(Cursor::new(rx))));
+        let start = Instant::now();
+        match client
+            .request_list(RPCCommand::RequestScannedWifiNetworks)
+            .unwrap()
+        {
+            ResultList::Partial(results, ClientErr::Timeout) => assert_eq!(results.len(), 1),
+            r => panic!("unexpected {:?}", r),
+        }
+        // the rest of the list gets the inter-frame timeout, not the request's
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn wait_for_state() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::CurrentState(CurrentState::Provisioning),
+            ImprovPacket::CurrentState(CurrentState::Provisioned),
+        ]);
+        client
+            .wait_for_state(CurrentState::Provisioned, Duration::from_secs(1))
+            .unwrap();
+    }
+
+    #[test]
+    fn watch_until() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::CurrentState(CurrentState::Provisioning),
+            ImprovPacket::ErrorState(ErrorState::UnableToConnect),
+            ImprovPacket::CurrentState(CurrentState::Provisioned),
+        ]);
+        client
+            .watch_until(CurrentState::Provisioned, Duration::from_secs(1))
+            .unwrap();
+        // nothing was asked of the device
+        assert!(client.into_inner().into_inner().tx.is_empty());
+
+        let mut client = Pipe::client(vec![ImprovPacket::CurrentState(CurrentState::Ready)]);
+        assert!(client
+            .watch_until(CurrentState::Provisioned, Duration::from_millis(10))
+            .is_err());
+    }
+
+    #[test]
+    fn retry() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::ErrorState(ErrorState::InvalidRPCPacket),
+            ImprovPacket::CurrentState(CurrentState::Ready),
+        ]);
+        client.set_retry(RetryPolicy {
+            max_attempts: 2,
+            ..RetryPolicy::never()
+        });
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        // the request was sent twice
+        assert_eq!(
+            client.into_inner().into_inner().tx,
+            [REQUEST_CURRENT_STATE, REQUEST_CURRENT_STATE].concat(),
+        );
+    }
+
+    #[test]
+    fn backoff() {
+        let b = Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let delays: Vec<_> = (1..=5).map(|n| b.delay(n).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000]);
+    }
+
+    #[test]
+    fn eof() {
+        let mut client = Pipe::client(vec![]);
+        assert!(matches!(client.current_state(), Err(ClientErr::Io(_))));
+    }
+
+    #[test]
+    fn provision_unable_to_connect() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::CurrentState(CurrentState::Provisioning),
+            ImprovPacket::ErrorState(ErrorState::UnableToConnect),
+        ]);
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        assert_eq!(
+            client
+                .provision(&settings, &ProvisionOptions::default())
+                .unwrap(),
+            ProvisionOutcome::UnableToConnect,
+        );
+    }
+
+    #[test]
+    fn wait_provisioned() {
+        let mut client = Pipe::client(vec![
+            ImprovPacket::CurrentState(CurrentState::Provisioned),
+            ImprovPacket::RPCResult(RPCResult {
+                command: 0x02,
+                data: vec![b"http://192.168.1.2".to_vec()],
+            }),
+        ]);
+        assert_eq!(
+            client
+                .wait_provisioned(&ProvisionOptions::default())
+                .unwrap(),
+            ProvisionOutcome::Provisioned(Some(String::from("http://192.168.1.2"))),
+        );
+    }
+
+    #[test]
+    fn provision_several() {
+        use crate::MockImprovDevice;
+
+        let mut clients = vec![
+            ImprovClient::new(MockImprovDevice::new()),
+            ImprovClient::new(MockImprovDevice::new().fails_with(ErrorState::UnableToConnect)),
+        ];
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        let outcomes = provision_all(&mut clients, &settings, &ProvisionOptions::default());
+        assert_eq!(
+            outcomes.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            [
+                ProvisionOutcome::Provisioned(None),
+                ProvisionOutcome::UnableToConnect
+            ],
+        );
+    }
+
+    #[test]
+    fn provision_any() {
+        use crate::mock::{Action, Rule};
+        use crate::MockImprovDevice;
+
+        let networks = [
+            WifiSettings::open("anthill"),
+            WifiSettings::open("beehive"),
+            WifiSettings::open("wasp-nest"),
+        ];
+        let cmd = RPCCommand::SendWifiSettings(WifiSettings::open("")).id();
+        let device = MockImprovDevice::new()
+            .with_rule(Rule::new(cmd, Action::Error(ErrorState::UnableToConnect)).times(1));
+        let mut client = ImprovClient::new(device);
+        assert_eq!(
+            client
+                .provision_any(&networks, &ProvisionOptions::default())
+                .unwrap(),
+            Some((1, None)),
+        );
+        let tried: Vec<_> = client.into_inner().credentials().to_vec();
+        assert_eq!(tried, [WifiSettings::open("beehive")]);
+
+        let device = MockImprovDevice::new().fails_with(ErrorState::UnableToConnect);
+        let mut client = ImprovClient::new(device);
+        assert_eq!(
+            client
+                .provision_any(&networks, &ProvisionOptions::default())
+                .unwrap(),
+            None,
+        );
+
+        let device = MockImprovDevice::new().fails_with(ErrorState::NotAuthorized);
+        let mut client = ImprovClient::new(device);
+        assert!(matches!(
+            client.provision_any(&networks, &ProvisionOptions::default()),
+            Err(ClientErr::Device(ErrorState::NotAuthorized))
+        ));
+    }
+
+    #[test]
+    fn expect_firmware() {
+        use crate::MockImprovDevice;
+
+        let expect = |name: &str, min_version: &str| ProvisionOptions {
+            expect_firmware: Some(ExpectedFirmware {
+                name: Some(name.into()),
+                min_version: Some(min_version.into()),
+            }),
+            ..Default::default()
+        };
+        let settings = WifiSettings::open("anthill");
+        let mut client = ImprovClient::new(MockImprovDevice::new());
+        assert!(matches!(
+            client.provision(&settings, &expect("esphome", "0.1")),
+            Err(ClientErr::UnexpectedFirmware(info)) if info.firmware_name == "improv-rs"
+        ));
+        assert!(matches!(
+            client.provision(&settings, &expect("improv-rs", "0.2")),
+            Err(ClientErr::UnexpectedFirmware(_))
+        ));
+        assert_eq!(
+            client
+                .provision(&settings, &expect("Improv-RS", "0.1.0"))
+                .unwrap(),
+            ProvisionOutcome::Provisioned(None)
+        );
+        assert_eq!(client.into_inner().credentials(), [settings]);
+
+        assert!(version_parts("2024.10.1") > version_parts("2024.6"));
+        assert_eq!(version_parts("v2024.6.0b1"), version_parts("2024.6-dev"));
+        assert_eq!(version_parts("1.0"), [1]);
+    }
+
+    #[test]
+    fn over_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let device = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            crate::MockImprovDevice::new().serve(stream).unwrap();
+        });
+
+        let mut client = ImprovClient::connect(addr).unwrap();
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        drop(client);
+        device.join().unwrap();
+    }
+
+    #[test]
+    fn builder() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let device = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            crate::MockImprovDevice::new().serve(stream).unwrap();
+        });
+
+        let scan = RPCCommand::RequestScannedWifiNetworks.id();
+        let mut client = ImprovClient::builder()
+            .port(format!("tcp://{}", addr))
+            .timeout(Duration::from_secs(3))
+            .chunking(4, Duration::from_millis(1))
+            .frame_gap(Duration::from_millis(5))
+            .command_timeout(scan, Duration::from_secs(20))
+            .build()
+            .unwrap();
+        assert_eq!(client.timeout(), Duration::from_secs(3));
+        assert_eq!(client.command_timeout(scan), Duration::from_secs(20));
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        drop(client);
+        device.join().unwrap();
+
+        assert!(matches!(
+            ImprovClient::builder().build(),
+            Err(ClientErr::Io(e)) if e.kind() == io::ErrorKind::InvalidInput
+        ));
+        let client = ImprovClient::builder()
+            .inter_frame_timeout(Duration::from_millis(1))
+            .build_with(crate::MockImprovDevice::new());
+        assert_eq!(client.inter_frame_timeout, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn send_wifi_settings_polls() {
+        // a device which only says what it's doing when asked
+        #[derive(Default)]
+        struct Quiet {
+            out: VecDeque<ImprovPacket>,
+            polls: usize,
+        }
+
+        impl Transport for Quiet {
+            fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+                match crate::decode_frame(frame).unwrap() {
+                    ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(_)) => self
+                        .out
+                        .push_back(ImprovPacket::CurrentState(CurrentState::Provisioning)),
+                    _ if self.polls < 2 => {
+                        self.polls += 1;
+                        self.out
+                            .push_back(ImprovPacket::CurrentState(CurrentState::Provisioning));
+                    }
+                    _ => self.out.extend([
+                        ImprovPacket::CurrentState(CurrentState::Provisioned),
+                        ImprovPacket::RPCResult(RPCResult::provisioned(
+                            0x02,
+                            Some("http://10.0.0.2"),
+                        )),
+                    ]),
+                }
+                Ok(())
+            }
+
+            fn recv_frame(&mut self, _: Duration) -> io::Result<Option<Vec<u8>>> {
+                Ok(self.out.pop_front().map(|p| Vec::try_from(p).unwrap()))
+            }
+        }
+
+        let mut client = ImprovClient::new(Quiet::default());
+        client.set_provision_options(ProvisionOptions {
+            poll_interval: Some(Duration::from_millis(1)),
+            ..Default::default()
+        });
+        assert_eq!(
+            client
+                .send_wifi_settings(&WifiSettings::open("anthill"))
+                .unwrap(),
+            Some(String::from("http://10.0.0.2"))
+        );
+        assert_eq!(client.into_inner().polls, 2);
+    }
+
+    #[test]
+    fn custom_transport_times_out() {
+        struct Silent;
+
+        impl Transport for Silent {
+            fn send_frame(&mut self, _: &[u8]) -> io::Result<()> {
+                Ok(())
+            }
+
+            fn recv_frame(&mut self, _: Duration) -> io::Result<Option<Vec<u8>>> {
+                Ok(None)
+            }
+        }
+
+        let mut client = ImprovClient::new(Silent);
+        assert!(matches!(client.current_state(), Err(ClientErr::Timeout)));
+    }
+
+    #[test]
+    fn command_timeouts() {
+        // never answers, but notes how long it was asked to wait
+        #[derive(Default)]
+        struct Silent(Vec<Duration>);
+
+        impl Transport for Silent {
+            fn send_frame(&mut self, _: &[u8]) -> io::Result<()> {
+                Ok(())
+            }
+
+            fn recv_frame(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+                self.0.push(timeout);
+                Ok(None)
+            }
+        }
+
+        let mut client = ImprovClient::new(Silent::default());
+        client.set_timeout(Duration::from_millis(20));
+        let scan = RPCCommand::RequestScannedWifiNetworks.id();
+        client.set_command_timeout(scan, Duration::from_secs(30));
+        assert_eq!(client.command_timeout(scan), Duration::from_secs(30));
+        assert_eq!(
+            client.command_timeout(RPCCommand::RequestCurrentState.id()),
+            Duration::from_millis(20)
+        );
+
+        assert!(matches!(client.current_state(), Err(ClientErr::Timeout)));
+        assert!(matches!(client.scan_networks(), Err(ClientErr::Timeout)));
+        let waits = client.into_inner().0;
+        assert!(waits[0] <= Duration::from_millis(20));
+        assert!(waits[1] > Duration::from_secs(20));
+    }
+
+    #[test]
+    fn error_chain() {
+        let e = ClientErr::from(ImprovErr::BadChecksum {
+            expected: 0xe5,
+            found: 0xe6,
+        });
+        assert_eq!(
+            e.to_string(),
+            "protocol error: bad checksum: expected 0xe5, found 0xe6"
+        );
+        let source = std::error::Error::source(&e).unwrap();
+        assert_eq!(
+            source.downcast_ref::<ImprovErr>(),
+            Some(&ImprovErr::BadChecksum {
+                expected: 0xe5,
+                found: 0xe6
+            })
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, SharedString, Unit};
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Counters(Mutex<HashMap<String, Arc<AtomicU64>>>);
+
+        impl Counters {
+            fn get(&self, name: &str) -> u64 {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .get(name)
+                    .map_or(0, |c| c.load(Ordering::Relaxed))
+            }
+        }
+
+        impl metrics::Recorder for Counters {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                let mut counters = self.0.lock().unwrap();
+                Counter::from_arc(counters.entry(key.name().to_string()).or_default().clone())
+            }
+
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+
+            fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+                Histogram::noop()
+            }
+        }
+
+        let mut bad = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        *bad.last_mut().unwrap() ^= 0xff;
+        let mut rx = b"boot: ok\r\n".to_vec();
+        rx.extend(bad);
+        for p in [
+            ImprovPacket::ErrorState(ErrorState::UnableToConnect),
+            ImprovPacket::CurrentState(CurrentState::Ready),
+        ] {
+            rx.extend(Vec::try_from(p).unwrap());
+        }
+        let mut client = ImprovClient::new(IoTransport::new(Pipe {
+            rx: Cursor::new(rx),
+            tx: Vec::new(),
+        }));
+
+        let counters = Counters::default();
+        metrics::with_local_recorder(&counters, || {
+            client
+                .send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))
+                .unwrap();
+            assert_eq!(
+                client.recv().unwrap(),
+                ImprovPacket::ErrorState(ErrorState::UnableToConnect)
+            );
+            assert_eq!(
+                client.recv().unwrap(),
+                ImprovPacket::CurrentState(CurrentState::Ready)
+            );
+        });
+        assert_eq!(counters.get("improv_frames_encoded_total"), 1);
+        assert_eq!(counters.get("improv_frames_decoded_total"), 2);
+        assert_eq!(counters.get("improv_checksum_failures_total"), 1);
+        assert_eq!(counters.get("improv_rpc_errors_total"), 1);
+        assert!(counters.get("improv_resyncs_total") > 0);
+    }
+}
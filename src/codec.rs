@@ -0,0 +1,150 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use futures_util::{Sink, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead, FramedWrite};
+
+use crate::{decode_frame, discard, scan, ImprovPacket, Scan};
+
+/// Frames `ImprovPacket`s over a byte stream, e.g. a `tokio_serial::SerialStream`.
+///
+/// Serial consoles carry plenty of bytes that aren't Improv frames, so the decoder discards
+/// anything that doesn't look like a checksum-valid frame. Frames that are well-formed but can't
+/// be decoded are yielded as `ImprovPacket::Raw` rather than ending the stream.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImprovCodec;
+
+impl ImprovCodec {
+    pub fn new() -> ImprovCodec {
+        ImprovCodec
+    }
+}
+
+impl Decoder for ImprovCodec {
+    type Item = ImprovPacket;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ImprovPacket>, io::Error> {
+        match scan(src) {
+            Scan::Frame { start, end } => {
+                discard(src, start);
+                src.advance(start);
+                let frame = src.split_to(end - start);
+                let p = decode_frame(&frame)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                Ok(Some(p))
+            }
+            Scan::Partial { start } => {
+                discard(src, start);
+                src.advance(start);
+                Ok(None)
+            }
+            Scan::Nothing => {
+                discard(src, src.len());
+                src.clear();
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Encoder<ImprovPacket> for ImprovCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: ImprovPacket, dst: &mut BytesMut) -> Result<(), io::Error> {
+        item.encode_to(dst)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// The packets arriving on a byte stream, e.g. for use in a `select!` loop.
+pub fn into_packet_stream<R: AsyncRead>(io: R) -> impl Stream<Item = io::Result<ImprovPacket>> {
+    FramedRead::new(io, ImprovCodec)
+}
+
+/// A sink which writes packets to a byte stream.
+pub fn into_packet_sink<W: AsyncWrite>(io: W) -> impl Sink<ImprovPacket, Error = io::Error> {
+    FramedWrite::new(io, ImprovCodec)
+}
+
+/// Both directions of a byte stream at once, so they can be driven from separate tasks.
+pub fn split_packets<T: AsyncRead + AsyncWrite>(
+    io: T,
+) -> (
+    impl Sink<ImprovPacket, Error = io::Error>,
+    impl Stream<Item = io::Result<ImprovPacket>>,
+) {
+    Framed::new(io, ImprovCodec).split()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CurrentState, RPCCommand};
+
+    #[test]
+    fn decode_skips_noise() {
+        let mut buf = BytesMut::from(&b"[I][wifi]: boot\r\n"[..]);
+        ImprovCodec
+            .encode(ImprovPacket::CurrentState(CurrentState::Ready), &mut buf)
+            .unwrap();
+        buf.extend_from_slice(b"IMPROV\x01\x01\x01\x02\xFF"); // bad checksum
+        ImprovCodec
+            .encode(
+                ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState),
+                &mut buf,
+            )
+            .unwrap();
+        buf.extend_from_slice(b"IMPR");
+
+        assert_eq!(
+            ImprovCodec.decode(&mut buf).unwrap(),
+            Some(ImprovPacket::CurrentState(CurrentState::Ready)),
+        );
+        assert_eq!(
+            ImprovCodec.decode(&mut buf).unwrap(),
+            Some(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState)),
+        );
+        assert_eq!(ImprovCodec.decode(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"IMPR");
+    }
+
+    #[test]
+    fn decode_partial_frame() {
+        let mut frame = BytesMut::new();
+        ImprovCodec
+            .encode(
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+                &mut frame,
+            )
+            .unwrap();
+
+        let mut buf = BytesMut::from(&frame[..5]);
+        assert_eq!(ImprovCodec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&frame[5..]);
+        assert_eq!(
+            ImprovCodec.decode(&mut buf).unwrap(),
+            Some(ImprovPacket::CurrentState(CurrentState::Provisioned)),
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_and_sink() {
+        use futures_util::SinkExt;
+
+        let (host, device) = tokio::io::duplex(64);
+        let mut sink = into_packet_sink(host);
+        let mut stream = into_packet_stream(device);
+        sink.send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState))
+            .await
+            .unwrap();
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState),
+        );
+    }
+}
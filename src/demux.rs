@@ -0,0 +1,533 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Improv frames and the console output around them, for links such as an ESP's UART where the
+//! device's logs share the line with the protocol.
+
+use alloc::vec::Vec;
+
+use crate::{checksum, decode_frame, scan, ImprovPacket, Scan, HEADER_LEN};
+
+// how much log output can be cut out of a single frame before giving up on it
+const MAX_SPLICED: usize = 1024;
+// the longest log line looked for inside a frame
+const MAX_LINE: usize = 512;
+
+/// A piece of a serial stream shared between log output and Improv frames.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Chunk {
+    Packet(ImprovPacket),
+    /// Anything that isn't a valid frame, passed through as-is.
+    Log(Vec<u8>),
+}
+
+/// Splits a stream such as an ESP's console into Improv packets and the log text around them.
+#[derive(Default)]
+pub struct Demux {
+    buf: Vec<u8>,
+    interleaved: bool,
+    filter: Option<LogFilter>,
+}
+
+impl Demux {
+    pub fn new() -> Demux {
+        Demux::default()
+    }
+
+    /// For ESPHome, whose logger shares the UART and can flush log lines into the middle of a
+    /// frame. Whole log lines found inside a frame are cut out of it and passed through first.
+    pub fn esphome() -> Demux {
+        Demux {
+            interleaved: true,
+            ..Demux::default()
+        }
+    }
+
+    /// Pass log text through a `LogFilter`, for display.
+    pub fn with_log_filter(mut self) -> Demux {
+        self.filter = Some(LogFilter::new());
+        self
+    }
+
+    /// Add bytes from the stream, returning whatever can be told apart so far. Bytes which might
+    /// be the start of a frame are held until the rest arrives.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Chunk> {
+        self.buf.extend_from_slice(bytes);
+        let out = self.split();
+        self.filtered(out)
+    }
+
+    fn split(&mut self) -> Vec<Chunk> {
+        let mut out = Vec::new();
+        loop {
+            let (log, frame) = match scan(&self.buf) {
+                Scan::Frame { start, end } => (start, Some(end)),
+                Scan::Partial { start } => (start, None),
+                Scan::Nothing => (self.buf.len(), None),
+            };
+            if self.interleaved {
+                // a frame broken up by log lines can only start before the next whole one
+                let limit = if frame.is_some() { log } else { self.buf.len() };
+                if let Some((start, spliced)) = find_spliced(&self.buf[..limit], frame.is_some()) {
+                    if start > 0 {
+                        out.push(Chunk::Log(self.buf.drain(..start).collect()));
+                    }
+                    let Spliced::Frame { frame, logs, len } = spliced else {
+                        return out;
+                    };
+                    self.buf.drain(..len);
+                    out.push(Chunk::Log(logs));
+                    out.push(match decode_frame(&frame) {
+                        Ok(p) => Chunk::Packet(p),
+                        Err(_) => Chunk::Log(frame),
+                    });
+                    continue;
+                }
+            }
+            if log > 0 {
+                out.push(Chunk::Log(self.buf.drain(..log).collect()));
+            }
+            let Some(end) = frame else {
+                return out;
+            };
+            let frame: Vec<u8> = self.buf.drain(..end - log).collect();
+            out.push(match decode_frame(&frame) {
+                Ok(p) => Chunk::Packet(p),
+                Err(_) => Chunk::Log(frame),
+            });
+        }
+    }
+
+    /// Give up on a held partial frame, e.g. when the stream goes quiet, and return it as text.
+    /// Filtered text can still come out if nothing was held.
+    pub fn flush(&mut self) -> Option<Chunk> {
+        let mut text = core::mem::take(&mut self.buf);
+        if let Some(filter) = &mut self.filter {
+            text = filter.filter(&text);
+            text.extend(filter.finish());
+        }
+        (!text.is_empty()).then_some(Chunk::Log(text))
+    }
+
+    fn filtered(&mut self, chunks: Vec<Chunk>) -> Vec<Chunk> {
+        let Some(filter) = &mut self.filter else {
+            return chunks;
+        };
+        chunks
+            .into_iter()
+            .filter_map(|c| match c {
+                Chunk::Log(text) => {
+                    let text = filter.filter(&text);
+                    (!text.is_empty()).then_some(Chunk::Log(text))
+                }
+                p => Some(p),
+            })
+            .collect()
+    }
+}
+
+/// Cleans up log text for display: ANSI escape sequences, such as color codes, are removed, and
+/// carriage returns become newlines, with CRLF becoming a single one. Sequences split between
+/// calls are handled.
+#[derive(Clone, Debug, Default)]
+pub struct LogFilter {
+    state: FilterState,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+enum FilterState {
+    #[default]
+    Text,
+    // after ESC
+    Escape,
+    // inside an `ESC [` control sequence
+    Csi,
+    // after a CR, which might be followed by an LF
+    Cr,
+}
+
+impl LogFilter {
+    pub fn new() -> LogFilter {
+        LogFilter::default()
+    }
+
+    pub fn filter(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(bytes.len());
+        for &b in bytes {
+            self.state = match (self.state, b) {
+                (FilterState::Escape, b'[') => FilterState::Csi,
+                (FilterState::Escape, _) => FilterState::Text,
+                (FilterState::Csi, 0x20..=0x3f) => FilterState::Csi,
+                (FilterState::Csi, 0x40..=0x7e) => FilterState::Text,
+                (FilterState::Cr, b'\n') => {
+                    out.push(b'\n');
+                    FilterState::Text
+                }
+                (FilterState::Cr, _) => {
+                    out.push(b'\n');
+                    text(&mut out, b)
+                }
+                // anything else ends a malformed sequence
+                (FilterState::Text | FilterState::Csi, _) => text(&mut out, b),
+            };
+        }
+        out
+    }
+
+    /// Anything held back waiting for the next byte, at the end of the stream.
+    pub fn finish(&mut self) -> Vec<u8> {
+        match core::mem::take(&mut self.state) {
+            FilterState::Cr => Vec::from([b'\n']),
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn text(out: &mut Vec<u8>, b: u8) -> FilterState {
+    match b {
+        0x1b => FilterState::Escape,
+        b'\r' => FilterState::Cr,
+        b => {
+            out.push(b);
+            FilterState::Text
+        }
+    }
+}
+
+enum Spliced {
+    /// The frame put back together, the log lines cut out of it, and how many bytes both took.
+    Frame {
+        frame: Vec<u8>,
+        logs: Vec<u8>,
+        len: usize,
+    },
+    /// It might be one, once more arrives.
+    Partial,
+    Not,
+}
+
+// the first frame in `buf` with log lines in the middle of it. if `complete`, no more bytes will
+// follow, so there's nothing to wait for
+fn find_spliced(buf: &[u8], complete: bool) -> Option<(usize, Spliced)> {
+    (0..buf.len())
+        .filter(|&i| buf[i] == b'I')
+        .map(|i| (i, splice(&buf[i..], complete)))
+        .find(|(_, s)| !matches!(s, Spliced::Not))
+}
+
+fn splice(buf: &[u8], complete: bool) -> Spliced {
+    let mut frame = Vec::new();
+    let mut logs = Vec::new();
+    let mut i = 0;
+    loop {
+        let needed = match frame.get(8) {
+            Some(&len) => HEADER_LEN + len as usize + 1,
+            None => HEADER_LEN,
+        };
+        if frame.len() == needed {
+            break;
+        }
+        if (frame.len() <= 6 && !b"IMPROV".starts_with(&frame)) || logs.len() > MAX_SPLICED {
+            return Spliced::Not;
+        }
+        match log_line(&buf[i..]) {
+            Line::Complete(n) => {
+                logs.extend_from_slice(&buf[i..i + n]);
+                i += n;
+            }
+            Line::Incomplete if !complete => return Spliced::Partial,
+            _ => match buf.get(i) {
+                Some(&b) => {
+                    frame.push(b);
+                    i += 1;
+                }
+                None if complete => return Spliced::Not,
+                None => return Spliced::Partial,
+            },
+        }
+    }
+    let (body, sum) = frame.split_at(frame.len() - 1);
+    if logs.is_empty() || checksum(body) != sum[0] {
+        return Spliced::Not;
+    }
+    Spliced::Frame {
+        frame,
+        logs,
+        len: i,
+    }
+}
+
+enum Line {
+    No,
+    Incomplete,
+    /// This many bytes long, newline included.
+    Complete(usize),
+}
+
+// ESPHome's log lines start with the level in brackets, like `[D]`, usually after a color code
+fn log_line(buf: &[u8]) -> Line {
+    let mut i = 0;
+    if buf.first() == Some(&0x1b) {
+        i = 1;
+        loop {
+            match buf.get(i) {
+                None => return Line::Incomplete,
+                Some(b'[') if i == 1 => (),
+                Some(b'm') if i > 1 => break,
+                Some(b) if i > 1 && (b.is_ascii_digit() || *b == b';') => (),
+                Some(_) => return Line::No,
+            }
+            i += 1;
+        }
+        i += 1;
+    }
+    for (j, expect) in [&b"["[..], b"EWICDV", b"]"].into_iter().enumerate() {
+        match buf.get(i + j) {
+            None if !buf.is_empty() => return Line::Incomplete,
+            Some(b) if expect.contains(b) => (),
+            _ => return Line::No,
+        }
+    }
+    let rest = &buf[i..buf.len().min(i + MAX_LINE)];
+    match rest.iter().position(|&b| b == b'\n') {
+        Some(n) => Line::Complete(i + n + 1),
+        None if rest.len() < MAX_LINE => Line::Incomplete,
+        None => Line::No,
+    }
+}
+
+/// A piece of a buffer walked by `FrameSplitter`. Offsets are from the start of the buffer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Split<'a> {
+    /// A complete frame with a valid checksum. Its contents may still not decode.
+    Frame { offset: usize, bytes: &'a [u8] },
+    /// Bytes between frames, including any truncated frame at the end.
+    Garbage { offset: usize, bytes: &'a [u8] },
+}
+
+/// Walks a complete buffer, such as a console capture, yielding its frames and the garbage around
+/// them. Unlike `Demux`, nothing is held back waiting for more input.
+pub struct FrameSplitter<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FrameSplitter<'a> {
+    pub fn new(buf: &'a [u8]) -> FrameSplitter<'a> {
+        FrameSplitter { buf, pos: 0 }
+    }
+}
+
+// find the next frame, skipping over headers whose length runs past the end of the buffer
+pub(crate) fn next_frame(buf: &[u8]) -> Option<(usize, usize)> {
+    let mut from = 0;
+    loop {
+        match scan(&buf[from..]) {
+            Scan::Frame { start, end } => return Some((from + start, from + end)),
+            Scan::Partial { start } => from += start + 1,
+            Scan::Nothing => return None,
+        }
+    }
+}
+
+impl<'a> Iterator for FrameSplitter<'a> {
+    type Item = Split<'a>;
+
+    fn next(&mut self) -> Option<Split<'a>> {
+        let rest = &self.buf[self.pos..];
+        if rest.is_empty() {
+            return None;
+        }
+        let offset = self.pos;
+        match next_frame(rest) {
+            Some((0, end)) => {
+                self.pos += end;
+                Some(Split::Frame {
+                    offset,
+                    bytes: &rest[..end],
+                })
+            }
+            Some((start, _)) => {
+                self.pos += start;
+                Some(Split::Garbage {
+                    offset,
+                    bytes: &rest[..start],
+                })
+            }
+            None => {
+                self.pos = self.buf.len();
+                Some(Split::Garbage {
+                    offset,
+                    bytes: rest,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CurrentState;
+
+    #[test]
+    fn split_logs_and_frames() {
+        let frame = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        let mut stream = b"[I][wifi]: scanning\r\n".to_vec();
+        stream.extend(&frame);
+        stream.extend(b"[D][improv]: ok\r\nIMP");
+
+        let mut demux = Demux::new();
+        let (a, b) = stream.split_at(25);
+        let mut out = demux.feed(a);
+        out.extend(demux.feed(b));
+        assert_eq!(
+            out,
+            [
+                Chunk::Log(b"[I][wifi]: scanning\r\n".to_vec()),
+                Chunk::Packet(ImprovPacket::CurrentState(CurrentState::Ready)),
+                Chunk::Log(b"[D][improv]: ok\r\n".to_vec()),
+            ],
+        );
+        assert_eq!(demux.flush(), Some(Chunk::Log(b"IMP".to_vec())));
+        assert_eq!(demux.flush(), None);
+    }
+
+    #[test]
+    fn split_a_capture() {
+        let frame = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        let mut capture = frame.clone();
+        // a header whose length runs past the end shouldn't hide the frame after it
+        capture.extend(b"boot\r\nIMPROV\x01\x01\xff");
+        capture.extend(&frame);
+        capture.extend(b"IMP");
+
+        let split: Vec<Split> = FrameSplitter::new(&capture).collect();
+        assert_eq!(
+            split,
+            [
+                Split::Frame {
+                    offset: 0,
+                    bytes: &frame[..],
+                },
+                Split::Garbage {
+                    offset: 11,
+                    bytes: b"boot\r\nIMPROV\x01\x01\xff",
+                },
+                Split::Frame {
+                    offset: 26,
+                    bytes: &frame[..],
+                },
+                Split::Garbage {
+                    offset: 37,
+                    bytes: b"IMP",
+                },
+            ],
+        );
+    }
+
+    // everything a demuxer makes of `capture`, fed `n` bytes at a time
+    fn demux(mut demux: Demux, capture: &[u8], n: usize) -> (Vec<ImprovPacket>, usize) {
+        let mut chunks = Vec::new();
+        for bytes in capture.chunks(n) {
+            chunks.extend(demux.feed(bytes));
+        }
+        chunks.extend(demux.flush());
+        let mut packets = Vec::new();
+        let mut len = 0;
+        for chunk in chunks {
+            match chunk {
+                Chunk::Packet(p) => {
+                    len += Vec::try_from(p.clone()).unwrap().len();
+                    packets.push(p);
+                }
+                Chunk::Log(text) => len += text.len(),
+            }
+        }
+        (packets, len)
+    }
+
+    // hand-built streams, not captures from a device: they follow ESPHome's colored log format,
+    // with log lines spliced into the middle of frames in split.bin and failed.bin
+    #[test]
+    fn synthetic_esphome_streams() {
+        use crate::{ErrorState, RPCResult};
+
+        let provisioned = ImprovPacket::RPCResult(RPCResult {
+            command: 0x01,
+            data: vec![b"http://anthill-sensor.local".to_vec()],
+        });
+        let streams: [(&[u8], Vec<ImprovPacket>); 3] = [
+            (
+                include_bytes!("../testdata/esphome-synthetic/boot.bin"),
+                vec![
+                    ImprovPacket::CurrentState(CurrentState::Ready),
+                    ImprovPacket::RPCResult(RPCResult {
+                        command: 0x03,
+                        data: vec![
+                            b"ESPHome".to_vec(),
+                            b"2024.6.0".to_vec(),
+                            b"ESP32-C3".to_vec(),
+                            b"anthill-sensor".to_vec(),
+                        ],
+                    }),
+                ],
+            ),
+            (
+                include_bytes!("../testdata/esphome-synthetic/split.bin"),
+                vec![
+                    ImprovPacket::CurrentState(CurrentState::Provisioning),
+                    provisioned,
+                    ImprovPacket::CurrentState(CurrentState::Provisioned),
+                ],
+            ),
+            (
+                include_bytes!("../testdata/esphome-synthetic/failed.bin"),
+                vec![
+                    ImprovPacket::CurrentState(CurrentState::Provisioning),
+                    ImprovPacket::CurrentState(CurrentState::Ready),
+                    ImprovPacket::ErrorState(ErrorState::UnableToConnect),
+                ],
+            ),
+        ];
+        for (stream, expected) in streams {
+            for n in [1, 7, 64, stream.len()] {
+                // nothing is lost: every byte comes out as either log text or a packet
+                assert_eq!(
+                    demux(Demux::esphome(), stream, n),
+                    (expected.clone(), stream.len())
+                );
+            }
+        }
+
+        // without splicing, the frames broken up by log lines are lost
+        let split = include_bytes!("../testdata/esphome-synthetic/split.bin");
+        assert_eq!(
+            demux(Demux::new(), split, 64).0,
+            [ImprovPacket::CurrentState(CurrentState::Provisioned)]
+        );
+    }
+
+    #[test]
+    fn log_filter() {
+        let mut filter = LogFilter::new();
+        let mut out = filter.filter(b"\x1b[0;32m[I][app:029]: ready\x1b");
+        out.extend(filter.filter(b"[0m\r"));
+        out.extend(filter.filter(b"\n50%\r100%\r"));
+        assert_eq!(out, b"[I][app:029]: ready\n50%\n100%");
+        assert_eq!(filter.finish(), b"\n");
+
+        let stream = include_bytes!("../testdata/esphome-synthetic/boot.bin");
+        let mut demux = Demux::esphome().with_log_filter();
+        let mut text = Vec::new();
+        let mut packets = 0;
+        for chunk in stream.chunks(5).flat_map(|b| demux.feed(b)) {
+            match chunk {
+                Chunk::Log(t) => text.extend(t),
+                Chunk::Packet(_) => packets += 1,
+            }
+        }
+        assert_eq!(packets, 2);
+        assert!(text.starts_with(b"[I][logger:034]: Log initialized\n[C]"));
+        assert!(!text.contains(&0x1b) && !text.contains(&b'\r'));
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+// human-readable forms of the packet types, for tools and logs. like Debug, these never print the
+// PSK, and show strings as text rather than bytes.
+
+use core::fmt::{self, Debug, Display, Formatter};
+
+use alloc::string::String;
+
+use crate::{
+    CurrentState, DeviceInfo, ErrorState, ImprovPacket, RPCCommand, RPCResult, Ssid, WifiNetwork,
+    WifiSettings, WifiSettingsRef,
+};
+
+impl Display for ImprovPacket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ImprovPacket::CurrentState(s) => write!(f, "CurrentState::{:?}", s),
+            ImprovPacket::ErrorState(e) => write!(f, "ErrorState::{:?}", e),
+            ImprovPacket::RPCCommand(c) => write!(f, "RPCCommand::{}", c),
+            ImprovPacket::RPCResult(r) => write!(f, "{}", r),
+            ImprovPacket::Raw { pkt_type, payload } => {
+                write!(f, "Raw(type=0x{:02x}, [", pkt_type)?;
+                write_hex(f, payload)?;
+                write!(f, "])")
+            }
+        }
+    }
+}
+
+impl Display for CurrentState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CurrentState::Ready => write!(f, "ready"),
+            CurrentState::Provisioning => write!(f, "provisioning"),
+            CurrentState::Provisioned => write!(f, "provisioned"),
+            CurrentState::Unknown(b) => write!(f, "unknown state 0x{:02x}", b),
+        }
+    }
+}
+
+impl Display for ErrorState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorState::NoError => write!(f, "no error"),
+            ErrorState::InvalidRPCPacket => write!(f, "invalid RPC packet"),
+            ErrorState::UnknownRPCCommand => write!(f, "unknown RPC command"),
+            ErrorState::UnableToConnect => write!(f, "unable to connect"),
+            ErrorState::NotAuthorized => write!(f, "not authorized"),
+            ErrorState::UnknownError => write!(f, "unknown error"),
+            ErrorState::Other(b) => write!(f, "error 0x{:02x}", b),
+        }
+    }
+}
+
+impl Display for RPCCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RPCCommand::SendWifiSettings(s) => write!(f, "SendWifiSettings({})", s),
+            RPCCommand::RequestCurrentState => write!(f, "RequestCurrentState"),
+            RPCCommand::RequestDeviceInformation => write!(f, "RequestDeviceInformation"),
+            RPCCommand::RequestScannedWifiNetworks => write!(f, "RequestScannedWifiNetworks"),
+            RPCCommand::Custom { id, payload } => {
+                write!(f, "Custom(id=0x{:02x}, [", id)?;
+                write_hex(f, payload)?;
+                write!(f, "])")
+            }
+        }
+    }
+}
+
+fn write_hex(f: &mut Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for (i, b) in bytes.iter().enumerate() {
+        let sep = if i == 0 { "" } else { " " };
+        write!(f, "{}{:02x}", sep, b)?;
+    }
+    Ok(())
+}
+
+impl Display for Ssid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.pad(&self.to_string_lossy())
+    }
+}
+
+impl Debug for Ssid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&SsidBytes(self.as_bytes()), f)
+    }
+}
+
+// an SSID is quoted like a string when it is one, and as escaped bytes otherwise
+struct SsidBytes<'a>(&'a [u8]);
+
+impl Debug for SsidBytes<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match core::str::from_utf8(self.0) {
+            Ok(s) => Debug::fmt(s, f),
+            Err(_) => write!(f, "b\"{}\"", self.0.escape_ascii()),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Ssid {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self.to_str() {
+            Some(s) => defmt::write!(f, "{=str}", s),
+            None => defmt::write!(f, "{=[u8]}", self.as_bytes()),
+        }
+    }
+}
+
+impl Display for WifiSettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ssid={:?}, psk=<redacted>", self.ssid)
+    }
+}
+
+impl Debug for WifiSettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiSettings")
+            .field("ssid", &self.ssid)
+            .field("psk", &format_args!("<redacted>"))
+            .finish()
+    }
+}
+
+impl Debug for WifiSettingsRef<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiSettingsRef")
+            .field("ssid", &SsidBytes(self.ssid))
+            .field("psk", &format_args!("<redacted>"))
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for WifiSettings {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "WifiSettings(ssid={}, psk=<redacted>)", self.ssid)
+    }
+}
+
+/// Debug-formats `WifiSettings` including the PSK. See `WifiSettings::reveal`.
+pub struct Revealed<'a>(&'a WifiSettings);
+
+impl Debug for Revealed<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiSettings")
+            .field("ssid", &self.0.ssid)
+            .field("psk", &self.0.psk)
+            .finish()
+    }
+}
+
+impl WifiSettings {
+    /// Debug output that includes the PSK, which is otherwise redacted.
+    pub fn reveal(&self) -> Revealed<'_> {
+        Revealed(self)
+    }
+}
+
+impl Display for RPCResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "RPCResult(command=0x{:02x}, [", self.command)?;
+        for (i, s) in self.data.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            write!(f, "{}{:?}", sep, String::from_utf8_lossy(s))?;
+        }
+        write!(f, "])")
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} {} on {})",
+            self.device_name, self.firmware_name, self.firmware_version, self.hardware
+        )
+    }
+}
+
+impl Display for WifiNetwork {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let auth = if self.auth { "secured" } else { "open" };
+        write!(f, "{:?} ({} dBm, {})", self.ssid, self.rssi, auth)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn display() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
+            ssid: "anthill".into(),
+            psk: "hunter2".into(),
+        }));
+        assert_eq!(
+            p.to_string(),
+            r#"RPCCommand::SendWifiSettings(ssid="anthill", psk=<redacted>)"#
+        );
+
+        let p = ImprovPacket::RPCResult(RPCResult {
+            command: 0x01,
+            data: vec![b"http://10.0.0.2".to_vec()],
+        });
+        assert_eq!(
+            p.to_string(),
+            r#"RPCResult(command=0x01, ["http://10.0.0.2"])"#
+        );
+
+        let p = ImprovPacket::Raw {
+            pkt_type: 0x09,
+            payload: vec![0xde, 0xad],
+        };
+        assert_eq!(p.to_string(), "Raw(type=0x09, [de ad])");
+        assert_eq!(ErrorState::UnableToConnect.to_string(), "unable to connect");
+    }
+
+    #[test]
+    fn debug_redacts_psk() {
+        let w = WifiSettings {
+            ssid: "anthill".into(),
+            psk: "hunter2".into(),
+        };
+        let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(w.clone()));
+        assert_eq!(
+            format!("{:?}", p),
+            r#"RPCCommand(SendWifiSettings(WifiSettings { ssid: "anthill", psk: <redacted> }))"#
+        );
+        assert_eq!(
+            format!("{:?}", w.reveal()),
+            r#"WifiSettings { ssid: "anthill", psk: "hunter2" }"#
+        );
+    }
+}
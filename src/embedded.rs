@@ -0,0 +1,725 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Adapters for running the protocol over `embedded-io` and `embedded-io-async` streams, such as
+//! HAL UART drivers.
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "embedded-io-async")]
+use crate::demux::next_frame;
+#[cfg(feature = "embedded-io-async")]
+use crate::fields::FieldWriter;
+#[cfg(feature = "embedded-io-async")]
+use crate::protocol::write_header;
+#[cfg(feature = "embedded-io-async")]
+use crate::server::fingerprint;
+#[cfg(feature = "embedded-io-async")]
+use crate::{
+    checksum, CurrentState, CustomCommand, ErrorState, Identify, PacketRef, RPCCommand,
+    RPCCommandRef, RPCResult, TypedPacket, HEADER_LEN,
+};
+use crate::{
+    decode_frame, discard, scan, wipe, CredentialStore, ImprovErr, ImprovPacket, ImprovServer,
+    Scan, WifiProvisioner, MAX_FRAME_LEN,
+};
+
+#[derive(Debug)]
+pub enum IoErr<E> {
+    Io(E),
+    Improv(ImprovErr),
+    /// The stream ended.
+    Eof,
+}
+
+impl<E> From<ImprovErr> for IoErr<E> {
+    fn from(e: ImprovErr) -> IoErr<E> {
+        IoErr::Improv(e)
+    }
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for IoErr<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            IoErr::Io(e) => write!(f, "I/O error: {:?}", e),
+            IoErr::Improv(e) => write!(f, "protocol error: {}", e),
+            IoErr::Eof => write!(f, "stream ended"),
+        }
+    }
+}
+
+/// Reads and writes packets over a byte stream.
+pub struct ImprovIo<T> {
+    io: T,
+    buf: Vec<u8>,
+}
+
+impl<T> ImprovIo<T> {
+    pub fn new(io: T) -> ImprovIo<T> {
+        ImprovIo {
+            io,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    // pull a frame out of what's been read so far, if there is one
+    fn take_frame(&mut self) -> Option<Vec<u8>> {
+        match scan(&self.buf) {
+            Scan::Frame { start, end } => {
+                discard(&self.buf, start);
+                Some(self.buf.drain(..end).skip(start).collect())
+            }
+            Scan::Partial { start } => {
+                discard(&self.buf, start);
+                self.buf.drain(..start);
+                None
+            }
+            Scan::Nothing => {
+                discard(&self.buf, self.buf.len());
+                self.buf.clear();
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<T: embedded_io::Read + embedded_io::Write> ImprovIo<T> {
+    pub fn write_packet(&mut self, packet: &ImprovPacket) -> Result<(), IoErr<T::Error>> {
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        let n = packet.encode_into(&mut frame)?;
+        let written = self.io.write_all(&frame[..n]).map_err(IoErr::Io);
+        wipe(&mut frame);
+        written?;
+        self.io.flush().map_err(IoErr::Io)
+    }
+
+    /// Block until a complete packet arrives. Bytes that aren't part of a frame are discarded.
+    pub fn read_packet(&mut self) -> Result<ImprovPacket, IoErr<T::Error>> {
+        let mut chunk = [0u8; 64];
+        loop {
+            if let Some(frame) = self.take_frame() {
+                return Ok(decode_frame(&frame)?);
+            }
+            match self.io.read(&mut chunk).map_err(IoErr::Io)? {
+                0 => return Err(IoErr::Eof),
+                n => self.buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    /// Read whatever is available, hand it to `server`, and transmit its response.
+    pub fn serve<P: WifiProvisioner, S: CredentialStore>(
+        &mut self,
+        server: &mut ImprovServer<P, S>,
+    ) -> Result<(), IoErr<T::Error>> {
+        let mut chunk = [0u8; 64];
+        let n = self.io.read(&mut chunk).map_err(IoErr::Io)?;
+        if n == 0 {
+            return Err(IoErr::Eof);
+        }
+        let out = server.feed(&chunk[..n]);
+        if !out.is_empty() {
+            self.io.write_all(&out).map_err(IoErr::Io)?;
+            self.io.flush().map_err(IoErr::Io)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `embedded-io-async` equivalent of `ImprovIo`.
+#[cfg(feature = "embedded-io-async")]
+pub struct ImprovAsyncIo<T>(ImprovIo<T>);
+
+#[cfg(feature = "embedded-io-async")]
+impl<T: embedded_io_async::Read + embedded_io_async::Write> ImprovAsyncIo<T> {
+    pub fn new(io: T) -> ImprovAsyncIo<T> {
+        ImprovAsyncIo(ImprovIo::new(io))
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+
+    pub async fn write_packet(&mut self, packet: &ImprovPacket) -> Result<(), IoErr<T::Error>> {
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        let n = packet.encode_into(&mut frame)?;
+        let written = self.0.io.write_all(&frame[..n]).await.map_err(IoErr::Io);
+        wipe(&mut frame);
+        written?;
+        self.0.io.flush().await.map_err(IoErr::Io)
+    }
+
+    /// Wait until a complete packet arrives. Bytes that aren't part of a frame are discarded.
+    pub async fn read_packet(&mut self) -> Result<ImprovPacket, IoErr<T::Error>> {
+        let mut chunk = [0u8; 64];
+        loop {
+            if let Some(frame) = self.0.take_frame() {
+                return Ok(decode_frame(&frame)?);
+            }
+            match self.0.io.read(&mut chunk).await.map_err(IoErr::Io)? {
+                0 => return Err(IoErr::Eof),
+                n => self.0.buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    /// Read whatever is available, hand it to `server`, and transmit its response.
+    pub async fn serve<P: WifiProvisioner, S: CredentialStore>(
+        &mut self,
+        server: &mut ImprovServer<P, S>,
+    ) -> Result<(), IoErr<T::Error>> {
+        let mut chunk = [0u8; 64];
+        let n = self.0.io.read(&mut chunk).await.map_err(IoErr::Io)?;
+        if n == 0 {
+            return Err(IoErr::Eof);
+        }
+        let out = server.feed(&chunk[..n]);
+        if !out.is_empty() {
+            self.0.io.write_all(&out).await.map_err(IoErr::Io)?;
+            self.0.io.flush().await.map_err(IoErr::Io)?;
+        }
+        Ok(())
+    }
+}
+
+/// A network as an `AsyncWifiProvisioner` reports it, borrowed from wherever the firmware keeps its
+/// scan results.
+#[cfg(feature = "embedded-io-async")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NetworkRef<'a> {
+    pub ssid: &'a [u8],
+    pub rssi: i32,
+    pub auth: bool,
+}
+
+/// The borrowed equivalent of `DeviceInfo`.
+#[cfg(feature = "embedded-io-async")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DeviceInfoRef<'a> {
+    pub firmware_name: &'a str,
+    pub firmware_version: &'a str,
+    pub hardware: &'a str,
+    pub device_name: &'a str,
+}
+
+/// The platform side of provisioning for `AsyncDeviceServer`. Everything it hands back is
+/// borrowed, so firmware can keep it all in statics.
+#[cfg(feature = "embedded-io-async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncWifiProvisioner {
+    /// Join the network, or return the error to report, usually `ErrorState::UnableToConnect`.
+    async fn connect(&mut self, ssid: &[u8], psk: &str) -> Result<(), ErrorState>;
+
+    /// Where to send the user once connected, if anywhere.
+    fn redirect_url(&self) -> Option<&str> {
+        None
+    }
+
+    /// Scan for networks, which are then read back with `network`.
+    async fn scan(&mut self) {}
+
+    /// The `index`th network found by the last scan.
+    fn network(&self, index: usize) -> Option<NetworkRef<'_>> {
+        let _ = index;
+        None
+    }
+
+    fn device_info(&self) -> DeviceInfoRef<'_>;
+
+    /// Make the device known, e.g. by blinking an LED, when sent `Identify`.
+    async fn identify(&mut self) -> Result<(), ErrorState> {
+        Err(ErrorState::UnknownRPCCommand)
+    }
+
+    /// Handle a vendor-specific command by pushing the fields to answer with onto `result`, or
+    /// return the error to report. Commands left as `None` are reported as unknown.
+    async fn custom(
+        &mut self,
+        id: u8,
+        payload: &[u8],
+        result: &mut FieldWriter<'_>,
+    ) -> Option<Result<(), ErrorState>> {
+        let _ = (id, payload, result);
+        None
+    }
+}
+
+// room for a whole frame behind a partial one
+#[cfg(feature = "embedded-io-async")]
+const RX_BUF_LEN: usize = 2 * MAX_FRAME_LEN;
+
+/// The device side of the serial protocol for async firmware, e.g. on Embassy. Unlike
+/// `ImprovServer` it never allocates: frames are read into and built in fixed buffers. Resent
+/// credentials are handled as `ImprovServer` handles them.
+#[cfg(feature = "embedded-io-async")]
+pub struct AsyncDeviceServer<T, P> {
+    io: T,
+    provisioner: P,
+    state: CurrentState,
+    error: ErrorState,
+    provisioned_with: Option<u64>,
+    buf: [u8; RX_BUF_LEN],
+    len: usize,
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<T: embedded_io_async::Read + embedded_io_async::Write, P: AsyncWifiProvisioner>
+    AsyncDeviceServer<T, P>
+{
+    pub fn new(io: T, provisioner: P) -> AsyncDeviceServer<T, P> {
+        AsyncDeviceServer {
+            io,
+            provisioner,
+            state: CurrentState::Ready,
+            error: ErrorState::NoError,
+            provisioned_with: None,
+            buf: [0; RX_BUF_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn provisioner(&mut self) -> &mut P {
+        &mut self.provisioner
+    }
+
+    pub fn state(&self) -> CurrentState {
+        self.state
+    }
+
+    pub fn error(&self) -> ErrorState {
+        self.error
+    }
+
+    pub fn into_inner(self) -> (T, P) {
+        (self.io, self.provisioner)
+    }
+
+    /// Change state outside of the protocol, e.g. when booting with stored credentials, and
+    /// announce it.
+    pub async fn set_state(&mut self, state: CurrentState) -> Result<(), IoErr<T::Error>> {
+        self.provisioned_with = None;
+        self.transition(state).await
+    }
+
+    /// Answer commands until the stream fails or ends.
+    pub async fn run(&mut self) -> IoErr<T::Error> {
+        loop {
+            if let Err(e) = self.serve().await {
+                return e;
+            }
+        }
+    }
+
+    /// Read whatever is available and answer any commands in it.
+    pub async fn serve(&mut self) -> Result<(), IoErr<T::Error>> {
+        let n = self
+            .io
+            .read(&mut self.buf[self.len..])
+            .await
+            .map_err(IoErr::Io)?;
+        if n == 0 {
+            return Err(IoErr::Eof);
+        }
+        self.len += n;
+
+        while let Some((start, end)) = next_frame(&self.buf[..self.len]) {
+            let mut frame = [0u8; MAX_FRAME_LEN];
+            let frame = &mut frame[..end - start];
+            frame.copy_from_slice(&self.buf[start..end]);
+            self.consume(end);
+            let handled = self.handle(frame).await;
+            wipe(frame);
+            handled?;
+        }
+        let keep = match scan(&self.buf[..self.len]) {
+            Scan::Partial { start } => start,
+            _ => self.len,
+        };
+        self.consume(keep);
+        Ok(())
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+        wipe(&mut self.buf[self.len..]);
+    }
+
+    async fn handle(&mut self, frame: &[u8]) -> Result<(), IoErr<T::Error>> {
+        let is_command = frame[7] == RPCCommand::TYPE;
+        match PacketRef::try_from(frame) {
+            Ok(PacketRef::RPCCommand(cmd)) => {
+                if self.error != ErrorState::NoError {
+                    self.set_error(ErrorState::NoError).await?;
+                }
+                self.command(cmd).await
+            }
+            Err(e) if is_command => self.set_error(e.into()).await,
+            // we're not interested in anything else the host might send
+            _ => Ok(()),
+        }
+    }
+
+    async fn command(&mut self, cmd: RPCCommandRef<'_>) -> Result<(), IoErr<T::Error>> {
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        let n = match cmd {
+            RPCCommandRef::RequestCurrentState => {
+                self.write(&ImprovPacket::CurrentState(self.state)).await?;
+                if self.state != CurrentState::Provisioned {
+                    return Ok(());
+                }
+                let url = self.provisioner.redirect_url();
+                result_frame(&mut frame, 0x02, url.map(str::as_bytes))?
+            }
+            RPCCommandRef::RequestDeviceInformation => {
+                let info = self.provisioner.device_info();
+                let fields = [
+                    info.firmware_name,
+                    info.firmware_version,
+                    info.hardware,
+                    info.device_name,
+                ];
+                result_frame(&mut frame, 0x03, fields.map(str::as_bytes))?
+            }
+            RPCCommandRef::RequestScannedWifiNetworks => {
+                self.provisioner.scan().await;
+                for i in 0.. {
+                    let Some(network) = self.provisioner.network(i) else {
+                        break;
+                    };
+                    let mut rssi = [0u8; 11];
+                    let fields = [
+                        network.ssid,
+                        format_i32(network.rssi, &mut rssi),
+                        if network.auth { b"YES" } else { b"NO" },
+                    ];
+                    let n = result_frame(&mut frame, 0x04, fields)?;
+                    self.write_frame(&frame[..n]).await?;
+                }
+                result_frame(&mut frame, 0x04, [])?
+            }
+            RPCCommandRef::Custom { id, payload } => {
+                let mut result = FieldWriter::new(&mut frame[HEADER_LEN + 2..MAX_FRAME_LEN - 1]);
+                match self.provisioner.custom(id, payload, &mut result).await {
+                    Some(Ok(())) => {
+                        let len = result.written();
+                        seal_result(&mut frame, id, len)
+                    }
+                    Some(Err(e)) => return self.set_error(e).await,
+                    None if id == Identify::ID => match self.provisioner.identify().await {
+                        Ok(()) => seal_result(&mut frame, id, 0),
+                        Err(e) => return self.set_error(e).await,
+                    },
+                    None => return self.set_error(ErrorState::UnknownRPCCommand).await,
+                }
+            }
+            RPCCommandRef::SendWifiSettings(_) if self.state == CurrentState::Provisioning => {
+                event!(debug, "ignoring credentials while provisioning");
+                return self.write(&ImprovPacket::CurrentState(self.state)).await;
+            }
+            RPCCommandRef::SendWifiSettings(settings)
+                if self.state == CurrentState::Provisioned
+                    && self.provisioned_with == Some(fingerprint(settings.ssid, settings.psk)) =>
+            {
+                event!(debug, "credentials resent after provisioning");
+                self.write(&ImprovPacket::CurrentState(self.state)).await?;
+                let url = self.provisioner.redirect_url();
+                result_frame(&mut frame, 0x01, url.map(str::as_bytes))?
+            }
+            RPCCommandRef::SendWifiSettings(settings) => {
+                self.transition(CurrentState::Provisioning).await?;
+                self.provisioned_with = None;
+                if let Err(e) = self.provisioner.connect(settings.ssid, settings.psk).await {
+                    self.transition(CurrentState::Ready).await?;
+                    return self.set_error(e).await;
+                }
+                self.provisioned_with = Some(fingerprint(settings.ssid, settings.psk));
+                self.transition(CurrentState::Provisioned).await?;
+                let url = self.provisioner.redirect_url();
+                result_frame(&mut frame, 0x01, url.map(str::as_bytes))?
+            }
+        };
+        self.write_frame(&frame[..n]).await
+    }
+
+    async fn transition(&mut self, state: CurrentState) -> Result<(), IoErr<T::Error>> {
+        event!(debug, from = %self.state, to = %state, "state transition");
+        self.state = state;
+        self.write(&ImprovPacket::CurrentState(state)).await
+    }
+
+    async fn set_error(&mut self, error: ErrorState) -> Result<(), IoErr<T::Error>> {
+        event!(debug, %error, "error state");
+        self.error = error;
+        self.write(&ImprovPacket::ErrorState(error)).await
+    }
+
+    // only for states and errors, which encode without allocating
+    async fn write(&mut self, packet: &ImprovPacket) -> Result<(), IoErr<T::Error>> {
+        let mut frame = [0u8; HEADER_LEN + 2];
+        let n = packet.encode_into(&mut frame)?;
+        self.write_frame(&frame[..n]).await
+    }
+
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), IoErr<T::Error>> {
+        self.io.write_all(frame).await.map_err(IoErr::Io)?;
+        self.io.flush().await.map_err(IoErr::Io)
+    }
+}
+
+// an RPC result frame built in place, rather than through `RPCResult` and its allocations
+#[cfg(feature = "embedded-io-async")]
+fn result_frame<'a>(
+    frame: &mut [u8; MAX_FRAME_LEN],
+    command: u8,
+    fields: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<usize, ImprovErr> {
+    let mut w = FieldWriter::new(&mut frame[HEADER_LEN + 2..MAX_FRAME_LEN - 1]);
+    for f in fields {
+        w.push(f).map_err(|_| ImprovErr::PayloadTooLong)?;
+    }
+    let len = w.written();
+    Ok(seal_result(frame, command, len))
+}
+
+// fill in everything around `len` bytes of fields already written after the header
+#[cfg(feature = "embedded-io-async")]
+fn seal_result(frame: &mut [u8; MAX_FRAME_LEN], command: u8, len: usize) -> usize {
+    let end = HEADER_LEN + 2 + len;
+    write_header(frame, RPCResult::TYPE, (len + 2) as u8);
+    frame[9] = command;
+    frame[10] = len as u8;
+    frame[end] = checksum(&frame[..end]);
+    end + 1
+}
+
+#[cfg(feature = "embedded-io-async")]
+fn format_i32(n: i32, buf: &mut [u8; 11]) -> &[u8] {
+    let mut i = buf.len();
+    let mut v = n.unsigned_abs();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (v % 10) as u8;
+        v /= 10;
+        if v == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        i -= 1;
+        buf[i] = b'-';
+    }
+    &buf[i..]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DeviceInfo, ErrorState, RPCCommand};
+    use alloc::string::String;
+    use core::convert::Infallible;
+    #[cfg(feature = "embedded-io")]
+    use embedded_io::ErrorType;
+    #[cfg(not(feature = "embedded-io"))]
+    use embedded_io_async::ErrorType;
+
+    // a loopback UART: reads come from `rx`, writes land in `tx`
+    struct Uart {
+        rx: Vec<u8>,
+        tx: Vec<u8>,
+    }
+
+    impl ErrorType for Uart {
+        type Error = Infallible;
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::Read for Uart {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let n = buf.len().min(self.rx.len());
+            buf[..n].copy_from_slice(&self.rx[..n]);
+            self.rx.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::Write for Uart {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.tx.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct Device;
+
+    impl WifiProvisioner for Device {
+        fn connect(&mut self, _: &[u8], _: &str) -> Result<Option<String>, ErrorState> {
+            Err(ErrorState::UnableToConnect)
+        }
+
+        fn device_info(&mut self) -> DeviceInfo {
+            DeviceInfo {
+                firmware_name: "improv-rs".into(),
+                firmware_version: "0.1.0".into(),
+                hardware: "RP2040".into(),
+                device_name: "anthill-sensor".into(),
+            }
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn serve_over_uart() {
+        let request = ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState);
+        let mut io = ImprovIo::new(Uart {
+            rx: Vec::try_from(request).unwrap(),
+            tx: Vec::new(),
+        });
+        let mut server = ImprovServer::new(Device);
+        io.serve(&mut server).unwrap();
+
+        let Uart { tx, .. } = io.into_inner();
+        let mut io = ImprovIo::new(Uart { rx: tx, tx: vec![] });
+        assert_eq!(
+            io.read_packet().unwrap(),
+            ImprovPacket::CurrentState(crate::CurrentState::Ready),
+        );
+        assert!(matches!(io.read_packet(), Err(IoErr::Eof)));
+    }
+
+    #[cfg(feature = "embedded-io-async")]
+    mod r#async {
+        use super::*;
+        use crate::test_util::{packets, result};
+
+        impl embedded_io_async::Read for Uart {
+            async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+                let n = buf.len().min(self.rx.len());
+                buf[..n].copy_from_slice(&self.rx[..n]);
+                self.rx.drain(..n);
+                Ok(n)
+            }
+        }
+
+        impl embedded_io_async::Write for Uart {
+            async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+                self.tx.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+
+        #[tokio::test]
+        async fn serve_over_async_uart() {
+            let request = ImprovPacket::RPCCommand(RPCCommand::RequestDeviceInformation);
+            let mut io = ImprovAsyncIo::new(Uart {
+                rx: Vec::try_from(request).unwrap(),
+                tx: Vec::new(),
+            });
+            let mut server = ImprovServer::new(Device);
+            io.serve(&mut server).await.unwrap();
+
+            let Uart { tx, .. } = io.into_inner();
+            let mut io = ImprovAsyncIo::new(Uart { rx: tx, tx: vec![] });
+            assert!(matches!(
+                io.read_packet().await.unwrap(),
+                ImprovPacket::RPCResult(r) if r.command == 0x03
+            ));
+        }
+
+        struct Firmware {
+            joined: Option<Vec<u8>>,
+            joins: usize,
+        }
+
+        impl AsyncWifiProvisioner for Firmware {
+            async fn connect(&mut self, ssid: &[u8], _: &str) -> Result<(), ErrorState> {
+                self.joined = Some(ssid.into());
+                self.joins += 1;
+                Ok(())
+            }
+
+            fn redirect_url(&self) -> Option<&str> {
+                Some("http://anthill-sensor.local")
+            }
+
+            fn network(&self, index: usize) -> Option<NetworkRef<'_>> {
+                [(&b"anthill"[..], -48, true), (b"beehive", -71, false)]
+                    .get(index)
+                    .map(|&(ssid, rssi, auth)| NetworkRef { ssid, rssi, auth })
+            }
+
+            fn device_info(&self) -> DeviceInfoRef<'_> {
+                DeviceInfoRef {
+                    firmware_name: "improv-rs",
+                    firmware_version: "0.1.0",
+                    hardware: "RP2040",
+                    device_name: "anthill-sensor",
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn device_server() {
+            let mut rx = b"boot noise IMPROV".to_vec();
+            let settings = crate::WifiSettings {
+                ssid: "anthill".into(),
+                psk: String::from("ants in my pants"),
+            };
+            for cmd in [
+                RPCCommand::RequestScannedWifiNetworks,
+                RPCCommand::SendWifiSettings(settings.clone()),
+                RPCCommand::SendWifiSettings(settings),
+                RPCCommand::Custom {
+                    id: 0x10,
+                    payload: vec![],
+                },
+                RPCCommand::RequestCurrentState,
+            ] {
+                rx.extend(Vec::try_from(ImprovPacket::RPCCommand(cmd)).unwrap());
+            }
+            let uart = Uart { rx, tx: vec![] };
+            let mut server = AsyncDeviceServer::new(
+                uart,
+                Firmware {
+                    joined: None,
+                    joins: 0,
+                },
+            );
+            while server.serve().await.is_ok() {}
+            assert_eq!(server.state(), crate::CurrentState::Provisioned);
+
+            let (Uart { tx, .. }, firmware) = server.into_inner();
+            assert_eq!(firmware.joined.as_deref(), Some(&b"anthill"[..]));
+            assert_eq!(firmware.joins, 1);
+            let mut packets = packets(&tx).into_iter();
+            let state = |s| ImprovPacket::CurrentState(s);
+            use crate::CurrentState::*;
+            for expected in [
+                result(0x04, ["anthill", "-48", "YES"]),
+                result(0x04, ["beehive", "-71", "NO"]),
+                result(0x04, []),
+                state(Provisioning),
+                state(Provisioned),
+                result(0x01, ["http://anthill-sensor.local"]),
+                state(Provisioned),
+                result(0x01, ["http://anthill-sensor.local"]),
+                ImprovPacket::ErrorState(ErrorState::UnknownRPCCommand),
+                ImprovPacket::ErrorState(ErrorState::NoError),
+                state(Provisioned),
+                result(0x02, ["http://anthill-sensor.local"]),
+            ] {
+                assert_eq!(packets.next(), Some(expected));
+            }
+            assert_eq!(packets.next(), None);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Decoding from and encoding into `bytes` buffers, so frames can be passed around a networking
+//! stack without being copied.
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{wipe, ImprovErr, ImprovPacket};
+
+/// An encoded frame. Clones share the same buffer, so they're cheap.
+///
+/// Unlike the `Vec`s frames are otherwise encoded into, the buffer isn't wiped when the last clone
+/// is dropped, even with the `zeroize` feature, so don't keep frames carrying a PSK around.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct EncodedFrame(Bytes);
+
+impl EncodedFrame {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+}
+
+impl AsRef<[u8]> for EncodedFrame {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl core::ops::Deref for EncodedFrame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<EncodedFrame> for Bytes {
+    fn from(f: EncodedFrame) -> Bytes {
+        f.0
+    }
+}
+
+impl ImprovPacket {
+    /// Append the encoded frame to `dst`, returning the number of bytes written. `dst` is left as
+    /// it was if the packet can't be encoded.
+    pub fn encode_to(&self, dst: &mut BytesMut) -> Result<usize, ImprovErr> {
+        let start = dst.len();
+        dst.resize(start + self.encoded_len(), 0);
+        self.encode_into(&mut dst[start..]).inspect_err(|_| {
+            dst.truncate(start);
+        })
+    }
+
+    /// Encode into a buffer of its own, which can be handed on without copying.
+    pub fn encode_bytes(&self) -> Result<EncodedFrame, ImprovErr> {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.encode_to(&mut buf)?;
+        Ok(EncodedFrame(buf.freeze()))
+    }
+}
+
+/// Decode a buffer holding exactly one frame, e.g. one split off a read buffer.
+impl TryFrom<Bytes> for ImprovPacket {
+    type Error = ImprovErr;
+
+    fn try_from(b: Bytes) -> Result<ImprovPacket, ImprovErr> {
+        ImprovPacket::try_from(&b[..])
+    }
+}
+
+/// Like decoding from `Bytes`, but the buffer is wiped afterwards.
+impl TryFrom<BytesMut> for ImprovPacket {
+    type Error = ImprovErr;
+
+    fn try_from(mut b: BytesMut) -> Result<ImprovPacket, ImprovErr> {
+        let p = ImprovPacket::try_from(&b[..]);
+        wipe(&mut b);
+        p
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CurrentState, RPCCommand, WifiSettings, REQUEST_CURRENT_STATE};
+
+    #[test]
+    fn round_trip() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState);
+        let frame = p.encode_bytes().unwrap();
+        assert_eq!(frame.as_ref(), REQUEST_CURRENT_STATE);
+        assert_eq!(ImprovPacket::try_from(frame.into_bytes()), Ok(p));
+
+        let mut buf = BytesMut::from(&b"log"[..]);
+        let state = ImprovPacket::CurrentState(CurrentState::Ready);
+        let n = state.encode_to(&mut buf).unwrap();
+        let frame = buf.split_off(3);
+        assert_eq!(frame.len(), n);
+        assert_eq!(ImprovPacket::try_from(frame), Ok(state));
+    }
+
+    #[test]
+    fn failed_encode_leaves_buffer() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings::open(
+            "x".repeat(33).as_str(),
+        )));
+        let mut buf = BytesMut::from(&b"log"[..]);
+        assert!(p.encode_to(&mut buf).is_err());
+        assert_eq!(&buf[..], b"log");
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! A C ABI for the packet codec, so existing C and C++ tools can share this implementation.
+//!
+//! Every buffer handed out must be released with `improv_buffer_free`, and every decoded packet
+//! with `improv_packet_free`. Generate a header with `cbindgen --config cbindgen.toml`.
+
+use core::ffi::{c_char, CStr};
+use core::{ptr, slice};
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{CommandId, ImprovErr, ImprovPacket, RPCCommand, WifiSettings};
+
+/// Bytes allocated by this library.
+#[repr(C)]
+pub struct ImprovBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl ImprovBuffer {
+    fn new(v: Vec<u8>) -> ImprovBuffer {
+        let len = v.len();
+        let data = Box::into_raw(v.into_boxed_slice()) as *mut u8;
+        ImprovBuffer { data, len }
+    }
+
+    unsafe fn free(self) {
+        if !self.data.is_null() {
+            let mut data = Box::from_raw(ptr::slice_from_raw_parts_mut(self.data, self.len));
+            // frames may carry a PSK
+            crate::wipe(&mut data);
+        }
+    }
+}
+
+/// A decoded packet, flattened for C.
+#[repr(C)]
+pub struct ImprovDecoded {
+    /// 0x01 current state, 0x02 error state, 0x03 RPC command, 0x04 RPC result, or the type of a
+    /// packet this library doesn't know
+    pub pkt_type: u8,
+    /// The state or error byte, or the RPC command id
+    pub value: u8,
+    /// The strings of an RPC result, the SSID and PSK of Wi-Fi settings, or the payload of a
+    /// custom command or unknown packet
+    pub fields: *mut ImprovBuffer,
+    pub fields_len: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ImprovStatus {
+    Ok = 0,
+    NullPointer = 1,
+    InvalidCurrentStateByte = 2,
+    // 3 is retired: unknown error states decode as `ErrorState::Other`
+    InvalidRPCCommand = 4,
+    NotAnImprovPacket = 5,
+    BadLength = 6,
+    Truncated = 7,
+    BadChecksum = 8,
+    InvalidUtf8 = 9,
+    SsidTooLong = 10,
+    PskTooLong = 11,
+    PayloadTooLong = 12,
+    InvalidRPCResult = 13,
+    UnsupportedVersion = 14,
+    BufferTooSmall = 15,
+    UnknownPacketType = 16,
+    InvalidQrCode = 17,
+    InvalidHex = 18,
+}
+
+impl From<ImprovErr> for ImprovStatus {
+    fn from(e: ImprovErr) -> ImprovStatus {
+        match e {
+            ImprovErr::InvalidCurrentStateByte(_) => ImprovStatus::InvalidCurrentStateByte,
+            ImprovErr::InvalidRPCCommand(_) => ImprovStatus::InvalidRPCCommand,
+            ImprovErr::NotAnImprovPacket => ImprovStatus::NotAnImprovPacket,
+            ImprovErr::BadLength { .. } => ImprovStatus::BadLength,
+            ImprovErr::Truncated => ImprovStatus::Truncated,
+            ImprovErr::BadChecksum { .. } => ImprovStatus::BadChecksum,
+            ImprovErr::InvalidUtf8 => ImprovStatus::InvalidUtf8,
+            ImprovErr::SsidTooLong(_) => ImprovStatus::SsidTooLong,
+            ImprovErr::PskTooLong(_) => ImprovStatus::PskTooLong,
+            ImprovErr::PayloadTooLong => ImprovStatus::PayloadTooLong,
+            ImprovErr::InvalidRPCResult => ImprovStatus::InvalidRPCResult,
+            ImprovErr::UnsupportedVersion(_) => ImprovStatus::UnsupportedVersion,
+            ImprovErr::BufferTooSmall { .. } => ImprovStatus::BufferTooSmall,
+            ImprovErr::UnknownPacketType(_) => ImprovStatus::UnknownPacketType,
+            ImprovErr::InvalidQrCode => ImprovStatus::InvalidQrCode,
+            ImprovErr::InvalidHex => ImprovStatus::InvalidHex,
+        }
+    }
+}
+
+fn encode(packet: ImprovPacket, out: *mut ImprovBuffer) -> ImprovStatus {
+    match Vec::try_from(packet) {
+        Ok(frame) => {
+            // callers have checked `out`
+            unsafe { out.write(ImprovBuffer::new(frame)) };
+            ImprovStatus::Ok
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Encode a request which takes no arguments: 0x02 (current state), 0x03 (device information), or
+/// 0x04 (scan).
+///
+/// # Safety
+///
+/// `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn improv_encode_request(
+    command: u8,
+    out: *mut ImprovBuffer,
+) -> ImprovStatus {
+    if out.is_null() {
+        return ImprovStatus::NullPointer;
+    }
+    let cmd = match CommandId::try_from(command) {
+        Ok(CommandId::RequestCurrentState) => RPCCommand::RequestCurrentState,
+        Ok(CommandId::RequestDeviceInformation) => RPCCommand::RequestDeviceInformation,
+        Ok(CommandId::RequestScannedWifiNetworks) => RPCCommand::RequestScannedWifiNetworks,
+        _ => return ImprovStatus::InvalidRPCCommand,
+    };
+    encode(ImprovPacket::RPCCommand(cmd), out)
+}
+
+/// Encode a command sending Wi-Fi credentials. Use an empty `psk` for open networks.
+///
+/// # Safety
+///
+/// `ssid` and `psk` must be NUL-terminated strings, and `out` must be valid for writes. The SSID
+/// is sent as the bytes given; only the PSK has to be UTF-8.
+#[no_mangle]
+pub unsafe extern "C" fn improv_encode_wifi_settings(
+    ssid: *const c_char,
+    psk: *const c_char,
+    out: *mut ImprovBuffer,
+) -> ImprovStatus {
+    if ssid.is_null() || psk.is_null() || out.is_null() {
+        return ImprovStatus::NullPointer;
+    }
+    let Ok(psk) = CStr::from_ptr(psk).to_str() else {
+        return ImprovStatus::InvalidUtf8;
+    };
+    let settings = WifiSettings {
+        ssid: CStr::from_ptr(ssid).to_bytes().into(),
+        psk: psk.into(),
+    };
+    encode(
+        ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(settings)),
+        out,
+    )
+}
+
+/// Decode one complete frame. Packets with unknown contents are decoded leniently rather than
+/// rejected, but the framing must be valid.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes, and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn improv_decode_frame(
+    data: *const u8,
+    len: usize,
+    out: *mut ImprovDecoded,
+) -> ImprovStatus {
+    if data.is_null() || out.is_null() {
+        return ImprovStatus::NullPointer;
+    }
+    let frame = slice::from_raw_parts(data, len).to_vec();
+    let packet = match ImprovPacket::decode_lenient(frame) {
+        Ok(p) => p,
+        Err(e) => return e.into(),
+    };
+
+    let (pkt_type, value, fields) = match packet {
+        ImprovPacket::CurrentState(s) => (0x01, s.into(), Vec::new()),
+        ImprovPacket::ErrorState(e) => (0x02, e.into(), Vec::new()),
+        ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(s)) => (
+            0x03,
+            0x01,
+            vec![s.ssid.as_bytes().to_vec(), s.psk.as_bytes().to_vec()],
+        ),
+        ImprovPacket::RPCCommand(RPCCommand::Custom { id, payload }) => (0x03, id, vec![payload]),
+        ImprovPacket::RPCCommand(c) => (0x03, c.id(), Vec::new()),
+        ImprovPacket::RPCResult(r) => (0x04, r.command, r.data),
+        ImprovPacket::Raw { pkt_type, payload } => (pkt_type, 0, vec![payload]),
+    };
+    let fields: Vec<ImprovBuffer> = fields.into_iter().map(ImprovBuffer::new).collect();
+    let fields_len = fields.len();
+    out.write(ImprovDecoded {
+        pkt_type,
+        value,
+        fields: Box::into_raw(fields.into_boxed_slice()) as *mut ImprovBuffer,
+        fields_len,
+    });
+    ImprovStatus::Ok
+}
+
+/// # Safety
+///
+/// `buf` must have come from this library, and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn improv_buffer_free(buf: ImprovBuffer) {
+    buf.free();
+}
+
+/// Free the fields of a decoded packet. The struct itself belongs to the caller. Freeing it again
+/// does nothing.
+///
+/// # Safety
+///
+/// `packet` must have been filled in by `improv_decode_frame`.
+#[no_mangle]
+pub unsafe extern "C" fn improv_packet_free(packet: *mut ImprovDecoded) {
+    let Some(packet) = packet.as_mut().filter(|p| !p.fields.is_null()) else {
+        return;
+    };
+    let fields = Box::from_raw(ptr::slice_from_raw_parts_mut(
+        packet.fields,
+        packet.fields_len,
+    ));
+    for f in fields.into_vec() {
+        f.free();
+    }
+    packet.fields = ptr::null_mut();
+    packet.fields_len = 0;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::mem::MaybeUninit;
+
+    #[test]
+    fn round_trip() {
+        unsafe {
+            let mut buf = MaybeUninit::uninit();
+            let status = improv_encode_wifi_settings(
+                c"anthill".as_ptr(),
+                c"hunter2".as_ptr(),
+                buf.as_mut_ptr(),
+            );
+            assert_eq!(status, ImprovStatus::Ok);
+            let buf = buf.assume_init();
+
+            let mut packet = MaybeUninit::uninit();
+            assert_eq!(
+                improv_decode_frame(buf.data, buf.len, packet.as_mut_ptr()),
+                ImprovStatus::Ok
+            );
+            let mut packet = packet.assume_init();
+            assert_eq!((packet.pkt_type, packet.value), (0x03, 0x01));
+            let fields = slice::from_raw_parts(packet.fields, packet.fields_len);
+            assert_eq!(
+                slice::from_raw_parts(fields[0].data, fields[0].len),
+                b"anthill"
+            );
+            assert_eq!(
+                slice::from_raw_parts(fields[1].data, fields[1].len),
+                b"hunter2"
+            );
+
+            improv_packet_free(&mut packet);
+            assert!(packet.fields.is_null());
+            // freeing twice is harmless
+            improv_packet_free(&mut packet);
+            improv_buffer_free(buf);
+        }
+    }
+
+    #[test]
+    fn bad_frame() {
+        let mut packet = MaybeUninit::uninit();
+        let status = unsafe { improv_decode_frame(b"IMPROV".as_ptr(), 6, packet.as_mut_ptr()) };
+        assert_eq!(status, ImprovStatus::Truncated);
+    }
+}
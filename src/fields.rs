@@ -0,0 +1,173 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! The fields Wi-Fi settings and RPC results are made of: a length byte, then that many bytes.
+//! Custom commands can use them for their payloads too.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::ImprovErr;
+
+/// The encoded length of `fields`. Fails if any is longer than 255 bytes.
+pub fn encoded_len<'a>(fields: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, ImprovErr> {
+    fields.into_iter().try_fold(0, |len, f| {
+        if f.len() > u8::MAX as usize {
+            return Err(ImprovErr::PayloadTooLong);
+        }
+        Ok(len + 1 + f.len())
+    })
+}
+
+/// Encode `fields` one after another.
+pub fn encode<'a>(
+    fields: impl IntoIterator<Item = &'a [u8]> + Clone,
+) -> Result<Vec<u8>, ImprovErr> {
+    let mut v = vec![0; encoded_len(fields.clone())?];
+    let mut w = FieldWriter::new(&mut v);
+    for f in fields {
+        w.push(f)?;
+    }
+    Ok(v)
+}
+
+/// Reads fields out of a buffer, checking each length against what's left. As an iterator it
+/// stops after the first error.
+#[derive(Clone, Debug)]
+pub struct FieldReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    pub fn new(buf: &'a [u8]) -> FieldReader<'a> {
+        FieldReader { buf, pos: 0 }
+    }
+
+    /// The next field. Fails with `Truncated` if there isn't one, or it runs past the end.
+    pub fn field(&mut self) -> Result<&'a [u8], ImprovErr> {
+        let len = *self.buf.get(self.pos).ok_or(ImprovErr::Truncated)? as usize;
+        let field = self
+            .buf
+            .get(self.pos + 1..self.pos + 1 + len)
+            .ok_or(ImprovErr::Truncated)?;
+        self.pos += 1 + len;
+        Ok(field)
+    }
+
+    /// Whether every byte has been read.
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    /// Check that nothing follows the fields read so far.
+    pub fn finish(self) -> Result<(), ImprovErr> {
+        if !self.is_empty() {
+            return Err(ImprovErr::BadLength {
+                expected: self.pos,
+                found: self.buf.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for FieldReader<'a> {
+    type Item = Result<&'a [u8], ImprovErr>;
+
+    fn next(&mut self) -> Option<Result<&'a [u8], ImprovErr>> {
+        if self.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            self.pos = self.buf.len();
+        }
+        Some(field)
+    }
+}
+
+/// Writes fields into a buffer.
+#[derive(Debug)]
+pub struct FieldWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> FieldWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> FieldWriter<'a> {
+        FieldWriter { buf, pos: 0 }
+    }
+
+    /// Append a field. Fails if it's longer than 255 bytes or doesn't fit.
+    pub fn push(&mut self, field: &[u8]) -> Result<(), ImprovErr> {
+        let len = u8::try_from(field.len()).map_err(|_| ImprovErr::PayloadTooLong)?;
+        let end = self.pos + 1 + field.len();
+        let available = self.buf.len();
+        let dst = self
+            .buf
+            .get_mut(self.pos..end)
+            .ok_or(ImprovErr::BufferTooSmall {
+                needed: end,
+                available,
+            })?;
+        dst[0] = len;
+        dst[1..].copy_from_slice(field);
+        self.pos = end;
+        Ok(())
+    }
+
+    /// The number of bytes written so far.
+    pub fn written(&self) -> usize {
+        self.pos
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let fields: [&[u8]; 3] = [b"anthill", b"", b"ants in my pants"];
+        let v = encode(fields).unwrap();
+        assert_eq!(v.len(), encoded_len(fields).unwrap());
+        assert_eq!(&v[..8], b"\x07anthill");
+
+        let mut r = FieldReader::new(&v);
+        assert_eq!(r.field(), Ok(&b"anthill"[..]));
+        assert_eq!(
+            r.clone().collect::<Result<Vec<_>, _>>().unwrap(),
+            fields[1..]
+        );
+        assert_eq!(
+            r.clone().finish(),
+            Err(ImprovErr::BadLength {
+                expected: 8,
+                found: 26
+            })
+        );
+        r.nth(1);
+        assert_eq!(r.finish(), Ok(()));
+    }
+
+    #[test]
+    fn bounds() {
+        let mut r = FieldReader::new(&[0x03, b'a', b'b']);
+        assert_eq!(r.next(), Some(Err(ImprovErr::Truncated)));
+        assert_eq!(r.next(), None);
+        assert_eq!(FieldReader::new(&[]).field(), Err(ImprovErr::Truncated));
+
+        let mut buf = [0; 4];
+        let mut w = FieldWriter::new(&mut buf);
+        w.push(b"ab").unwrap();
+        assert_eq!(
+            w.push(b"c"),
+            Err(ImprovErr::BufferTooSmall {
+                needed: 5,
+                available: 4
+            })
+        );
+        assert_eq!(w.written(), 3);
+        assert_eq!(encoded_len([&[0; 256][..]]), Err(ImprovErr::PayloadTooLong));
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Frames as they are on the wire: the header, a payload of any type, and the checksum, whether
+//! or not they make a valid packet.
+
+use alloc::vec::Vec;
+
+use crate::{
+    checksum, unframe, wipe, FrameHeader, ImprovErr, ImprovPacket, Mode, HEADER_LEN,
+    IMPROV_VERSION, MAX_DATA_LEN,
+};
+
+/// The framing layer on its own, without interpreting the payload. Useful for sniffers and bridges
+/// which pass frames along, and for building deliberately malformed ones.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frame {
+    pub version: u8,
+    pub pkt_type: u8,
+    pub payload: Vec<u8>,
+    /// As received, or as it will be sent. `is_valid` checks it against the rest of the frame.
+    pub checksum: u8,
+}
+
+impl Frame {
+    /// A frame of the current protocol version with the right checksum.
+    pub fn new(pkt_type: u8, payload: Vec<u8>) -> Result<Frame, ImprovErr> {
+        if payload.len() > MAX_DATA_LEN {
+            return Err(ImprovErr::PayloadTooLong);
+        }
+        let mut frame = Frame {
+            version: IMPROV_VERSION,
+            pkt_type,
+            payload,
+            checksum: 0,
+        };
+        frame.checksum = frame.expected_checksum();
+        Ok(frame)
+    }
+
+    /// Read exactly one frame, checking its length and checksum but not its version or contents.
+    pub fn parse(b: &[u8]) -> Result<Frame, ImprovErr> {
+        let (header, payload) = unframe(b)?;
+        Ok(Frame {
+            version: header.version,
+            pkt_type: header.pkt_type,
+            payload: payload.to_vec(),
+            checksum: b[b.len() - 1],
+        })
+    }
+
+    pub fn header(&self) -> FrameHeader {
+        FrameHeader {
+            version: self.version,
+            pkt_type: self.pkt_type,
+            len: self.payload.len() as u8,
+        }
+    }
+
+    pub fn expected_checksum(&self) -> u8 {
+        let header = [self.version, self.pkt_type, self.payload.len() as u8];
+        checksum(b"IMPROV")
+            .wrapping_add(checksum(&header))
+            .wrapping_add(checksum(&self.payload))
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.payload.len() <= MAX_DATA_LEN && self.checksum == self.expected_checksum()
+    }
+
+    /// The frame as sent on the wire, with `checksum` as it is, right or not.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(HEADER_LEN + self.payload.len() + 1);
+        v.extend_from_slice(b"IMPROV");
+        v.extend_from_slice(&[self.version, self.pkt_type, self.payload.len() as u8]);
+        v.extend_from_slice(&self.payload);
+        v.push(self.checksum);
+        v
+    }
+
+    /// Interpret the payload, as `ImprovPacket::try_from` would the frame. Only the current
+    /// protocol version is understood.
+    pub fn decode(mut self) -> Result<ImprovPacket, ImprovErr> {
+        if self.version != IMPROV_VERSION {
+            return Err(ImprovErr::UnsupportedVersion(self.version));
+        }
+        if self.payload.len() > MAX_DATA_LEN {
+            return Err(ImprovErr::PayloadTooLong);
+        }
+        if !self.is_valid() {
+            return Err(ImprovErr::BadChecksum {
+                expected: self.expected_checksum(),
+                found: self.checksum,
+            });
+        }
+        let p = ImprovPacket::decode_payload(self.pkt_type, &self.payload, Mode::Strict);
+        wipe(&mut self.payload);
+        p
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CurrentState, REQUEST_CURRENT_STATE};
+
+    #[test]
+    fn round_trip() {
+        let frame = Frame::parse(&REQUEST_CURRENT_STATE).unwrap();
+        assert_eq!(frame.pkt_type, 0x03);
+        assert_eq!(frame.payload, [0x02, 0x00]);
+        assert_eq!(frame.to_bytes(), REQUEST_CURRENT_STATE);
+
+        let mut frame = Frame::new(0x01, vec![0x02]).unwrap();
+        assert_eq!(
+            frame.clone().decode(),
+            Ok(ImprovPacket::CurrentState(CurrentState::Ready))
+        );
+
+        frame.checksum ^= 0xff;
+        assert!(!frame.is_valid());
+        assert!(matches!(
+            frame.clone().decode(),
+            Err(ImprovErr::BadChecksum { .. })
+        ));
+        assert!(matches!(
+            Frame::parse(&frame.to_bytes()),
+            Err(ImprovErr::BadChecksum { .. })
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+// Arbitrary impls for property testing and fuzzing. Everything generated fits on the wire and
+// survives a round trip through strict decoding, so unknown states and raw packets are never
+// produced.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::{
+    CurrentState, ErrorState, ImprovPacket, RPCCommand, RPCResult, Ssid, WifiSettings, MAX_PSK_LEN,
+    MAX_SSID_LEN,
+};
+
+// a string of at most `max` bytes
+fn bounded_str(u: &mut Unstructured<'_>, max: usize) -> Result<String> {
+    let s: &str = u.arbitrary()?;
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    Ok(String::from(&s[..end]))
+}
+
+impl<'a> Arbitrary<'a> for Ssid {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Ssid> {
+        let len = u.int_in_range(0..=MAX_SSID_LEN)?.min(u.len());
+        Ok(Ssid::new(u.bytes(len)?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for WifiSettings {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<WifiSettings> {
+        Ok(WifiSettings {
+            ssid: u.arbitrary()?,
+            psk: bounded_str(u, MAX_PSK_LEN)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for RPCCommand {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<RPCCommand> {
+        Ok(match u.int_in_range(0..=4)? {
+            0 => RPCCommand::SendWifiSettings(u.arbitrary()?),
+            1 => RPCCommand::RequestCurrentState,
+            2 => RPCCommand::RequestDeviceInformation,
+            3 => RPCCommand::RequestScannedWifiNetworks,
+            _ => {
+                // the payload shares the frame with the command id and its length
+                let len = u.int_in_range(0..=u8::MAX as usize - 2)?.min(u.len());
+                RPCCommand::Custom {
+                    id: u.int_in_range(0x05..=0xff)?,
+                    payload: u.bytes(len)?.to_vec(),
+                }
+            }
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for RPCResult {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<RPCResult> {
+        let command = u.arbitrary()?;
+        // each field costs a length byte, and the command and total length take two more
+        let mut budget = u8::MAX as usize - 2;
+        let mut data = Vec::new();
+        while budget > 0 && u.arbitrary()? {
+            let len = u.int_in_range(0..=budget - 1)?.min(u.len());
+            data.push(u.bytes(len)?.to_vec());
+            budget -= 1 + len;
+        }
+        Ok(RPCResult { command, data })
+    }
+}
+
+impl<'a> Arbitrary<'a> for ImprovPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<ImprovPacket> {
+        Ok(match u.int_in_range(0..=3)? {
+            0 => ImprovPacket::CurrentState(*u.choose(&[
+                CurrentState::Ready,
+                CurrentState::Provisioning,
+                CurrentState::Provisioned,
+            ])?),
+            1 => ImprovPacket::ErrorState(*u.choose(&[
+                ErrorState::NoError,
+                ErrorState::InvalidRPCPacket,
+                ErrorState::UnknownRPCCommand,
+                ErrorState::UnableToConnect,
+                ErrorState::NotAuthorized,
+                ErrorState::UnknownError,
+                ErrorState::Other(0x05),
+            ])?),
+            2 => ImprovPacket::RPCCommand(u.arbitrary()?),
+            _ => ImprovPacket::RPCResult(u.arbitrary()?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // xorshift, so the run is reproducible without another dependency
+        let mut x: u32 = 0x1234_5678;
+        let bytes: Vec<u8> = (0..1 << 16)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+
+        let mut u = Unstructured::new(&bytes);
+        while !u.is_empty() {
+            let Ok(p) = ImprovPacket::arbitrary(&mut u) else {
+                break;
+            };
+            let frame = Vec::try_from(p.clone()).unwrap();
+            assert_eq!(ImprovPacket::try_from(frame), Ok(p));
+        }
+    }
+}
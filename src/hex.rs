@@ -0,0 +1,107 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Frames as hex text, for pasting out of logs and into tests. Bytes can be run together or
+//! separated by spaces, commas or colons, and each group may have a `0x` prefix.
+
+use core::fmt::Write;
+use core::str::FromStr;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{Frame, ImprovErr, ImprovPacket};
+
+/// Read hex bytes, like `49 4d 50`, `0x49,0x4d,0x50` or `494d50`.
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, ImprovErr> {
+    let mut bytes = Vec::new();
+    for group in s.split(|c: char| c.is_whitespace() || c == ',' || c == ':') {
+        let group = group
+            .strip_prefix("0x")
+            .or_else(|| group.strip_prefix("0X"))
+            .unwrap_or(group);
+        if group.len() % 2 != 0 || !group.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ImprovErr::InvalidHex);
+        }
+        for i in (0..group.len()).step_by(2) {
+            bytes
+                .push(u8::from_str_radix(&group[i..i + 2], 16).map_err(|_| ImprovErr::InvalidHex)?);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Lowercase hex, with a space between bytes.
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 3);
+    for (i, b) in bytes.iter().enumerate() {
+        let sep = if i == 0 { "" } else { " " };
+        let _ = write!(s, "{}{:02x}", sep, b);
+    }
+    s
+}
+
+impl Frame {
+    /// The frame as sent, in the form `FromStr` reads.
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.to_bytes())
+    }
+}
+
+impl FromStr for Frame {
+    type Err = ImprovErr;
+
+    fn from_str(s: &str) -> Result<Frame, ImprovErr> {
+        Frame::parse(&parse_hex(s)?)
+    }
+}
+
+impl ImprovPacket {
+    /// The encoded frame, in the form `FromStr` reads.
+    pub fn to_hex(&self) -> Result<String, ImprovErr> {
+        Vec::try_from(self).map(|frame| to_hex(&frame))
+    }
+}
+
+impl FromStr for ImprovPacket {
+    type Err = ImprovErr;
+
+    fn from_str(s: &str) -> Result<ImprovPacket, ImprovErr> {
+        ImprovPacket::try_from(parse_hex(s)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CurrentState, REQUEST_CURRENT_STATE};
+
+    #[test]
+    fn parse() {
+        let ready = ImprovPacket::CurrentState(CurrentState::Ready);
+        assert_eq!("494d50524f5601010102e2".parse(), Ok(ready.clone()));
+        assert_eq!(
+            " 49 4D 50 52 4F 56 01 01 01 02 E2\n".parse(),
+            Ok(ready.clone())
+        );
+        assert_eq!(
+            "0x49,0x4d,0x50,0x52,0x4f,0x56,0x01,0x01,0x01,0x02,0xe2".parse(),
+            Ok(ready.clone())
+        );
+        assert_eq!(ready.to_hex().unwrap(), "49 4d 50 52 4f 56 01 01 01 02 e2");
+
+        let frame: Frame = "494d50524f56 01 03 02 0200 e5".parse().unwrap();
+        assert_eq!(frame.to_bytes(), REQUEST_CURRENT_STATE);
+        assert_eq!(frame.to_hex().parse(), Ok(frame));
+
+        assert_eq!(parse_hex(""), Ok(Vec::new()));
+        assert_eq!(parse_hex("494"), Err(ImprovErr::InvalidHex));
+        assert_eq!(parse_hex("+1"), Err(ImprovErr::InvalidHex));
+        assert_eq!(
+            "49 4d 50 52 4f 56 01 01 01 02 e3".parse::<Frame>(),
+            Err(ImprovErr::BadChecksum {
+                expected: 0xe2,
+                found: 0xe3
+            })
+        );
+    }
+}
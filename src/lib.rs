@@ -1,326 +1,128 @@
 // Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
 
-const IMPROV_VERSION: u8 = 0x01;
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum ImprovPacket {
-    CurrentState(CurrentState),
-    ErrorState(ErrorState),
-    RPCCommand(RPCCommand),
-    RPCResult(RPCResult),
-}
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum CurrentState {
-    Ready,
-    Provisioning,
-    Provisioned,
-}
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum ErrorState {
-    NoError,
-    InvalidRPCPacket,
-    UnknownRPCCommand,
-    UnableToConnect,
-    UnknownError,
-}
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum RPCCommand {
-    SendWifiSettings(WifiSettings),
-    RequestCurrentState,
-    RequestDeviceInformation,
-    RequestScannedWifiNetworks,
-}
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct WifiSettings {
-    pub ssid: String,
-    pub psk: String,
-}
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct RPCResult(Vec<Vec<u8>>);
-
-trait TypedPacket {
-    const TYPE: u8;
-}
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum ImprovErr {
-    InvalidCurrentStateByte,
-    InvalidErrorStateByte,
-    InvalidRPCCommand,
-    NotAnImprovPacket,
-    BadLength,
-    UnsupportedVersion,
-    GoAway,
-}
-
-impl TypedPacket for CurrentState {
-    const TYPE: u8 = 0x01;
-}
-
-impl From<CurrentState> for u8 {
-    fn from(c: CurrentState) -> u8 {
-        match c {
-            CurrentState::Ready => 0x2,
-            CurrentState::Provisioning => 0x3,
-            CurrentState::Provisioned => 0x4,
-        }
-    }
-}
-
-impl TryFrom<u8> for CurrentState {
-    type Error = ImprovErr;
-
-    fn try_from(b: u8) -> Result<CurrentState, ImprovErr> {
-        match b {
-            0x2 => Ok(CurrentState::Ready),
-            0x3 => Ok(CurrentState::Provisioning),
-            0x4 => Ok(CurrentState::Provisioned),
-            _ => Err(ImprovErr::InvalidCurrentStateByte),
-        }
-    }
-}
-
-impl TypedPacket for ErrorState {
-    const TYPE: u8 = 0x02;
-}
-
-impl From<ErrorState> for u8 {
-    fn from(e: ErrorState) -> u8 {
-        match e {
-            ErrorState::NoError => 0x00,
-            ErrorState::InvalidRPCPacket => 0x01,
-            ErrorState::UnknownRPCCommand => 0x02,
-            ErrorState::UnableToConnect => 0x03,
-            ErrorState::UnknownError => 0xFF,
-        }
-    }
-}
-
-impl TryFrom<u8> for ErrorState {
-    type Error = ImprovErr;
-
-    fn try_from(b: u8) -> Result<ErrorState, ImprovErr> {
-        match b {
-            0x00 => Ok(ErrorState::NoError),
-            0x01 => Ok(ErrorState::InvalidRPCPacket),
-            0x02 => Ok(ErrorState::UnknownRPCCommand),
-            0x03 => Ok(ErrorState::UnableToConnect),
-            0xFF => Ok(ErrorState::UnknownError),
-            _ => Err(ImprovErr::InvalidErrorStateByte),
-        }
-    }
-}
-
-impl TypedPacket for RPCCommand {
-    const TYPE: u8 = 0x03;
-}
-
-impl RPCCommand {
-    fn inner(self) -> Vec<u8> {
-        match self {
-            RPCCommand::SendWifiSettings(w) => {
-                let mut inner: Vec<u8> = w.into();
-                let mut r = vec![0x01, inner.len() as u8];
-                r.append(&mut inner);
-                r
-            }
-            RPCCommand::RequestCurrentState => vec![0x02, 0x00],
-            RPCCommand::RequestDeviceInformation => vec![0x03, 0x00],
-            RPCCommand::RequestScannedWifiNetworks => vec![0x04, 0x00],
-        }
-    }
-}
-
-impl TryFrom<Vec<u8>> for RPCCommand {
-    type Error = ImprovErr;
-
-    fn try_from(b: Vec<u8>) -> Result<RPCCommand, ImprovErr> {
-        match b[0] {
-            0x01 => {
-                if b[1] as usize != b.len() - 2 {
-                    return Err(ImprovErr::BadLength);
-                }
-
-                let ssid = unsafe { String::from_utf8_unchecked(b[3..(b[2] as usize)].to_vec()) };
-                let psk = unsafe { String::from_utf8_unchecked(b[(3 + b[2] as usize)..].to_vec()) };
-
-                Ok(RPCCommand::SendWifiSettings(WifiSettings { ssid, psk }))
-            }
-            0x02 => Ok(RPCCommand::RequestCurrentState),
-            0x03 => Ok(RPCCommand::RequestDeviceInformation),
-            0x04 => Ok(RPCCommand::RequestScannedWifiNetworks),
-            _ => Err(ImprovErr::InvalidRPCCommand),
-        }
-    }
-}
-
-impl From<WifiSettings> for Vec<u8> {
-    fn from(w: WifiSettings) -> Vec<u8> {
-        vec![
-            vec![w.ssid.len() as u8],
-            w.ssid.into_bytes(),
-            vec![w.psk.len() as u8],
-            w.psk.into_bytes(),
-        ]
-        .into_iter()
-        .flatten()
-        .collect()
-    }
-}
-
-impl TypedPacket for RPCResult {
-    const TYPE: u8 = 0x04;
-}
-
-impl RPCResult {
-    fn inner(self) -> Vec<u8> {
-        self.0
-            .into_iter()
-            .flat_map(|mut v| {
-                v.insert(0, v.len() as u8);
-                v
-            })
-            .collect()
-    }
-}
-
-impl ImprovPacket {
-    fn inner(self) -> Vec<u8> {
-        match self {
-            ImprovPacket::CurrentState(c) => vec![c.into()],
-            ImprovPacket::ErrorState(e) => vec![e.into()],
-            ImprovPacket::RPCCommand(c) => c.inner(),
-            ImprovPacket::RPCResult(r) => r.inner(),
-        }
-    }
-
-    fn pkt_type(&self) -> u8 {
-        match self {
-            ImprovPacket::CurrentState(_) => CurrentState::TYPE,
-            ImprovPacket::ErrorState(_) => ErrorState::TYPE,
-            ImprovPacket::RPCCommand(_) => RPCCommand::TYPE,
-            ImprovPacket::RPCResult(_) => RPCResult::TYPE,
-        }
-    }
-}
-
-impl From<ImprovPacket> for Vec<u8> {
-    fn from(p: ImprovPacket) -> Vec<u8> {
-        let pkt_type = p.pkt_type();
-        let inner = p.inner();
-        let mut data: Vec<u8> = vec![
-            String::from("IMPROV").into_bytes(),
-            vec![
-                IMPROV_VERSION,
-                pkt_type,
-                inner.len() as u8, // data len
-            ],
-            inner,
-        ]
-        .into_iter()
-        .flatten()
-        .collect();
-        data.push(checksum(&data));
-        data
-    }
-}
-
-fn checksum(data: &[u8]) -> u8 {
-    data.iter().fold(0u8, |s, &n| s.wrapping_add(n))
-}
-
-impl TryFrom<Vec<u8>> for ImprovPacket {
-    type Error = ImprovErr;
-
-    fn try_from(mut b: Vec<u8>) -> Result<ImprovPacket, ImprovErr> {
-        if &b[0..6] != "IMPROV".as_bytes() {
-            return Err(ImprovErr::NotAnImprovPacket);
-        }
-
-        if b[6] != IMPROV_VERSION {
-            return Err(ImprovErr::UnsupportedVersion);
-        }
-
-        if b[8] as usize != b.len() - 10 {
-            return Err(ImprovErr::BadLength);
-        }
-
-        // TODO validate checksum
-
-        match b[7] {
-            CurrentState::TYPE => Ok(ImprovPacket::CurrentState(CurrentState::try_from(b[9])?)),
-            ErrorState::TYPE => Ok(ImprovPacket::ErrorState(ErrorState::try_from(b[9])?)),
-            RPCCommand::TYPE => Ok(ImprovPacket::RPCCommand(RPCCommand::try_from({
-                let mut data = b.split_off(9);
-                data.pop(); // remove the checksum
-                data
-            })?)),
-            //RPCResult::TYPE => {},
-            _ => Err(ImprovErr::GoAway),
-        }
-    }
-}
-
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+// forwards to the tracing macro of the same name with the tracing feature, and otherwise expands to
+// nothing
+macro_rules! event {
+    ($level:ident, $($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)*);
+    };
+}
+
+// bumps a counter through the metrics facade with the metrics feature, and otherwise expands to
+// nothing
+macro_rules! count {
+    ($name:literal $(, $label:literal => $value:expr)*) => {
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!($name $(, $label => $value)*).increment(1);
+    };
+}
+
+#[cfg(feature = "async")]
+mod async_client;
+pub mod ble;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "tokio")]
+mod codec;
+mod demux;
+mod display;
+#[cfg(any(feature = "embedded-io", feature = "embedded-io-async"))]
+pub mod embedded;
+#[cfg(feature = "bytes")]
+mod encoded;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fields;
+mod frame;
+#[cfg(feature = "arbitrary")]
+mod fuzz;
+pub mod hex;
+#[cfg(feature = "std")]
+pub mod mock;
+mod networks;
+mod packet_ref;
+pub mod prelude;
+pub mod protocol;
+mod qr;
+#[cfg(feature = "std")]
+pub mod record;
+pub mod serial;
+pub mod server;
+#[cfg(feature = "std")]
+mod shared;
+mod ssid;
+#[cfg(feature = "static-ip")]
+pub mod static_ip;
+#[cfg(feature = "std")]
+pub mod system;
 #[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn decode_get_current_state() {
-        let v: Vec<u8> = vec![
-            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x02, 0x00, 0xE5,
-        ];
-        assert_eq!(
-            ImprovPacket::try_from(v),
-            Ok(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState)),
-        );
-    }
-
-    #[test]
-    fn build_get_current_state() {
-        let p = ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState);
-        assert_eq!(
-            vec![0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x02, 0x00, 0xE5],
-            <ImprovPacket as Into<Vec<u8>>>::into(p),
-        );
-    }
-
-    #[test]
-    fn build_get_device_info() {
-        let p = ImprovPacket::RPCCommand(RPCCommand::RequestDeviceInformation);
-        assert_eq!(
-            vec![0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x03, 0x00, 0xE6],
-            <ImprovPacket as Into<Vec<u8>>>::into(p),
-        );
-    }
-
-    #[test]
-    fn build_get_networks() {
-        let p = ImprovPacket::RPCCommand(RPCCommand::RequestScannedWifiNetworks);
-        assert_eq!(
-            vec![0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x04, 0x00, 0xE7],
-            <ImprovPacket as Into<Vec<u8>>>::into(p),
-        );
-    }
-
-    #[test]
-    fn build_send_wifi() {
-        let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
-            ssid: String::from("anthill"),
-            psk: String::from("ants in my pants"),
-        }));
-        assert_eq!(
-            vec![
-                0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x1B, 0x01, 0x19, 0x07, 0x61, 0x6E,
-                0x74, 0x68, 0x69, 0x6C, 0x6C, 0x10, 0x61, 0x6E, 0x74, 0x73, 0x20, 0x69, 0x6E, 0x20,
-                0x6D, 0x79, 0x20, 0x70, 0x61, 0x6E, 0x74, 0x73, 0x12
-            ],
-            <ImprovPacket as Into<Vec<u8>>>::into(p),
-        );
-    }
-}
+mod test_util;
+#[cfg(feature = "std")]
+mod tracker;
+#[cfg(feature = "std")]
+mod transport;
+pub mod usb;
+#[cfg(feature = "std")]
+pub mod verify;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "smol")]
+pub use async_client::SmolTimer;
+#[cfg(feature = "tokio")]
+pub use async_client::TokioTimer;
+#[cfg(feature = "async")]
+pub use async_client::{cancellable, AsyncImprovClient, AsyncPacketReader, Timer};
+#[cfg(feature = "std")]
+pub use client::{
+    provision_all, Backoff, ClientErr, ExpectedFirmware, ImprovClient, ImprovClientBuilder,
+    Progress, ProvisionOptions, ProvisionOutcome, RetryPolicy,
+};
+#[cfg(feature = "tokio")]
+pub use codec::{into_packet_sink, into_packet_stream, split_packets, ImprovCodec};
+pub use demux::{Chunk, Demux, FrameSplitter, LogFilter, Split};
+pub use display::Revealed;
+#[cfg(feature = "bytes")]
+pub use encoded::EncodedFrame;
+pub use frame::Frame;
+#[cfg(feature = "std")]
+pub use mock::MockImprovDevice;
+pub use networks::ScanResults;
+pub use packet_ref::{PacketRef, RPCCommandRef, RPCResultRef, WifiSettingsRef};
+pub use protocol::{
+    encoded_len, frame_len, Capabilities, CommandId, CurrentState, CustomCommand, DeviceInfo,
+    ErrorState, Identify, ImprovErr, ImprovPacket, PacketType, RPCCommand, RPCResult, WifiNetwork,
+    WifiSettings, HEADER_LEN, IMPROV_VERSION, MAX_DATA_LEN, MAX_FRAME_LEN, MAX_PSK_LEN,
+    MAX_SSID_LEN, REQUEST_CURRENT_STATE, REQUEST_DEVICE_INFORMATION, REQUEST_SCANNED_WIFI_NETWORKS,
+};
+pub use serial::{FrameHeader, ImprovDecoder, V1Decoder, VersionDecoder};
+pub use server::{CredentialStore, ImprovServer, WifiProvisioner};
+#[cfg(feature = "std")]
+pub use shared::SharedClient;
+pub use ssid::Ssid;
+#[cfg(feature = "static-ip")]
+pub use static_ip::{NetworkConfig, StaticWifiSettings};
+#[cfg(feature = "std")]
+pub use tracker::{DeviceStateTracker, StateChange};
+#[cfg(feature = "std")]
+pub use transport::{
+    Direction, IoTransport, PacketReader, Reconnecting, Traced, Transport, FRAME_TERMINATOR,
+};
+#[cfg(feature = "std")]
+pub use verify::Verified;
+
+pub(crate) use protocol::{checksum, split_rpc, split_wifi_settings, wipe, Mode, TypedPacket};
+#[cfg(any(
+    feature = "std",
+    feature = "async",
+    feature = "tokio",
+    feature = "embedded-io",
+    feature = "embedded-io-async"
+))]
+pub(crate) use serial::discard;
+pub(crate) use serial::{decode_frame, scan, unframe, Scan};
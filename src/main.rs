@@ -1,64 +1,2326 @@
 // Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
 
-use std::io::Write;
-use std::{io, thread};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, IsTerminal, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use improv::{ImprovPacket, RPCCommand, WifiSettings};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use dialoguer::{BasicHistory, Input, Password, Select};
+use serde_json::{json, Value};
 
-fn usage() -> ! {
-    panic!(
-        "usage: {} <port> <ssid> <psk>",
-        std::env::args().next().unwrap()
-    )
+use improv::hex::{parse_hex, to_hex};
+use improv::mock::Scenario;
+use improv::record::{self, PcapngWriter, Replay};
+use improv::serial::{
+    self, DataBits, FlowControl, Parity, SerialOptions, SerialPort, SerialPortType, StopBits,
+};
+use improv::system;
+#[cfg(unix)]
+use serialport::TTYPort;
+
+use improv::{
+    provision_all, Chunk, ClientErr, CurrentState, Demux, DeviceInfo, Direction, ErrorState,
+    ExpectedFirmware, Frame, FrameHeader, ImprovClient, ImprovErr, ImprovPacket, IoTransport,
+    MockImprovDevice, PacketType, Progress, ProvisionOptions, ProvisionOutcome, Reconnecting,
+    ScanResults, Ssid, Traced, Transport, Verified, WifiNetwork, WifiSettings, MAX_DATA_LEN,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Provision Wi-Fi devices over Improv serial")]
+struct Cli {
+    /// The serial port the device is attached to (see `improv ports`), or tcp://host:port for a
+    /// serial-to-TCP bridge
+    ///
+    /// Repeat it to provision several devices at once.
+    #[arg(short, long)]
+    port: Vec<String>,
+
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Log every frame sent and received to stderr, as hex
+    #[arg(long, global = true)]
+    trace: bool,
+
+    /// Save every frame sent and received to FILE, to replay later, or as a pcapng capture if
+    /// FILE ends in .pcapng
+    #[arg(long, global = true, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Talk to a recording made with --record instead of a real device
+    #[arg(long, global = true, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// Seconds to wait for the device before giving up: for each answer, or for provisioning to
+    /// finish when provisioning [default: 10, or 30 to provision]
+    #[arg(long, global = true, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// The port's baud rate, or `auto` to try the common ones until the device answers
+    #[arg(short, long, default_value = "115200", value_parser = parse_baud)]
+    baud: Baud,
+
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(5..=8))]
+    data_bits: u8,
+
+    #[arg(long, value_enum, default_value_t = ParityArg::None)]
+    parity: ParityArg,
+
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=2))]
+    stop_bits: u8,
+
+    #[arg(long, value_enum, default_value_t = FlowArg::None)]
+    flow_control: FlowArg,
+
+    /// How long a single serial read may block, in milliseconds
+    #[arg(long, default_value_t = 100)]
+    read_timeout: u64,
+
+    /// Hold DTR at this level once the port is open
+    #[arg(long)]
+    dtr: Option<bool>,
+
+    /// Hold RTS at this level once the port is open
+    #[arg(long)]
+    rts: Option<bool>,
+
+    /// Reset the board with DTR/RTS and wait for it to boot before talking to it
+    #[arg(long)]
+    reset: bool,
+
+    /// With --reset, wait for this text in the boot log rather than for the log to go quiet
+    #[arg(long, requires = "reset")]
+    boot_banner: Option<String>,
+
+    /// The byte sent after each frame, as hex, or "none". Most firmware only acts on a frame once
+    /// the following byte arrives; the reference SDK sends a newline.
+    #[arg(long, value_name = "HEX", default_value = "0a", value_parser = parse_terminator)]
+    terminator: Terminator,
+
+    /// Write frames this many bytes at a time, for USB-UART bridges which drop bytes when a whole
+    /// frame arrives at once
+    #[arg(long, value_name = "BYTES")]
+    chunk_size: Option<usize>,
+
+    /// With --chunk-size, wait this many milliseconds between chunks
+    #[arg(long, value_name = "MS", default_value_t = 0, requires = "chunk_size")]
+    chunk_delay: u64,
+
+    /// Write frames a byte at a time, this many milliseconds apart
+    #[arg(long, value_name = "MS", conflicts_with = "chunk_size")]
+    byte_delay: Option<u64>,
+
+    /// Wait at least this many milliseconds after sending a frame before sending the next, for
+    /// bridges which lose the start of back-to-back frames
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    frame_gap: u64,
+
+    /// Re-open the port if it drops, e.g. as the board reboots after provisioning, so long as the
+    /// same device answers on it
+    #[arg(long)]
+    reconnect: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+struct PskArgs {
+    /// Read the password from the first line of stdin
+    #[arg(long)]
+    psk_stdin: bool,
+
+    /// Look the password up in a file of `ssid=password` lines
+    #[arg(long, value_name = "FILE")]
+    credentials: Option<PathBuf>,
+
+    /// Use the password this machine has saved for the network, from NetworkManager or
+    /// wpa_supplicant. Usually needs root
+    #[arg(long)]
+    from_system: bool,
+}
+
+impl PskArgs {
+    // tries stdin, the system's saved networks, then $IMPROV_PSK, then the credentials file, and
+    // finally prompts without echo. an empty password means an open network.
+    fn psk(&self, ssid: &Ssid) -> io::Result<String> {
+        if self.psk_stdin {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+        }
+        if self.from_system {
+            return match system::lookup(ssid)? {
+                Some(settings) => Ok(settings.psk.clone()),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("this machine has no saved password for {}", ssid),
+                )),
+            };
+        }
+        if let Ok(psk) = env::var("IMPROV_PSK") {
+            return Ok(psk);
+        }
+        if let Some(path) = &self.credentials {
+            if let Some(psk) = lookup(&fs::read_to_string(path)?, ssid) {
+                return Ok(psk.to_string());
+            }
+        }
+        Password::new()
+            .with_prompt(format!("Password for {} (empty if open)", ssid))
+            .allow_empty_password(true)
+            .interact()
+            .map_err(|dialoguer::Error::IO(e)| e)
+    }
+}
+
+// the credentials given to provision, prompting for the password if need be
+fn credentials(
+    ssid: &Option<String>,
+    psk: &Option<String>,
+    qr: &Option<WifiSettings>,
+    psk_from: &PskArgs,
+) -> io::Result<WifiSettings> {
+    if let Some(settings) = qr {
+        return Ok(settings.clone());
+    }
+    // clap requires one of --qr or the SSID
+    let ssid = Ssid::from(ssid.clone().unwrap_or_default());
+    let psk = match psk {
+        Some(psk) => psk.clone(),
+        None => psk_from.psk(&ssid)?,
+    };
+    Ok(WifiSettings { ssid, psk })
+}
+
+// finds `ssid` in lines of `ssid=password`, skipping blank lines and # comments
+fn lookup<'a>(file: &'a str, ssid: &Ssid) -> Option<&'a str> {
+    file.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .find(|(s, _)| *ssid == s.trim_end())
+        .map(|(_, psk)| psk.trim_start())
+}
+
+#[derive(Args)]
+struct WaitArgs {
+    // the global --timeout, which is set after parsing
+    #[arg(skip = PROVISION_TIMEOUT)]
+    timeout: u64,
+
+    /// Ask the device for its state whenever it's been quiet this many seconds
+    #[arg(long)]
+    poll: Option<u64>,
+
+    /// Once provisioned, check that the device came online, by fetching its redirect URL or
+    /// looking up its name over mDNS
+    #[arg(long)]
+    verify: bool,
+
+    /// Once provisioned, open the device's redirect URL, if it gave one, in the default browser
+    #[arg(long)]
+    open: bool,
+
+    /// Write a report of the run (the device, the network, each state change and the outcome) to
+    /// this file, as Markdown if it ends in .md and otherwise JSON
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Print nothing but errors, leaving the result to the exit code
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+impl WaitArgs {
+    fn options(&self) -> ProvisionOptions {
+        ProvisionOptions {
+            timeout: Duration::from_secs(self.timeout),
+            poll_interval: self.poll.map(Duration::from_secs),
+            ..Default::default()
+        }
+    }
+
+    fn provision_options(&self, firmware: &FirmwareArgs) -> ProvisionOptions {
+        ProvisionOptions {
+            expect_firmware: firmware.expected(),
+            ..self.options()
+        }
+    }
+}
+
+#[derive(Args)]
+struct FirmwareArgs {
+    /// Check the device's firmware name (as `improv info` shows it) before sending credentials,
+    /// and give up if it's something else, so they don't go to the wrong board
+    #[arg(long, value_name = "NAME")]
+    expect_firmware: Option<String>,
+
+    /// Likewise, give up if the device's firmware is older than this
+    #[arg(long, value_name = "VERSION")]
+    min_version: Option<String>,
+}
+
+impl FirmwareArgs {
+    fn expected(&self) -> Option<ExpectedFirmware> {
+        if self.expect_firmware.is_none() && self.min_version.is_none() {
+            return None;
+        }
+        Some(ExpectedFirmware {
+            name: self.expect_firmware.clone(),
+            min_version: self.min_version.clone(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ParityArg {
+    None,
+    Odd,
+    Even,
 }
 
-fn main() {
-    let port_name = std::env::args().nth(1).unwrap_or_else(|| usage());
+#[derive(Clone, Copy, ValueEnum)]
+enum StateArg {
+    Ready,
+    Provisioning,
+    Provisioned,
+}
 
-    let mut port = serialport::new(port_name, 115200)
-        .open()
-        .expect("Failed to open serial port");
+impl From<StateArg> for CurrentState {
+    fn from(s: StateArg) -> CurrentState {
+        match s {
+            StateArg::Ready => CurrentState::Ready,
+            StateArg::Provisioning => CurrentState::Provisioning,
+            StateArg::Provisioned => CurrentState::Provisioned,
+        }
+    }
+}
 
-    let mut outp = port.try_clone().expect("Failed to clone");
+#[derive(Clone, Copy)]
+enum Baud {
+    Auto,
+    Rate(u32),
+}
 
-    let mut input: String = String::new();
+fn parse_baud(s: &str) -> Result<Baud, String> {
+    if s == "auto" {
+        return Ok(Baud::Auto);
+    }
+    s.parse()
+        .map(Baud::Rate)
+        .map_err(|_| format!("expected a number or `auto`, not {:?}", s))
+}
 
-    let packets = [
-        ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState),
-        ImprovPacket::RPCCommand(RPCCommand::RequestDeviceInformation),
-        ImprovPacket::RPCCommand(RPCCommand::RequestScannedWifiNetworks),
-        ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
-            ssid: std::env::args().nth(2).unwrap_or_else(|| usage()),
-            psk: std::env::args().nth(3).unwrap_or_else(|| usage()),
-        })),
-    ];
-    let mut i = 0;
+#[derive(Clone, Copy, ValueEnum)]
+enum FlowArg {
+    None,
+    Software,
+    Hardware,
+}
 
-    thread::spawn(move || loop {
-        // block until we hit enter
-        let _ = std::io::stdin().read_line(&mut input);
+impl Cli {
+    fn serial_options(&self) -> SerialOptions {
+        SerialOptions {
+            baud_rate: match self.baud {
+                Baud::Rate(rate) => rate,
+                Baud::Auto => serial::COMMON_BAUD_RATES[0],
+            },
+            data_bits: match self.data_bits {
+                5 => DataBits::Five,
+                6 => DataBits::Six,
+                7 => DataBits::Seven,
+                _ => DataBits::Eight,
+            },
+            parity: match self.parity {
+                ParityArg::None => Parity::None,
+                ParityArg::Odd => Parity::Odd,
+                ParityArg::Even => Parity::Even,
+            },
+            stop_bits: match self.stop_bits {
+                2 => StopBits::Two,
+                _ => StopBits::One,
+            },
+            flow_control: match self.flow_control {
+                FlowArg::None => FlowControl::None,
+                FlowArg::Software => FlowControl::Software,
+                FlowArg::Hardware => FlowControl::Hardware,
+            },
+            read_timeout: Duration::from_millis(self.read_timeout),
+            dtr: self.dtr,
+            rts: self.rts,
+        }
+    }
 
-        let p = packets[i].clone();
-        i += 1;
-        i %= packets.len();
+    // --timeout is global, but provisioning reads it from its WaitArgs
+    fn apply_timeout(&mut self) {
+        if let (Some(timeout), Some(wait)) = (self.timeout, self.command.wait_mut()) {
+            wait.timeout = timeout;
+        }
+    }
 
-        println!("sending!");
-        outp.write_all(&<ImprovPacket as Into<Vec<u8>>>::into(p))
-            .expect("Failed to write to serial port");
+    // how long each request waits for an answer. when provisioning --timeout is the deadline for
+    // the whole thing instead, and requests along the way keep the default
+    fn request_timeout(&self) -> Duration {
+        if let (None, Command::Watch { .. }) = (self.timeout, &self.command) {
+            return Duration::from_secs(WATCH_TIMEOUT);
+        }
+        match (self.timeout, self.command.wait()) {
+            (Some(timeout), None) => Duration::from_secs(timeout),
+            _ => Duration::from_secs(REQUEST_TIMEOUT),
+        }
+    }
+
+    fn timeout_message(&self) -> String {
+        if let Command::Watch { until, .. } = self.command {
+            return format!(
+                "the device did not report {} within {}s",
+                CurrentState::from(until),
+                self.request_timeout().as_secs()
+            );
+        }
+        match self.command.wait() {
+            Some(wait) => format!(
+                "the device did not finish provisioning within {}s",
+                wait.timeout
+            ),
+            None => format!(
+                "the device did not respond within {}s",
+                self.request_timeout().as_secs()
+            ),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the device's current state
+    State,
+    /// Show the device's firmware and hardware information
+    Info,
+    /// List the Wi-Fi networks the device can see
+    Scan,
+    /// Ask the device to make itself known, e.g. by blinking an LED
+    Identify,
+    /// Send Wi-Fi credentials and wait for the device to connect
+    Provision {
+        #[arg(required_unless_present = "qr")]
+        ssid: Option<String>,
+        /// Ends up in shell history; prefer one of the options below, or the prompt
+        psk: Option<String>,
+        /// Take the SSID and password from a `WIFI:S:...;P:...;;` QR code payload, like the one
+        /// printed on many routers
+        #[arg(long, conflicts_with_all = ["ssid", "psk"])]
+        qr: Option<WifiSettings>,
+        #[command(flatten)]
+        psk_from: PskArgs,
+        #[command(flatten)]
+        wait: WaitArgs,
+        #[command(flatten)]
+        firmware: FirmwareArgs,
+    },
+    /// Pick a network from a scan, enter its password, and provision
+    Interactive {
+        #[command(flatten)]
+        psk_from: PskArgs,
+        #[command(flatten)]
+        wait: WaitArgs,
+        #[command(flatten)]
+        firmware: FirmwareArgs,
+    },
+    /// Send commands one at a time from a prompt, and see the device's responses
+    Shell,
+    /// Send an RPC command with any id and payload, e.g. a vendor extension, and print what the
+    /// device sends back
+    SendRaw {
+        /// The command id, in hex
+        #[arg(long, value_name = "HEX", value_parser = parse_byte)]
+        cmd: u8,
+        /// The command's data, in hex; empty if not given
+        #[arg(long, value_name = "HEX", value_parser = parse_bytes, default_value = "")]
+        payload: HexBytes,
+    },
+    /// Provision each device in a manifest in turn, writing the results in the same format
+    ///
+    /// The manifest is JSON (an array of objects) or, if it ends in .csv, CSV with a header row.
+    /// Each entry has an ssid, a psk (empty or missing for an open network), and either the port
+    /// the device is on or the serial number of its USB adapter.
+    Batch {
+        manifest: PathBuf,
+        /// Write the results here rather than to stdout
+        #[arg(long, short, value_name = "FILE")]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        wait: WaitArgs,
+        #[command(flatten)]
+        firmware: FirmwareArgs,
+    },
+    /// Wait for a device that's already provisioning to finish
+    Wait {
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    /// Wait, without asking the device anything, until it reports a state, e.g. while it's
+    /// provisioned over BLE [default timeout: 300]
+    Watch {
+        /// The state to wait for
+        #[arg(long, value_enum)]
+        until: StateArg,
+        /// Print nothing but errors, leaving the result to the exit code
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Print the device's log output, with any Improv packets in it decoded
+    Monitor {
+        /// Print one timestamped JSON object per line for each packet, state change, and piece of
+        /// log output, for other programs to consume
+        #[arg(long)]
+        ndjson: bool,
+        /// Find frames the ESPHome logger has written log lines into the middle of
+        #[arg(long)]
+        esphome: bool,
+        /// Strip color codes from the log output, and turn carriage returns into newlines
+        #[arg(long)]
+        plain: bool,
+    },
+    /// Find and decode the Improv frames in a capture: a hex dump (as from xxd or hexdump -C),
+    /// pasted hex, or the raw bytes
+    Decode {
+        /// The capture to read, or - for stdin
+        input: PathBuf,
+    },
+    /// Decode a single frame given as hex, such as one copied from a log: `49 4d 50 52 4f 56 ...`,
+    /// with or without spaces or 0x prefixes
+    DecodeHex {
+        /// The frame's bytes
+        #[arg(required = true)]
+        hex: Vec<String>,
+    },
+    /// List the serial ports on this machine
+    #[command(alias = "list")]
+    Ports {
+        /// Only show USB adapters commonly found on ESP boards
+        #[arg(long)]
+        esp: bool,
+    },
+    /// Ask every serial port for an Improv device, and list the ones that answer [default
+    /// timeout: 1]
+    Discover {
+        /// Only try USB adapters commonly found on ESP boards
+        #[arg(long)]
+        esp: bool,
+    },
+    /// Pretend to be an Improv device on a pseudo-terminal, for testing without hardware
+    Simulate {
+        /// Listen on this TCP address instead of opening a pseudo-terminal
+        #[arg(long)]
+        tcp: Option<String>,
+        /// Refuse all credentials as if the network couldn't be reached
+        #[arg(long)]
+        fail: bool,
+        /// Set up the device and script its misbehaviour from this JSON file (see
+        /// `improv::mock::Scenario`)
+        #[arg(long)]
+        scenario: Option<PathBuf>,
+    },
+}
+
+impl Command {
+    fn wait(&self) -> Option<&WaitArgs> {
+        match self {
+            Command::Provision { wait, .. }
+            | Command::Interactive { wait, .. }
+            | Command::Batch { wait, .. }
+            | Command::Wait { wait } => Some(wait),
+            _ => None,
+        }
+    }
+
+    fn wait_mut(&mut self) -> Option<&mut WaitArgs> {
+        match self {
+            Command::Provision { wait, .. }
+            | Command::Interactive { wait, .. }
+            | Command::Batch { wait, .. }
+            | Command::Wait { wait } => Some(wait),
+            _ => None,
+        }
+    }
+
+    // whether `run` handles it, i.e. it only talks to the device through a client, so it can be
+    // replayed
+    fn uses_client(&self) -> bool {
+        !matches!(
+            self,
+            Command::Ports { .. }
+                | Command::Discover { .. }
+                | Command::Monitor { .. }
+                | Command::Simulate { .. }
+                | Command::Batch { .. }
+                | Command::Decode { .. }
+                | Command::DecodeHex { .. }
+        )
+    }
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let hex = s.trim_start_matches("0x");
+    u8::from_str_radix(hex, 16).map_err(|_| format!("expected a hex byte, not {:?}", s))
+}
+
+#[derive(Clone)]
+struct HexBytes(Vec<u8>);
+
+fn parse_bytes(s: &str) -> Result<HexBytes, String> {
+    if s.is_empty() {
+        return Ok(HexBytes(Vec::new()));
+    }
+    parse_hex(s)
+        .map(HexBytes)
+        .map_err(|_| format!("expected hex bytes, not {:?}", s))
+}
 
-        // why is one more byte required? I don't know. any byte will do.
-        let _ = outp.write_all(&[0x01]);
-    });
+#[derive(Clone, Copy)]
+struct Terminator(Option<u8>);
 
-    // print whatever comes down the pipe
-    let mut buffer: [u8; 1024] = [0; 1024];
+fn parse_terminator(s: &str) -> Result<Terminator, String> {
+    if s == "none" {
+        return Ok(Terminator(None));
+    }
+    let hex = s.trim_start_matches("0x");
+    u8::from_str_radix(hex, 16)
+        .map(|b| Terminator(Some(b)))
+        .map_err(|_| format!("expected a hex byte or \"none\", not {:?}", s))
+}
+
+fn main() -> ExitCode {
+    let mut cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        // clap would exit with 2, which is TIMED_OUT here
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                ExitCode::from(INVALID_INPUT)
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+    cli.apply_timeout();
+    let timeout = cli.timeout_message();
+    if cli.reconnect && matches!(cli.baud, Baud::Auto) {
+        eprintln!("error: --reconnect needs a fixed --baud");
+        return ExitCode::from(INVALID_INPUT);
+    }
+    if cli.replay.is_some() && !cli.command.uses_client() {
+        eprintln!("error: --replay can only be used with commands which make requests");
+        return ExitCode::from(INVALID_INPUT);
+    }
+
+    match cli.command {
+        Command::Ports { esp } => return list_ports(esp),
+        Command::Discover { esp } => return discover(&cli, esp),
+        Command::Decode { ref input } => return decode(input, cli.json),
+        Command::DecodeHex { ref hex } => return decode_hex(&hex.join(" "), cli.json),
+        Command::Batch {
+            ref manifest,
+            ref output,
+            ref wait,
+            ref firmware,
+        } => return batch(&cli, manifest, output.as_deref(), wait, firmware),
+        Command::Simulate {
+            tcp,
+            fail,
+            scenario,
+        } => {
+            return match simulate(tcp.as_deref(), fail, scenario.as_deref()) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    ExitCode::from(PORT_ERROR)
+                }
+            }
+        }
+        _ => (),
+    }
+    if let Some(path) = &cli.replay {
+        if !cli.port.is_empty() || cli.record.is_some() {
+            eprintln!("error: --replay can't be used with --port or --record");
+            return ExitCode::from(INVALID_INPUT);
+        }
+        let replay = match File::open(path).and_then(|f| Replay::read(BufReader::new(f))) {
+            Ok(replay) => replay,
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", path.display(), e);
+                return ExitCode::from(INVALID_INPUT);
+            }
+        };
+        let mut client =
+            ImprovClient::new(Traced::new(replay, tracer(cli.trace.then(String::new))));
+        client.set_timeout(cli.request_timeout());
+        return finish(run(&mut client, cli.command, cli.json), &timeout);
+    }
+
+    let path = match cli.port.as_slice() {
+        [] => {
+            eprintln!("error: --port is required (see `improv ports`)");
+            return ExitCode::from(INVALID_INPUT);
+        }
+        [path] => path,
+        _ => return provision_many(&cli),
+    };
+
+    let port = match open(&cli, path) {
+        Ok(port) => port,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", path, e);
+            return ExitCode::from(PORT_ERROR);
+        }
+    };
+    if let Command::Monitor {
+        ndjson,
+        esphome,
+        plain,
+    } = cli.command
+    {
+        let mut demux = if esphome {
+            Demux::esphome()
+        } else {
+            Demux::new()
+        };
+        if plain {
+            demux = demux.with_log_filter();
+        }
+        return match monitor(port, demux, cli.json, ndjson) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(PORT_ERROR)
+            }
+        };
+    }
+    let recording = match cli.record.as_deref().map(recording).transpose() {
+        Ok(recording) => recording,
+        Err(e) => {
+            eprintln!("Failed to create the recording: {}", e);
+            return ExitCode::from(INVALID_INPUT);
+        }
+    };
+    let mut client = client(&cli, path, port, cli.trace.then(String::new), recording);
+    finish(run(&mut client, cli.command, cli.json), &timeout)
+}
+
+fn finish(result: Result<ExitCode, ClientErr>, timeout: &str) -> ExitCode {
+    match result {
+        Ok(code) => code,
+        Err(ClientErr::Timeout) => {
+            eprintln!("error: {}", timeout);
+            ExitCode::from(TIMED_OUT)
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(error_code(&e))
+        }
+    }
+}
+
+// the device's garbled frames are skipped rather than returned, so protocol errors are about what
+// was asked of it, like an SSID that's too long
+fn error_code(e: &ClientErr) -> u8 {
+    match e {
+        ClientErr::Io(_) => PORT_ERROR,
+        ClientErr::Improv(_) => INVALID_INPUT,
+        ClientErr::Device(_) | ClientErr::UnexpectedFirmware(_) => DEVICE_ERROR,
+        ClientErr::Timeout | ClientErr::Cancelled => TIMED_OUT,
+    }
+}
+
+fn outcome_code(outcome: &ProvisionOutcome) -> u8 {
+    match outcome {
+        ProvisionOutcome::Provisioned(_) => 0,
+        ProvisionOutcome::UnableToConnect => UNABLE_TO_CONNECT,
+        ProvisionOutcome::Rejected(_) => DEVICE_ERROR,
+    }
+}
+
+enum Recording {
+    Text(File),
+    Pcapng(PcapngWriter<File>),
+}
+
+fn recording(path: &Path) -> io::Result<Recording> {
+    let file = File::create(path)?;
+    if path.extension().is_some_and(|e| e == "pcapng") {
+        return PcapngWriter::new(file).map(Recording::Pcapng);
+    }
+    Ok(Recording::Text(file))
+}
+
+// the defaults for --timeout, in seconds
+const REQUEST_TIMEOUT: u64 = 10;
+const PROVISION_TIMEOUT: u64 = 30;
+// long enough for someone to provision the device by hand
+const WATCH_TIMEOUT: u64 = 300;
+// each port gets this long, as most won't have a device to answer
+const DISCOVER_TIMEOUT: u64 = 1;
+
+// exit codes, which scripts depend on, so they mustn't change (see the README). 0 is success
+const UNABLE_TO_CONNECT: u8 = 1;
+const TIMED_OUT: u8 = 2;
+// the device refused the command or reported an error, or is running the wrong firmware
+const DEVICE_ERROR: u8 = 3;
+// the port couldn't be opened, or failed while talking to the device
+const PORT_ERROR: u8 = 4;
+// bad arguments, or a file given as input couldn't be read or understood
+const INVALID_INPUT: u8 = 5;
+
+// frames are traced with `trace` as their label, if it's given
+fn client(
+    cli: &Cli,
+    path: &str,
+    port: Box<dyn Stream>,
+    trace: Option<String>,
+    recording: Option<Recording>,
+) -> ImprovClient<impl Transport + Send> {
+    let terminator = cli.terminator.0;
+    let chunking = match cli.byte_delay {
+        Some(ms) => (Some(1), Duration::from_millis(ms)),
+        None => (cli.chunk_size, Duration::from_millis(cli.chunk_delay)),
+    };
+    let frame_gap = Duration::from_millis(cli.frame_gap);
+    let link = move |port: Box<dyn Stream>| {
+        let mut io = IoTransport::new(port);
+        io.set_terminator(terminator);
+        io.set_chunking(chunking.0, chunking.1);
+        io.set_frame_gap(frame_gap);
+        io
+    };
+    let mut transport: Box<dyn Transport + Send> = if cli.reconnect {
+        // the port is re-opened as it is, without --reset
+        let (path, options) = (path.to_string(), cli.serial_options());
+        let mut t = Reconnecting::new(link(port), move || Ok(link(connect(&path, &options)?)));
+        t.match_device(true);
+        Box::new(t)
+    } else {
+        Box::new(link(port))
+    };
+    match recording {
+        Some(Recording::Text(file)) => transport = Box::new(record::record(transport, file)),
+        Some(Recording::Pcapng(w)) => transport = Box::new(record::record_pcapng(transport, w)),
+        None => (),
+    }
+    let mut client = ImprovClient::new(Traced::new(transport, tracer(trace)));
+    client.set_timeout(cli.request_timeout());
+    client
+}
+
+fn tracer(trace: Option<String>) -> impl FnMut(Direction, &[u8]) + Send {
+    move |dir: Direction, frame: &[u8]| {
+        if let Some(label) = &trace {
+            trace_frame(label, dir, frame)
+        }
+    }
+}
+
+fn provision_many(cli: &Cli) -> ExitCode {
+    let Command::Provision {
+        ssid,
+        psk,
+        qr,
+        psk_from,
+        wait,
+        firmware,
+    } = &cli.command
+    else {
+        eprintln!("error: only provision can be given more than one --port");
+        return ExitCode::from(INVALID_INPUT);
+    };
+    if cli.record.is_some() {
+        eprintln!("error: --record only works with a single --port");
+        return ExitCode::from(INVALID_INPUT);
+    }
+    if wait.verify {
+        eprintln!("error: --verify only works with a single --port");
+        return ExitCode::from(INVALID_INPUT);
+    }
+    if wait.open {
+        eprintln!("error: --open only works with a single --port");
+        return ExitCode::from(INVALID_INPUT);
+    }
+    if wait.report.is_some() {
+        eprintln!("error: --report only works with a single --port");
+        return ExitCode::from(INVALID_INPUT);
+    }
+    let settings = match credentials(ssid, psk, qr, psk_from) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(INVALID_INPUT);
+        }
+    };
+
+    let mut clients = Vec::new();
+    for path in &cli.port {
+        match open(cli, path) {
+            Ok(port) => clients.push(client(
+                cli,
+                path,
+                port,
+                cli.trace.then(|| format!("{} ", path)),
+                None,
+            )),
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", path, e);
+                return ExitCode::from(PORT_ERROR);
+            }
+        }
+    }
+    let outcomes = provision_all(&mut clients, &settings, &wait.provision_options(firmware));
+
+    if wait.quiet {
+        return exit_code(&outcomes);
+    }
+    if cli.json {
+        let results: Vec<Value> = cli
+            .port
+            .iter()
+            .zip(&outcomes)
+            .map(|(path, outcome)| {
+                let mut v = result_json(outcome);
+                v["port"] = json!(path);
+                v
+            })
+            .collect();
+        println!("{}", json!(results));
+    } else {
+        println!("{:<24} {:<20} URL", "PORT", "RESULT");
+        for (path, outcome) in cli.port.iter().zip(&outcomes) {
+            let (result, url) = describe(outcome);
+            println!("{:<24} {:<20} {}", path, result, url.unwrap_or("-"));
+        }
+    }
+    exit_code(&outcomes)
+}
+
+// a short description of the outcome for a table, and the redirect URL if there is one
+fn describe(outcome: &Result<ProvisionOutcome, ClientErr>) -> (String, Option<&str>) {
+    match outcome {
+        Ok(ProvisionOutcome::Provisioned(url)) => (String::from("provisioned"), url.as_deref()),
+        Ok(ProvisionOutcome::UnableToConnect) => (String::from("unable to connect"), None),
+        Ok(ProvisionOutcome::Rejected(e)) => (format!("rejected: {}", e), None),
+        Err(ClientErr::Timeout) => (String::from("timed out"), None),
+        Err(e) => (e.to_string(), None),
+    }
+}
+
+fn result_json(outcome: &Result<ProvisionOutcome, ClientErr>) -> Value {
+    match outcome {
+        Ok(outcome) => outcome_json(outcome),
+        Err(ClientErr::Timeout) => json!({ "outcome": "timed_out" }),
+        Err(e) => json!({ "outcome": "error", "error": e.to_string() }),
+    }
+}
+
+// the first device's failure, otherwise timed out if any did
+fn exit_code(outcomes: &[Result<ProvisionOutcome, ClientErr>]) -> ExitCode {
+    let code = |o: &Result<ProvisionOutcome, ClientErr>| match o {
+        Ok(outcome) => outcome_code(outcome),
+        Err(e) => error_code(e),
+    };
+    let codes = outcomes.iter().map(code);
+    let failed = codes.clone().find(|&c| c != 0 && c != TIMED_OUT);
+    ExitCode::from(failed.or(codes.clone().find(|&c| c != 0)).unwrap_or(0))
+}
+
+// one device in a batch manifest
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct Entry {
+    #[serde(default)]
+    port: Option<String>,
+    #[serde(default)]
+    serial: Option<String>,
+    ssid: String,
+    #[serde(default)]
+    psk: String,
+}
+
+fn batch(
+    cli: &Cli,
+    manifest: &Path,
+    output: Option<&Path>,
+    wait: &WaitArgs,
+    firmware: &FirmwareArgs,
+) -> ExitCode {
+    if wait.report.is_some() {
+        eprintln!("error: batch writes its results with --output rather than --report");
+        return ExitCode::from(INVALID_INPUT);
+    }
+    let csv = manifest.extension().is_some_and(|e| e == "csv");
+    let entries = match fs::read_to_string(manifest).and_then(|file| {
+        if csv {
+            parse_csv_manifest(&file)
+        } else {
+            serde_json::from_str::<Vec<Entry>>(&file).map_err(io::Error::from)
+        }
+    }) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", manifest.display(), e);
+            return ExitCode::from(INVALID_INPUT);
+        }
+    };
+
+    let mut outcomes = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let label = entry.port.as_ref().or(entry.serial.as_ref());
+        if !wait.quiet {
+            eprintln!(
+                "[{}/{}] Provisioning {} on {}...",
+                i + 1,
+                entries.len(),
+                entry.ssid,
+                label.map_or("?", String::as_str)
+            );
+        }
+        let outcome = provision_entry(cli, entry, wait, firmware);
+        if !wait.quiet {
+            eprintln!("[{}/{}] {}", i + 1, entries.len(), describe(&outcome).0);
+        }
+        outcomes.push(outcome);
+    }
+
+    let results = if csv {
+        let mut out = String::from("port,serial,ssid,result,redirect_url\n");
+        for (entry, outcome) in entries.iter().zip(&outcomes) {
+            let (result, url) = describe(outcome);
+            let row = [
+                entry.port.as_deref().unwrap_or(""),
+                entry.serial.as_deref().unwrap_or(""),
+                &entry.ssid,
+                &result,
+                url.unwrap_or(""),
+            ];
+            out += &row.map(csv_field).join(",");
+            out.push('\n');
+        }
+        out
+    } else {
+        let results: Vec<Value> = entries
+            .iter()
+            .zip(&outcomes)
+            .map(|(entry, outcome)| {
+                let mut v = result_json(outcome);
+                v["port"] = json!(entry.port);
+                v["serial"] = json!(entry.serial);
+                v["ssid"] = json!(entry.ssid);
+                v
+            })
+            .collect();
+        format!("{:#}\n", json!(results))
+    };
+    let written = match output {
+        Some(path) => fs::write(path, results),
+        None if wait.quiet => Ok(()),
+        None => io::stdout().write_all(results.as_bytes()),
+    };
+    if let Err(e) = written {
+        eprintln!("Failed to write the results: {}", e);
+        return ExitCode::from(INVALID_INPUT);
+    }
+    exit_code(&outcomes)
+}
+
+fn provision_entry(
+    cli: &Cli,
+    entry: &Entry,
+    wait: &WaitArgs,
+    firmware: &FirmwareArgs,
+) -> Result<ProvisionOutcome, ClientErr> {
+    let path = match (&entry.port, &entry.serial) {
+        (Some(port), _) => port.clone(),
+        (None, Some(serial)) => port_for_serial(serial)?,
+        (None, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the entry has neither a port nor a serial number",
+            )
+            .into())
+        }
+    };
+    let port = open(cli, &path)?;
+    let label = cli.trace.then(|| format!("{} ", path));
+    let mut client = client(cli, &path, port, label, None);
+    let settings = WifiSettings {
+        ssid: entry.ssid.as_str().into(),
+        psk: entry.psk.clone(),
+    };
+    let outcome = client.provision(&settings, &wait.provision_options(firmware))?;
+    if let (ProvisionOutcome::Provisioned(url), true) = (&outcome, wait.verify) {
+        client.verify_online(url.as_deref(), Duration::from_secs(wait.timeout))?;
+    }
+    if let (ProvisionOutcome::Provisioned(url), true) = (&outcome, wait.open) {
+        launch(url.as_deref());
+    }
+    Ok(outcome)
+}
+
+// the port of the USB serial adapter with this serial number
+fn port_for_serial(serial: &str) -> io::Result<String> {
+    serial::ports(false)?
+        .into_iter()
+        .find(|p| match &p.port_type {
+            SerialPortType::UsbPort(usb) => usb.serial_number.as_deref() == Some(serial),
+            _ => false,
+        })
+        .map(|p| p.port_name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no USB serial adapter has serial number {}", serial),
+            )
+        })
+}
+
+// CSV with a header row naming the columns; fields may be quoted, with "" for a quote
+fn parse_csv_manifest(file: &str) -> io::Result<Vec<Entry>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut lines = file.lines().filter(|l| !l.trim().is_empty());
+    let header = csv_row(lines.next().unwrap_or_default());
+    let column = |name: &str| header.iter().position(|h| h.trim() == name);
+    let (port, serial, psk) = (column("port"), column("serial"), column("psk"));
+    let ssid = column("ssid").ok_or_else(|| invalid(String::from("no ssid column")))?;
+
+    lines
+        .enumerate()
+        .map(|(i, line)| {
+            let row = csv_row(line);
+            let get = |c: Option<usize>| {
+                c.and_then(|c| row.get(c))
+                    .filter(|v| !v.is_empty())
+                    .cloned()
+            };
+            Ok(Entry {
+                port: get(port),
+                serial: get(serial),
+                ssid: get(Some(ssid))
+                    .ok_or_else(|| invalid(format!("row {} has no ssid", i + 1)))?,
+                psk: get(psk).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+fn csv_row(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn run<T: Transport>(
+    client: &mut ImprovClient<T>,
+    command: Command,
+    json: bool,
+) -> Result<ExitCode, ClientErr> {
+    match command {
+        Command::State => {
+            let state = client.current_state()?;
+            if json {
+                println!("{}", json!({ "state": state }));
+            } else {
+                println!("{}", state);
+            }
+        }
+        Command::Info => {
+            let info = client.device_info()?;
+            if json {
+                println!("{}", json!(info));
+            } else {
+                println!("Device:   {}", info.device_name);
+                println!("Firmware: {} {}", info.firmware_name, info.firmware_version);
+                println!("Hardware: {}", info.hardware);
+            }
+        }
+        Command::Scan => {
+            let networks = client.scan_networks()?;
+            if json {
+                println!("{}", json!(networks));
+            } else {
+                println!("{:<32} {:>5}  SECURED", "SSID", "RSSI");
+                for n in networks {
+                    let auth = if n.auth { "yes" } else { "no" };
+                    println!("{:<32} {:>5}  {}", n.ssid, n.rssi, auth);
+                }
+            }
+        }
+        Command::Identify => match client.identify() {
+            Ok(()) if json => println!("{}", json!({ "identified": true })),
+            Ok(()) => println!("Identified"),
+            Err(ClientErr::Device(ErrorState::UnknownRPCCommand)) => {
+                eprintln!("error: the device doesn't support identify");
+                return Ok(ExitCode::from(DEVICE_ERROR));
+            }
+            Err(e) => return Err(e),
+        },
+        Command::Provision {
+            ssid,
+            psk,
+            qr,
+            psk_from,
+            wait,
+            firmware,
+        } => {
+            let settings = match credentials(&ssid, &psk, &qr, &psk_from) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    return Ok(ExitCode::from(INVALID_INPUT));
+                }
+            };
+            let options = wait.provision_options(&firmware);
+            let outcome = provision(client, Some(&settings), &options, &wait)?;
+            return conclude(client, &outcome, &wait, json);
+        }
+        Command::Interactive {
+            psk_from,
+            wait,
+            firmware,
+        } => {
+            let Some(network) = pick_network(client)? else {
+                return Ok(ExitCode::from(UNABLE_TO_CONNECT));
+            };
+            let psk = if network.auth {
+                match psk_from.psk(&network.ssid) {
+                    Ok(psk) => psk,
+                    Err(e) => {
+                        eprintln!("error: {}", e);
+                        return Ok(ExitCode::from(INVALID_INPUT));
+                    }
+                }
+            } else {
+                String::new()
+            };
+            let settings = WifiSettings {
+                ssid: network.ssid,
+                psk,
+            };
+            if !wait.quiet {
+                println!("Provisioning {}...", settings.ssid);
+            }
+            let options = wait.provision_options(&firmware);
+            let outcome = provision(client, Some(&settings), &options, &wait)?;
+            return conclude(client, &outcome, &wait, json);
+        }
+        Command::Wait { wait } => {
+            let outcome = provision(client, None, &wait.options(), &wait)?;
+            return conclude(client, &outcome, &wait, json);
+        }
+        Command::Watch { until, quiet } => {
+            let state = CurrentState::from(until);
+            client.watch_until(state, client.timeout())?;
+            match (quiet, json) {
+                (true, _) => (),
+                (false, true) => println!("{}", json!({ "state": state })),
+                (false, false) => println!("{}", state),
+            }
+        }
+        Command::Shell => shell(client)?,
+        Command::SendRaw { cmd, payload } => return send_raw(client, cmd, &payload.0, json),
+        Command::Ports { .. }
+        | Command::Discover { .. }
+        | Command::Monitor { .. }
+        | Command::Simulate { .. }
+        | Command::Batch { .. }
+        | Command::Decode { .. }
+        | Command::DecodeHex { .. } => unreachable!(),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+// `path` is either a serial port or, for serial-to-TCP bridges, tcp://host:port
+fn open(cli: &Cli, path: &str) -> io::Result<Box<dyn Stream>> {
+    if path.starts_with("tcp://") {
+        if cli.reset {
+            return Err(io::Error::other("--reset needs a local serial port"));
+        }
+        return connect(path, &cli.serial_options());
+    }
+
+    let mut port = cli.serial_options().open(path)?;
+    if cli.reset {
+        reset(&mut port, cli.boot_banner.as_deref())?;
+    }
+    if let Baud::Auto = cli.baud {
+        detect_baud(port.as_mut(), path)?;
+    }
+    Ok(Box::new(port))
+}
+
+fn connect(path: &str, options: &SerialOptions) -> io::Result<Box<dyn Stream>> {
+    match path.strip_prefix("tcp://") {
+        Some(addr) => {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            stream.set_read_timeout(Some(options.read_timeout))?;
+            Ok(Box::new(stream))
+        }
+        None => Ok(Box::new(options.open(path)?)),
+    }
+}
+
+fn detect_baud(port: &mut dyn SerialPort, path: &str) -> io::Result<()> {
+    let rates = serial::COMMON_BAUD_RATES;
+    match serial::detect_baud(port, rates, Duration::from_secs(1))? {
+        Some(rate) => {
+            eprintln!("Detected {} baud", rate);
+            Ok(())
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no answer from {} at any of {:?} baud", path, rates),
+        )),
+    }
+}
+
+fn reset(port: &mut Box<dyn SerialPort>, banner: Option<&str>) -> io::Result<()> {
+    serial::hard_reset(port.as_mut())?;
+    if !serial::wait_for_boot(port, banner.map(str::as_bytes), Duration::from_secs(10))? {
+        eprintln!("warning: didn't see the device finish booting");
+    }
+    Ok(())
+}
+
+// passes log text through to stdout and prints packets on lines of their own. with --json, packets
+// go to stdout as JSON and the log to stderr.
+fn monitor(mut port: impl Read, mut demux: Demux, json: bool, ndjson: bool) -> io::Result<()> {
+    let mut buf = [0u8; 256];
+    let mut at_line_start = true;
+    let mut state = None;
+    loop {
+        let chunks = match port.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => demux.feed(&buf[..n]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                demux.flush().into_iter().collect()
+            }
+            Err(e) => return Err(e),
+        };
+        for chunk in chunks {
+            if ndjson {
+                for event in events(&chunk, &mut state) {
+                    println!("{}", event);
+                }
+                continue;
+            }
+            match chunk {
+                Chunk::Log(text) if json => io::stderr().write_all(&text)?,
+                Chunk::Log(text) => {
+                    at_line_start = text.ends_with(b"\n");
+                    io::stdout().write_all(&text)?;
+                }
+                Chunk::Packet(p) if json => println!("{}", json!(p)),
+                Chunk::Packet(p) => {
+                    if !at_line_start {
+                        println!();
+                    }
+                    println!("<< {}", p);
+                    at_line_start = true;
+                }
+            }
+        }
+        io::stdout().flush()?;
+    }
+}
+
+// the monitor --ndjson lines for a chunk of device output. `state` is the last state the device
+// reported, for spotting transitions
+fn events(chunk: &Chunk, state: &mut Option<CurrentState>) -> Vec<Value> {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let p = match chunk {
+        Chunk::Log(text) => {
+            return vec![json!({
+                "ts": ts,
+                "event": "log",
+                "direction": "rx",
+                "text": String::from_utf8_lossy(text),
+            })]
+        }
+        Chunk::Packet(p) => p,
+    };
+
+    let kind = match p {
+        ImprovPacket::CurrentState(_) => "current_state",
+        ImprovPacket::ErrorState(_) => "error_state",
+        ImprovPacket::RPCCommand(_) => "rpc_command",
+        ImprovPacket::RPCResult(_) => "rpc_result",
+        ImprovPacket::Raw { .. } => "raw",
+    };
+    let mut events = vec![json!({
+        "ts": ts,
+        "event": "packet",
+        "direction": "rx",
+        "type": kind,
+        "packet": p,
+    })];
+    if let ImprovPacket::CurrentState(new) = p {
+        if *state != Some(*new) {
+            events.push(json!({
+                "ts": ts,
+                "event": "state_change",
+                "from": state,
+                "to": new,
+            }));
+            *state = Some(*new);
+        }
+    }
+    events
+}
+
+fn trace_frame(label: &str, dir: Direction, frame: &[u8]) {
+    let hex = to_hex(frame);
+    match ImprovPacket::decode_lenient(frame.to_vec()) {
+        Ok(p) => eprintln!("{}{} {}  {}", label, dir.marker(), hex, p),
+        Err(e) => eprintln!("{}{} {}  ({})", label, dir.marker(), hex, e),
+    }
+}
+
+fn report(outcome: &ProvisionOutcome, json: bool, quiet: bool) -> ExitCode {
+    if quiet {
+        return ExitCode::from(outcome_code(outcome));
+    }
+    if json {
+        println!("{}", outcome_json(outcome));
+    } else {
+        match outcome {
+            ProvisionOutcome::Provisioned(Some(url)) => println!("Provisioned: {}", url),
+            ProvisionOutcome::Provisioned(None) => println!("Provisioned"),
+            ProvisionOutcome::UnableToConnect => println!("Unable to connect"),
+            ProvisionOutcome::Rejected(e) => println!("Rejected: {}", e),
+        }
+    }
+    ExitCode::from(outcome_code(outcome))
+}
+
+// reports the outcome, first checking that the device came online if asked to
+fn conclude<T: Transport>(
+    client: &mut ImprovClient<T>,
+    outcome: &ProvisionOutcome,
+    wait: &WaitArgs,
+    json: bool,
+) -> Result<ExitCode, ClientErr> {
+    let ProvisionOutcome::Provisioned(url) = outcome else {
+        return Ok(report(outcome, json, wait.quiet));
+    };
+    if !wait.verify {
+        let code = report(outcome, json, wait.quiet);
+        if wait.open {
+            launch(url.as_deref());
+        }
+        return Ok(code);
+    }
+
+    if !json && !wait.quiet {
+        report(outcome, false, false);
+        eprintln!("Verifying...");
+    }
+    let verified = client.verify_online(url.as_deref(), Duration::from_secs(wait.timeout));
+    if !wait.quiet {
+        if json {
+            let mut v = outcome_json(outcome);
+            v["verified"] = match &verified {
+                Ok(verified) => json!(verified),
+                Err(e) => json!({ "error": e.to_string() }),
+            };
+            println!("{}", v);
+        } else {
+            match &verified {
+                Ok(Verified::Http(status)) => println!("Online: HTTP {}", status),
+                Ok(Verified::Resolved(ip)) => println!("Online: {}", ip),
+                Err(e) => println!("Not reachable: {}", e),
+            }
+        }
+    }
+    Ok(match verified {
+        Ok(_) => {
+            if wait.open {
+                launch(url.as_deref());
+            }
+            ExitCode::SUCCESS
+        }
+        Err(_) => ExitCode::from(UNABLE_TO_CONNECT),
+    })
+}
+
+// sends `settings`, or without them waits for a device that's already provisioning, and writes
+// the --report if there is one
+fn provision<T: Transport>(
+    client: &mut ImprovClient<T>,
+    settings: Option<&WifiSettings>,
+    options: &ProvisionOptions,
+    wait: &WaitArgs,
+) -> Result<ProvisionOutcome, ClientErr> {
+    let Some(path) = &wait.report else {
+        return match settings {
+            Some(settings) => client.provision(settings, options),
+            None => client.wait_provisioned(options),
+        };
+    };
+
+    let started = SystemTime::now();
+    let device = client.device_info();
+    let timeline = Timeline::start(client);
+    let outcome = match settings {
+        Some(settings) => client.provision(settings, options),
+        None => client.wait_provisioned(options),
+    };
+    let report = Report {
+        device: device.map_err(|e| e.to_string()),
+        ssid: settings.map(|s| &s.ssid),
+        started,
+        finished: SystemTime::now(),
+        events: timeline.finish(),
+        outcome: &outcome,
+    };
+    let text = if path.extension().is_some_and(|e| e == "md") {
+        report.markdown()
+    } else {
+        format!("{:#}\n", report.json())
+    };
+    if let Err(e) = fs::write(path, text) {
+        eprintln!("Failed to write the report: {}", e);
+    }
+    outcome
+}
+
+// timestamps the client's progress as it's made, which reading the events afterwards can't
+struct Timeline {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<Vec<(SystemTime, Progress)>>,
+}
+
+impl Timeline {
+    fn start<T: Transport>(client: &mut ImprovClient<T>) -> Timeline {
+        let progress = client.progress();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut events = Vec::new();
+            while !stopped.load(Ordering::Relaxed) {
+                if let Ok(p) = progress.recv_timeout(Duration::from_millis(50)) {
+                    events.push((SystemTime::now(), p));
+                }
+            }
+            // anything sent just before stopping
+            events.extend(progress.try_iter().map(|p| (SystemTime::now(), p)));
+            events
+        });
+        Timeline { stop, thread }
+    }
+
+    fn finish(self) -> Vec<(SystemTime, Progress)> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+// what --report records. the PSK is left out, since reports get passed around
+struct Report<'a> {
+    device: Result<DeviceInfo, String>,
+    ssid: Option<&'a Ssid>,
+    started: SystemTime,
+    finished: SystemTime,
+    events: Vec<(SystemTime, Progress)>,
+    outcome: &'a Result<ProvisionOutcome, ClientErr>,
+}
+
+impl Report<'_> {
+    fn json(&self) -> Value {
+        let mut state = None;
+        let events: Vec<Value> = self
+            .events
+            .iter()
+            .map(|(time, p)| {
+                let mut v = match p {
+                    Progress::CommandSent(id) => json!({ "event": "command", "command": id }),
+                    Progress::StateChanged(to) => {
+                        let v = json!({ "event": "state_change", "from": state, "to": to });
+                        state = Some(*to);
+                        v
+                    }
+                    Progress::ResultReceived(id) => json!({ "event": "result", "command": id }),
+                    Progress::Error(e) => json!({ "event": "error", "error": e }),
+                    Progress::Verified(v) => json!({ "event": "verified", "verified": v }),
+                };
+                v["time"] = json!(utc(*time));
+                v
+            })
+            .collect();
+        let mut v = result_json(self.outcome);
+        v["started"] = json!(utc(self.started));
+        v["finished"] = json!(utc(self.finished));
+        v["device"] = match &self.device {
+            Ok(info) => json!(info),
+            Err(e) => json!({ "error": e }),
+        };
+        v["ssid"] = json!(self.ssid);
+        v["events"] = json!(events);
+        v
+    }
+
+    fn markdown(&self) -> String {
+        let (outcome, url) = describe(self.outcome);
+        let mut out = String::from("# Provisioning report\n\n");
+        out += &format!("- Started: {}\n", utc(self.started));
+        out += &format!("- Finished: {}\n", utc(self.finished));
+        match &self.device {
+            Ok(info) => out += &format!("- Device: {}\n", info),
+            Err(e) => out += &format!("- Device: unknown ({})\n", e),
+        }
+        if let Some(ssid) = self.ssid {
+            out += &format!("- Network: {}\n", ssid);
+        }
+        out += &format!("- Outcome: {}\n", outcome);
+        if let Some(url) = url {
+            out += &format!("- Redirect URL: {}\n", url);
+        }
+        out += "\n| Time | Event |\n| --- | --- |\n";
+        for (time, p) in &self.events {
+            let event = match p {
+                Progress::CommandSent(id) => format!("sent command 0x{:02x}", id),
+                Progress::StateChanged(s) => format!("state changed to {}", s),
+                Progress::ResultReceived(id) => format!("result for command 0x{:02x}", id),
+                Progress::Error(e) => format!("error: {}", e),
+                Progress::Verified(_) => String::from("found online"),
+            };
+            out += &format!("| {} | {} |\n", utc(*time), event);
+        }
+        out
+    }
+}
+
+// RFC 3339, in UTC to the millisecond
+fn utc(time: SystemTime) -> String {
+    let t = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, secs) = ((t.as_secs() / 86400) as i64, t.as_secs() % 86400);
+    // the civil date from days since the epoch, after Howard Hinnant's days_from_civil
+    let z = days + 719_468;
+    let (era, doe) = (z.div_euclid(146_097), z.rem_euclid(146_097));
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        t.subsec_millis()
+    )
+}
+
+// opens the redirect URL in the browser. not having one, or no browser, isn't worth failing over
+fn launch(url: Option<&str>) {
+    match url {
+        Some(url) => {
+            if let Err(e) = open::that_detached(url) {
+                eprintln!("Failed to open {}: {}", url, e);
+            }
+        }
+        None => eprintln!("warning: the device gave no URL to open"),
+    }
+}
+
+const SHELL_HELP: &str = "\
+state                    show the device's current state
+info                     show the device's firmware and hardware
+scan                     list the networks the device can see
+provision <ssid> [psk]   send credentials and wait for the device to connect
+raw <hex>                send bytes as they are, e.g. a hand-built frame
+help                     show this
+quit                     leave the shell (or ctrl-d)";
+
+// how long to keep printing what the device sends after a raw frame
+const RAW_QUIET: Duration = Duration::from_secs(1);
+
+// reads commands until the user quits. errors from the device are printed rather than returned, so
+// that one bad command doesn't end the session
+fn shell<T: Transport>(client: &mut ImprovClient<T>) -> Result<(), ClientErr> {
+    let mut history = BasicHistory::new().max_entries(100).no_duplicates(true);
+    let tty = io::stdin().is_terminal();
+    if tty {
+        eprintln!("Type help for a list of commands");
+    }
     loop {
-        match port.read(&mut buffer) {
-            Ok(bytes) => {
-                io::stdout().write_all(&buffer[0..bytes]).unwrap();
+        let Some(line) = read_command(tty, &mut history)? else {
+            return Ok(());
+        };
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => Ok(()),
+            ["quit" | "exit"] => return Ok(()),
+            ["help"] => {
+                println!("{}", SHELL_HELP);
+                Ok(())
+            }
+            ["state"] => client.current_state().map(|s| println!("{}", s)),
+            ["info"] => client.device_info().map(|i| println!("{}", i)),
+            ["scan"] => client.scan_networks().map(|networks| {
+                for n in networks {
+                    println!("{}", n);
+                }
+            }),
+            ["provision", ssid, psk @ ..] if psk.len() <= 1 => {
+                let settings = WifiSettings {
+                    ssid: Ssid::from(*ssid),
+                    psk: psk.first().unwrap_or(&"").to_string(),
+                };
+                client
+                    .provision(&settings, &ProvisionOptions::default())
+                    .map(|outcome| {
+                        report(&outcome, false, false);
+                    })
+            }
+            ["raw", bytes @ ..] if !bytes.is_empty() => match parse_hex(&bytes.join(" ")) {
+                Ok(frame) => raw(client, &frame, RAW_QUIET, true).map(|_| ()),
+                Err(_) => {
+                    eprintln!("not a hex string: {}", bytes.join(" "));
+                    Ok(())
+                }
+            },
+            _ => {
+                eprintln!("unknown command; type help for a list");
+                Ok(())
+            }
+        };
+        match result {
+            Ok(()) => (),
+            Err(ClientErr::Timeout) => eprintln!(
+                "the device did not respond within {}s",
+                client.timeout().as_secs()
+            ),
+            Err(ClientErr::Io(e)) => return Err(ClientErr::Io(e)),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+}
+
+// prompts with history on a terminal, and otherwise reads plain lines so that commands can be
+// piped in. `None` at the end of input
+fn read_command(tty: bool, history: &mut BasicHistory) -> io::Result<Option<String>> {
+    if !tty {
+        let mut line = String::new();
+        return match io::stdin().read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        };
+    }
+    match Input::<String>::new()
+        .with_prompt("improv")
+        .allow_empty(true)
+        .history_with(history)
+        .interact_text()
+    {
+        Ok(line) => Ok(Some(line)),
+        Err(dialoguer::Error::IO(e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(dialoguer::Error::IO(e)) => Err(e),
+    }
+}
+
+// sends `frame` and collects whatever comes back, waiting `first` for the first packet and then
+// until the device goes quiet. each packet is printed as it arrives if `print`
+fn raw<T: Transport>(
+    client: &mut ImprovClient<T>,
+    frame: &[u8],
+    first: Duration,
+    print: bool,
+) -> Result<Vec<ImprovPacket>, ClientErr> {
+    client.send_frame(frame)?;
+    let timeout = client.timeout();
+    client.set_timeout(first);
+    let mut packets = Vec::new();
+    let result = loop {
+        match client.recv() {
+            Ok(p) => {
+                if print {
+                    println!("{}", p);
+                }
+                packets.push(p);
+                client.set_timeout(RAW_QUIET);
             }
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
-            Err(e) => eprintln!("{:?}", e),
+            Err(ClientErr::Timeout) => break Ok(packets),
+            Err(e) => break Err(e),
         }
+    };
+    client.set_timeout(timeout);
+    result
+}
+
+// frames `cmd` and `payload` as an RPC command without checking either, so standard commands can
+// be sent with odd payloads too
+fn send_raw<T: Transport>(
+    client: &mut ImprovClient<T>,
+    cmd: u8,
+    payload: &[u8],
+    json: bool,
+) -> Result<ExitCode, ClientErr> {
+    if payload.len() > MAX_DATA_LEN - 2 {
+        eprintln!(
+            "error: the payload can be at most {} bytes",
+            MAX_DATA_LEN - 2
+        );
+        return Ok(ExitCode::from(INVALID_INPUT));
+    }
+    let mut data = vec![cmd, payload.len() as u8];
+    data.extend_from_slice(payload);
+    let frame = Frame::new(PacketType::RPCCommand.into(), data)?;
+
+    let timeout = client.timeout();
+    let packets = raw(client, &frame.to_bytes(), timeout, !json)?;
+    if json {
+        println!("{}", json!(packets));
+    }
+    if packets.is_empty() {
+        return Err(ClientErr::Timeout);
+    }
+    let failed = packets
+        .iter()
+        .any(|p| matches!(p, ImprovPacket::ErrorState(e) if *e != ErrorState::NoError));
+    if failed {
+        return Ok(ExitCode::from(DEVICE_ERROR));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// a frame found by `decode`, `offset` bytes into the capture
+struct Found<'a> {
+    offset: usize,
+    bytes: &'a [u8],
+    checksum_ok: bool,
+    packet: Result<ImprovPacket, ImprovErr>,
+}
+
+fn decode(input: &Path, json: bool) -> ExitCode {
+    let read = if input == Path::new("-") {
+        let mut capture = Vec::new();
+        io::stdin().read_to_end(&mut capture).map(|_| capture)
+    } else {
+        fs::read(input)
+    };
+    let capture = match read {
+        Ok(capture) => capture,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", input.display(), e);
+            return ExitCode::from(INVALID_INPUT);
+        }
+    };
+    let bytes = std::str::from_utf8(&capture)
+        .ok()
+        .and_then(parse_dump)
+        .unwrap_or(capture);
+
+    let found = find_frames(&bytes);
+    for f in &found {
+        let hex = to_hex(f.bytes);
+        if json {
+            println!(
+                "{}",
+                json!({
+                    "offset": f.offset,
+                    "checksum_valid": f.checksum_ok,
+                    "packet": f.packet.as_ref().ok(),
+                    "error": f.packet.as_ref().err().map(ToString::to_string),
+                    "frame": hex,
+                })
+            );
+            continue;
+        }
+        let checksum = if f.checksum_ok { "ok " } else { "bad" };
+        match &f.packet {
+            Ok(p) => println!("{:08x}  {}  {}", f.offset, checksum, p),
+            Err(e) => println!("{:08x}  {}  {} ({})", f.offset, checksum, e, hex),
+        }
+    }
+    if found.is_empty() {
+        eprintln!("No Improv frames found");
+        return ExitCode::from(INVALID_INPUT);
+    }
+    ExitCode::SUCCESS
+}
+
+fn decode_hex(hex: &str, json: bool) -> ExitCode {
+    let packet = hex.parse::<Frame>().and_then(Frame::decode);
+    match (packet, json) {
+        (Ok(p), true) => println!("{}", json!(p)),
+        (Ok(p), false) => println!("{}", p),
+        (Err(e), _) => {
+            eprintln!("Failed to decode {}: {}", hex, e);
+            return ExitCode::from(INVALID_INPUT);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+// every frame that starts in `bytes`, whether or not it checks out
+fn find_frames(bytes: &[u8]) -> Vec<Found<'_>> {
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(at) = bytes[from..].windows(6).position(|w| w == b"IMPROV") {
+        let offset = from + at;
+        let rest = &bytes[offset..];
+        let len = FrameHeader::parse(rest).map_or(rest.len(), |h| h.frame_len().min(rest.len()));
+        let frame = &rest[..len];
+        let checksum_ok = Frame::parse(frame).is_ok();
+        // one that doesn't check out may just be "IMPROV" in the log, with a real frame inside it
+        from = if checksum_ok {
+            offset + len
+        } else {
+            offset + 1
+        };
+        found.push(Found {
+            offset,
+            bytes: frame,
+            checksum_ok,
+            packet: ImprovPacket::decode_lenient(frame.to_vec()),
+        });
+    }
+    found
+}
+
+// the bytes of a hex dump, or None if it isn't one. offsets (`00000010:` from xxd, or the first
+// column of hexdump -C) and the ASCII column are skipped; anything else has to be hex bytes,
+// optionally 0x-prefixed or comma-separated
+fn parse_dump(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for line in text.lines() {
+        let mut line = line.trim();
+        if let Some((hex, _)) = line.split_once('|') {
+            line = hex
+                .split_once(char::is_whitespace)
+                .map_or("", |(_, rest)| rest);
+        } else if let Some((offset, rest)) = line.split_once(':') {
+            if u64::from_str_radix(offset.trim(), 16).is_ok() {
+                // xxd puts two spaces before the ASCII column, and only one between groups
+                line = rest.trim_start().split("  ").next().unwrap_or_default();
+            }
+        }
+        for token in line.split(|c: char| c.is_whitespace() || c == ',') {
+            let token = token.trim_start_matches("0x");
+            if !token.is_empty() {
+                bytes.extend(parse_hex(token).ok()?);
+            }
+        }
+    }
+    (!bytes.is_empty()).then_some(bytes)
+}
+
+// scans and lets the user choose a network, strongest first
+fn pick_network<T: Transport>(
+    client: &mut ImprovClient<T>,
+) -> Result<Option<WifiNetwork>, ClientErr> {
+    eprintln!("Scanning...");
+    let mut networks = client.scan_networks()?;
+    networks.tidy();
+    if networks.is_empty() {
+        eprintln!("No networks found");
+        return Ok(None);
+    }
+
+    let items: Vec<String> = networks
+        .iter()
+        .map(|n| {
+            let lock = if n.auth { "secured" } else { "open" };
+            format!("{:<32} {:>4} dBm  {}", n.ssid, n.rssi, lock)
+        })
+        .collect();
+    let Some(i) = Select::new()
+        .with_prompt("Network")
+        .items(&items)
+        .default(0)
+        .interact_opt()
+        .map_err(|dialoguer::Error::IO(e)| e)?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(networks.swap_remove(i)))
+}
+
+fn outcome_json(outcome: &ProvisionOutcome) -> Value {
+    match outcome {
+        ProvisionOutcome::Provisioned(url) => {
+            json!({ "outcome": "provisioned", "redirect_url": url })
+        }
+        ProvisionOutcome::UnableToConnect => json!({ "outcome": "unable_to_connect" }),
+        ProvisionOutcome::Rejected(e) => json!({ "outcome": "rejected", "error": e }),
+    }
+}
+
+fn simulated_device(fail: bool, scenario: Option<&Scenario>) -> MockImprovDevice {
+    let network = |ssid: &str, rssi, auth| WifiNetwork {
+        ssid: ssid.into(),
+        rssi,
+        auth,
+    };
+    let device = MockImprovDevice::new().with_networks(vec![
+        network("anthill", -48, true),
+        network("anthill-guest", -61, false),
+        network("neighbours", -83, true),
+    ]);
+    let device = if fail {
+        device.fails_with(ErrorState::UnableToConnect)
+    } else {
+        device.provisions(Some(String::from("http://mock-device.local")))
+    };
+    match scenario {
+        Some(scenario) => device.with_scenario(scenario.clone()),
+        None => device,
+    }
+}
+
+fn simulate(tcp: Option<&str>, fail: bool, scenario: Option<&Path>) -> io::Result<()> {
+    let scenario: Option<Scenario> = match scenario {
+        Some(path) => Some(
+            serde_json::from_str(&fs::read_to_string(path)?).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad scenario {}: {}", path.display(), e),
+                )
+            })?,
+        ),
+        None => None,
+    };
+    if let Some(addr) = tcp {
+        let listener = TcpListener::bind(addr)?;
+        eprintln!("Simulating a device on {}", listener.local_addr()?);
+        // each connection gets a fresh device, so the scenario starts over
+        for stream in listener.incoming() {
+            simulated_device(fail, scenario.as_ref()).serve(stream?)?;
+        }
+        return Ok(());
+    }
+    simulate_pty(simulated_device(fail, scenario.as_ref()))
+}
+
+#[cfg(unix)]
+fn simulate_pty(mut device: MockImprovDevice) -> io::Result<()> {
+    let (master, slave) = TTYPort::pair()?;
+    eprintln!(
+        "Simulating a device on {}",
+        slave.name().unwrap_or_default()
+    );
+    // the other end has to stay open for the pty to survive clients coming and going
+    let _slave = slave;
+    device.serve(master)
+}
+
+#[cfg(not(unix))]
+fn simulate_pty(_device: MockImprovDevice) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pseudo-terminals aren't supported on this platform; use --tcp",
+    ))
+}
+
+fn list_ports(esp: bool) -> ExitCode {
+    let ports = match serial::ports(esp) {
+        Ok(ports) => ports,
+        Err(e) => {
+            eprintln!("Failed to list serial ports: {}", e);
+            return ExitCode::from(PORT_ERROR);
+        }
+    };
+    for port in ports {
+        match port.port_type {
+            SerialPortType::UsbPort(usb) => println!(
+                "{}  {:04x}:{:04x}  {}",
+                port.port_name,
+                usb.vid,
+                usb.pid,
+                [usb.manufacturer, usb.product]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            _ => println!("{}", port.port_name),
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn discover(cli: &Cli, esp: bool) -> ExitCode {
+    let timeout = Duration::from_secs(cli.timeout.unwrap_or(DISCOVER_TIMEOUT));
+    let found = match serial::discover(esp, &cli.serial_options(), timeout) {
+        Ok(found) => found,
+        Err(e) => {
+            eprintln!("Failed to list serial ports: {}", e);
+            return ExitCode::from(PORT_ERROR);
+        }
+    };
+    if cli.json {
+        let devices: Vec<Value> = found
+            .iter()
+            .map(|d| json!({ "port": d.port.port_name, "state": d.state, "info": d.info }))
+            .collect();
+        println!("{}", json!(devices));
+    } else if found.is_empty() {
+        eprintln!("No Improv devices found");
+    } else {
+        println!("{:<24} {:<14} {:<24} FIRMWARE", "PORT", "STATE", "DEVICE");
+        for d in &found {
+            let (name, firmware) = match &d.info {
+                Some(info) => (
+                    info.device_name.clone(),
+                    format!("{} {}", info.firmware_name, info.firmware_version),
+                ),
+                None => (String::from("-"), String::from("-")),
+            };
+            println!(
+                "{:<24} {:<14} {:<24} {}",
+                d.port.port_name,
+                d.state.to_string(),
+                name,
+                firmware
+            );
+        }
+    }
+    if found.is_empty() {
+        return ExitCode::from(TIMED_OUT);
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn timeouts() {
+        let parse = |args: &[&str]| {
+            let mut cli =
+                Cli::try_parse_from([&["improv", "-p", "/dev/ttyUSB0"], args].concat()).unwrap();
+            cli.apply_timeout();
+            (cli.request_timeout().as_secs(), cli.timeout_message())
+        };
+        assert_eq!(
+            parse(&["state"]),
+            (10, String::from("the device did not respond within 10s"))
+        );
+        assert_eq!(parse(&["scan", "--timeout", "3"]).0, 3);
+        assert_eq!(parse(&["--timeout", "3", "info"]).0, 3);
+        assert_eq!(
+            parse(&["provision", "anthill", "ants", "--timeout", "60"]),
+            (
+                10,
+                String::from("the device did not finish provisioning within 60s")
+            )
+        );
+        assert_eq!(
+            parse(&["wait"]).1,
+            "the device did not finish provisioning within 30s"
+        );
+    }
+
+    #[test]
+    fn replayable() {
+        let parse = |args: &[&str]| Cli::try_parse_from([&["improv"], args].concat()).unwrap();
+        assert!(parse(&["state"]).command.uses_client());
+        assert!(parse(&["provision", "anthill", "ants"])
+            .command
+            .uses_client());
+        assert!(!parse(&["monitor"]).command.uses_client());
+        assert!(!parse(&["decode-hex", "00"]).command.uses_client());
+    }
+
+    #[test]
+    fn reports() {
+        let at = |ms| UNIX_EPOCH + Duration::from_millis(ms);
+        assert_eq!(utc(at(0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(utc(at(951_827_696_789)), "2000-02-29T12:34:56.789Z");
+        assert_eq!(utc(at(1_791_763_200_000)), "2026-10-12T00:00:00.000Z");
+
+        let ssid = Ssid::from("anthill");
+        let outcome = Ok(ProvisionOutcome::Provisioned(Some(String::from(
+            "http://anthill-sensor.local",
+        ))));
+        let report = Report {
+            device: Err(String::from("timed out")),
+            ssid: Some(&ssid),
+            started: at(1_000),
+            finished: at(3_500),
+            events: vec![
+                (at(1_010), Progress::CommandSent(0x01)),
+                (
+                    at(1_020),
+                    Progress::StateChanged(CurrentState::Provisioning),
+                ),
+                (at(3_400), Progress::StateChanged(CurrentState::Provisioned)),
+                (at(3_450), Progress::ResultReceived(0x01)),
+            ],
+            outcome: &outcome,
+        };
+        let v = report.json();
+        assert_eq!(v["outcome"], "provisioned");
+        assert_eq!(v["redirect_url"], "http://anthill-sensor.local");
+        assert_eq!(v["ssid"], "anthill");
+        assert_eq!(v["device"]["error"], "timed out");
+        assert_eq!(
+            v["events"][2],
+            json!({
+                "time": "1970-01-01T00:00:03.400Z",
+                "event": "state_change",
+                "from": "Provisioning",
+                "to": "Provisioned",
+            })
+        );
+
+        let md = report.markdown();
+        assert!(md.contains("- Network: anthill\n- Outcome: provisioned\n"));
+        assert!(md.contains("| 1970-01-01T00:00:01.020Z | state changed to provisioning |\n"));
+    }
+
+    #[test]
+    fn csv_manifest() {
+        let file = "port,ssid,psk\n/dev/ttyUSB0,anthill,\"ants, in my \"\"pants\"\"\"\n\n\
+                    /dev/ttyUSB1,cafe,\n";
+        assert_eq!(
+            parse_csv_manifest(file).unwrap(),
+            [
+                Entry {
+                    port: Some(String::from("/dev/ttyUSB0")),
+                    serial: None,
+                    ssid: "anthill".into(),
+                    psk: String::from("ants, in my \"pants\""),
+                },
+                Entry {
+                    port: Some(String::from("/dev/ttyUSB1")),
+                    serial: None,
+                    ssid: "cafe".into(),
+                    psk: String::new(),
+                },
+            ]
+        );
+        assert!(parse_csv_manifest("port,psk\nCOM3,secret\n").is_err());
+        assert_eq!(csv_field("a \"b\", c"), "\"a \"\"b\"\", c\"");
+    }
+
+    #[test]
+    fn ndjson_events() {
+        let mut state = None;
+        let ready = Chunk::Packet(ImprovPacket::CurrentState(CurrentState::Ready));
+        let out = events(&ready, &mut state);
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0]["type"], "current_state");
+        assert_eq!(out[0]["packet"], json!({ "CurrentState": "Ready" }));
+        assert_eq!(out[1]["event"], "state_change");
+        assert_eq!(out[1]["from"], Value::Null);
+        assert_eq!(out[1]["to"], "Ready");
+
+        assert_eq!(events(&ready, &mut state).len(), 1);
+        let log = events(&Chunk::Log(b"boot\n".to_vec()), &mut state);
+        assert_eq!(log[0]["text"], "boot\n");
+    }
+
+    #[test]
+    fn exit_codes() {
+        let rejected = ProvisionOutcome::Rejected(ErrorState::NotAuthorized);
+        assert_eq!(outcome_code(&rejected), DEVICE_ERROR);
+        assert_eq!(
+            outcome_code(&ProvisionOutcome::UnableToConnect),
+            UNABLE_TO_CONNECT
+        );
+        let closed = io::Error::from(io::ErrorKind::BrokenPipe);
+        assert_eq!(error_code(&ClientErr::Io(closed)), PORT_ERROR);
+        assert_eq!(
+            error_code(&ClientErr::Improv(ImprovErr::SsidTooLong(40))),
+            INVALID_INPUT
+        );
+    }
+
+    #[test]
+    fn dumps() {
+        let frame = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        assert_eq!(frame, parse_hex("494d50524f5601010102e2").unwrap());
+
+        let xxd = "00000000: 626f 6f74 0a49 4d50 524f 5601 0101 02e2  boot.IMPROV.....\n";
+        let hexdump =
+            "00000000  62 6f 6f 74 0a 49 4d 50  52 4f 56 01 01 01 02 e2  |boot.IMPROV.....|\n";
+        let pasted = "0x62, 0x6f, 0x6f, 0x74, 0x0a,\n494d50524f56 01010102e2";
+        for dump in [xxd, hexdump, pasted] {
+            let bytes = parse_dump(dump).unwrap();
+            assert_eq!(&bytes[..5], b"boot\n");
+            assert_eq!(bytes[5..], frame);
+        }
+        assert_eq!(parse_dump("I (312) wifi: started"), None);
+
+        // a stray IMPROV in the log, a good frame, and one with a bad checksum
+        let mut capture = b"IMPROV says hi ".to_vec();
+        capture.extend(&frame);
+        capture.extend(&frame);
+        *capture.last_mut().unwrap() ^= 0xff;
+        let found = find_frames(&capture);
+        assert_eq!(found.len(), 3);
+        assert!(!found[0].checksum_ok);
+        assert_eq!(found[1].offset, 15);
+        assert!(found[1].checksum_ok);
+        assert_eq!(
+            found[1].packet,
+            Ok(ImprovPacket::CurrentState(CurrentState::Ready))
+        );
+        assert!(!found[2].checksum_ok);
+        assert!(matches!(
+            found[2].packet,
+            Err(ImprovErr::BadChecksum { .. })
+        ));
     }
 }
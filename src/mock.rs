@@ -0,0 +1,491 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! A simulated device for testing host tooling, which can be scripted to misbehave.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    checksum, decode_frame, scan, CurrentState, DeviceInfo, ErrorState, ImprovPacket, ImprovServer,
+    Scan, Transport, WifiNetwork, WifiProvisioner, WifiSettings,
+};
+
+struct Canned {
+    info: DeviceInfo,
+    networks: Vec<WifiNetwork>,
+    outcome: Result<Option<String>, ErrorState>,
+    credentials: Vec<WifiSettings>,
+}
+
+impl WifiProvisioner for Canned {
+    fn connect(&mut self, ssid: &[u8], psk: &str) -> Result<Option<String>, ErrorState> {
+        self.credentials.push(WifiSettings {
+            ssid: ssid.into(),
+            psk: psk.into(),
+        });
+        self.outcome.clone()
+    }
+
+    fn scan(&mut self) -> Vec<WifiNetwork> {
+        self.networks.clone()
+    }
+
+    fn device_info(&mut self) -> DeviceInfo {
+        self.info.clone()
+    }
+
+    fn identify(&mut self) -> Result<(), ErrorState> {
+        Ok(())
+    }
+}
+
+/// How a simulated device misbehaves when a `Rule` applies.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Action {
+    /// Don't answer at all, as if the command was lost.
+    Drop,
+    /// Answer after this many milliseconds.
+    Delay(u64),
+    /// Answer with this error instead of carrying out the command.
+    Error(ErrorState),
+    /// Answer with these packets instead of carrying out the command.
+    Respond(Vec<ImprovPacket>),
+    /// Carry out the command, but corrupt the checksum of every frame in the answer.
+    Corrupt,
+}
+
+/// Misbehaves when the device is sent a particular command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rule {
+    /// The id of the command this applies to, or any command if `None`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub command: Option<u8>,
+    /// Let this many matching commands through first.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub skip: u32,
+    /// Stop applying after this many times, or never stop if `None`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub times: Option<u32>,
+    pub action: Action,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    seen: u32,
+}
+
+impl Rule {
+    /// Apply `action` to every command with this id. Use `skip` and `times` to narrow it down.
+    pub fn new(command: u8, action: Action) -> Rule {
+        Rule {
+            command: Some(command),
+            skip: 0,
+            times: None,
+            action,
+            seen: 0,
+        }
+    }
+
+    pub fn skip(mut self, skip: u32) -> Rule {
+        self.skip = skip;
+        self
+    }
+
+    pub fn times(mut self, times: u32) -> Rule {
+        self.times = Some(times);
+        self
+    }
+
+    // count a command against the rule, returning whether it applies
+    fn applies(&mut self, id: u8) -> bool {
+        if self.command.is_some_and(|c| c != id) {
+            return false;
+        }
+        self.seen += 1;
+        self.seen > self.skip && self.times.is_none_or(|t| self.seen <= self.skip + t)
+    }
+}
+
+/// A simulated device's setup and misbehaviour, e.g. loaded from a JSON file with the `serde`
+/// feature. Anything left out keeps `MockImprovDevice`'s defaults.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Scenario {
+    pub state: Option<CurrentState>,
+    pub device_info: Option<DeviceInfo>,
+    pub networks: Option<Vec<WifiNetwork>>,
+    /// Refuse credentials with this error, rather than accepting them.
+    pub fails_with: Option<ErrorState>,
+    pub redirect_url: Option<String>,
+    /// Checked in order for each command; the first which applies wins.
+    pub rules: Vec<Rule>,
+}
+
+/// A simulated device with canned responses, for testing host tooling without hardware.
+///
+/// It implements `Transport`, so it can be handed straight to an `ImprovClient`. Each frame the
+/// client sends is answered immediately; when there's nothing left to read, `recv_frame` times out
+/// without waiting.
+pub struct MockImprovDevice {
+    server: ImprovServer<Canned>,
+    rules: Vec<Rule>,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+    // answers held back by `Action::Delay`, and when they're due
+    delayed: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl MockImprovDevice {
+    pub fn new() -> MockImprovDevice {
+        MockImprovDevice {
+            server: ImprovServer::new(Canned {
+                info: DeviceInfo {
+                    firmware_name: String::from("improv-rs"),
+                    firmware_version: String::from(env!("CARGO_PKG_VERSION")),
+                    hardware: String::from("mock"),
+                    device_name: String::from("mock-device"),
+                },
+                networks: Vec::new(),
+                outcome: Ok(None),
+                credentials: Vec::new(),
+            }),
+            rules: Vec::new(),
+            buf: Vec::new(),
+            out: Vec::new(),
+            delayed: VecDeque::new(),
+        }
+    }
+
+    /// Set up a device as `scenario` describes.
+    pub fn from_scenario(scenario: Scenario) -> MockImprovDevice {
+        MockImprovDevice::new().with_scenario(scenario)
+    }
+
+    /// Apply `scenario` on top of how the device is already set up.
+    pub fn with_scenario(mut self, scenario: Scenario) -> MockImprovDevice {
+        if let Some(state) = scenario.state {
+            self = self.with_state(state);
+        }
+        if let Some(info) = scenario.device_info {
+            self = self.with_device_info(info);
+        }
+        if let Some(networks) = scenario.networks {
+            self = self.with_networks(networks);
+        }
+        match (scenario.fails_with, scenario.redirect_url) {
+            (Some(e), _) => self = self.fails_with(e),
+            (None, Some(url)) => self = self.provisions(Some(url)),
+            (None, None) => (),
+        }
+        self.rules.extend(scenario.rules);
+        self
+    }
+
+    pub fn with_state(mut self, state: CurrentState) -> MockImprovDevice {
+        // announcing the state is the device's business, not the client's
+        let _ = self.server.set_state(state, None);
+        self
+    }
+
+    pub fn with_device_info(mut self, info: DeviceInfo) -> MockImprovDevice {
+        self.server.provisioner().info = info;
+        self
+    }
+
+    pub fn with_networks(mut self, networks: Vec<WifiNetwork>) -> MockImprovDevice {
+        self.server.provisioner().networks = networks;
+        self
+    }
+
+    /// Accept credentials, answering with `redirect_url`. This is the default, with no URL.
+    pub fn provisions(mut self, redirect_url: Option<String>) -> MockImprovDevice {
+        self.server.provisioner().outcome = Ok(redirect_url);
+        self
+    }
+
+    /// Refuse credentials with `error`.
+    pub fn fails_with(mut self, error: ErrorState) -> MockImprovDevice {
+        self.server.provisioner().outcome = Err(error);
+        self
+    }
+
+    /// Misbehave as `rule` says. Rules are checked in the order they're added.
+    pub fn with_rule(mut self, rule: Rule) -> MockImprovDevice {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn state(&self) -> CurrentState {
+        self.server.state()
+    }
+
+    /// Every set of credentials the device has been sent, oldest first.
+    pub fn credentials(&mut self) -> &[WifiSettings] {
+        &self.server.provisioner().credentials
+    }
+
+    /// Act as a device on the far end of a byte stream, such as a pseudo-terminal or a socket,
+    /// until it closes. Read timeouts are ignored, so serial ports can be served too.
+    pub fn serve<S: Read + Write>(&mut self, mut stream: S) -> io::Result<()> {
+        let mut buf = [0u8; 256];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    let (delay, out) = self.answer(&buf[..n]);
+                    thread::sleep(delay);
+                    stream.write_all(&out)?;
+                    stream.flush()?;
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut
+                            | io::ErrorKind::WouldBlock
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl MockImprovDevice {
+    // pass each frame in `bytes` to the server unless a rule says otherwise, returning what to
+    // send back and how long to wait first
+    fn answer(&mut self, bytes: &[u8]) -> (Duration, Vec<u8>) {
+        self.buf.extend_from_slice(bytes);
+        let mut delay = Duration::ZERO;
+        let mut out = Vec::new();
+        loop {
+            let frame: Vec<u8> = match scan(&self.buf) {
+                Scan::Frame { start, end } => self.buf.drain(..end).skip(start).collect(),
+                Scan::Partial { start } => {
+                    self.buf.drain(..start);
+                    break;
+                }
+                Scan::Nothing => {
+                    self.buf.clear();
+                    break;
+                }
+            };
+            let action = match decode_frame(&frame) {
+                Ok(ImprovPacket::RPCCommand(cmd)) => {
+                    let id = cmd.id();
+                    // every matching rule counts the command, even if an earlier one applies
+                    let applies: Vec<bool> = self.rules.iter_mut().map(|r| r.applies(id)).collect();
+                    applies
+                        .iter()
+                        .position(|&a| a)
+                        .map(|i| self.rules[i].action.clone())
+                }
+                _ => None,
+            };
+            match action {
+                None => out.extend(self.server.feed(&frame)),
+                Some(Action::Drop) => (),
+                Some(Action::Delay(ms)) => {
+                    delay += Duration::from_millis(ms);
+                    out.extend(self.server.feed(&frame));
+                }
+                Some(Action::Error(e)) => {
+                    out.extend(Vec::try_from(ImprovPacket::ErrorState(e)).unwrap_or_default())
+                }
+                Some(Action::Respond(packets)) => {
+                    for p in packets {
+                        out.extend(Vec::try_from(p).unwrap_or_default());
+                    }
+                }
+                Some(Action::Corrupt) => out.extend(corrupt(self.server.feed(&frame))),
+            }
+        }
+        (delay, out)
+    }
+}
+
+// break the checksum of every frame in `bytes`
+fn corrupt(mut bytes: Vec<u8>) -> Vec<u8> {
+    let mut from = 0;
+    while let Scan::Frame { start, end } = scan(&bytes[from..]) {
+        let (start, end) = (from + start, from + end);
+        bytes[end - 1] = !checksum(&bytes[start..end - 1]);
+        from = end;
+    }
+    bytes
+}
+
+impl Default for MockImprovDevice {
+    fn default() -> MockImprovDevice {
+        MockImprovDevice::new()
+    }
+}
+
+impl Transport for MockImprovDevice {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let (delay, out) = self.answer(frame);
+        if delay.is_zero() {
+            self.out.extend(out);
+        } else {
+            self.delayed.push_back((Instant::now() + delay, out));
+        }
+        Ok(())
+    }
+
+    // delayed answers are waited for if they're due within `timeout`
+    fn recv_frame(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Scan::Frame { start, end } = scan(&self.out) {
+                return Ok(Some(self.out.drain(..end).skip(start).collect()));
+            }
+            match self.delayed.front() {
+                Some(&(due, _)) if due <= deadline => {
+                    thread::sleep(due.saturating_duration_since(Instant::now()));
+                    if let Some((_, out)) = self.delayed.pop_front() {
+                        self.out.extend(out);
+                    }
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ClientErr, ImprovClient, ImprovPacket, ProvisionOptions, ProvisionOutcome, RPCCommand,
+    };
+
+    fn settings() -> WifiSettings {
+        WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        }
+    }
+
+    #[test]
+    fn provisions() {
+        let device = MockImprovDevice::new().provisions(Some(String::from("http://10.0.0.2")));
+        let mut client = ImprovClient::new(device);
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        assert_eq!(
+            client
+                .provision(&settings(), &ProvisionOptions::default())
+                .unwrap(),
+            ProvisionOutcome::Provisioned(Some(String::from("http://10.0.0.2"))),
+        );
+
+        let mut device = client.into_inner();
+        assert_eq!(device.state(), CurrentState::Provisioned);
+        assert_eq!(device.credentials(), [settings()]);
+    }
+
+    #[test]
+    fn fails() {
+        let device = MockImprovDevice::new().fails_with(ErrorState::UnableToConnect);
+        let mut client = ImprovClient::new(device);
+        assert!(matches!(
+            client.send_wifi_settings(&settings()),
+            Err(ClientErr::Device(ErrorState::UnableToConnect)),
+        ));
+    }
+
+    #[test]
+    fn serves_a_stream() {
+        struct Stream {
+            rx: io::Cursor<Vec<u8>>,
+            tx: Vec<u8>,
+        }
+
+        impl Read for Stream {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.rx.read(buf)
+            }
+        }
+
+        impl Write for Stream {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.tx.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let request = ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState);
+        let mut stream = Stream {
+            rx: io::Cursor::new(Vec::try_from(request).unwrap()),
+            tx: Vec::new(),
+        };
+        MockImprovDevice::new().serve(&mut stream).unwrap();
+        assert_eq!(
+            stream.tx,
+            Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap(),
+        );
+    }
+
+    #[test]
+    fn scans() {
+        let networks = vec![WifiNetwork {
+            ssid: "anthill".into(),
+            rssi: -50,
+            auth: true,
+        }];
+        let device = MockImprovDevice::new()
+            .with_state(CurrentState::Provisioned)
+            .with_networks(networks.clone());
+        let mut client = ImprovClient::new(device);
+        assert_eq!(client.scan_networks().unwrap(), networks);
+        assert_eq!(client.current_state().unwrap(), CurrentState::Provisioned);
+    }
+
+    #[test]
+    fn rules() {
+        let device = MockImprovDevice::new()
+            .with_rule(Rule::new(0x01, Action::Error(ErrorState::UnableToConnect)).times(1))
+            .with_rule(Rule::new(0x02, Action::Drop).skip(1).times(1))
+            .with_rule(Rule::new(0x03, Action::Corrupt))
+            .with_rule(Rule::new(0x04, Action::Delay(20)));
+        let mut client = ImprovClient::new(device);
+        client.set_timeout(Duration::from_millis(500));
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        assert!(matches!(client.current_state(), Err(ClientErr::Timeout)));
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        assert!(matches!(client.device_info(), Err(ClientErr::Timeout)));
+        assert!(client.scan_networks().unwrap().is_empty());
+
+        assert!(matches!(
+            client.send_wifi_settings(&settings()),
+            Err(ClientErr::Device(ErrorState::UnableToConnect)),
+        ));
+        assert_eq!(client.send_wifi_settings(&settings()).unwrap(), None);
+        assert_eq!(client.into_inner().credentials(), [settings()]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn scenario() {
+        let scenario: Scenario = serde_json::from_str(
+            r#"{
+                "state": "Provisioned",
+                "redirect_url": "http://10.0.0.2",
+                "rules": [
+                    {"command": 3, "action": {"Respond": [{"ErrorState": "UnknownError"}]}},
+                    {"times": 1, "action": {"Delay": 10}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let mut client = ImprovClient::new(MockImprovDevice::from_scenario(scenario));
+        assert_eq!(client.current_state().unwrap(), CurrentState::Provisioned);
+        assert!(matches!(
+            client.device_info(),
+            Err(ClientErr::Device(ErrorState::UnknownError))
+        ));
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Tidying up scan results before they're shown to someone choosing a network.
+
+use alloc::vec::Vec;
+
+use crate::WifiNetwork;
+
+/// Cleanup for the networks a device reports from a scan.
+pub trait ScanResults {
+    /// Keep only the strongest entry for each SSID. Mesh networks show up once per access point.
+    fn dedup_strongest(&mut self);
+
+    /// Strongest signal first.
+    fn sort_by_signal(&mut self);
+
+    /// Drop networks which don't broadcast their SSID.
+    fn retain_visible(&mut self);
+
+    /// All of the above, for showing a user a list of networks to pick from.
+    fn tidy(&mut self) {
+        self.retain_visible();
+        self.dedup_strongest();
+        self.sort_by_signal();
+    }
+}
+
+impl ScanResults for Vec<WifiNetwork> {
+    fn dedup_strongest(&mut self) {
+        // on a tie the first entry wins. scans are short, so quadratic is fine
+        let keep: Vec<bool> = self
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                !self.iter().enumerate().any(|(j, m)| {
+                    m.ssid == n.ssid && (m.rssi > n.rssi || (m.rssi == n.rssi && j < i))
+                })
+            })
+            .collect();
+        let mut keep = keep.into_iter();
+        self.retain(|_| keep.next().unwrap_or(true));
+    }
+
+    fn sort_by_signal(&mut self) {
+        self.sort_by_key(|n| core::cmp::Reverse(n.rssi));
+    }
+
+    fn retain_visible(&mut self) {
+        // hidden networks have an empty SSID, or one of NULs
+        self.retain(|n| n.ssid.as_bytes().iter().any(|&b| b != 0));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn network(ssid: &str, rssi: i32) -> WifiNetwork {
+        WifiNetwork {
+            ssid: ssid.into(),
+            rssi,
+            auth: true,
+        }
+    }
+
+    #[test]
+    fn tidy() {
+        let mut networks = vec![
+            network("anthill", -70),
+            network("", -30),
+            network("beehive", -60),
+            network("anthill", -40),
+            network("\0\0\0", -50),
+            network("anthill", -40),
+        ];
+        networks.tidy();
+        assert_eq!(networks, [network("anthill", -40), network("beehive", -60)]);
+    }
+}
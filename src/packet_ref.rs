@@ -0,0 +1,215 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Borrowed views of packets, for decoding without allocating. Each converts to its owned
+//! counterpart with `From`.
+
+use crate::fields::FieldReader;
+use crate::{
+    split_rpc, split_wifi_settings, unframe, CommandId, CurrentState, ErrorState, FrameHeader,
+    ImprovErr, ImprovPacket, RPCCommand, RPCResult, TypedPacket, WifiSettings, IMPROV_VERSION,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PacketRef<'a> {
+    CurrentState(CurrentState),
+    ErrorState(ErrorState),
+    RPCCommand(RPCCommandRef<'a>),
+    RPCResult(RPCResultRef<'a>),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RPCCommandRef<'a> {
+    SendWifiSettings(WifiSettingsRef<'a>),
+    RequestCurrentState,
+    RequestDeviceInformation,
+    RequestScannedWifiNetworks,
+    Custom { id: u8, payload: &'a [u8] },
+}
+
+// Debug is implemented by hand so the PSK doesn't end up in logs
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct WifiSettingsRef<'a> {
+    pub ssid: &'a [u8],
+    pub psk: &'a str,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RPCResultRef<'a> {
+    pub command: u8,
+    // checked to be a well-formed sequence of length-prefixed fields
+    data: &'a [u8],
+}
+
+impl<'a> RPCResultRef<'a> {
+    /// The length-prefixed fields, in order.
+    pub fn fields(&self) -> impl Iterator<Item = &'a [u8]> {
+        FieldReader::new(self.data).filter_map(Result::ok)
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for PacketRef<'a> {
+    type Error = ImprovErr;
+
+    fn try_from(b: &'a [u8]) -> Result<PacketRef<'a>, ImprovErr> {
+        let header = FrameHeader::parse(b)?;
+        if header.version != IMPROV_VERSION {
+            return Err(ImprovErr::UnsupportedVersion(header.version));
+        }
+
+        let (header, data) = unframe(b)?;
+        match header.pkt_type {
+            CurrentState::TYPE => Ok(PacketRef::CurrentState(CurrentState::try_from(
+                *data.first().ok_or(ImprovErr::Truncated)?,
+            )?)),
+            ErrorState::TYPE => Ok(PacketRef::ErrorState(ErrorState::try_from(
+                *data.first().ok_or(ImprovErr::Truncated)?,
+            )?)),
+            RPCCommand::TYPE => Ok(PacketRef::RPCCommand(RPCCommandRef::try_from(data)?)),
+            RPCResult::TYPE => Ok(PacketRef::RPCResult(RPCResultRef::try_from(data)?)),
+            pkt_type => Err(ImprovErr::UnknownPacketType(pkt_type)),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for RPCCommandRef<'a> {
+    type Error = ImprovErr;
+
+    fn try_from(b: &'a [u8]) -> Result<RPCCommandRef<'a>, ImprovErr> {
+        let (cmd, data) = split_rpc(b)?;
+        match CommandId::try_from(cmd) {
+            Ok(CommandId::SendWifiSettings) => {
+                WifiSettingsRef::try_from(data).map(RPCCommandRef::SendWifiSettings)
+            }
+            Ok(CommandId::RequestCurrentState) => Ok(RPCCommandRef::RequestCurrentState),
+            Ok(CommandId::RequestDeviceInformation) => Ok(RPCCommandRef::RequestDeviceInformation),
+            Ok(CommandId::RequestScannedWifiNetworks) => {
+                Ok(RPCCommandRef::RequestScannedWifiNetworks)
+            }
+            Err(_) => Ok(RPCCommandRef::Custom {
+                id: cmd,
+                payload: data,
+            }),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for WifiSettingsRef<'a> {
+    type Error = ImprovErr;
+
+    fn try_from(b: &'a [u8]) -> Result<WifiSettingsRef<'a>, ImprovErr> {
+        let (ssid, psk) = split_wifi_settings(b)?;
+        Ok(WifiSettingsRef {
+            ssid,
+            psk: core::str::from_utf8(psk).map_err(|_| ImprovErr::InvalidUtf8)?,
+        })
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for RPCResultRef<'a> {
+    type Error = ImprovErr;
+
+    fn try_from(b: &'a [u8]) -> Result<RPCResultRef<'a>, ImprovErr> {
+        let (command, data) = split_rpc(b)?;
+        for field in FieldReader::new(data) {
+            field?;
+        }
+        Ok(RPCResultRef { command, data })
+    }
+}
+
+impl From<PacketRef<'_>> for ImprovPacket {
+    fn from(p: PacketRef<'_>) -> ImprovPacket {
+        match p {
+            PacketRef::CurrentState(c) => ImprovPacket::CurrentState(c),
+            PacketRef::ErrorState(e) => ImprovPacket::ErrorState(e),
+            PacketRef::RPCCommand(c) => ImprovPacket::RPCCommand(c.into()),
+            PacketRef::RPCResult(r) => ImprovPacket::RPCResult(r.into()),
+        }
+    }
+}
+
+impl From<RPCCommandRef<'_>> for RPCCommand {
+    fn from(c: RPCCommandRef<'_>) -> RPCCommand {
+        match c {
+            RPCCommandRef::SendWifiSettings(w) => RPCCommand::SendWifiSettings(w.into()),
+            RPCCommandRef::RequestCurrentState => RPCCommand::RequestCurrentState,
+            RPCCommandRef::RequestDeviceInformation => RPCCommand::RequestDeviceInformation,
+            RPCCommandRef::RequestScannedWifiNetworks => RPCCommand::RequestScannedWifiNetworks,
+            RPCCommandRef::Custom { id, payload } => RPCCommand::Custom {
+                id,
+                payload: payload.to_vec(),
+            },
+        }
+    }
+}
+
+impl From<WifiSettingsRef<'_>> for WifiSettings {
+    fn from(w: WifiSettingsRef<'_>) -> WifiSettings {
+        WifiSettings {
+            ssid: w.ssid.into(),
+            psk: w.psk.into(),
+        }
+    }
+}
+
+impl From<RPCResultRef<'_>> for RPCResult {
+    fn from(r: RPCResultRef<'_>) -> RPCResult {
+        RPCResult {
+            command: r.command,
+            data: r.fields().map(|v| v.to_vec()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn borrows_and_converts() {
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        let frame = Vec::try_from(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(
+            settings.clone(),
+        )))
+        .unwrap();
+        let p = PacketRef::try_from(&frame[..]).unwrap();
+        assert_eq!(
+            p,
+            PacketRef::RPCCommand(RPCCommandRef::SendWifiSettings(WifiSettingsRef {
+                ssid: b"anthill",
+                psk: "ants in my pants",
+            }))
+        );
+        assert_eq!(
+            ImprovPacket::from(p),
+            ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(settings))
+        );
+
+        let r = RPCResult {
+            command: 0x03,
+            data: vec![b"improv-rs".to_vec(), b"".to_vec(), b"mock".to_vec()],
+        };
+        let frame = Vec::try_from(ImprovPacket::RPCResult(r.clone())).unwrap();
+        match PacketRef::try_from(&frame[..]).unwrap() {
+            PacketRef::RPCResult(v) => {
+                assert_eq!(
+                    v.fields().collect::<Vec<_>>(),
+                    [&b"improv-rs"[..], b"", b"mock"]
+                );
+                assert_eq!(RPCResult::from(v), r);
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+
+        assert_eq!(
+            RPCResultRef::try_from(&[0x03, 0x02, 0x05, b'x'][..]),
+            Err(ImprovErr::Truncated)
+        );
+    }
+}
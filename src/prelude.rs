@@ -0,0 +1,18 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! The types most users need, for glob importing: `use improv::prelude::*;`
+
+pub use crate::protocol::{
+    Capabilities, CurrentState, CustomCommand, DeviceInfo, ErrorState, ImprovErr, ImprovPacket,
+    RPCCommand, RPCResult, WifiNetwork, WifiSettings,
+};
+pub use crate::server::{CredentialStore, ImprovServer, WifiProvisioner};
+pub use crate::{ScanResults, Ssid};
+
+#[cfg(feature = "async")]
+pub use crate::AsyncImprovClient;
+#[cfg(feature = "std")]
+pub use crate::{
+    client::{ClientErr, ImprovClient, ProvisionOptions, ProvisionOutcome},
+    Transport,
+};
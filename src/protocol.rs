@@ -0,0 +1,1660 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! The wire types, and their encoding as serial frames.
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fields::{FieldReader, FieldWriter};
+use crate::serial::{unframe, FrameHeader};
+use crate::{RPCResultRef, Ssid};
+
+pub const IMPROV_VERSION: u8 = 0x01;
+/// The bytes before the payload: `IMPROV`, the version, the packet type, and the payload length.
+pub const HEADER_LEN: usize = 9;
+/// The largest payload a frame can carry, as its length is a single byte.
+pub const MAX_DATA_LEN: usize = u8::MAX as usize;
+
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_PSK_LEN: usize = 64;
+
+// every frame of the current version starts with this, then the packet type and payload length
+const PREAMBLE: [u8; 7] = [b'I', b'M', b'P', b'R', b'O', b'V', IMPROV_VERSION];
+
+/// The largest frame the protocol can express, for sizing fixed buffers.
+pub const MAX_FRAME_LEN: usize = frame_len(MAX_DATA_LEN);
+
+/// The length of a frame carrying `data_len` bytes of payload, for sizing buffers at compile time
+/// when the largest packet sent is known, e.g. `[u8; frame_len(2)]` for a state packet.
+pub const fn frame_len(data_len: usize) -> usize {
+    HEADER_LEN + data_len + 1
+}
+
+/// The length of `p` once encoded; the same as `ImprovPacket::encoded_len`.
+pub fn encoded_len(p: &ImprovPacket) -> usize {
+    p.encoded_len()
+}
+
+const REQUEST_FRAME_LEN: usize = frame_len(2);
+
+/// `RPCCommand::RequestCurrentState`, pre-encoded.
+pub const REQUEST_CURRENT_STATE: [u8; REQUEST_FRAME_LEN] =
+    request_frame(CommandId::RequestCurrentState);
+/// `RPCCommand::RequestDeviceInformation`, pre-encoded.
+pub const REQUEST_DEVICE_INFORMATION: [u8; REQUEST_FRAME_LEN] =
+    request_frame(CommandId::RequestDeviceInformation);
+/// `RPCCommand::RequestScannedWifiNetworks`, pre-encoded.
+pub const REQUEST_SCANNED_WIFI_NETWORKS: [u8; REQUEST_FRAME_LEN] =
+    request_frame(CommandId::RequestScannedWifiNetworks);
+
+/// The packet type byte of each kind of frame this crate understands.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PacketType {
+    CurrentState = 0x01,
+    ErrorState = 0x02,
+    RPCCommand = 0x03,
+    RPCResult = 0x04,
+}
+
+impl PacketType {
+    pub const ALL: [PacketType; 4] = [
+        PacketType::CurrentState,
+        PacketType::ErrorState,
+        PacketType::RPCCommand,
+        PacketType::RPCResult,
+    ];
+}
+
+impl From<PacketType> for u8 {
+    fn from(t: PacketType) -> u8 {
+        t as u8
+    }
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = ImprovErr;
+
+    fn try_from(b: u8) -> Result<PacketType, ImprovErr> {
+        PacketType::ALL
+            .into_iter()
+            .find(|&t| t as u8 == b)
+            .ok_or(ImprovErr::UnknownPacketType(b))
+    }
+}
+
+/// The id of each standard RPC command. Any other id is a vendor extension, see `CustomCommand`.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandId {
+    SendWifiSettings = 0x01,
+    RequestCurrentState = 0x02,
+    RequestDeviceInformation = 0x03,
+    RequestScannedWifiNetworks = 0x04,
+}
+
+impl CommandId {
+    pub const ALL: [CommandId; 4] = [
+        CommandId::SendWifiSettings,
+        CommandId::RequestCurrentState,
+        CommandId::RequestDeviceInformation,
+        CommandId::RequestScannedWifiNetworks,
+    ];
+}
+
+impl From<CommandId> for u8 {
+    fn from(c: CommandId) -> u8 {
+        c as u8
+    }
+}
+
+impl TryFrom<u8> for CommandId {
+    type Error = ImprovErr;
+
+    fn try_from(b: u8) -> Result<CommandId, ImprovErr> {
+        CommandId::ALL
+            .into_iter()
+            .find(|&c| c as u8 == b)
+            .ok_or(ImprovErr::InvalidRPCCommand(b))
+    }
+}
+
+pub const STATE_READY: u8 = 0x02;
+pub const STATE_PROVISIONING: u8 = 0x03;
+pub const STATE_PROVISIONED: u8 = 0x04;
+
+pub const ERROR_NONE: u8 = 0x00;
+pub const ERROR_INVALID_RPC_PACKET: u8 = 0x01;
+pub const ERROR_UNKNOWN_RPC_COMMAND: u8 = 0x02;
+pub const ERROR_UNABLE_TO_CONNECT: u8 = 0x03;
+pub const ERROR_NOT_AUTHORIZED: u8 = 0x04;
+pub const ERROR_UNKNOWN: u8 = 0xFF;
+
+// the conversions between states and their bytes, both ways
+const CURRENT_STATES: [(CurrentState, u8); 3] = [
+    (CurrentState::Ready, STATE_READY),
+    (CurrentState::Provisioning, STATE_PROVISIONING),
+    (CurrentState::Provisioned, STATE_PROVISIONED),
+];
+
+const ERROR_STATES: [(ErrorState, u8); 6] = [
+    (ErrorState::NoError, ERROR_NONE),
+    (ErrorState::InvalidRPCPacket, ERROR_INVALID_RPC_PACKET),
+    (ErrorState::UnknownRPCCommand, ERROR_UNKNOWN_RPC_COMMAND),
+    (ErrorState::UnableToConnect, ERROR_UNABLE_TO_CONNECT),
+    (ErrorState::NotAuthorized, ERROR_NOT_AUTHORIZED),
+    (ErrorState::UnknownError, ERROR_UNKNOWN),
+];
+
+// look up `key` in the first column of `table`
+fn lookup<K: PartialEq + Copy, V: Copy>(table: &[(K, V)], key: K) -> Option<V> {
+    table.iter().find(|(k, _)| *k == key).map(|&(_, v)| v)
+}
+
+// look up `value` in the second column of `table`
+fn reverse<K: Copy, V: PartialEq + Copy>(table: &[(K, V)], value: V) -> Option<K> {
+    table.iter().find(|(_, v)| *v == value).map(|&(k, _)| k)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImprovPacket {
+    CurrentState(CurrentState),
+    ErrorState(ErrorState),
+    RPCCommand(RPCCommand),
+    RPCResult(RPCResult),
+    Raw { pkt_type: u8, payload: Vec<u8> },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CurrentState {
+    Ready,
+    Provisioning,
+    Provisioned,
+    Unknown(u8),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ErrorState {
+    NoError,
+    InvalidRPCPacket,
+    UnknownRPCCommand,
+    UnableToConnect,
+    /// The user hasn't authorized provisioning, e.g. by pressing a button on the device.
+    NotAuthorized,
+    UnknownError,
+    /// A code this crate doesn't know, kept as sent so newer firmware can still be understood.
+    /// Decoding only produces this for codes without a variant of their own. One built by hand
+    /// with a known code, e.g. `Other(0x03)`, encodes the same as the named variant but isn't
+    /// equal to it; `ErrorState::try_from(u8::from(e))` gives the named variant.
+    Other(u8),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RPCCommand {
+    SendWifiSettings(WifiSettings),
+    RequestCurrentState,
+    RequestDeviceInformation,
+    RequestScannedWifiNetworks,
+    /// A vendor extension, with a command id this crate doesn't define. See `CustomCommand`.
+    Custom {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+/// A vendor-specific RPC command, carried as `RPCCommand::Custom`.
+pub trait CustomCommand: Sized {
+    /// The command id, which must not be one of the standard commands.
+    const ID: u8;
+
+    /// The payload, or an error if it can't be encoded, e.g. because a field is too long.
+    fn encode(&self) -> Result<Vec<u8>, ImprovErr>;
+
+    fn decode(payload: &[u8]) -> Result<Self, ImprovErr>;
+}
+
+/// Ask the device to make itself known, e.g. by blinking an LED, so the user can tell which board
+/// they're about to provision. BLE has this as a standard command; over serial it's a vendor
+/// extension, with no payload, which the device answers with an empty result.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Identify;
+
+impl CustomCommand for Identify {
+    const ID: u8 = 0x82;
+
+    fn encode(&self) -> Result<Vec<u8>, ImprovErr> {
+        Ok(Vec::new())
+    }
+
+    fn decode(payload: &[u8]) -> Result<Identify, ImprovErr> {
+        if !payload.is_empty() {
+            return Err(ImprovErr::BadLength {
+                expected: 0,
+                found: payload.len(),
+            });
+        }
+        Ok(Identify)
+    }
+}
+
+// Debug is implemented by hand so the PSK doesn't end up in logs
+#[derive(Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WifiSettings {
+    pub ssid: Ssid,
+    /// Empty for an open network.
+    pub psk: String,
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for WifiSettings {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.psk);
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for WifiSettings {}
+
+// overwrite a buffer which may have held a PSK; a no-op without the zeroize feature
+#[allow(unused_variables)]
+pub(crate) fn wipe(buf: &mut [u8]) {
+    #[cfg(feature = "zeroize")]
+    zeroize::Zeroize::zeroize(buf);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// The answer to an RPC command: the command it answers, then the spec's length-prefixed strings.
+///
+/// The strings are kept as the bytes the device sent rather than as `String`s. Scan results carry
+/// SSIDs, which needn't be UTF-8 (see `Ssid`), and vendor commands may answer with anything, so
+/// decoding them as text would lose networks and results which are valid on the wire. `strings`
+/// gives them as text where they are.
+pub struct RPCResult {
+    /// The command this answers, to match it to the request.
+    pub command: u8,
+    /// The strings, as sent.
+    pub data: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceInfo {
+    pub firmware_name: String,
+    pub firmware_version: String,
+    pub hardware: String,
+    pub device_name: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WifiNetwork {
+    pub ssid: Ssid,
+    pub rssi: i32,
+    pub auth: bool,
+}
+
+/// The optional features a device supports, as reported in the capabilities byte. Bits this crate
+/// doesn't know are kept.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    /// The device can make itself known, e.g. by blinking an LED, when sent `Identify`.
+    pub const IDENTIFY: Capabilities = Capabilities(0x01);
+
+    pub const fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn supports_identify(self) -> bool {
+        self.contains(Capabilities::IDENTIFY)
+    }
+}
+
+impl From<u8> for Capabilities {
+    fn from(b: u8) -> Capabilities {
+        Capabilities(b)
+    }
+}
+
+impl From<Capabilities> for u8 {
+    fn from(c: Capabilities) -> u8 {
+        c.0
+    }
+}
+
+impl core::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+pub(crate) trait TypedPacket {
+    const TYPE: u8;
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImprovErr {
+    InvalidCurrentStateByte(u8),
+    InvalidRPCCommand(u8),
+    NotAnImprovPacket,
+    /// A length field disagrees with the number of bytes actually present.
+    BadLength {
+        expected: usize,
+        found: usize,
+    },
+    Truncated,
+    BadChecksum {
+        expected: u8,
+        found: u8,
+    },
+    InvalidUtf8,
+    /// The SSID is this many bytes long, more than `MAX_SSID_LEN`.
+    SsidTooLong(usize),
+    /// The PSK is this many bytes long, more than `MAX_PSK_LEN`.
+    PskTooLong(usize),
+    PayloadTooLong,
+    InvalidRPCResult,
+    UnsupportedVersion(u8),
+    BufferTooSmall {
+        needed: usize,
+        available: usize,
+    },
+    UnknownPacketType(u8),
+    /// Not a `WIFI:` QR code payload, or one without an SSID.
+    InvalidQrCode,
+    /// Text that should be hex bytes isn't.
+    InvalidHex,
+}
+
+impl core::fmt::Display for ImprovErr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ImprovErr::InvalidCurrentStateByte(b) => write!(f, "invalid current state 0x{:02x}", b),
+            ImprovErr::InvalidRPCCommand(b) => write!(f, "unknown RPC command 0x{:02x}", b),
+            ImprovErr::NotAnImprovPacket => write!(f, "not an Improv packet"),
+            ImprovErr::BadLength { expected, found } => {
+                write!(
+                    f,
+                    "length field says {} bytes but found {}",
+                    expected, found
+                )
+            }
+            ImprovErr::Truncated => write!(f, "packet is truncated"),
+            ImprovErr::BadChecksum { expected, found } => write!(
+                f,
+                "bad checksum: expected 0x{:02x}, found 0x{:02x}",
+                expected, found
+            ),
+            ImprovErr::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            ImprovErr::SsidTooLong(len) => {
+                write!(f, "SSID is {} bytes, more than {}", len, MAX_SSID_LEN)
+            }
+            ImprovErr::PskTooLong(len) => {
+                write!(f, "PSK is {} bytes, more than {}", len, MAX_PSK_LEN)
+            }
+            ImprovErr::PayloadTooLong => write!(f, "payload is longer than 255 bytes"),
+            ImprovErr::InvalidRPCResult => write!(f, "RPC result has the wrong fields"),
+            ImprovErr::UnsupportedVersion(v) => write!(f, "unsupported Improv version {}", v),
+            ImprovErr::BufferTooSmall { needed, available } => {
+                write!(f, "buffer is {} bytes but {} are needed", available, needed)
+            }
+            ImprovErr::UnknownPacketType(t) => write!(f, "unknown packet type 0x{:02x}", t),
+            ImprovErr::InvalidQrCode => write!(f, "not a Wi-Fi QR code"),
+            ImprovErr::InvalidHex => write!(f, "not hex bytes"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ImprovErr {}
+
+impl TypedPacket for CurrentState {
+    const TYPE: u8 = PacketType::CurrentState as u8;
+}
+
+impl From<CurrentState> for u8 {
+    fn from(c: CurrentState) -> u8 {
+        match c {
+            CurrentState::Unknown(b) => b,
+            c => lookup(&CURRENT_STATES, c).expect("every known state has a byte"),
+        }
+    }
+}
+
+impl TryFrom<u8> for CurrentState {
+    type Error = ImprovErr;
+
+    fn try_from(b: u8) -> Result<CurrentState, ImprovErr> {
+        reverse(&CURRENT_STATES, b).ok_or(ImprovErr::InvalidCurrentStateByte(b))
+    }
+}
+
+impl TypedPacket for ErrorState {
+    const TYPE: u8 = PacketType::ErrorState as u8;
+}
+
+impl From<ErrorState> for u8 {
+    fn from(e: ErrorState) -> u8 {
+        match e {
+            ErrorState::Other(b) => b,
+            e => lookup(&ERROR_STATES, e).expect("every known error has a byte"),
+        }
+    }
+}
+
+impl TryFrom<u8> for ErrorState {
+    type Error = ImprovErr;
+
+    fn try_from(b: u8) -> Result<ErrorState, ImprovErr> {
+        Ok(reverse(&ERROR_STATES, b).unwrap_or(ErrorState::Other(b)))
+    }
+}
+
+/// The error a device reports when it can't make sense of a command: `UnknownRPCCommand` for a
+/// command id it doesn't know, and `InvalidRPCPacket` for anything malformed.
+impl From<ImprovErr> for ErrorState {
+    fn from(e: ImprovErr) -> ErrorState {
+        match e {
+            ImprovErr::InvalidRPCCommand(_) => ErrorState::UnknownRPCCommand,
+            _ => ErrorState::InvalidRPCPacket,
+        }
+    }
+}
+
+impl TypedPacket for RPCCommand {
+    const TYPE: u8 = PacketType::RPCCommand as u8;
+}
+
+impl RPCCommand {
+    pub(crate) fn id(&self) -> u8 {
+        let id = match self {
+            RPCCommand::SendWifiSettings(_) => CommandId::SendWifiSettings,
+            RPCCommand::RequestCurrentState => CommandId::RequestCurrentState,
+            RPCCommand::RequestDeviceInformation => CommandId::RequestDeviceInformation,
+            RPCCommand::RequestScannedWifiNetworks => CommandId::RequestScannedWifiNetworks,
+            RPCCommand::Custom { id, .. } => return *id,
+        };
+        id.into()
+    }
+
+    pub fn custom<C: CustomCommand>(cmd: &C) -> Result<RPCCommand, ImprovErr> {
+        Ok(RPCCommand::Custom {
+            id: C::ID,
+            payload: cmd.encode()?,
+        })
+    }
+
+    /// Decode this as a `C`, if it's a custom command with `C`'s id.
+    pub fn to_custom<C: CustomCommand>(&self) -> Option<Result<C, ImprovErr>> {
+        match self {
+            RPCCommand::Custom { id, payload } if *id == C::ID => Some(C::decode(payload)),
+            _ => None,
+        }
+    }
+
+    /// The pre-encoded frame for this command, if it takes no arguments.
+    pub const fn frame(&self) -> Option<&'static [u8]> {
+        match self {
+            RPCCommand::SendWifiSettings(_) => None,
+            RPCCommand::RequestCurrentState => Some(&REQUEST_CURRENT_STATE),
+            RPCCommand::RequestDeviceInformation => Some(&REQUEST_DEVICE_INFORMATION),
+            RPCCommand::RequestScannedWifiNetworks => Some(&REQUEST_SCANNED_WIFI_NETWORKS),
+            RPCCommand::Custom { .. } => None,
+        }
+    }
+
+    pub(crate) fn inner_len(&self) -> usize {
+        match self {
+            RPCCommand::SendWifiSettings(w) => 2 + w.encoded_len(),
+            RPCCommand::Custom { payload, .. } => 2 + payload.len(),
+            _ => 2,
+        }
+    }
+
+    pub(crate) fn write_inner(&self, buf: &mut [u8]) -> Result<(), ImprovErr> {
+        buf[0] = self.id();
+        match self {
+            RPCCommand::SendWifiSettings(w) => {
+                buf[1] = w.encoded_len() as u8;
+                w.write(&mut buf[2..])?;
+            }
+            RPCCommand::Custom { payload, .. } => {
+                buf[1] = payload.len() as u8;
+                buf[2..].copy_from_slice(payload);
+            }
+            _ => buf[1] = 0x00,
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<Vec<u8>> for RPCCommand {
+    type Error = ImprovErr;
+
+    fn try_from(mut b: Vec<u8>) -> Result<RPCCommand, ImprovErr> {
+        let cmd = RPCCommand::decode(&b, Mode::Strict);
+        wipe(&mut b);
+        cmd
+    }
+}
+
+impl TryFrom<&[u8]> for RPCCommand {
+    type Error = ImprovErr;
+
+    fn try_from(b: &[u8]) -> Result<RPCCommand, ImprovErr> {
+        RPCCommand::decode(b, Mode::Strict)
+    }
+}
+
+impl RPCCommand {
+    /// Like `try_from`, but replaces invalid UTF-8 in the SSID/PSK instead of failing. Intended
+    /// for display purposes only.
+    pub fn decode_lossy(mut b: Vec<u8>) -> Result<RPCCommand, ImprovErr> {
+        let cmd = RPCCommand::decode(&b, Mode::Lossy);
+        wipe(&mut b);
+        cmd
+    }
+
+    fn decode(b: &[u8], mode: Mode) -> Result<RPCCommand, ImprovErr> {
+        let (cmd, data) = split_rpc(b)?;
+        match CommandId::try_from(cmd) {
+            Ok(CommandId::SendWifiSettings) => {
+                WifiSettings::decode(data, mode).map(RPCCommand::SendWifiSettings)
+            }
+            Ok(CommandId::RequestCurrentState) => Ok(RPCCommand::RequestCurrentState),
+            Ok(CommandId::RequestDeviceInformation) => Ok(RPCCommand::RequestDeviceInformation),
+            Ok(CommandId::RequestScannedWifiNetworks) => Ok(RPCCommand::RequestScannedWifiNetworks),
+            Err(_) => Ok(RPCCommand::Custom {
+                id: cmd,
+                payload: data.to_vec(),
+            }),
+        }
+    }
+}
+
+// split an RPC command or result into its command byte and data, checking the length byte
+pub(crate) fn split_rpc(b: &[u8]) -> Result<(u8, &[u8]), ImprovErr> {
+    if b.len() < 2 {
+        return Err(ImprovErr::Truncated);
+    }
+
+    if b[1] as usize != b.len() - 2 {
+        return Err(ImprovErr::BadLength {
+            expected: b[1] as usize,
+            found: b.len() - 2,
+        });
+    }
+
+    Ok((b[0], &b[2..]))
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Mode {
+    Strict,
+    Lossy,
+    Lenient,
+}
+
+impl Mode {
+    fn lossy(self) -> bool {
+        self != Mode::Strict
+    }
+}
+
+fn to_string(b: &[u8], lossy: bool) -> Result<String, ImprovErr> {
+    if lossy {
+        Ok(String::from_utf8_lossy(b).into_owned())
+    } else {
+        String::from_utf8(b.to_vec()).map_err(|_| ImprovErr::InvalidUtf8)
+    }
+}
+
+// split a send-wifi-settings payload into the SSID and PSK bytes
+pub(crate) fn split_wifi_settings(data: &[u8]) -> Result<(&[u8], &[u8]), ImprovErr> {
+    let mut fields = FieldReader::new(data);
+    let ssid = fields.field()?;
+    let psk = fields.field()?;
+    fields.finish()?;
+    Ok((ssid, psk))
+}
+
+impl WifiSettings {
+    /// Settings for a network without a password.
+    pub fn open(ssid: impl Into<Ssid>) -> WifiSettings {
+        WifiSettings {
+            ssid: ssid.into(),
+            psk: String::new(),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.psk.is_empty()
+    }
+
+    pub(crate) fn decode(data: &[u8], mode: Mode) -> Result<WifiSettings, ImprovErr> {
+        let (ssid, psk) = if mode == Mode::Lenient {
+            // newer firmware may follow the PSK with fields of its own
+            let mut fields = FieldReader::new(data);
+            (fields.field()?, fields.field()?)
+        } else {
+            split_wifi_settings(data)?
+        };
+        Ok(WifiSettings {
+            ssid: Ssid::from(ssid),
+            psk: to_string(psk, mode.lossy())?,
+        })
+    }
+
+    /// Check that the SSID and PSK fit within the limits of the Wi-Fi spec.
+    pub fn validate(&self) -> Result<(), ImprovErr> {
+        if self.ssid.len() > MAX_SSID_LEN {
+            return Err(ImprovErr::SsidTooLong(self.ssid.len()));
+        }
+        if self.psk.len() > MAX_PSK_LEN {
+            return Err(ImprovErr::PskTooLong(self.psk.len()));
+        }
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        2 + self.ssid.len() + self.psk.len()
+    }
+
+    fn write(&self, buf: &mut [u8]) -> Result<(), ImprovErr> {
+        let mut fields = FieldWriter::new(buf);
+        fields.push(self.ssid.as_bytes())?;
+        fields.push(self.psk.as_bytes())
+    }
+}
+
+impl TryFrom<WifiSettings> for Vec<u8> {
+    type Error = ImprovErr;
+
+    fn try_from(w: WifiSettings) -> Result<Vec<u8>, ImprovErr> {
+        w.validate()?;
+        let mut v = vec![0; w.encoded_len()];
+        w.write(&mut v)?;
+        Ok(v)
+    }
+}
+
+impl TypedPacket for RPCResult {
+    const TYPE: u8 = PacketType::RPCResult as u8;
+}
+
+impl RPCResult {
+    /// An answer to `command` made of text strings.
+    pub fn from_strings<'a>(command: u8, strings: impl IntoIterator<Item = &'a str>) -> RPCResult {
+        RPCResult {
+            command,
+            data: strings.into_iter().map(|s| s.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    /// The answer to `RPCCommand::SendWifiSettings` once connected, and to
+    /// `RPCCommand::RequestCurrentState` while provisioned.
+    pub fn provisioned(command: u8, redirect_url: Option<&str>) -> RPCResult {
+        RPCResult::from_strings(command, redirect_url)
+    }
+
+    /// The answer to `RPCCommand::RequestDeviceInformation`.
+    pub fn device_information(info: &DeviceInfo) -> RPCResult {
+        RPCResult::from_strings(
+            CommandId::RequestDeviceInformation.into(),
+            [
+                info.firmware_name.as_str(),
+                &info.firmware_version,
+                &info.hardware,
+                &info.device_name,
+            ],
+        )
+    }
+
+    /// One network found by `RPCCommand::RequestScannedWifiNetworks`.
+    pub fn scan_entry(network: &WifiNetwork) -> RPCResult {
+        let auth = if network.auth { "YES" } else { "NO" };
+        RPCResult {
+            command: CommandId::RequestScannedWifiNetworks.into(),
+            data: vec![
+                network.ssid.as_bytes().to_vec(),
+                network.rssi.to_string().into_bytes(),
+                auth.as_bytes().to_vec(),
+            ],
+        }
+    }
+
+    /// The end of the list of networks.
+    pub fn scan_complete() -> RPCResult {
+        RPCResult::from_strings(CommandId::RequestScannedWifiNetworks.into(), [])
+    }
+
+    /// The strings as text, as the standard commands send them. Fails if any isn't UTF-8, e.g. a
+    /// scan result for a network with a Latin-1 name.
+    pub fn strings(&self) -> Result<Vec<&str>, ImprovErr> {
+        self.data
+            .iter()
+            .map(|v| core::str::from_utf8(v).map_err(|_| ImprovErr::InvalidUtf8))
+            .collect()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ImprovErr> {
+        if crate::fields::encoded_len(self.data.iter().map(Vec::as_slice))? > MAX_DATA_LEN {
+            return Err(ImprovErr::PayloadTooLong);
+        }
+        Ok(())
+    }
+
+    fn data_len(&self) -> usize {
+        self.data.iter().map(|v| 1 + v.len()).sum()
+    }
+
+    pub(crate) fn inner_len(&self) -> usize {
+        2 + self.data_len()
+    }
+
+    pub(crate) fn write_inner(&self, buf: &mut [u8]) -> Result<(), ImprovErr> {
+        buf[0] = self.command;
+        buf[1] = self.data_len() as u8;
+        let mut fields = FieldWriter::new(&mut buf[2..]);
+        for v in &self.data {
+            fields.push(v)?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<Vec<u8>> for RPCResult {
+    type Error = ImprovErr;
+
+    fn try_from(b: Vec<u8>) -> Result<RPCResult, ImprovErr> {
+        RPCResult::try_from(&b[..])
+    }
+}
+
+impl TryFrom<&[u8]> for RPCResult {
+    type Error = ImprovErr;
+
+    fn try_from(b: &[u8]) -> Result<RPCResult, ImprovErr> {
+        RPCResultRef::try_from(b).map(RPCResult::from)
+    }
+}
+
+impl TryFrom<RPCResult> for DeviceInfo {
+    type Error = ImprovErr;
+
+    fn try_from(r: RPCResult) -> Result<DeviceInfo, ImprovErr> {
+        let mut fields = r.data.into_iter().map(|v| to_string(&v, false));
+        let mut next = || fields.next().ok_or(ImprovErr::InvalidRPCResult)?;
+        Ok(DeviceInfo {
+            firmware_name: next()?,
+            firmware_version: next()?,
+            hardware: next()?,
+            device_name: next()?,
+        })
+    }
+}
+
+impl TryFrom<RPCResult> for WifiNetwork {
+    type Error = ImprovErr;
+
+    fn try_from(r: RPCResult) -> Result<WifiNetwork, ImprovErr> {
+        let mut fields = r.data.into_iter();
+        let ssid = Ssid::new(fields.next().ok_or(ImprovErr::InvalidRPCResult)?);
+        let mut next = || {
+            let v = fields.next().ok_or(ImprovErr::InvalidRPCResult)?;
+            to_string(&v, false)
+        };
+        Ok(WifiNetwork {
+            ssid,
+            rssi: next()?.parse().map_err(|_| ImprovErr::InvalidRPCResult)?,
+            auth: next()? == "YES",
+        })
+    }
+}
+
+impl ImprovPacket {
+    pub(crate) fn inner_len(&self) -> usize {
+        match self {
+            ImprovPacket::CurrentState(_) | ImprovPacket::ErrorState(_) => 1,
+            ImprovPacket::RPCCommand(c) => c.inner_len(),
+            ImprovPacket::RPCResult(r) => r.inner_len(),
+            ImprovPacket::Raw { payload, .. } => payload.len(),
+        }
+    }
+
+    pub(crate) fn write_inner(&self, buf: &mut [u8]) -> Result<(), ImprovErr> {
+        match self {
+            ImprovPacket::CurrentState(c) => buf[0] = (*c).into(),
+            ImprovPacket::ErrorState(e) => buf[0] = (*e).into(),
+            ImprovPacket::RPCCommand(c) => c.write_inner(buf)?,
+            ImprovPacket::RPCResult(r) => r.write_inner(buf)?,
+            ImprovPacket::Raw { payload, .. } => buf.copy_from_slice(payload),
+        }
+        Ok(())
+    }
+
+    fn pkt_type(&self) -> u8 {
+        match self {
+            ImprovPacket::CurrentState(_) => CurrentState::TYPE,
+            ImprovPacket::ErrorState(_) => ErrorState::TYPE,
+            ImprovPacket::RPCCommand(_) => RPCCommand::TYPE,
+            ImprovPacket::RPCResult(_) => RPCResult::TYPE,
+            ImprovPacket::Raw { pkt_type, .. } => *pkt_type,
+        }
+    }
+
+    /// Check that every field of this packet can be represented on the wire.
+    pub fn validate(&self) -> Result<(), ImprovErr> {
+        match self {
+            ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(w)) => w.validate()?,
+            // a custom command mustn't be mistaken for a standard one
+            ImprovPacket::RPCCommand(RPCCommand::Custom { id, .. })
+                if CommandId::try_from(*id).is_ok() =>
+            {
+                return Err(ImprovErr::InvalidRPCCommand(*id))
+            }
+            ImprovPacket::RPCResult(r) => r.validate()?,
+            _ => (),
+        }
+        if self.inner_len() > MAX_DATA_LEN {
+            return Err(ImprovErr::PayloadTooLong);
+        }
+        Ok(())
+    }
+
+    /// The length of the encoded frame, for sizing the buffer given to `encode_into`.
+    pub fn encoded_len(&self) -> usize {
+        frame_len(self.inner_len())
+    }
+
+    /// Serialize this packet into `buf` without allocating, returning the number of bytes written.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, ImprovErr> {
+        self.validate()?;
+
+        let len = self.encoded_len();
+        if buf.len() < len {
+            return Err(ImprovErr::BufferTooSmall {
+                needed: len,
+                available: buf.len(),
+            });
+        }
+
+        write_header(buf, self.pkt_type(), (len - HEADER_LEN - 1) as u8);
+        self.write_inner(&mut buf[HEADER_LEN..len - 1])?;
+        buf[len - 1] = checksum(&buf[..len - 1]);
+        event!(trace, pkt_type = buf[7], len, "encoded frame");
+        count!("improv_frames_encoded_total");
+
+        Ok(len)
+    }
+}
+
+impl TryFrom<ImprovPacket> for Vec<u8> {
+    type Error = ImprovErr;
+
+    fn try_from(p: ImprovPacket) -> Result<Vec<u8>, ImprovErr> {
+        Vec::try_from(&p)
+    }
+}
+
+impl TryFrom<&ImprovPacket> for Vec<u8> {
+    type Error = ImprovErr;
+
+    fn try_from(p: &ImprovPacket) -> Result<Vec<u8>, ImprovErr> {
+        let mut data = vec![0; p.encoded_len()];
+        p.encode_into(&mut data)?;
+        Ok(data)
+    }
+}
+
+// the header of a frame of the current version, which `buf` must have room for
+pub(crate) fn write_header(buf: &mut [u8], pkt_type: u8, len: u8) {
+    buf[..PREAMBLE.len()].copy_from_slice(&PREAMBLE);
+    buf[7] = pkt_type;
+    buf[8] = len;
+}
+
+pub(crate) const fn checksum(data: &[u8]) -> u8 {
+    let mut sum = 0u8;
+    let mut i = 0;
+    while i < data.len() {
+        sum = sum.wrapping_add(data[i]);
+        i += 1;
+    }
+    sum
+}
+
+// the frame for a command which takes no arguments
+const fn request_frame(cmd: CommandId) -> [u8; REQUEST_FRAME_LEN] {
+    let mut f = [
+        b'I',
+        b'M',
+        b'P',
+        b'R',
+        b'O',
+        b'V',
+        IMPROV_VERSION,
+        <RPCCommand as TypedPacket>::TYPE,
+        0x02,
+        cmd as u8,
+        0x00,
+        0x00,
+    ];
+    let (data, _) = f.split_at(REQUEST_FRAME_LEN - 1);
+    f[REQUEST_FRAME_LEN - 1] = checksum(data);
+    f
+}
+
+impl TryFrom<Vec<u8>> for ImprovPacket {
+    type Error = ImprovErr;
+
+    fn try_from(mut b: Vec<u8>) -> Result<ImprovPacket, ImprovErr> {
+        let p = ImprovPacket::decode(&b, Mode::Strict);
+        wipe(&mut b);
+        p
+    }
+}
+
+/// Decode straight from a borrowed buffer, e.g. a read buffer or a slice of a larger capture. The
+/// slice must hold exactly one frame.
+impl TryFrom<&[u8]> for ImprovPacket {
+    type Error = ImprovErr;
+
+    fn try_from(b: &[u8]) -> Result<ImprovPacket, ImprovErr> {
+        ImprovPacket::decode(b, Mode::Strict)
+    }
+}
+
+impl ImprovPacket {
+    /// Like `try_from`, but replaces invalid UTF-8 in string fields instead of failing. Intended
+    /// for display purposes only.
+    pub fn decode_lossy(mut b: Vec<u8>) -> Result<ImprovPacket, ImprovErr> {
+        let p = ImprovPacket::decode(&b, Mode::Lossy);
+        wipe(&mut b);
+        p
+    }
+
+    /// Decode without failing on content this crate doesn't understand: unknown state bytes become
+    /// `CurrentState::Unknown`, fields after the PSK of Wi-Fi settings are ignored, invalid UTF-8
+    /// is replaced, and anything else that can't be decoded (an unknown packet type, or a payload
+    /// which doesn't match its type) becomes `Raw`. The framing itself (header, length, checksum)
+    /// must still be valid.
+    pub fn decode_lenient(mut b: Vec<u8>) -> Result<ImprovPacket, ImprovErr> {
+        let p = ImprovPacket::decode(&b, Mode::Lenient);
+        wipe(&mut b);
+        p
+    }
+
+    fn decode(b: &[u8], mode: Mode) -> Result<ImprovPacket, ImprovErr> {
+        let header = FrameHeader::parse(b)?;
+        if header.version != IMPROV_VERSION {
+            return Err(ImprovErr::UnsupportedVersion(header.version));
+        }
+
+        let (header, data) = unframe(b)?;
+        event!(
+            trace,
+            pkt_type = header.pkt_type,
+            len = header.len,
+            "decoding frame"
+        );
+        count!("improv_frames_decoded_total");
+        ImprovPacket::decode_payload(header.pkt_type, data, mode)
+    }
+
+    pub(crate) fn decode_payload(
+        pkt_type: u8,
+        data: &[u8],
+        mode: Mode,
+    ) -> Result<ImprovPacket, ImprovErr> {
+        match ImprovPacket::decode_typed(pkt_type, data, mode) {
+            Err(_) if mode == Mode::Lenient => Ok(ImprovPacket::Raw {
+                pkt_type,
+                payload: data.to_vec(),
+            }),
+            p => p,
+        }
+    }
+
+    fn decode_typed(pkt_type: u8, data: &[u8], mode: Mode) -> Result<ImprovPacket, ImprovErr> {
+        match pkt_type {
+            CurrentState::TYPE => {
+                let c = *data.first().ok_or(ImprovErr::Truncated)?;
+                match CurrentState::try_from(c) {
+                    Err(_) if mode == Mode::Lenient => {
+                        Ok(ImprovPacket::CurrentState(CurrentState::Unknown(c)))
+                    }
+                    r => Ok(ImprovPacket::CurrentState(r?)),
+                }
+            }
+            ErrorState::TYPE => Ok(ImprovPacket::ErrorState(ErrorState::try_from(
+                *data.first().ok_or(ImprovErr::Truncated)?,
+            )?)),
+            RPCCommand::TYPE => Ok(ImprovPacket::RPCCommand(RPCCommand::decode(data, mode)?)),
+            RPCResult::TYPE => Ok(ImprovPacket::RPCResult(RPCResult::try_from(data)?)),
+            pkt_type => Err(ImprovErr::UnknownPacketType(pkt_type)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        let c = Capabilities::from(0x81);
+        assert!(c.supports_identify());
+        assert!(c.contains(Capabilities::IDENTIFY));
+        assert_eq!(u8::from(c), 0x81);
+        assert!(!Capabilities::empty().supports_identify());
+        assert_eq!(
+            Capabilities::empty() | Capabilities::IDENTIFY,
+            Capabilities::IDENTIFY
+        );
+    }
+
+    #[test]
+    fn decode_get_current_state() {
+        let v: Vec<u8> = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x02, 0x00, 0xE5,
+        ];
+        assert_eq!(
+            ImprovPacket::try_from(v),
+            Ok(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState)),
+        );
+    }
+
+    #[test]
+    fn build_get_current_state() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState);
+        assert_eq!(
+            vec![0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x02, 0x00, 0xE5],
+            Vec::try_from(p).unwrap(),
+        );
+    }
+
+    #[test]
+    fn build_get_device_info() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::RequestDeviceInformation);
+        assert_eq!(
+            vec![0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x03, 0x00, 0xE6],
+            Vec::try_from(p).unwrap(),
+        );
+    }
+
+    #[test]
+    fn build_get_networks() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::RequestScannedWifiNetworks);
+        assert_eq!(
+            vec![0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x04, 0x00, 0xE7],
+            Vec::try_from(p).unwrap(),
+        );
+    }
+
+    #[test]
+    fn build_send_wifi() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        }));
+        assert_eq!(
+            vec![
+                0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x1B, 0x01, 0x19, 0x07, 0x61, 0x6E,
+                0x74, 0x68, 0x69, 0x6C, 0x6C, 0x10, 0x61, 0x6E, 0x74, 0x73, 0x20, 0x69, 0x6E, 0x20,
+                0x6D, 0x79, 0x20, 0x70, 0x61, 0x6E, 0x74, 0x73, 0x12
+            ],
+            Vec::try_from(p).unwrap(),
+        );
+    }
+
+    #[test]
+    fn encode_into_matches_vec() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        }));
+        let mut buf = [0u8; 64];
+        let n = p.encode_into(&mut buf).unwrap();
+        assert_eq!(n, p.encoded_len());
+        assert_eq!(&buf[..n], Vec::<u8>::try_from(&p).unwrap().as_slice());
+    }
+
+    #[test]
+    fn encode_into_short_buffer() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState);
+        let mut buf = [0u8; 11];
+        assert_eq!(
+            p.encode_into(&mut buf),
+            Err(ImprovErr::BufferTooSmall {
+                needed: 12,
+                available: 11
+            })
+        );
+    }
+
+    #[test]
+    fn buffer_sizes() {
+        assert_eq!(MAX_FRAME_LEN, 265);
+        assert_eq!(REQUEST_CURRENT_STATE.len(), frame_len(2));
+
+        let p = ImprovPacket::CurrentState(CurrentState::Ready);
+        let mut buf = [0u8; frame_len(1)];
+        assert_eq!(p.encode_into(&mut buf), Ok(encoded_len(&p)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        }));
+        let json = serde_json::to_string(&p).unwrap();
+        assert_eq!(serde_json::from_str::<ImprovPacket>(&json).unwrap(), p);
+    }
+
+    #[cfg(feature = "zeroize")]
+    #[test]
+    fn wipes_buffers() {
+        let mut frame = Vec::try_from(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(
+            WifiSettings {
+                ssid: "anthill".into(),
+                psk: String::from("ants in my pants"),
+            },
+        )))
+        .unwrap();
+        wipe(&mut frame);
+        assert!(frame.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn const_frames() {
+        for cmd in [
+            RPCCommand::RequestCurrentState,
+            RPCCommand::RequestDeviceInformation,
+            RPCCommand::RequestScannedWifiNetworks,
+        ] {
+            let frame = cmd.frame().unwrap();
+            assert_eq!(frame, Vec::try_from(ImprovPacket::RPCCommand(cmd)).unwrap());
+        }
+        assert_eq!(
+            REQUEST_CURRENT_STATE,
+            [0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x02, 0x00, 0xE5]
+        );
+    }
+
+    #[test]
+    fn decode_borrowed() {
+        let mut capture = b"boot ok\r\n".to_vec();
+        capture.extend_from_slice(&REQUEST_DEVICE_INFORMATION);
+        capture.extend_from_slice(b"\r\n");
+        let frame = &capture[9..9 + REQUEST_FRAME_LEN];
+        assert_eq!(
+            ImprovPacket::try_from(frame),
+            Ok(ImprovPacket::RPCCommand(
+                RPCCommand::RequestDeviceInformation
+            ))
+        );
+        assert_eq!(
+            ImprovPacket::try_from(&capture[9..]),
+            Err(ImprovErr::BadLength {
+                expected: 2,
+                found: 4
+            })
+        );
+        assert_eq!(
+            RPCResult::try_from(&[0x03, 0x03, 0x02, b'o', b'k'][..]),
+            Ok(RPCResult {
+                command: 0x03,
+                data: vec![b"ok".to_vec()],
+            })
+        );
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let v: Vec<u8> = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x02, 0x00, 0xE5,
+        ];
+        for n in 0..v.len() {
+            assert_eq!(
+                ImprovPacket::try_from(v[..n].to_vec()),
+                Err(ImprovErr::Truncated),
+            );
+        }
+        assert_eq!(
+            ImprovPacket::try_from(b"IMPORT".to_vec()),
+            Err(ImprovErr::NotAnImprovPacket),
+        );
+    }
+
+    #[test]
+    fn decode_bad_checksum() {
+        let v: Vec<u8> = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x02, 0x00, 0xE6,
+        ];
+        assert_eq!(
+            ImprovPacket::try_from(v),
+            Err(ImprovErr::BadChecksum {
+                expected: 0xE5,
+                found: 0xE6
+            })
+        );
+    }
+
+    #[test]
+    fn decode_malformed_wifi_settings() {
+        // ssid length runs past the end of the payload
+        assert_eq!(
+            RPCCommand::try_from(vec![0x01, 0x03, 0x09, 0x61, 0x00]),
+            Err(ImprovErr::Truncated),
+        );
+        assert_eq!(RPCCommand::try_from(vec![0x01]), Err(ImprovErr::Truncated));
+        assert_eq!(
+            RPCCommand::try_from(vec![0x01, 0x05, 0x00]),
+            Err(ImprovErr::BadLength {
+                expected: 5,
+                found: 1
+            }),
+        );
+    }
+
+    #[test]
+    fn decode_send_wifi() {
+        let v: Vec<u8> = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x1B, 0x01, 0x19, 0x07, 0x61, 0x6E,
+            0x74, 0x68, 0x69, 0x6C, 0x6C, 0x10, 0x61, 0x6E, 0x74, 0x73, 0x20, 0x69, 0x6E, 0x20,
+            0x6D, 0x79, 0x20, 0x70, 0x61, 0x6E, 0x74, 0x73, 0x12,
+        ];
+        assert_eq!(
+            ImprovPacket::try_from(v),
+            Ok(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(
+                WifiSettings {
+                    ssid: "anthill".into(),
+                    psk: String::from("ants in my pants"),
+                }
+            ))),
+        );
+    }
+
+    #[test]
+    fn send_wifi_round_trips() {
+        // lengths at and around the limits, and contents which look like lengths or framing
+        let ssids = [
+            String::new(),
+            String::from("a"),
+            String::from("IMPROV\x01\x03"),
+            "é".repeat(15) + "x",
+            "s".repeat(MAX_SSID_LEN - 1),
+            "s".repeat(MAX_SSID_LEN),
+        ];
+        let psks = [
+            String::new(),
+            String::from("\x00"),
+            String::from("12345678"),
+            "ü".repeat(MAX_PSK_LEN / 2),
+            "p".repeat(MAX_PSK_LEN - 1),
+            "p".repeat(MAX_PSK_LEN),
+        ];
+        for ssid in &ssids {
+            for psk in &psks {
+                let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
+                    ssid: ssid.as_str().into(),
+                    psk: psk.clone(),
+                }));
+                let v = Vec::try_from(p.clone()).unwrap();
+                assert_eq!(v[HEADER_LEN + 2] as usize, ssid.len());
+                assert_eq!(ImprovPacket::try_from(&v[..]), Ok(p.clone()));
+                assert_eq!(
+                    crate::PacketRef::try_from(&v[..]).map(ImprovPacket::from),
+                    Ok(p)
+                );
+            }
+        }
+
+        // a byte past the PSK
+        assert_eq!(
+            RPCCommand::try_from(vec![0x01, 0x05, 0x01, b'a', 0x01, b'b', b'c']),
+            Err(ImprovErr::BadLength {
+                expected: 4,
+                found: 5
+            }),
+        );
+    }
+
+    #[test]
+    fn custom_command() {
+        #[derive(Debug, PartialEq)]
+        struct Blink(u8);
+
+        impl CustomCommand for Blink {
+            const ID: u8 = 0x42;
+
+            fn encode(&self) -> Result<Vec<u8>, ImprovErr> {
+                Ok(vec![self.0])
+            }
+
+            fn decode(payload: &[u8]) -> Result<Blink, ImprovErr> {
+                match payload {
+                    [n] => Ok(Blink(*n)),
+                    _ => Err(ImprovErr::Truncated),
+                }
+            }
+        }
+
+        let p = ImprovPacket::RPCCommand(RPCCommand::custom(&Blink(3)).unwrap());
+        let v = Vec::try_from(p.clone()).unwrap();
+        assert_eq!(&v[9..12], [0x42, 0x01, 0x03]);
+        let ImprovPacket::RPCCommand(cmd) = ImprovPacket::try_from(v).unwrap() else {
+            panic!("not a command");
+        };
+        assert_eq!(cmd.to_custom::<Blink>(), Some(Ok(Blink(3))));
+        assert_eq!(RPCCommand::RequestCurrentState.to_custom::<Blink>(), None);
+
+        let reserved = ImprovPacket::RPCCommand(RPCCommand::Custom {
+            id: 0x02,
+            payload: vec![],
+        });
+        assert_eq!(
+            Vec::try_from(reserved),
+            Err(ImprovErr::InvalidRPCCommand(0x02))
+        );
+    }
+
+    #[test]
+    fn open_network() {
+        // as sent by the Improv web SDK for an open network: the PSK is just a zero length
+        let mut v: Vec<u8> = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x0C, 0x01, 0x0A, 0x08, 0x63, 0x6F,
+            0x66, 0x66, 0x65, 0x65, 0x73, 0x68, 0x00, 0x00,
+        ];
+        v[21] = checksum(&v[..21]);
+        let p =
+            ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings::open("coffeesh")));
+        assert_eq!(Vec::try_from(p.clone()).unwrap(), v);
+        let ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(w)) =
+            ImprovPacket::try_from(v).unwrap()
+        else {
+            panic!("not wifi settings");
+        };
+        assert!(w.is_open());
+        assert_eq!(w.ssid, "coffeesh");
+    }
+
+    #[test]
+    fn result_command() {
+        let mut v: Vec<u8> = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x04, 0x06, 0x01, 0x04, 0x03, 0x61, 0x6E,
+            0x74, 0x00,
+        ];
+        v[15] = checksum(&v[..15]);
+        let ImprovPacket::RPCResult(r) = ImprovPacket::try_from(v.clone()).unwrap() else {
+            panic!("not a result");
+        };
+        assert_eq!(r.command, 0x01);
+        assert_eq!(r.strings(), Ok(vec!["ant"]));
+        assert_eq!(r, RPCResult::from_strings(0x01, ["ant"]));
+        assert_eq!(Vec::try_from(ImprovPacket::RPCResult(r)).unwrap(), v);
+    }
+
+    #[test]
+    fn result_constructors() {
+        let info = DeviceInfo {
+            firmware_name: String::from("improv-rs"),
+            firmware_version: String::from("0.1.0"),
+            hardware: String::from("ESP32-C3"),
+            device_name: String::from("anthill-sensor"),
+        };
+        assert_eq!(
+            DeviceInfo::try_from(RPCResult::device_information(&info)),
+            Ok(info)
+        );
+
+        let network = WifiNetwork {
+            ssid: "anthill".into(),
+            rssi: -40,
+            auth: false,
+        };
+        let entry = RPCResult::scan_entry(&network);
+        assert_eq!(entry.strings(), Ok(vec!["anthill", "-40", "NO"]));
+        assert_eq!(WifiNetwork::try_from(entry), Ok(network));
+
+        assert!(RPCResult::scan_complete().data.is_empty());
+        assert_eq!(
+            RPCResult::provisioned(0x01, Some("http://10.0.0.2")).strings(),
+            Ok(vec!["http://10.0.0.2"])
+        );
+    }
+
+    #[test]
+    fn decode_invalid_utf8() {
+        let b = vec![0x01, 0x05, 0x01, 0x61, 0x02, 0x62, 0xFF];
+        assert_eq!(RPCCommand::try_from(b.clone()), Err(ImprovErr::InvalidUtf8));
+        assert_eq!(
+            RPCCommand::decode_lossy(b),
+            Ok(RPCCommand::SendWifiSettings(WifiSettings {
+                ssid: "a".into(),
+                psk: String::from("b\u{FFFD}"),
+            })),
+        );
+    }
+
+    #[test]
+    fn ssid_not_utf8() {
+        // a Latin-1 name is kept as sent, in both directions
+        let b = vec![0x01, 0x07, 0x04, 0x63, 0x61, 0x66, 0xE9, 0x01, 0x62];
+        let Ok(RPCCommand::SendWifiSettings(w)) = RPCCommand::try_from(b.clone()) else {
+            panic!("not wifi settings");
+        };
+        assert_eq!(w.ssid.as_bytes(), b"caf\xe9");
+        let frame =
+            Vec::try_from(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(w))).unwrap();
+        assert_eq!(frame[HEADER_LEN..frame.len() - 1], b[..]);
+
+        let network = WifiNetwork {
+            ssid: Ssid::new(&b"caf\xe9"[..]),
+            rssi: -60,
+            auth: true,
+        };
+        let entry = RPCResult::scan_entry(&network);
+        assert_eq!(entry.strings(), Err(ImprovErr::InvalidUtf8));
+        assert_eq!(WifiNetwork::try_from(entry), Ok(network));
+    }
+
+    #[test]
+    fn build_oversized_wifi() {
+        let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
+            ssid: "a".repeat(33).into(),
+            psk: String::from("ants in my pants"),
+        }));
+        assert_eq!(Vec::try_from(p), Err(ImprovErr::SsidTooLong(33)));
+
+        let p = ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
+            ssid: "anthill".into(),
+            psk: "a".repeat(65),
+        }));
+        assert_eq!(Vec::try_from(p), Err(ImprovErr::PskTooLong(65)));
+    }
+
+    #[test]
+    fn build_oversized_result() {
+        let p = ImprovPacket::RPCResult(RPCResult {
+            command: 0x03,
+            data: vec![vec![0x61; 200], vec![0x62; 200]],
+        });
+        assert_eq!(Vec::try_from(p), Err(ImprovErr::PayloadTooLong));
+    }
+
+    #[test]
+    fn decode_lenient_unknowns() {
+        let mut state = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x01, 0x01, 0x09, 0x00,
+        ];
+        state[10] = checksum(&state[..10]);
+        assert_eq!(
+            ImprovPacket::try_from(state.clone()),
+            Err(ImprovErr::InvalidCurrentStateByte(0x09)),
+        );
+        assert_eq!(
+            ImprovPacket::decode_lenient(state),
+            Ok(ImprovPacket::CurrentState(CurrentState::Unknown(0x09))),
+        );
+
+        let raw = ImprovPacket::Raw {
+            pkt_type: 0x7F,
+            payload: vec![0x01, 0x02, 0x03],
+        };
+        let v: Vec<u8> = raw.clone().try_into().unwrap();
+        assert_eq!(
+            ImprovPacket::try_from(v.clone()),
+            Err(ImprovErr::UnknownPacketType(0x7F))
+        );
+        assert_eq!(ImprovPacket::decode_lenient(v), Ok(raw));
+
+        // Wi-Fi settings with a field this crate doesn't know
+        let cmd = [
+            0x01, 0x0A, 0x03, b'a', b'n', b't', 0x01, b'b', 0x03, 0x01, 0x02, 0x03,
+        ];
+        let v: Vec<u8> = ImprovPacket::Raw {
+            pkt_type: RPCCommand::TYPE,
+            payload: cmd.to_vec(),
+        }
+        .try_into()
+        .unwrap();
+        assert!(matches!(
+            ImprovPacket::try_from(v.clone()),
+            Err(ImprovErr::BadLength { .. })
+        ));
+        assert_eq!(
+            ImprovPacket::decode_lenient(v),
+            Ok(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(
+                WifiSettings {
+                    ssid: "ant".into(),
+                    psk: String::from("b"),
+                }
+            ))),
+        );
+
+        // a result whose fields run past its end
+        let bad = ImprovPacket::Raw {
+            pkt_type: RPCResult::TYPE,
+            payload: vec![0x04, 0x02, 0x05, b'x'],
+        };
+        let v: Vec<u8> = bad.clone().try_into().unwrap();
+        assert_eq!(ImprovPacket::try_from(v.clone()), Err(ImprovErr::Truncated));
+        assert_eq!(ImprovPacket::decode_lenient(v), Ok(bad));
+    }
+
+    #[test]
+    fn error_states() {
+        for b in 0x00..=0xFF {
+            assert_eq!(u8::from(ErrorState::try_from(b).unwrap()), b);
+        }
+        assert_eq!(ErrorState::try_from(0x04), Ok(ErrorState::NotAuthorized));
+        assert_eq!(ErrorState::try_from(0x05), Ok(ErrorState::Other(0x05)));
+        assert_eq!(
+            ErrorState::try_from(u8::from(ErrorState::Other(0x03))),
+            Ok(ErrorState::UnableToConnect)
+        );
+
+        assert_eq!(
+            ErrorState::from(ImprovErr::InvalidRPCCommand(0x7f)),
+            ErrorState::UnknownRPCCommand
+        );
+        assert_eq!(
+            ErrorState::from(ImprovErr::Truncated),
+            ErrorState::InvalidRPCPacket
+        );
+    }
+
+    #[test]
+    fn wire_constants() {
+        for t in PacketType::ALL {
+            assert_eq!(PacketType::try_from(u8::from(t)), Ok(t));
+        }
+        assert_eq!(PacketType::RPCCommand as u8, RPCCommand::TYPE);
+        assert_eq!(
+            PacketType::try_from(0x05),
+            Err(ImprovErr::UnknownPacketType(0x05))
+        );
+
+        for c in CommandId::ALL {
+            assert_eq!(CommandId::try_from(u8::from(c)), Ok(c));
+        }
+        assert_eq!(RPCCommand::RequestScannedWifiNetworks.id(), 0x04);
+        assert_eq!(
+            CommandId::try_from(0x81),
+            Err(ImprovErr::InvalidRPCCommand(0x81))
+        );
+
+        assert_eq!(u8::from(CurrentState::Provisioned), STATE_PROVISIONED);
+        assert_eq!(CurrentState::try_from(STATE_READY), Ok(CurrentState::Ready));
+        assert_eq!(
+            ErrorState::try_from(ERROR_UNKNOWN),
+            Ok(ErrorState::UnknownError)
+        );
+    }
+
+    #[test]
+    fn result_round_trip() {
+        let p = ImprovPacket::RPCResult(RPCResult {
+            command: 0x01,
+            data: vec![b"http://192.168.1.2".to_vec()],
+        });
+        let v: Vec<u8> = p.clone().try_into().unwrap();
+        assert_eq!(&v[HEADER_LEN..HEADER_LEN + 3], &[0x01, 0x13, 0x12]);
+        assert_eq!(ImprovPacket::try_from(v), Ok(p));
+    }
+
+    #[test]
+    fn parse_typed_results() {
+        let r = RPCResult {
+            command: 0x04,
+            data: vec![b"anthill".to_vec(), b"-62".to_vec(), b"YES".to_vec()],
+        };
+        assert_eq!(
+            WifiNetwork::try_from(r),
+            Ok(WifiNetwork {
+                ssid: "anthill".into(),
+                rssi: -62,
+                auth: true,
+            }),
+        );
+
+        let r = RPCResult {
+            command: 0x03,
+            data: vec![b"ESPHome".to_vec(), b"2024.6.0".to_vec()],
+        };
+        assert_eq!(DeviceInfo::try_from(r), Err(ImprovErr::InvalidRPCResult));
+    }
+}
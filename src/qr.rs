@@ -0,0 +1,96 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+// the `WIFI:T:WPA;S:myssid;P:mypass;;` payload of the QR codes printed on routers and shown by
+// phones when sharing a network. fields can come in any order, and `\`, `;`, `,`, `:` and `"` are
+// escaped with a backslash.
+
+use core::str::FromStr;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{ImprovErr, Ssid, WifiSettings};
+
+impl FromStr for WifiSettings {
+    type Err = ImprovErr;
+
+    fn from_str(s: &str) -> Result<WifiSettings, ImprovErr> {
+        let s = s.trim();
+        if !s.get(..5).is_some_and(|p| p.eq_ignore_ascii_case("WIFI:")) {
+            return Err(ImprovErr::InvalidQrCode);
+        }
+
+        let mut ssid = None;
+        let mut psk = String::new();
+        let mut open = false;
+        for field in fields(&s[5..])? {
+            let (key, value) = field.split_once(':').ok_or(ImprovErr::InvalidQrCode)?;
+            match key {
+                "S" => ssid = Some(Ssid::from(value)),
+                "P" => psk = String::from(value),
+                "T" => open = value.eq_ignore_ascii_case("nopass"),
+                _ => (),
+            }
+        }
+
+        Ok(WifiSettings {
+            ssid: ssid.ok_or(ImprovErr::InvalidQrCode)?,
+            psk: if open { String::new() } else { psk },
+        })
+    }
+}
+
+// split on unescaped semicolons, undoing the escapes. the empty field from the closing `;;` is
+// dropped, and a missing one is tolerated
+fn fields(s: &str) -> Result<Vec<String>, ImprovErr> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => field.push(chars.next().ok_or(ImprovErr::InvalidQrCode)?),
+            ';' if field.is_empty() => (),
+            ';' => fields.push(core::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() {
+        fields.push(field);
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "WIFI:T:WPA;S:anthill;P:ants in my pants;;".parse(),
+            Ok(WifiSettings {
+                ssid: "anthill".into(),
+                psk: String::from("ants in my pants"),
+            })
+        );
+        assert_eq!(
+            r#"WIFI:P:a\;b\\c\:d;S:\"ant\,hill\";H:true;;"#.parse(),
+            Ok(WifiSettings {
+                ssid: r#""ant,hill""#.into(),
+                psk: String::from(r"a;b\c:d"),
+            })
+        );
+        assert_eq!(
+            "WIFI:S:cafe;T:nopass;P:;".parse(),
+            Ok(WifiSettings::open("cafe"))
+        );
+        assert_eq!(
+            "WIFI:T:WPA;P:secret;;".parse::<WifiSettings>(),
+            Err(ImprovErr::InvalidQrCode)
+        );
+        assert_eq!(
+            "anthill".parse::<WifiSettings>(),
+            Err(ImprovErr::InvalidQrCode)
+        );
+    }
+}
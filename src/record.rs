@@ -0,0 +1,385 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Capturing sessions to a file and playing the device's side of them back.
+//!
+//! A recording is text, one frame per line: milliseconds since the session started, `>>` for frames
+//! the client sent or `<<` for frames it received, and the frame in hex.
+//!
+//! ```text
+//! 0 >> 49 4d 50 52 4f 56 01 03 02 02 00 e5
+//! 12 << 49 4d 50 52 4f 56 01 01 01 02 e2
+//! ```
+//!
+//! Recordings are meant to be shared, so PSKs aren't kept: each is replaced by as many `*`s, and
+//! the frame's checksum fixed to match. A replayed session must send a PSK of the same length.
+//!
+//! Sessions can also be captured as pcapng, for Wireshark and other tools which read it. Each frame
+//! is an Enhanced Packet Block on an interface of link type `LINKTYPE_USER0` (147), with its
+//! direction in the `epb_flags` option and how it decoded in a comment.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::hex::{parse_hex, to_hex};
+use crate::{Direction, ImprovPacket, RPCCommand, Traced, Transport, WifiSettings};
+
+/// The link type of the interface frames are captured on in pcapng: `LINKTYPE_USER0`, since
+/// Improv doesn't have one of its own.
+pub const LINKTYPE_IMPROV: u16 = 147;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_COMMENT: u16 = 1;
+const EPB_FLAGS: u16 = 2;
+// the low two bits of epb_flags
+const INBOUND: u32 = 1;
+const OUTBOUND: u32 = 2;
+
+/// Wrap `inner` so every frame is written to `out` as it's sent or received. Failing to write the
+/// recording doesn't interrupt the session.
+pub fn record<T: Transport, W: Write>(
+    inner: T,
+    mut out: W,
+) -> Traced<T, impl FnMut(Direction, &[u8])> {
+    let start = Instant::now();
+    Traced::new(inner, move |dir: Direction, frame: &[u8]| {
+        let _ = writeln!(
+            out,
+            "{} {} {}",
+            start.elapsed().as_millis(),
+            dir.marker(),
+            to_hex(&redact(frame))
+        );
+        let _ = out.flush();
+    })
+}
+
+/// Like `record`, but captures to pcapng.
+pub fn record_pcapng<T: Transport, W: Write>(
+    inner: T,
+    mut out: PcapngWriter<W>,
+) -> Traced<T, impl FnMut(Direction, &[u8])> {
+    Traced::new(inner, move |dir: Direction, frame: &[u8]| {
+        let _ = out.write_frame(dir, &redact(frame), SystemTime::now());
+    })
+}
+
+/// Writes frames as a pcapng capture, with one interface for the serial link.
+#[derive(Debug)]
+pub struct PcapngWriter<W> {
+    out: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Start a capture, writing its section header and interface description.
+    pub fn new(mut out: W) -> io::Result<PcapngWriter<W>> {
+        let mut shb = Vec::with_capacity(16);
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        // version 1.0, and a section of unknown length
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut out, SECTION_HEADER, &shb)?;
+
+        let mut idb = Vec::with_capacity(8);
+        idb.extend_from_slice(&LINKTYPE_IMPROV.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        // no snapshot length limit
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut out, INTERFACE_DESCRIPTION, &idb)?;
+        out.flush()?;
+        Ok(PcapngWriter { out })
+    }
+
+    /// Add a frame, sent or received at `time`. It's commented with the packet it decodes to, or
+    /// why it doesn't. The frame is written as given, so use `redact` first to leave its PSK out.
+    pub fn write_frame(
+        &mut self,
+        dir: Direction,
+        frame: &[u8],
+        time: SystemTime,
+    ) -> io::Result<()> {
+        let micros = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let len = frame.len() as u32;
+        let mut epb = Vec::with_capacity(frame.len() + 64);
+        // interface 0, then the timestamp's high and low words
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&len.to_le_bytes());
+        epb.extend_from_slice(&len.to_le_bytes());
+        epb.extend_from_slice(frame);
+        pad(&mut epb);
+
+        let flags = match dir {
+            Direction::Sent => OUTBOUND,
+            Direction::Received => INBOUND,
+        };
+        push_option(&mut epb, EPB_FLAGS, &flags.to_le_bytes());
+        let comment = match ImprovPacket::try_from(frame) {
+            Ok(p) => p.to_string(),
+            Err(e) => format!("invalid: {}", e),
+        };
+        push_option(&mut epb, OPT_COMMENT, comment.as_bytes());
+        // opt_endofopt
+        epb.extend_from_slice(&[0; 4]);
+
+        write_block(&mut self.out, ENHANCED_PACKET, &epb)?;
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// `frame` with the PSK of any Wi-Fi settings it carries replaced by `*`s, and its checksum fixed.
+/// Other frames, including ones which don't decode, are returned as they are.
+pub fn redact(frame: &[u8]) -> Cow<'_, [u8]> {
+    let masked = |settings: &WifiSettings| WifiSettings {
+        ssid: settings.ssid.clone(),
+        psk: "*".repeat(settings.psk.len()),
+    };
+    let packet = match ImprovPacket::try_from(frame) {
+        Ok(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(settings))) => {
+            ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(masked(&settings)))
+        }
+        #[cfg(feature = "static-ip")]
+        Ok(ImprovPacket::RPCCommand(cmd)) => match cmd.to_custom::<crate::StaticWifiSettings>() {
+            Some(Ok(mut settings)) => {
+                settings.wifi = masked(&settings.wifi);
+                match RPCCommand::custom(&settings) {
+                    Ok(cmd) => ImprovPacket::RPCCommand(cmd),
+                    Err(_) => return Cow::Borrowed(frame),
+                }
+            }
+            _ => return Cow::Borrowed(frame),
+        },
+        _ => return Cow::Borrowed(frame),
+    };
+    // masking keeps every length, so this encodes whenever the original decoded
+    Vec::try_from(&packet).map_or(Cow::Borrowed(frame), Cow::Owned)
+}
+
+// a block's body must already be padded to a multiple of four bytes
+fn write_block<W: Write>(out: &mut W, kind: u32, body: &[u8]) -> io::Result<()> {
+    let len = (body.len() + 12) as u32;
+    out.write_all(&kind.to_le_bytes())?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&len.to_le_bytes())
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+/// Plays back the device's side of a recording. Each frame the client sends must match the next
+/// one it sent in the recording, once its PSK is redacted; the device's answers are then available
+/// to receive.
+pub struct Replay {
+    frames: VecDeque<(Direction, Vec<u8>)>,
+}
+
+impl Replay {
+    pub fn read<R: BufRead>(recording: R) -> io::Result<Replay> {
+        let mut frames = VecDeque::new();
+        for line in recording.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            frames.push_back(parse_line(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad recording line: {}", line),
+                )
+            })?);
+        }
+        Ok(Replay { frames })
+    }
+}
+
+// the hex may be run together, as older recordings have it
+fn parse_line(line: &str) -> Option<(Direction, Vec<u8>)> {
+    let (millis, rest) = line.split_once(char::is_whitespace)?;
+    let _millis: u64 = millis.parse().ok()?;
+    let (dir, hex) = rest.trim_start().split_once(char::is_whitespace)?;
+    let dir = match dir {
+        ">>" => Direction::Sent,
+        "<<" => Direction::Received,
+        _ => return None,
+    };
+    let frame = parse_hex(hex.trim()).ok()?;
+    Some((dir, frame))
+}
+
+impl Transport for Replay {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self.frames.front() {
+            Some((Direction::Sent, expected)) if **expected == *redact(frame) => {
+                self.frames.pop_front();
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the client's frame doesn't match the recording",
+            )),
+        }
+    }
+
+    // the device only speaks when the recording says it did; anything else is a timeout
+    fn recv_frame(&mut self, _timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        match self.frames.front() {
+            Some((Direction::Received, _)) => Ok(self.frames.pop_front().map(|(_, f)| f)),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CurrentState, ImprovClient, MockImprovDevice};
+
+    #[test]
+    fn record_then_replay() {
+        let mut recording = Vec::new();
+        let mut client = ImprovClient::new(record(MockImprovDevice::new(), &mut recording));
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        drop(client);
+
+        let text = String::from_utf8(recording).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text
+            .lines()
+            .next()
+            .unwrap()
+            .contains(" >> 49 4d 50 52 4f 56"));
+
+        let mut client = ImprovClient::new(Replay::read(text.as_bytes()).unwrap());
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        // the recording is over, so the device has nothing more to say
+        assert!(client.device_info().is_err());
+    }
+
+    #[test]
+    fn pcapng() {
+        let mut capture = Vec::new();
+        let writer = PcapngWriter::new(&mut capture).unwrap();
+        let mut client = ImprovClient::new(record_pcapng(MockImprovDevice::new(), writer));
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        drop(client);
+
+        let u32_at = |i: usize| u32::from_le_bytes(capture[i..i + 4].try_into().unwrap());
+        let mut blocks = Vec::new();
+        let mut i = 0;
+        while i < capture.len() {
+            let len = u32_at(i + 4) as usize;
+            assert_eq!(u32_at(i + len - 4) as usize, len);
+            blocks.push((u32_at(i), &capture[i + 8..i + len - 4]));
+            i += len;
+        }
+        assert_eq!(i, capture.len());
+
+        assert_eq!(blocks[0].0, SECTION_HEADER);
+        assert_eq!(blocks[0].1[..4], BYTE_ORDER_MAGIC.to_le_bytes());
+        assert_eq!(blocks[1].0, INTERFACE_DESCRIPTION);
+        assert_eq!(blocks[1].1[..2], LINKTYPE_IMPROV.to_le_bytes());
+
+        let packets: Vec<&[u8]> = blocks[2..].iter().map(|b| b.1).collect();
+        assert!(blocks[2..].iter().all(|b| b.0 == ENHANCED_PACKET));
+        assert_eq!(packets.len(), 2);
+        let sent = packets[0];
+        assert_eq!(sent[12..16], 12u32.to_le_bytes());
+        assert_eq!(sent[20..32], crate::REQUEST_CURRENT_STATE);
+        // epb_flags follows the frame
+        assert_eq!(sent[32..40], [2, 0, 4, 0, 2, 0, 0, 0]);
+        let received = packets[1];
+        assert_eq!(received[36..40], INBOUND.to_le_bytes());
+        assert!(String::from_utf8_lossy(received).contains("CurrentState::Ready"));
+    }
+
+    #[test]
+    fn psk_is_redacted() {
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        let mut recording = Vec::new();
+        let mut client = ImprovClient::new(record(MockImprovDevice::new(), &mut recording));
+        client.send_wifi_settings(&settings).unwrap();
+        drop(client);
+
+        let text = String::from_utf8(recording).unwrap();
+        let (_, sent) = parse_line(text.lines().next().unwrap()).unwrap();
+        assert_eq!(
+            ImprovPacket::try_from(&sent[..]).unwrap(),
+            ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(WifiSettings {
+                ssid: "anthill".into(),
+                psk: "*".repeat(16),
+            }))
+        );
+        assert!(!text.contains(&to_hex(b"ants")));
+
+        // replaying still matches what the client sends
+        let mut client = ImprovClient::new(Replay::read(text.as_bytes()).unwrap());
+        client.send_wifi_settings(&settings).unwrap();
+
+        let frame = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        assert!(matches!(redact(&frame), Cow::Borrowed(_)));
+    }
+
+    #[cfg(feature = "static-ip")]
+    #[test]
+    fn static_psk_is_redacted() {
+        use crate::{NetworkConfig, StaticWifiSettings};
+        use std::net::Ipv4Addr;
+
+        let mut settings = StaticWifiSettings {
+            wifi: WifiSettings {
+                ssid: "anthill".into(),
+                psk: String::from("ants in my pants"),
+            },
+            network: NetworkConfig {
+                address: Ipv4Addr::new(192, 168, 1, 50),
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                gateway: Ipv4Addr::new(192, 168, 1, 1),
+                dns: vec![],
+            },
+        };
+        let packet =
+            |s: &StaticWifiSettings| ImprovPacket::RPCCommand(RPCCommand::custom(s).unwrap());
+        let frame = Vec::try_from(packet(&settings)).unwrap();
+        settings.wifi.psk = "*".repeat(16);
+        assert_eq!(
+            ImprovPacket::try_from(&redact(&frame)[..]).unwrap(),
+            packet(&settings)
+        );
+    }
+
+    #[test]
+    fn bad_line() {
+        assert!(Replay::read(&b"0 >> 494d5"[..]).is_err());
+        assert!(Replay::read(&b"0 <> 494d"[..]).is_err());
+        assert_eq!(
+            parse_line("0 << 494d50"),
+            Some((Direction::Received, vec![0x49, 0x4d, 0x50]))
+        );
+    }
+}
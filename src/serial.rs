@@ -0,0 +1,262 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Finding frames in a serial byte stream and decoding them. Opening serial ports (with the
+//! `serial` feature) lives here too.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::protocol::{checksum, wipe, ImprovErr, ImprovPacket, Mode, HEADER_LEN, IMPROV_VERSION};
+
+#[cfg(feature = "serial")]
+mod port;
+#[cfg(feature = "serial")]
+pub use port::*;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub pkt_type: u8,
+    pub len: u8,
+}
+
+impl FrameHeader {
+    /// Read the header from the start of a frame. Only the magic is checked; the version is left
+    /// for the caller to interpret.
+    pub fn parse(b: &[u8]) -> Result<FrameHeader, ImprovErr> {
+        if b.iter().zip(b"IMPROV").any(|(x, y)| x != y) {
+            return Err(ImprovErr::NotAnImprovPacket);
+        }
+
+        if b.len() < HEADER_LEN {
+            return Err(ImprovErr::Truncated);
+        }
+
+        Ok(FrameHeader {
+            version: b[6],
+            pkt_type: b[7],
+            len: b[8],
+        })
+    }
+
+    /// The length of the whole frame this header starts, checksum included.
+    pub fn frame_len(&self) -> usize {
+        HEADER_LEN + self.len as usize + 1
+    }
+}
+
+pub(crate) enum Scan {
+    Frame { start: usize, end: usize },
+    Partial { start: usize },
+    Nothing,
+}
+
+// find the first checksum-valid frame in a buffer of arbitrary serial data
+pub(crate) fn scan(buf: &[u8]) -> Scan {
+    let mut start = 0;
+    while start < buf.len() {
+        let rest = &buf[start..];
+        if rest.iter().zip(b"IMPROV").any(|(x, y)| x != y) {
+            start += 1;
+            continue;
+        }
+
+        if rest.len() < HEADER_LEN {
+            return Scan::Partial { start };
+        }
+
+        let len = HEADER_LEN + rest[8] as usize + 1;
+        if rest.len() < len {
+            return Scan::Partial { start };
+        }
+
+        if checksum(&rest[..len - 1]) == rest[len - 1] {
+            return Scan::Frame {
+                start,
+                end: start + len,
+            };
+        }
+
+        start += 1;
+    }
+    Scan::Nothing
+}
+
+// the first `n` bytes of `buf` are being thrown away while looking for a frame. with the metrics
+// feature that counts as a resync, and any complete frame starting among them must have had a bad
+// checksum
+#[cfg(any(
+    feature = "std",
+    feature = "async",
+    feature = "tokio",
+    feature = "embedded-io",
+    feature = "embedded-io-async"
+))]
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn discard(buf: &[u8], n: usize) {
+    if n == 0 {
+        return;
+    }
+    count!("improv_resyncs_total");
+    #[cfg(feature = "metrics")]
+    for start in 0..n {
+        let rest = &buf[start..];
+        if rest.starts_with(b"IMPROV")
+            && rest
+                .get(8)
+                .is_some_and(|&len| rest.len() > HEADER_LEN + len as usize)
+        {
+            count!("improv_checksum_failures_total");
+        }
+    }
+}
+
+// decode a complete frame leniently, so contents which can't be understood come back as `Raw`
+pub(crate) fn decode_frame(frame: &[u8]) -> Result<ImprovPacket, ImprovErr> {
+    let (header, payload) = unframe(frame)?;
+    if header.version != IMPROV_VERSION {
+        return Err(ImprovErr::UnsupportedVersion(header.version));
+    }
+    event!(
+        trace,
+        pkt_type = header.pkt_type,
+        len = header.len,
+        "decoding frame"
+    );
+    count!("improv_frames_decoded_total");
+    ImprovPacket::decode_payload(header.pkt_type, payload, Mode::Lenient)
+}
+
+// split a complete frame into its header and payload, checking the length and checksum
+pub(crate) fn unframe(b: &[u8]) -> Result<(FrameHeader, &[u8]), ImprovErr> {
+    let header = FrameHeader::parse(b)?;
+
+    let len = HEADER_LEN + header.len as usize + 1;
+    if b.len() < len {
+        return Err(ImprovErr::Truncated);
+    }
+    if b.len() > len {
+        return Err(ImprovErr::BadLength {
+            expected: header.len as usize,
+            found: b.len() - HEADER_LEN - 1,
+        });
+    }
+
+    let sum = checksum(&b[..len - 1]);
+    if sum != b[len - 1] {
+        return Err(ImprovErr::BadChecksum {
+            expected: sum,
+            found: b[len - 1],
+        });
+    }
+
+    Ok((header, &b[HEADER_LEN..len - 1]))
+}
+
+/// Decodes the payload of frames carrying a particular protocol version.
+pub trait VersionDecoder {
+    fn decode(&self, header: &FrameHeader, payload: Vec<u8>) -> Result<ImprovPacket, ImprovErr>;
+}
+
+/// The decoder for version 1 of the serial protocol, which is what `ImprovPacket::try_from` uses.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct V1Decoder;
+
+impl VersionDecoder for V1Decoder {
+    fn decode(
+        &self,
+        header: &FrameHeader,
+        mut payload: Vec<u8>,
+    ) -> Result<ImprovPacket, ImprovErr> {
+        let p = ImprovPacket::decode_payload(header.pkt_type, &payload, Mode::Strict);
+        wipe(&mut payload);
+        p
+    }
+}
+
+/// Decodes frames by dispatching on their version byte to a registered `VersionDecoder`.
+pub struct ImprovDecoder {
+    decoders: Vec<(u8, Box<dyn VersionDecoder + Send + Sync>)>,
+}
+
+impl ImprovDecoder {
+    /// A decoder that understands only version 1 frames.
+    pub fn new() -> ImprovDecoder {
+        let mut d = ImprovDecoder {
+            decoders: Vec::new(),
+        };
+        d.register(IMPROV_VERSION, V1Decoder);
+        d
+    }
+
+    /// Use `decoder` for frames carrying `version`, replacing any previously registered decoder.
+    pub fn register<D>(&mut self, version: u8, decoder: D)
+    where
+        D: VersionDecoder + Send + Sync + 'static,
+    {
+        self.decoders.retain(|(v, _)| *v != version);
+        self.decoders.push((version, Box::new(decoder)));
+    }
+
+    pub fn decode(&self, mut b: Vec<u8>) -> Result<(FrameHeader, ImprovPacket), ImprovErr> {
+        let header = FrameHeader::parse(&b)?;
+        let decoder = self
+            .decoders
+            .iter()
+            .find(|(v, _)| *v == header.version)
+            .map(|(_, d)| d)
+            .ok_or(ImprovErr::UnsupportedVersion(header.version))?;
+
+        let (header, data) = unframe(&b)?;
+        let data = data.to_vec();
+        wipe(&mut b);
+        Ok((header, decoder.decode(&header, data)?))
+    }
+}
+
+impl Default for ImprovDecoder {
+    fn default() -> ImprovDecoder {
+        ImprovDecoder::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn decode_registered_version() {
+        struct V2;
+        impl VersionDecoder for V2 {
+            fn decode(&self, h: &FrameHeader, payload: Vec<u8>) -> Result<ImprovPacket, ImprovErr> {
+                Ok(ImprovPacket::Raw {
+                    pkt_type: h.pkt_type,
+                    payload,
+                })
+            }
+        }
+
+        let mut v: Vec<u8> = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x02, 0x03, 0x02, 0x02, 0x00, 0x00,
+        ];
+        v[11] = checksum(&v[..11]);
+
+        let mut d = ImprovDecoder::new();
+        assert_eq!(
+            d.decode(v.clone()),
+            Err(ImprovErr::UnsupportedVersion(0x02))
+        );
+
+        d.register(0x02, V2);
+        let (header, p) = d.decode(v).unwrap();
+        assert_eq!(header.version, 0x02);
+        assert_eq!(
+            p,
+            ImprovPacket::Raw {
+                pkt_type: 0x03,
+                payload: vec![0x02, 0x00],
+            }
+        );
+    }
+}
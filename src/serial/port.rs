@@ -0,0 +1,324 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Helpers for finding and opening the serial port a device is attached to.
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serialport::ClearBuffer;
+
+use crate::{
+    scan, CurrentState, DeviceInfo, ImprovClient, RetryPolicy, Scan, FRAME_TERMINATOR,
+    REQUEST_CURRENT_STATE,
+};
+
+pub use serialport::{
+    DataBits, FlowControl, Parity, SerialPort, SerialPortInfo, SerialPortType, StopBits,
+    UsbPortInfo,
+};
+
+// USB vendor ids of the chips ESP boards usually ship with
+const ESP_VIDS: &[u16] = &[
+    0x303a, // Espressif (native USB)
+    0x10c4, // Silicon Labs CP210x
+    0x1a86, // WCH CH340/CH9102
+    0x0403, // FTDI
+];
+
+/// Rates Improv firmware commonly runs its console at, most likely first. 74880 is what the
+/// ESP8266's boot ROM uses.
+pub const COMMON_BAUD_RATES: &[u32] = &[115200, 460800, 921600, 74880];
+
+/// Line settings for the port a device is attached to. The defaults (115200 8N1, no flow
+/// control) match most Improv firmware.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SerialOptions {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    /// How long a single read blocks; the client's own timeouts are checked between reads.
+    pub read_timeout: Duration,
+    /// Drive DTR to this level once the port is open, or leave it as the OS set it.
+    pub dtr: Option<bool>,
+    /// Drive RTS to this level once the port is open, or leave it as the OS set it.
+    pub rts: Option<bool>,
+}
+
+impl Default for SerialOptions {
+    fn default() -> SerialOptions {
+        SerialOptions {
+            baud_rate: 115200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            read_timeout: Duration::from_millis(100),
+            dtr: None,
+            rts: None,
+        }
+    }
+}
+
+impl SerialOptions {
+    pub fn open(&self, path: &str) -> serialport::Result<Box<dyn SerialPort>> {
+        let mut port = serialport::new(path, self.baud_rate)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .flow_control(self.flow_control)
+            .timeout(self.read_timeout)
+            .open()?;
+        if let Some(level) = self.dtr {
+            port.write_data_terminal_ready(level)?;
+        }
+        if let Some(level) = self.rts {
+            port.write_request_to_send(level)?;
+        }
+        Ok(port)
+    }
+}
+
+/// Reset an ESP board through the usual auto-reset circuit: RTS pulls EN low while DTR is held
+/// high so GPIO0 isn't, and the chip boots normally rather than into its bootloader.
+pub fn hard_reset(port: &mut dyn SerialPort) -> serialport::Result<()> {
+    port.write_data_terminal_ready(false)?;
+    port.write_request_to_send(true)?;
+    thread::sleep(Duration::from_millis(100));
+    port.write_request_to_send(false)?;
+    Ok(())
+}
+
+/// Read and discard the device's boot output until `banner` appears, or, without a banner, until
+/// it has been quiet for half a second. Returns false if that didn't happen within `timeout`.
+pub fn wait_for_boot<R: Read>(
+    port: &mut R,
+    banner: Option<&[u8]>,
+    timeout: Duration,
+) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    let mut last_heard = Instant::now();
+    let mut seen = Vec::new();
+    let mut buf = [0u8; 256];
+    while Instant::now() < deadline {
+        match port.read(&mut buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                last_heard = Instant::now();
+                let Some(banner) = banner else { continue };
+                seen.extend_from_slice(&buf[..n]);
+                if seen.windows(banner.len()).any(|w| w == banner) {
+                    return Ok(true);
+                }
+                // keep just enough to match a banner split across reads
+                let keep = seen.len().saturating_sub(banner.len());
+                seen.drain(..keep);
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                if banner.is_none() && last_heard.elapsed() >= Duration::from_millis(500) {
+                    return Ok(true);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// Find the rate the device on `port` talks at, by asking for its current state at each of
+/// `rates` in turn and keeping the first that gets a well-formed reply within `timeout`. The port
+/// is left at that rate.
+pub fn detect_baud(
+    port: &mut dyn SerialPort,
+    rates: &[u32],
+    timeout: Duration,
+) -> io::Result<Option<u32>> {
+    for &rate in rates {
+        port.set_baud_rate(rate)?;
+        // drop anything left over from the previous rate
+        port.clear(ClearBuffer::All)?;
+        if probe(port, timeout)? {
+            return Ok(Some(rate));
+        }
+    }
+    Ok(None)
+}
+
+/// Send `RequestCurrentState` and wait up to `timeout` for any checksum-valid frame in reply.
+/// Anything else the device prints, or line noise from a wrong baud rate, is ignored.
+pub fn probe<P: Read + Write + ?Sized>(port: &mut P, timeout: Duration) -> io::Result<bool> {
+    port.write_all(&REQUEST_CURRENT_STATE)?;
+    port.write_all(&[FRAME_TERMINATOR])?;
+    port.flush()?;
+
+    let deadline = Instant::now() + timeout;
+    let mut seen = Vec::new();
+    let mut buf = [0u8; 256];
+    while Instant::now() < deadline {
+        match port.read(&mut buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => seen.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        }
+        match scan(&seen) {
+            Scan::Frame { .. } => return Ok(true),
+            Scan::Partial { start } => drop(seen.drain(..start)),
+            Scan::Nothing => seen.clear(),
+        }
+    }
+    Ok(false)
+}
+
+/// Enumerate the serial ports on this machine, optionally keeping only the ones that look like
+/// an ESP board.
+pub fn ports(likely_only: bool) -> serialport::Result<Vec<SerialPortInfo>> {
+    let mut ports = serialport::available_ports()?;
+    if likely_only {
+        ports.retain(is_likely_esp);
+    }
+    Ok(ports)
+}
+
+/// An Improv device found by `discover`.
+#[derive(Clone, Debug)]
+pub struct Discovered {
+    pub port: SerialPortInfo,
+    pub state: CurrentState,
+    /// `None` if the device didn't answer when asked.
+    pub info: Option<DeviceInfo>,
+}
+
+/// Ask the device on each serial port (or, with `likely_only`, each port that looks like an ESP
+/// board) for its state and information, all at once, and return the ones that answered within
+/// `timeout`. Ports which can't be opened, e.g. because another program has them, are skipped.
+pub fn discover(
+    likely_only: bool,
+    options: &SerialOptions,
+    timeout: Duration,
+) -> serialport::Result<Vec<Discovered>> {
+    let ports = ports(likely_only)?;
+    Ok(thread::scope(|s| {
+        let handles: Vec<_> = ports
+            .into_iter()
+            .map(|port| s.spawn(move || query(port, options, timeout)))
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|h| h.join().ok().flatten())
+            .collect()
+    }))
+}
+
+fn query(port: SerialPortInfo, options: &SerialOptions, timeout: Duration) -> Option<Discovered> {
+    let mut client = ImprovClient::open(&port.port_name, options).ok()?;
+    client.set_timeout(timeout);
+    client.set_retry(RetryPolicy::never());
+    let state = client.current_state().ok()?;
+    let info = client.device_info().ok();
+    Some(Discovered { port, state, info })
+}
+
+/// Whether the port is a USB serial adapter commonly found on ESP boards.
+pub fn is_likely_esp(port: &SerialPortInfo) -> bool {
+    match &port.port_type {
+        SerialPortType::UsbPort(usb) => ESP_VIDS.contains(&usb.vid),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CurrentState, ImprovPacket};
+
+    fn usb(vid: u16) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: "/dev/ttyUSB0".into(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid: 0xea60,
+                serial_number: None,
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn banner_split_across_reads() {
+        // hands out two bytes at a time
+        struct Slow<'a>(&'a [u8]);
+
+        impl Read for Slow<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(2).min(buf.len());
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let mut port = Slow(b"ets Jun  8 2016\r\n[I][app:029]: Running through setup()\r\n");
+        assert!(wait_for_boot(&mut port, Some(b"setup()"), Duration::from_secs(1)).unwrap());
+    }
+
+    #[test]
+    fn probe_finds_reply_in_noise() {
+        // replies to anything written with noise, then a state frame split across reads
+        struct Device {
+            written: Vec<u8>,
+            reply: Vec<u8>,
+        }
+
+        impl Read for Device {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.written.is_empty() || self.reply.is_empty() {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                let n = self.reply.len().min(5).min(buf.len());
+                buf[..n].copy_from_slice(&self.reply[..n]);
+                self.reply.drain(..n);
+                Ok(n)
+            }
+        }
+
+        impl Write for Device {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.written.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let state = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        let mut dev = Device {
+            written: Vec::new(),
+            reply: [&b"\xf0\x0fIMPRO\x80\x00"[..], &state].concat(),
+        };
+        assert!(probe(&mut dev, Duration::from_secs(1)).unwrap());
+        assert_eq!(dev.written, [&REQUEST_CURRENT_STATE[..], b"\n"].concat());
+
+        let mut dev = Device {
+            written: Vec::new(),
+            reply: b"\xf0\x0f\xff".to_vec(),
+        };
+        assert!(!probe(&mut dev, Duration::from_millis(100)).unwrap());
+    }
+
+    #[test]
+    fn test_is_likely_esp() {
+        assert!(is_likely_esp(&usb(0x10c4)));
+        assert!(is_likely_esp(&usb(0x303a)));
+        assert!(!is_likely_esp(&usb(0x05ac)));
+        assert!(!is_likely_esp(&SerialPortInfo {
+            port_name: "/dev/ttyS0".into(),
+            port_type: SerialPortType::Unknown,
+        }));
+    }
+}
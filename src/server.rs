@@ -0,0 +1,742 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::ble;
+use crate::{
+    scan, CommandId, CurrentState, CustomCommand, DeviceInfo, ErrorState, Identify, ImprovPacket,
+    RPCCommand, RPCResult, Scan, TypedPacket, WifiNetwork, WifiSettings,
+};
+
+/// The platform side of provisioning, called by `ImprovServer` as commands arrive.
+pub trait WifiProvisioner {
+    /// Join the network. Return the redirect URL (if any) once connected, or the error to report,
+    /// usually `ErrorState::UnableToConnect`.
+    fn connect(&mut self, ssid: &[u8], psk: &str) -> Result<Option<String>, ErrorState>;
+
+    fn scan(&mut self) -> Vec<WifiNetwork> {
+        Vec::new()
+    }
+
+    fn device_info(&mut self) -> DeviceInfo;
+
+    /// Make the device known, e.g. by blinking an LED, when a BLE client asks or a serial one
+    /// sends `Identify`. Devices which can should say so in their `Capabilities`.
+    fn identify(&mut self) -> Result<(), ErrorState> {
+        Err(ErrorState::UnknownRPCCommand)
+    }
+
+    /// Handle a vendor-specific command, returning the strings to answer with or the error to
+    /// report. Commands left as `None` are reported as unknown.
+    fn custom(&mut self, id: u8, payload: &[u8]) -> Option<Result<Vec<Vec<u8>>, ErrorState>> {
+        let _ = (id, payload);
+        None
+    }
+}
+
+/// Where the device keeps the credentials it was provisioned with, e.g. NVS or EEPROM.
+/// `ImprovServer` saves them once `connect` succeeds, loads them in `resume`, and clears them in
+/// `forget`. The store deals with its own failures, such as by logging them.
+pub trait CredentialStore {
+    /// Keep these credentials, replacing any kept before.
+    fn save(&mut self, ssid: &[u8], psk: &str);
+
+    fn load(&mut self) -> Option<WifiSettings>;
+
+    fn clear(&mut self);
+}
+
+/// Keeps nothing, for a server without a `CredentialStore`.
+impl CredentialStore for () {
+    fn save(&mut self, _ssid: &[u8], _psk: &str) {}
+
+    fn load(&mut self) -> Option<WifiSettings> {
+        None
+    }
+
+    fn clear(&mut self) {}
+}
+
+/// The device side of the serial protocol.
+///
+/// Feed it whatever arrives on the UART and transmit whatever it hands back. The platform-specific
+/// work (joining a network, scanning, describing the device) is delegated to a `WifiProvisioner`.
+///
+/// Hosts sometimes send credentials again before seeing the answer to the first attempt. While
+/// provisioning (e.g. after `set_state` for a connection the firmware started itself), further
+/// credentials are answered with the state and otherwise ignored, and once provisioned, the same
+/// credentials again are answered with the result rather than passed to `connect` a second time.
+pub struct ImprovServer<P, S = ()> {
+    state: CurrentState,
+    error: ErrorState,
+    redirect_url: Option<String>,
+    // a fingerprint of the credentials which provisioned the device, for spotting resends
+    provisioned_with: Option<u64>,
+    buf: Vec<u8>,
+    provisioner: P,
+    store: S,
+    authorization: Option<Authorization>,
+}
+
+// the physical-presence gate BLE devices may put in front of provisioning
+struct Authorization {
+    timeout: Duration,
+    // time left before it lapses, while authorized
+    remaining: Option<Duration>,
+}
+
+impl<P: WifiProvisioner> ImprovServer<P> {
+    pub fn new(provisioner: P) -> ImprovServer<P> {
+        ImprovServer {
+            state: CurrentState::Ready,
+            error: ErrorState::NoError,
+            redirect_url: None,
+            provisioned_with: None,
+            buf: Vec::new(),
+            provisioner,
+            store: (),
+            authorization: None,
+        }
+    }
+}
+
+impl<P: WifiProvisioner, S: CredentialStore> ImprovServer<P, S> {
+    /// Keep the credentials the device is provisioned with in `store`.
+    pub fn with_store<T: CredentialStore>(self, store: T) -> ImprovServer<P, T> {
+        ImprovServer {
+            state: self.state,
+            error: self.error,
+            redirect_url: self.redirect_url,
+            provisioned_with: self.provisioned_with,
+            buf: self.buf,
+            provisioner: self.provisioner,
+            store,
+            authorization: self.authorization,
+        }
+    }
+
+    /// Refuse credentials with `ErrorState::NotAuthorized` until `authorize` is called, e.g. when
+    /// the user presses a button on the device. Each authorization lapses after `timeout`.
+    pub fn require_authorization(&mut self, timeout: Duration) {
+        self.authorization = Some(Authorization {
+            timeout,
+            remaining: None,
+        });
+    }
+
+    /// Accept credentials until the authorization timeout elapses.
+    pub fn authorize(&mut self) {
+        if let Some(auth) = &mut self.authorization {
+            event!(debug, "authorized");
+            auth.remaining = Some(auth.timeout);
+        }
+    }
+
+    pub fn is_authorized(&self) -> bool {
+        self.authorization
+            .as_ref()
+            .is_none_or(|auth| auth.remaining.is_some())
+    }
+
+    /// Let `elapsed` pass on the authorization timeout. Call this periodically when authorization
+    /// is required; returns true when the authorization has just lapsed.
+    pub fn tick(&mut self, elapsed: Duration) -> bool {
+        let Some(auth) = &mut self.authorization else {
+            return false;
+        };
+        let Some(remaining) = auth.remaining else {
+            return false;
+        };
+        auth.remaining = remaining.checked_sub(elapsed).filter(|r| !r.is_zero());
+        if auth.remaining.is_none() {
+            event!(debug, "authorization lapsed");
+        }
+        auth.remaining.is_none()
+    }
+
+    /// The state as the BLE current state characteristic reports it, which distinguishes waiting
+    /// for authorization from being ready for credentials.
+    pub fn ble_state(&self) -> ble::CurrentState {
+        match self.state {
+            CurrentState::Provisioning => ble::CurrentState::Provisioning,
+            CurrentState::Provisioned => ble::CurrentState::Provisioned,
+            _ if self.is_authorized() => ble::CurrentState::Authorized,
+            _ => ble::CurrentState::AuthorizationRequired,
+        }
+    }
+
+    pub fn provisioner(&mut self) -> &mut P {
+        &mut self.provisioner
+    }
+
+    pub fn store(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    pub fn state(&self) -> CurrentState {
+        self.state
+    }
+
+    pub fn error(&self) -> ErrorState {
+        self.error
+    }
+
+    /// Change state outside of the protocol, e.g. when booting with stored credentials, returning
+    /// the bytes to transmit to announce it.
+    pub fn set_state(&mut self, state: CurrentState, redirect_url: Option<String>) -> Vec<u8> {
+        self.redirect_url = redirect_url;
+        self.provisioned_with = None;
+        Vec::try_from(self.transition(state)).unwrap_or_default()
+    }
+
+    /// Join the network with the stored credentials, e.g. when booting, returning the bytes to
+    /// transmit to announce it. Does nothing if there are none.
+    pub fn resume(&mut self) -> Vec<u8> {
+        let Some(settings) = self.store.load() else {
+            return Vec::new();
+        };
+        let packets = self.join(settings.ssid.as_bytes(), &settings.psk, None);
+        self.encode(packets)
+    }
+
+    /// Clear the stored credentials and go back to `Ready`, e.g. for a factory reset, returning
+    /// the bytes to transmit to announce it.
+    pub fn forget(&mut self) -> Vec<u8> {
+        self.store.clear();
+        self.set_state(CurrentState::Ready, None)
+    }
+
+    /// Consume bytes received from the host, returning the bytes to transmit in response.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<u8> {
+        self.buf.extend_from_slice(bytes);
+
+        let mut out = Vec::new();
+        loop {
+            match scan(&self.buf) {
+                Scan::Frame { start, end } => {
+                    let frame: Vec<u8> = self.buf.drain(..end).skip(start).collect();
+                    let packets = self.handle(frame);
+                    out.extend(self.encode(packets));
+                }
+                Scan::Partial { start } => {
+                    self.buf.drain(..start);
+                    break;
+                }
+                Scan::Nothing => {
+                    self.buf.clear();
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    fn handle(&mut self, frame: Vec<u8>) -> Vec<ImprovPacket> {
+        let is_command = frame[7] == RPCCommand::TYPE;
+        match ImprovPacket::try_from(frame) {
+            Ok(ImprovPacket::RPCCommand(cmd)) => self.answer(cmd),
+            Err(e) if is_command => vec![self.set_error(e.into())],
+            // we're not interested in anything else the host might send
+            _ => vec![],
+        }
+    }
+
+    // answers a command however it arrived, clearing the error left by the last one first
+    pub(crate) fn answer(&mut self, cmd: RPCCommand) -> Vec<ImprovPacket> {
+        let mut out = self.clear_error();
+        out.extend(self.command(cmd));
+        out
+    }
+
+    pub(crate) fn clear_error(&mut self) -> Vec<ImprovPacket> {
+        if self.error == ErrorState::NoError {
+            return Vec::new();
+        }
+        vec![self.set_error(ErrorState::NoError)]
+    }
+
+    fn command(&mut self, cmd: RPCCommand) -> Vec<ImprovPacket> {
+        event!(debug, command = %cmd, "command");
+        match cmd {
+            RPCCommand::RequestCurrentState => {
+                let mut out = vec![ImprovPacket::CurrentState(self.state)];
+                if self.state == CurrentState::Provisioned {
+                    let url = self.redirect_url.as_deref();
+                    out.push(ImprovPacket::RPCResult(RPCResult::provisioned(
+                        CommandId::RequestCurrentState.into(),
+                        url,
+                    )));
+                }
+                out
+            }
+            RPCCommand::RequestDeviceInformation => {
+                let info = self.provisioner.device_info();
+                vec![ImprovPacket::RPCResult(RPCResult::device_information(
+                    &info,
+                ))]
+            }
+            RPCCommand::RequestScannedWifiNetworks => {
+                let mut out: Vec<ImprovPacket> = self
+                    .provisioner
+                    .scan()
+                    .iter()
+                    .map(|n| ImprovPacket::RPCResult(RPCResult::scan_entry(n)))
+                    .collect();
+                out.push(ImprovPacket::RPCResult(RPCResult::scan_complete()));
+                out
+            }
+            RPCCommand::Custom { id, payload } => match self.provisioner.custom(id, &payload) {
+                Some(Ok(data)) => vec![ImprovPacket::RPCResult(RPCResult { command: id, data })],
+                Some(Err(e)) => vec![self.set_error(e)],
+                None if id == Identify::ID => match self.provisioner.identify() {
+                    Ok(()) => vec![ImprovPacket::RPCResult(RPCResult {
+                        command: id,
+                        data: Vec::new(),
+                    })],
+                    Err(e) => vec![self.set_error(e)],
+                },
+                None => vec![self.set_error(ErrorState::UnknownRPCCommand)],
+            },
+            RPCCommand::SendWifiSettings(_) if !self.is_authorized() => {
+                vec![self.set_error(ErrorState::NotAuthorized)]
+            }
+            RPCCommand::SendWifiSettings(_) if self.state == CurrentState::Provisioning => {
+                event!(debug, "ignoring credentials while provisioning");
+                vec![ImprovPacket::CurrentState(self.state)]
+            }
+            RPCCommand::SendWifiSettings(settings)
+                if self.state == CurrentState::Provisioned
+                    && self.provisioned_with
+                        == Some(fingerprint(settings.ssid.as_bytes(), &settings.psk)) =>
+            {
+                event!(debug, "credentials resent after provisioning");
+                let url = self.redirect_url.as_deref();
+                vec![
+                    ImprovPacket::CurrentState(self.state),
+                    ImprovPacket::RPCResult(RPCResult::provisioned(
+                        CommandId::SendWifiSettings.into(),
+                        url,
+                    )),
+                ]
+            }
+            RPCCommand::SendWifiSettings(settings) => {
+                let ssid = settings.ssid.as_bytes();
+                let out = self.join(
+                    ssid,
+                    &settings.psk,
+                    Some(CommandId::SendWifiSettings.into()),
+                );
+                if self.state == CurrentState::Provisioned {
+                    self.store.save(ssid, &settings.psk);
+                }
+                out
+            }
+        }
+    }
+
+    // connect and report how it went, answering `command` with the redirect URL if there is one
+    // to answer
+    fn join(&mut self, ssid: &[u8], psk: &str, command: Option<u8>) -> Vec<ImprovPacket> {
+        let mut out = vec![self.transition(CurrentState::Provisioning)];
+        self.provisioned_with = None;
+        match self.provisioner.connect(ssid, psk) {
+            Ok(url) => {
+                out.push(self.transition(CurrentState::Provisioned));
+                if let Some(command) = command {
+                    let result = RPCResult::provisioned(command, url.as_deref());
+                    out.push(ImprovPacket::RPCResult(result));
+                }
+                self.redirect_url = url;
+                self.provisioned_with = Some(fingerprint(ssid, psk));
+            }
+            Err(e) => {
+                out.push(self.transition(CurrentState::Ready));
+                out.push(self.set_error(e));
+            }
+        }
+        out
+    }
+
+    fn transition(&mut self, state: CurrentState) -> ImprovPacket {
+        event!(debug, from = %self.state, to = %state, "state transition");
+        self.state = state;
+        ImprovPacket::CurrentState(state)
+    }
+
+    pub(crate) fn set_error(&mut self, error: ErrorState) -> ImprovPacket {
+        event!(debug, %error, "error state");
+        self.error = error;
+        ImprovPacket::ErrorState(error)
+    }
+
+    // a response the provisioner made too long to send, e.g. with an overlong device name, is
+    // answered with `UnknownError` rather than silently dropped, so the client isn't left
+    // waiting. it's recorded like any other error, so the next command clears it
+    fn encode(&mut self, packets: Vec<ImprovPacket>) -> Vec<u8> {
+        let mut out = Vec::new();
+        for p in packets {
+            match Vec::try_from(&p) {
+                Ok(frame) => out.extend(frame),
+                Err(_e) => {
+                    event!(warn, error = %_e, "response can't be encoded");
+                    let error = self.set_error(ErrorState::UnknownError);
+                    out.extend(error_frame(error));
+                }
+            }
+        }
+        out
+    }
+}
+
+// a single byte of payload, which always encodes
+fn error_frame(p: ImprovPacket) -> Vec<u8> {
+    let mut frame = vec![0; p.encoded_len()];
+    let n = p.encode_into(&mut frame);
+    debug_assert_eq!(n, Ok(frame.len()));
+    frame
+}
+
+// FNV-1a over both fields, length-prefixed so moving bytes between them changes it. credentials
+// are compared this way so that neither server has to keep the PSK around
+pub(crate) fn fingerprint(ssid: &[u8], psk: &str) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for field in [ssid, psk.as_bytes()] {
+        for &b in (field.len() as u64).to_le_bytes().iter().chain(field) {
+            h = (h ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    h
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{packets, result};
+    use crate::WifiSettings;
+
+    struct Device {
+        networks: Vec<WifiNetwork>,
+        connects: usize,
+    }
+
+    impl WifiProvisioner for Device {
+        fn connect(&mut self, _ssid: &[u8], psk: &str) -> Result<Option<String>, ErrorState> {
+            self.connects += 1;
+            if psk == "ants in my pants" {
+                Ok(Some(String::from("http://192.168.1.2")))
+            } else {
+                Err(ErrorState::UnableToConnect)
+            }
+        }
+
+        fn scan(&mut self) -> Vec<WifiNetwork> {
+            self.networks.clone()
+        }
+
+        fn device_info(&mut self) -> DeviceInfo {
+            DeviceInfo {
+                firmware_name: String::from("improv-rs"),
+                firmware_version: String::from("0.1.0"),
+                hardware: String::from("ESP32-C3"),
+                device_name: String::from("anthill-sensor"),
+            }
+        }
+
+        fn custom(&mut self, id: u8, payload: &[u8]) -> Option<Result<Vec<Vec<u8>>, ErrorState>> {
+            match id {
+                // echo
+                0x42 => Some(Ok(vec![payload.to_vec()])),
+                // more than fits in a frame
+                0x43 => Some(Ok(vec![vec![b'a'; 200]; 2])),
+                _ => None,
+            }
+        }
+
+        fn identify(&mut self) -> Result<(), ErrorState> {
+            Ok(())
+        }
+    }
+
+    fn server() -> ImprovServer<Device> {
+        ImprovServer::new(Device {
+            networks: vec![],
+            connects: 0,
+        })
+    }
+
+    fn request<S: CredentialStore>(
+        server: &mut ImprovServer<Device, S>,
+        cmd: RPCCommand,
+    ) -> Vec<ImprovPacket> {
+        let frame = Vec::try_from(ImprovPacket::RPCCommand(cmd)).unwrap();
+        packets(&server.feed(&frame))
+    }
+
+    #[test]
+    fn oversized_response() {
+        let mut s = server();
+        assert_eq!(
+            request(
+                &mut s,
+                RPCCommand::Custom {
+                    id: 0x43,
+                    payload: vec![]
+                }
+            ),
+            [ImprovPacket::ErrorState(ErrorState::UnknownError)],
+        );
+        assert_eq!(s.error(), ErrorState::UnknownError);
+
+        // the next command clears it
+        let out = request(
+            &mut s,
+            RPCCommand::Custom {
+                id: 0x42,
+                payload: vec![],
+            },
+        );
+        assert_eq!(out[0], ImprovPacket::ErrorState(ErrorState::NoError));
+    }
+
+    #[test]
+    fn current_state() {
+        let mut s = server();
+        assert_eq!(
+            request(&mut s, RPCCommand::RequestCurrentState),
+            [ImprovPacket::CurrentState(CurrentState::Ready)],
+        );
+    }
+
+    #[test]
+    fn provision() {
+        let mut s = server();
+
+        let wrong = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("hunter2"),
+        };
+        assert_eq!(
+            request(&mut s, RPCCommand::SendWifiSettings(wrong)),
+            [
+                ImprovPacket::CurrentState(CurrentState::Provisioning),
+                ImprovPacket::CurrentState(CurrentState::Ready),
+                ImprovPacket::ErrorState(ErrorState::UnableToConnect),
+            ],
+        );
+
+        let right = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        assert_eq!(
+            request(&mut s, RPCCommand::SendWifiSettings(right)),
+            [
+                ImprovPacket::ErrorState(ErrorState::NoError),
+                ImprovPacket::CurrentState(CurrentState::Provisioning),
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+                result(0x01, ["http://192.168.1.2"]),
+            ],
+        );
+        assert_eq!(s.state(), CurrentState::Provisioned);
+    }
+
+    #[test]
+    fn resent_credentials() {
+        let mut s = server();
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        let frame = Vec::try_from(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(
+            settings,
+        )))
+        .unwrap();
+        let out = s.feed(&[&frame[..], &frame].concat());
+        let sent = packets(&out);
+        assert_eq!(
+            sent,
+            [
+                ImprovPacket::CurrentState(CurrentState::Provisioning),
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+                result(0x01, ["http://192.168.1.2"]),
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+                result(0x01, ["http://192.168.1.2"]),
+            ]
+        );
+        assert_eq!(s.provisioner().connects, 1);
+
+        // other credentials are a new attempt
+        let other = WifiSettings {
+            ssid: "beehive".into(),
+            psk: String::from("ants in my pants"),
+        };
+        assert_eq!(
+            request(&mut s, RPCCommand::SendWifiSettings(other.clone()))[0],
+            ImprovPacket::CurrentState(CurrentState::Provisioning),
+        );
+        assert_eq!(s.provisioner().connects, 2);
+
+        // the firmware is connecting on its own
+        s.set_state(CurrentState::Provisioning, None);
+        assert_eq!(
+            request(&mut s, RPCCommand::SendWifiSettings(other)),
+            [ImprovPacket::CurrentState(CurrentState::Provisioning)],
+        );
+        assert_eq!(s.provisioner().connects, 2);
+    }
+
+    #[derive(Default)]
+    struct Store(Option<WifiSettings>);
+
+    impl CredentialStore for Store {
+        fn save(&mut self, ssid: &[u8], psk: &str) {
+            self.0 = Some(WifiSettings {
+                ssid: ssid.into(),
+                psk: psk.into(),
+            });
+        }
+
+        fn load(&mut self) -> Option<WifiSettings> {
+            self.0.clone()
+        }
+
+        fn clear(&mut self) {
+            self.0 = None;
+        }
+    }
+
+    #[test]
+    fn credential_store() {
+        let mut s = server().with_store(Store::default());
+        assert!(s.resume().is_empty());
+
+        let wrong = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("hunter2"),
+        };
+        request(&mut s, RPCCommand::SendWifiSettings(wrong));
+        assert_eq!(s.store().0, None);
+        let right = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        request(&mut s, RPCCommand::SendWifiSettings(right.clone()));
+        assert_eq!(s.store().0.as_ref(), Some(&right));
+
+        // after a reboot
+        let store = core::mem::take(s.store());
+        let mut s = server().with_store(store);
+        let out = s.resume();
+        let sent = packets(&out);
+        assert_eq!(
+            sent,
+            [
+                ImprovPacket::CurrentState(CurrentState::Provisioning),
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+            ]
+        );
+        assert_eq!(
+            request(&mut s, RPCCommand::RequestCurrentState)[1],
+            result(0x02, ["http://192.168.1.2"]),
+        );
+
+        s.forget();
+        assert_eq!(s.state(), CurrentState::Ready);
+        assert_eq!(s.store().0, None);
+    }
+
+    #[test]
+    fn authorization() {
+        let mut s = server();
+        s.require_authorization(Duration::from_secs(60));
+        assert_eq!(s.ble_state(), ble::CurrentState::AuthorizationRequired);
+
+        let settings = WifiSettings {
+            ssid: "anthill".into(),
+            psk: String::from("ants in my pants"),
+        };
+        assert_eq!(
+            request(&mut s, RPCCommand::SendWifiSettings(settings.clone())),
+            [ImprovPacket::ErrorState(ErrorState::NotAuthorized)],
+        );
+
+        s.authorize();
+        assert_eq!(s.ble_state(), ble::CurrentState::Authorized);
+        assert!(!s.tick(Duration::from_secs(30)));
+        assert!(s.tick(Duration::from_secs(30)));
+        assert_eq!(s.ble_state(), ble::CurrentState::AuthorizationRequired);
+
+        s.authorize();
+        assert_eq!(
+            request(&mut s, RPCCommand::SendWifiSettings(settings))[1..],
+            [
+                ImprovPacket::CurrentState(CurrentState::Provisioning),
+                ImprovPacket::CurrentState(CurrentState::Provisioned),
+                result(0x01, ["http://192.168.1.2"]),
+            ],
+        );
+        assert_eq!(s.ble_state(), ble::CurrentState::Provisioned);
+    }
+
+    #[test]
+    fn scan_networks() {
+        let mut s = ImprovServer::new(Device {
+            networks: vec![WifiNetwork {
+                ssid: "anthill".into(),
+                rssi: -40,
+                auth: true,
+            }],
+            connects: 0,
+        });
+        assert_eq!(
+            request(&mut s, RPCCommand::RequestScannedWifiNetworks),
+            [result(0x04, ["anthill", "-40", "YES"]), result(0x04, []),],
+        );
+    }
+
+    #[test]
+    fn unknown_command() {
+        let mut s = server();
+        let mut frame = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x02, 0x7F, 0x00, 0x00,
+        ];
+        frame[11] = crate::checksum(&frame[..11]);
+        let out = s.feed(&frame);
+        assert_eq!(
+            packets(&out),
+            [ImprovPacket::ErrorState(ErrorState::UnknownRPCCommand)]
+        );
+
+        // settings whose SSID runs past the end of the payload
+        let mut frame = vec![
+            0x49, 0x4D, 0x50, 0x52, 0x4F, 0x56, 0x01, 0x03, 0x03, 0x01, 0x01, 0x05, 0x00,
+        ];
+        frame[12] = crate::checksum(&frame[..12]);
+        let out = s.feed(&frame);
+        assert_eq!(
+            packets(&out),
+            [ImprovPacket::ErrorState(ErrorState::InvalidRPCPacket)]
+        );
+    }
+
+    #[test]
+    fn custom_command() {
+        let mut s = server();
+        let cmd = RPCCommand::Custom {
+            id: 0x42,
+            payload: b"hi".to_vec(),
+        };
+        assert_eq!(request(&mut s, cmd), [result(0x42, ["hi"])],);
+    }
+
+    #[test]
+    fn identify() {
+        assert_eq!(
+            request(&mut server(), RPCCommand::custom(&Identify).unwrap()),
+            [result(Identify::ID, [])],
+        );
+    }
+}
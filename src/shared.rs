@@ -0,0 +1,164 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! An `ImprovClient` which can be used from several threads at once.
+
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    ClientErr, CurrentState, DeviceInfo, ImprovClient, ImprovPacket, Progress, StateChange,
+    Transport, WifiNetwork, WifiSettings,
+};
+
+// how long the worker reads for between checking for requests, which bounds how long a request
+// waits for the line while it's idle
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
+type Job<T> = Box<dyn FnOnce(&mut ImprovClient<T>) + Send>;
+
+/// A handle to an `ImprovClient` running on its own thread, which can be cloned and shared between
+/// threads. Requests from each handle take turns on the line, and while none is running the
+/// thread keeps reading, so packets the device sends on its own reach `subscribe` and `progress`
+/// receivers as they arrive rather than only while a request is in flight.
+///
+/// The thread stops once every handle has been dropped, and a request running on it then finishes
+/// first. Requests made after it has stopped, e.g. because a request panicked, fail with
+/// `io::ErrorKind::BrokenPipe`.
+pub struct SharedClient<T> {
+    jobs: mpsc::Sender<Job<T>>,
+}
+
+impl<T> Clone for SharedClient<T> {
+    fn clone(&self) -> SharedClient<T> {
+        SharedClient {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+impl<T: Transport + Send + 'static> SharedClient<T> {
+    pub fn new(mut client: ImprovClient<T>) -> SharedClient<T> {
+        let (jobs, rx) = mpsc::channel::<Job<T>>();
+        thread::spawn(move || loop {
+            match rx.try_recv() {
+                Ok(job) => job(&mut client),
+                Err(mpsc::TryRecvError::Disconnected) => break,
+                Err(mpsc::TryRecvError::Empty) => {
+                    // keep the packets flowing; whatever this reads has been passed on to
+                    // subscribers, and errors surface in the next request instead. Transports
+                    // which fail or give up early mustn't turn this into a busy loop.
+                    let deadline = Instant::now() + IDLE_POLL;
+                    if client.recv_until(deadline).is_err() {
+                        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                    }
+                }
+            }
+        });
+        SharedClient { jobs }
+    }
+
+    /// Run `f` on the client, once the requests ahead of it have finished, and return its result.
+    /// Anything the client can do can be done this way; the other methods are shorthand for it.
+    pub fn with<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut ImprovClient<T>) -> R + Send + 'static,
+    ) -> Result<R, ClientErr> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.jobs
+            .send(Box::new(move |client| {
+                let _ = tx.send(f(client));
+            }))
+            .map_err(|_| stopped())?;
+        rx.recv().map_err(|_| stopped())
+    }
+
+    /// Receive a copy of every packet the device sends. See `ImprovClient::subscribe`.
+    pub fn subscribe(&self) -> Result<mpsc::Receiver<ImprovPacket>, ClientErr> {
+        self.with(|c| c.subscribe())
+    }
+
+    /// Receive progress events. See `ImprovClient::progress`.
+    pub fn progress(&self) -> Result<mpsc::Receiver<Progress>, ClientErr> {
+        self.with(|c| c.progress())
+    }
+
+    /// Receive the device's state changes. See `DeviceStateTracker::changes`.
+    pub fn state_changes(&self) -> Result<mpsc::Receiver<StateChange>, ClientErr> {
+        self.with(|c| c.device_state().changes())
+    }
+
+    pub fn current_state(&self) -> Result<CurrentState, ClientErr> {
+        self.with(|c| c.current_state())?
+    }
+
+    pub fn device_info(&self) -> Result<DeviceInfo, ClientErr> {
+        self.with(|c| c.device_info())?
+    }
+
+    pub fn scan_networks(&self) -> Result<Vec<WifiNetwork>, ClientErr> {
+        self.with(|c| c.scan_networks())?
+    }
+
+    pub fn identify(&self) -> Result<(), ClientErr> {
+        self.with(|c| c.identify())?
+    }
+
+    /// Send credentials and wait for the device to connect. See
+    /// `ImprovClient::send_wifi_settings`.
+    pub fn send_wifi_settings(&self, settings: &WifiSettings) -> Result<Option<String>, ClientErr> {
+        let settings = settings.clone();
+        self.with(move |c| c.send_wifi_settings(&settings))?
+    }
+}
+
+fn stopped() -> ClientErr {
+    ClientErr::Io(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "the client's thread has stopped",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MockImprovDevice, RPCCommand};
+
+    #[test]
+    fn requests_from_several_threads() {
+        let client = SharedClient::new(ImprovClient::new(MockImprovDevice::new()));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let client = client.clone();
+                thread::spawn(move || client.device_info().unwrap().device_name)
+            })
+            .collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), "mock-device");
+        }
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+    }
+
+    #[test]
+    fn streams_while_idle() {
+        let client = SharedClient::new(ImprovClient::new(MockImprovDevice::new()));
+        let packets = client.subscribe().unwrap();
+        client
+            .with(|c| c.send(ImprovPacket::RPCCommand(RPCCommand::RequestCurrentState)))
+            .unwrap()
+            .unwrap();
+        // nothing asks for the answer, but the idle reader still passes it on
+        assert_eq!(
+            packets.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ImprovPacket::CurrentState(CurrentState::Ready),
+        );
+    }
+
+    #[test]
+    fn stops_after_a_panic() {
+        let client = SharedClient::new(ImprovClient::new(MockImprovDevice::new()));
+        let _ = client.with(|_| panic!("boom"));
+        assert!(matches!(client.current_state(), Err(ClientErr::Io(_))));
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+// an SSID is up to 32 arbitrary bytes. most are UTF-8, but access points in the wild broadcast
+// Latin-1, GBK and worse, and those networks still have to be listed and joined
+
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// A network name, as the bytes it's broadcast as. It displays as text, with anything that isn't
+/// UTF-8 replaced, and compares equal to the `str` of the same bytes.
+#[derive(Clone, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Ssid(Vec<u8>);
+
+impl Ssid {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Ssid {
+        Ssid(bytes.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// The name as text, if it's UTF-8.
+    pub fn to_str(&self) -> Option<&str> {
+        core::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// The length in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<&str> for Ssid {
+    fn from(s: &str) -> Ssid {
+        Ssid(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Ssid {
+    fn from(s: String) -> Ssid {
+        Ssid(s.into_bytes())
+    }
+}
+
+impl From<&[u8]> for Ssid {
+    fn from(b: &[u8]) -> Ssid {
+        Ssid(b.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Ssid {
+    fn from(b: Vec<u8>) -> Ssid {
+        Ssid(b)
+    }
+}
+
+impl PartialEq<str> for Ssid {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for Ssid {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+// a string when it's UTF-8, so JSON stays readable, and an array of bytes when it isn't. either
+// is accepted back
+#[cfg(feature = "serde")]
+impl serde::Serialize for Ssid {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self.to_str() {
+            Some(name) => s.serialize_str(name),
+            None => s.collect_seq(&self.0),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Ssid {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Ssid, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Ssid;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.write_str("a string or an array of bytes")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Ssid, E> {
+                Ok(Ssid::from(s))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, b: &[u8]) -> Result<Ssid, E> {
+                Ok(Ssid::from(b))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Ssid, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(b) = seq.next_element()? {
+                    bytes.push(b);
+                }
+                Ok(Ssid(bytes))
+            }
+        }
+
+        d.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn not_utf8() {
+        // "café" in Latin-1
+        let ssid = Ssid::new(&b"caf\xe9"[..]);
+        assert_eq!(ssid.to_str(), None);
+        assert_eq!(format!("{}", ssid), "caf\u{FFFD}");
+        assert_eq!(format!("{:?}", ssid), r#"b"caf\xe9""#);
+
+        let ssid = Ssid::from("café");
+        assert_eq!(ssid, "café");
+        assert_eq!(format!("{:?}", ssid), r#""café""#);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let ssid = Ssid::new(&b"caf\xe9"[..]);
+        let json = serde_json::to_string(&ssid).unwrap();
+        assert_eq!(json, "[99,97,102,233]");
+        assert_eq!(serde_json::from_str::<Ssid>(&json).unwrap(), ssid);
+        assert_eq!(
+            serde_json::from_str::<Ssid>(r#""anthill""#).unwrap(),
+            "anthill"
+        );
+    }
+}
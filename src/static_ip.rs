@@ -0,0 +1,163 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! A vendor extension for provisioning devices which can't use DHCP: Wi-Fi settings along with a
+//! static address, gateway and DNS servers, sent as a custom command.
+//!
+//! The payload is the SSID and PSK fields of `SendWifiSettings`, then three more fields: the
+//! address and netmask together (8 bytes), the gateway (4 bytes), and the DNS servers (4 bytes
+//! each, possibly none). The device answers as it would `SendWifiSettings`, with a result carrying
+//! its redirect URL once it has joined the network.
+
+use core::net::Ipv4Addr;
+
+use alloc::vec::Vec;
+
+use crate::fields::{self, FieldReader};
+use crate::{CustomCommand, ImprovErr, Ssid, WifiSettings};
+
+/// The command id firmware implementing the extension answers to.
+pub const SEND_STATIC_WIFI_SETTINGS: u8 = 0x81;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NetworkConfig {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub dns: Vec<Ipv4Addr>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StaticWifiSettings {
+    pub wifi: WifiSettings,
+    pub network: NetworkConfig,
+}
+
+impl CustomCommand for StaticWifiSettings {
+    const ID: u8 = SEND_STATIC_WIFI_SETTINGS;
+
+    fn encode(&self) -> Result<Vec<u8>, ImprovErr> {
+        let n = &self.network;
+        let mut address = [0; 8];
+        address[..4].copy_from_slice(&n.address.octets());
+        address[4..].copy_from_slice(&n.netmask.octets());
+        let dns: Vec<u8> = n.dns.iter().flat_map(|a| a.octets()).collect();
+        let fields: [&[u8]; 5] = [
+            self.wifi.ssid.as_bytes(),
+            self.wifi.psk.as_bytes(),
+            &address,
+            &n.gateway.octets(),
+            &dns,
+        ];
+        fields::encode(fields)
+    }
+
+    fn decode(payload: &[u8]) -> Result<StaticWifiSettings, ImprovErr> {
+        let mut r = FieldReader::new(payload);
+        let ssid = r.field()?;
+        let psk = r.field()?;
+        let address = r.field()?;
+        let gateway = r.field()?;
+        let dns = r.field()?;
+        r.finish()?;
+
+        if address.len() != 8 {
+            return Err(bad_length(8, address.len()));
+        }
+        Ok(StaticWifiSettings {
+            wifi: WifiSettings {
+                ssid: Ssid::from(ssid),
+                psk: core::str::from_utf8(psk)
+                    .map_err(|_| ImprovErr::InvalidUtf8)?
+                    .into(),
+            },
+            network: NetworkConfig {
+                address: addr(&address[..4])?,
+                netmask: addr(&address[4..])?,
+                gateway: addr(gateway)?,
+                dns: dns.chunks(4).map(addr).collect::<Result<_, _>>()?,
+            },
+        })
+    }
+}
+
+fn addr(b: &[u8]) -> Result<Ipv4Addr, ImprovErr> {
+    <[u8; 4]>::try_from(b)
+        .map(Ipv4Addr::from)
+        .map_err(|_| bad_length(4, b.len()))
+}
+
+fn bad_length(expected: usize, found: usize) -> ImprovErr {
+    ImprovErr::BadLength { expected, found }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ImprovPacket, RPCCommand};
+    use alloc::vec;
+
+    #[test]
+    fn round_trip() {
+        let settings = StaticWifiSettings {
+            wifi: WifiSettings {
+                ssid: "anthill".into(),
+                psk: "ants in my pants".into(),
+            },
+            network: NetworkConfig {
+                address: Ipv4Addr::new(192, 168, 1, 50),
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                gateway: Ipv4Addr::new(192, 168, 1, 1),
+                dns: vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(9, 9, 9, 9)],
+            },
+        };
+        let payload = settings.encode().unwrap();
+        assert_eq!(
+            payload[25..],
+            [8, 192, 168, 1, 50, 255, 255, 255, 0, 4, 192, 168, 1, 1, 8, 1, 1, 1, 1, 9, 9, 9, 9]
+        );
+
+        let frame = Vec::try_from(ImprovPacket::RPCCommand(
+            RPCCommand::custom(&settings).unwrap(),
+        ))
+        .unwrap();
+        let ImprovPacket::RPCCommand(cmd) = ImprovPacket::try_from(frame).unwrap() else {
+            panic!("not a command");
+        };
+        assert_eq!(cmd.to_custom(), Some(Ok(settings)));
+
+        let odd_dns = fields::encode([&b"ant"[..], b"", &[0; 8], &[0; 4], &[1, 1, 1]]).unwrap();
+        assert_eq!(
+            StaticWifiSettings::decode(&odd_dns),
+            Err(ImprovErr::BadLength {
+                expected: 4,
+                found: 3
+            })
+        );
+        assert_eq!(
+            StaticWifiSettings::decode(&payload[..25]),
+            Err(ImprovErr::Truncated)
+        );
+    }
+
+    #[test]
+    fn too_many_dns_servers() {
+        let settings = StaticWifiSettings {
+            wifi: WifiSettings {
+                ssid: "anthill".into(),
+                psk: "ants in my pants".into(),
+            },
+            network: NetworkConfig {
+                address: Ipv4Addr::new(192, 168, 1, 50),
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                gateway: Ipv4Addr::new(192, 168, 1, 1),
+                // 256 bytes, one more than a field holds
+                dns: vec![Ipv4Addr::new(1, 1, 1, 1); 64],
+            },
+        };
+        assert_eq!(settings.encode(), Err(ImprovErr::PayloadTooLong));
+        assert_eq!(
+            RPCCommand::custom(&settings),
+            Err(ImprovErr::PayloadTooLong)
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Looking up the credentials of networks this machine already knows, from NetworkManager
+//! connection profiles or wpa_supplicant configuration. Both are usually readable only by root.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Ssid, WifiSettings};
+
+const NETWORKMANAGER_DIR: &str = "/etc/NetworkManager/system-connections";
+const WPA_SUPPLICANT_DIR: &str = "/etc/wpa_supplicant";
+const WPA_SUPPLICANT_CONF: &str = "/etc/wpa_supplicant.conf";
+
+/// Find the saved credentials for `ssid` in the usual NetworkManager and wpa_supplicant locations.
+/// Files that can't be read are skipped, but if nothing is found and some were unreadable, that
+/// error is returned, since it likely means running as root would help.
+pub fn lookup(ssid: &Ssid) -> io::Result<Option<WifiSettings>> {
+    let mut denied = None;
+    for (path, wpa) in candidates() {
+        let file = match fs::read_to_string(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                denied = Some(io::Error::new(
+                    e.kind(),
+                    format!("can't read {}: {}", path.display(), e),
+                ));
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let found = if wpa {
+            parse_wpa_supplicant(&file)
+                .into_iter()
+                .find(|w| w.ssid == *ssid)
+        } else {
+            parse_networkmanager(&file).filter(|w| w.ssid == *ssid)
+        };
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    match denied {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+// every file which might hold credentials, and whether it's wpa_supplicant's
+fn candidates() -> Vec<(PathBuf, bool)> {
+    let mut files: Vec<(PathBuf, bool)> = list(Path::new(NETWORKMANAGER_DIR))
+        .into_iter()
+        .map(|p| (p, false))
+        .collect();
+    files.extend(
+        list(Path::new(WPA_SUPPLICANT_DIR))
+            .into_iter()
+            .filter(|p| p.extension().is_some_and(|e| e == "conf"))
+            .map(|p| (p, true)),
+    );
+    files.push((PathBuf::from(WPA_SUPPLICANT_CONF), true));
+    files
+}
+
+fn list(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Read a NetworkManager keyfile connection profile (a `.nmconnection` file). Returns `None` if
+/// it isn't a Wi-Fi connection.
+pub fn parse_networkmanager(file: &str) -> Option<WifiSettings> {
+    let mut section = "";
+    let mut ssid = None;
+    let mut psk = String::new();
+    for line in file.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match (section, key.trim()) {
+            ("wifi", "ssid") => ssid = Some(keyfile_ssid(value.trim())),
+            ("wifi-security", "psk") => psk = value.trim().to_string(),
+            _ => (),
+        }
+    }
+    Some(WifiSettings { ssid: ssid?, psk })
+}
+
+// older versions of NetworkManager write the SSID as a list of byte values, like `97;110;116;`
+fn keyfile_ssid(value: &str) -> Ssid {
+    let bytes: Option<Vec<u8>> = value
+        .strip_suffix(';')
+        .map(|v| v.split(';').map(|b| b.parse().ok()).collect())
+        .unwrap_or(None);
+    match bytes {
+        Some(bytes) => Ssid::new(bytes),
+        None => Ssid::from(value),
+    }
+}
+
+/// Read the `network={...}` blocks of a wpa_supplicant configuration file.
+pub fn parse_wpa_supplicant(file: &str) -> Vec<WifiSettings> {
+    let mut networks = Vec::new();
+    let mut block: Option<(Option<Ssid>, String)> = None;
+    for line in file.lines().map(str::trim) {
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.replace(' ', "") == "network={" {
+            block = Some((None, String::new()));
+            continue;
+        }
+        let Some((ssid, psk)) = block.as_mut() else {
+            continue;
+        };
+        if line == "}" {
+            if let Some((Some(ssid), psk)) = block.take() {
+                networks.push(WifiSettings { ssid, psk });
+            }
+            continue;
+        }
+        match line.split_once('=') {
+            Some(("ssid", value)) => *ssid = Some(wpa_ssid(value)),
+            // unquoted, it's the 64 character hex key itself, which devices accept as-is
+            Some(("psk", value)) => *psk = unquote(value).unwrap_or(value).to_string(),
+            Some(("key_mgmt", "NONE")) => psk.clear(),
+            _ => (),
+        }
+    }
+    networks
+}
+
+fn unquote(value: &str) -> Option<&str> {
+    value.strip_prefix('"').and_then(|v| v.strip_suffix('"'))
+}
+
+// either quoted text or unquoted hex
+fn wpa_ssid(value: &str) -> Ssid {
+    if let Some(s) = unquote(value) {
+        return Ssid::from(s);
+    }
+    let bytes: Option<Vec<u8>> = (0..value.len())
+        .step_by(2)
+        .map(|i| {
+            value
+                .get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect();
+    match bytes {
+        Some(bytes) => Ssid::new(bytes),
+        None => Ssid::from(value),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn networkmanager() {
+        let file =
+            "[connection]\nid=Home\ntype=wifi\n\n[wifi]\nmode=infrastructure\nssid=anthill\n\n\
+                    [wifi-security]\nkey-mgmt=wpa-psk\npsk=ants in my pants\n";
+        assert_eq!(
+            parse_networkmanager(file),
+            Some(WifiSettings {
+                ssid: "anthill".into(),
+                psk: String::from("ants in my pants"),
+            })
+        );
+        assert_eq!(
+            parse_networkmanager("[wifi]\nssid=97;110;116;\n"),
+            Some(WifiSettings::open("ant"))
+        );
+        assert_eq!(parse_networkmanager("[connection]\ntype=ethernet\n"), None);
+    }
+
+    #[test]
+    fn wpa_supplicant() {
+        let file = "ctrl_interface=/run/wpa_supplicant\n\nnetwork={\n\tssid=\"anthill\"\n\
+                    \tpsk=\"ants in my pants\"\n}\n# a comment\nnetwork={\n\tssid=636166c3a9\n\
+                    \tkey_mgmt=NONE\n}\nnetwork={\n\tssid=636166e9\n\tpsk=\"croissant\"\n}\n";
+        assert_eq!(
+            parse_wpa_supplicant(file),
+            [
+                WifiSettings {
+                    ssid: "anthill".into(),
+                    psk: String::from("ants in my pants"),
+                },
+                WifiSettings::open("café"),
+                WifiSettings {
+                    ssid: Ssid::new(&b"caf\xe9"[..]),
+                    psk: String::from("croissant"),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,27 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Fixtures shared by the tests of the device side, which check what was sent by decoding it.
+
+use alloc::vec::Vec;
+
+use crate::{decode_frame, FrameSplitter, ImprovPacket, RPCResult, Split};
+
+/// Every packet in `bytes`, which must hold nothing but whole frames.
+pub(crate) fn packets(bytes: &[u8]) -> Vec<ImprovPacket> {
+    FrameSplitter::new(bytes)
+        .map(|s| match s {
+            Split::Frame { bytes, .. } => decode_frame(bytes).unwrap(),
+            s => panic!("unexpected {:?}", s),
+        })
+        .collect()
+}
+
+/// An answer to `command` made of text strings.
+pub(crate) fn rpc_result<'a>(command: u8, strings: impl IntoIterator<Item = &'a str>) -> RPCResult {
+    RPCResult::from_strings(command, strings)
+}
+
+/// The same, as a packet.
+pub(crate) fn result<'a>(command: u8, strings: impl IntoIterator<Item = &'a str>) -> ImprovPacket {
+    ImprovPacket::RPCResult(rpc_result(command, strings))
+}
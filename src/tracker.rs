@@ -0,0 +1,118 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! The device's state, as last reported, for applications following a device's packets.
+
+use std::sync::mpsc;
+
+use crate::client::notify;
+use crate::{CurrentState, ErrorState, ImprovPacket};
+
+/// A change in what the device last reported. See `DeviceStateTracker::changes`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StateChange {
+    State(CurrentState),
+    /// The device reported an error, or `ErrorState::NoError` once an error has cleared.
+    Error(ErrorState),
+}
+
+/// Mirrors the device's state and error from the packets it sends, so they needn't be pieced
+/// together from the packet stream. Every client keeps one up to date (see
+/// `ImprovClient::device_state`); for packets read some other way, e.g. with `PacketReader`, feed
+/// them to `ingest`.
+#[derive(Debug, Default)]
+pub struct DeviceStateTracker {
+    state: Option<CurrentState>,
+    error: Option<ErrorState>,
+    watchers: Vec<mpsc::Sender<StateChange>>,
+}
+
+impl DeviceStateTracker {
+    pub fn new() -> DeviceStateTracker {
+        DeviceStateTracker::default()
+    }
+
+    /// Note what `p` says about the device, returning (and sending to `changes` receivers) what
+    /// changed, if anything. Packets other than states and errors are ignored.
+    pub fn ingest(&mut self, p: &ImprovPacket) -> Option<StateChange> {
+        let change = match *p {
+            ImprovPacket::CurrentState(s) if self.state != Some(s) => {
+                self.state = Some(s);
+                StateChange::State(s)
+            }
+            ImprovPacket::ErrorState(e) if self.error.unwrap_or(ErrorState::NoError) != e => {
+                self.error = (e != ErrorState::NoError).then_some(e);
+                StateChange::Error(e)
+            }
+            _ => return None,
+        };
+        notify(&mut self.watchers, &change);
+        Some(change)
+    }
+
+    /// The state the device last reported, if it's reported one.
+    pub fn last_state(&self) -> Option<CurrentState> {
+        self.state
+    }
+
+    /// The error the device last reported, unless it has since reported `NoError`.
+    pub fn last_error(&self) -> Option<ErrorState> {
+        self.error
+    }
+
+    pub fn is_provisioned(&self) -> bool {
+        self.state == Some(CurrentState::Provisioned)
+    }
+
+    /// Receive each change as it's ingested.
+    pub fn changes(&mut self) -> mpsc::Receiver<StateChange> {
+        let (tx, rx) = mpsc::channel();
+        self.watchers.push(tx);
+        rx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn follows_the_device() {
+        let mut tracker = DeviceStateTracker::new();
+        let changes = tracker.changes();
+        assert_eq!(tracker.last_state(), None);
+
+        for p in [
+            ImprovPacket::CurrentState(CurrentState::Ready),
+            ImprovPacket::CurrentState(CurrentState::Ready),
+            ImprovPacket::ErrorState(ErrorState::NoError),
+            ImprovPacket::ErrorState(ErrorState::UnableToConnect),
+        ] {
+            tracker.ingest(&p);
+        }
+        assert_eq!(tracker.last_error(), Some(ErrorState::UnableToConnect));
+        assert!(!tracker.is_provisioned());
+
+        for p in [
+            ImprovPacket::ErrorState(ErrorState::NoError),
+            ImprovPacket::CurrentState(CurrentState::Provisioning),
+            ImprovPacket::CurrentState(CurrentState::Provisioned),
+        ] {
+            tracker.ingest(&p);
+        }
+        assert_eq!(tracker.last_error(), None);
+        assert_eq!(tracker.last_state(), Some(CurrentState::Provisioned));
+        assert!(tracker.is_provisioned());
+
+        assert_eq!(
+            changes.try_iter().collect::<Vec<_>>(),
+            [
+                StateChange::State(CurrentState::Ready),
+                StateChange::Error(ErrorState::UnableToConnect),
+                StateChange::Error(ErrorState::NoError),
+                StateChange::State(CurrentState::Provisioning),
+                StateChange::State(CurrentState::Provisioned),
+            ],
+        );
+    }
+}
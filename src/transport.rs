@@ -0,0 +1,704 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::{self, Backoff, ClientErr};
+use crate::demux::next_frame;
+use crate::{
+    decode_frame, discard, scan, DeviceInfo, ImprovPacket, Scan, REQUEST_DEVICE_INFORMATION,
+};
+
+// how often a non-blocking stream is read while it has nothing
+const WOULD_BLOCK_POLL: Duration = Duration::from_millis(5);
+
+// how long a re-opened link has to say which device is on it
+const MATCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Carries whole frames between the client and a device.
+pub trait Transport {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Wait up to `timeout` for the next frame, returning `None` if none arrived in time.
+    fn recv_frame(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        (**self).send_frame(frame)
+    }
+
+    fn recv_frame(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        (**self).recv_frame(timeout)
+    }
+}
+
+/// What the reference SDK sends after each frame. ESPHome and the Improv C++ library only act on a
+/// frame once the byte after its checksum arrives, so without it the device never answers.
+pub const FRAME_TERMINATOR: u8 = b'\n';
+
+/// A `Transport` over any byte stream, such as a `serialport::SerialPort`, a `TcpStream`, or an
+/// in-memory pipe. Bytes that aren't part of a frame are discarded.
+///
+/// Timeouts are checked between reads, so the stream should itself time out (as serial ports do)
+/// rather than block forever. The stream is flushed after each frame.
+pub struct IoTransport<T> {
+    io: T,
+    buf: Vec<u8>,
+    terminator: Option<u8>,
+    chunk_size: Option<usize>,
+    chunk_delay: Duration,
+    frame_gap: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl<T> IoTransport<T> {
+    pub fn new(io: T) -> IoTransport<T> {
+        IoTransport {
+            io,
+            buf: Vec::new(),
+            terminator: None,
+            chunk_size: None,
+            chunk_delay: Duration::ZERO,
+            frame_gap: Duration::ZERO,
+            last_sent: None,
+        }
+    }
+
+    /// Send `terminator` after each frame. Frames are sent bare unless this is set, although
+    /// `ImprovClient::open` and `connect` set `FRAME_TERMINATOR`.
+    pub fn set_terminator(&mut self, terminator: Option<u8>) {
+        self.terminator = terminator;
+    }
+
+    /// Write frames `size` bytes at a time, flushing and then waiting `delay` between writes, for
+    /// USB-UART bridges which drop bytes from a whole frame sent in one burst. A size of `None` or
+    /// 0 writes each frame at once, which is the default. A size of 1 paces each byte.
+    pub fn set_chunking(&mut self, size: Option<usize>, delay: Duration) {
+        self.chunk_size = size.filter(|&n| n > 0);
+        self.chunk_delay = delay;
+    }
+
+    /// Leave at least `gap` between the end of one frame and the start of the next, for bridges
+    /// which lose the start of a frame sent right behind another. None by default.
+    pub fn set_frame_gap(&mut self, gap: Duration) {
+        self.frame_gap = gap;
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+impl<T: Read + Write> Transport for IoTransport<T> {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some(last) = self.last_sent {
+            thread::sleep(self.frame_gap.saturating_sub(last.elapsed()));
+        }
+        let mut out = frame.to_vec();
+        out.extend(self.terminator);
+        let size = self.chunk_size.unwrap_or(out.len().max(1));
+        for (i, chunk) in out.chunks(size).enumerate() {
+            if i > 0 {
+                self.io.flush()?;
+                thread::sleep(self.chunk_delay);
+            }
+            self.io.write_all(chunk)?;
+        }
+        self.io.flush()?;
+        self.last_sent = Some(Instant::now());
+        Ok(())
+    }
+
+    fn recv_frame(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        let mut chunk = [0u8; 256];
+        loop {
+            match scan(&self.buf) {
+                Scan::Frame { start, end } => {
+                    discard(&self.buf, start);
+                    return Ok(Some(self.buf.drain(..end).skip(start).collect()));
+                }
+                Scan::Partial { start } => {
+                    discard(&self.buf, start);
+                    self.buf.drain(..start);
+                }
+                Scan::Nothing => {
+                    discard(&self.buf, self.buf.len());
+                    self.buf.clear();
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+
+            match self.io.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                // a non-blocking stream has nothing yet, so wait a little rather than spin
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(
+                    WOULD_BLOCK_POLL.min(deadline.saturating_duration_since(Instant::now())),
+                ),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Reads the packets out of a byte stream, such as a serial port or a console capture, skipping
+/// whatever is between them. Iteration ends when the stream does; reads that time out are retried.
+/// Frames which can't be decoded come back as `ClientErr::Improv`, and failed reads as
+/// `ClientErr::Io`. Neither ends iteration. A non-blocking reader's `WouldBlock` comes back too,
+/// rather than being retried, so wait for the reader to be ready before asking again.
+pub struct PacketReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(reader: R) -> PacketReader<R> {
+        PacketReader {
+            reader,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for PacketReader<R> {
+    type Item = Result<ImprovPacket, ClientErr>;
+
+    fn next(&mut self) -> Option<Result<ImprovPacket, ClientErr>> {
+        let mut chunk = [0u8; 256];
+        loop {
+            // a bogus header can't hide a frame behind it
+            if let Some((start, end)) = next_frame(&self.buf) {
+                discard(&self.buf, start);
+                let p = decode_frame(&self.buf[start..end]);
+                self.buf.drain(..end);
+                return Some(p.map_err(ClientErr::Improv));
+            }
+            match scan(&self.buf) {
+                Scan::Partial { start } => {
+                    discard(&self.buf, start);
+                    self.buf.drain(..start);
+                }
+                _ => {
+                    discard(&self.buf, self.buf.len());
+                    self.buf.clear();
+                }
+            }
+
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return None,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+/// Which way a frame was travelling.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    /// `>>` for sent frames, `<<` for received ones.
+    pub fn marker(self) -> &'static str {
+        match self {
+            Direction::Sent => ">>",
+            Direction::Received => "<<",
+        }
+    }
+}
+
+/// Wraps a `Transport`, passing every frame sent or received to a callback, e.g. for logging.
+pub struct Traced<T, F> {
+    inner: T,
+    trace: F,
+}
+
+impl<T, F> Traced<T, F>
+where
+    T: Transport,
+    F: FnMut(Direction, &[u8]),
+{
+    pub fn new(inner: T, trace: F) -> Traced<T, F> {
+        Traced { inner, trace }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, F> Transport for Traced<T, F>
+where
+    T: Transport,
+    F: FnMut(Direction, &[u8]),
+{
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        (self.trace)(Direction::Sent, frame);
+        self.inner.send_frame(frame)
+    }
+
+    fn recv_frame(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let frame = self.inner.recv_frame(timeout)?;
+        if let Some(frame) = &frame {
+            (self.trace)(Direction::Received, frame);
+        }
+        Ok(frame)
+    }
+}
+
+/// Wraps a `Transport`, opening a new one with `open` when it fails, e.g. when an ESP board
+/// re-enumerates its USB serial port as it reboots after provisioning. Any error sending or
+/// receiving, including the end of the stream, drops the link; `open` is then tried, with
+/// `backoff` before each try, until it succeeds or the attempts run out, and the last error is
+/// returned. A frame being sent is sent again on the new link, and a receive carries on waiting
+/// on it for the rest of its timeout.
+///
+/// With `match_device`, the device's answers to `RequestDeviceInformation` are remembered as they
+/// pass through, and a re-opened link is only taken if the device on it gives the same answer, so
+/// a different board turning up on the port isn't mistaken for the one that left.
+pub struct Reconnecting<T, F> {
+    inner: Option<T>,
+    open: F,
+    backoff: Backoff,
+    max_attempts: u32,
+    match_device: bool,
+    device: Option<DeviceInfo>,
+    // frames read while checking a new link, still to be received
+    pending: VecDeque<Vec<u8>>,
+    reconnects: u32,
+}
+
+impl<T, F> Reconnecting<T, F>
+where
+    T: Transport,
+    F: FnMut() -> io::Result<T>,
+{
+    /// Reconnect up to 10 times, waiting from a quarter of a second up to four seconds between
+    /// tries, which covers an ESP32 rebooting.
+    pub fn new(inner: T, open: F) -> Reconnecting<T, F> {
+        Reconnecting {
+            inner: Some(inner),
+            open,
+            backoff: Backoff::Exponential {
+                initial: Duration::from_millis(250),
+                max: Duration::from_secs(4),
+            },
+            max_attempts: 10,
+            match_device: false,
+            device: None,
+            pending: VecDeque::new(),
+            reconnects: 0,
+        }
+    }
+
+    /// How long to wait before each try at re-opening the link, and how many tries to make.
+    pub fn set_backoff(&mut self, backoff: Backoff, max_attempts: u32) {
+        self.backoff = backoff;
+        self.max_attempts = max_attempts;
+    }
+
+    /// Only take a re-opened link if the same device is on it. See `Reconnecting`.
+    pub fn match_device(&mut self, enabled: bool) {
+        self.match_device = enabled;
+    }
+
+    /// The device's information, as last seen with `match_device`.
+    pub fn device(&self) -> Option<&DeviceInfo> {
+        self.device.as_ref()
+    }
+
+    /// How many times the link has been re-opened.
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.inner
+    }
+
+    fn reconnect(&mut self) -> io::Result<&mut T> {
+        self.inner = None;
+        let mut last = io::Error::from(io::ErrorKind::NotConnected);
+        for attempt in 1..=self.max_attempts {
+            thread::sleep(self.backoff.delay(attempt));
+            match (self.open)().and_then(|mut t| Ok((self.same_device(&mut t)?, t))) {
+                Ok((true, t)) => {
+                    event!(info, attempt, "reconnected");
+                    self.reconnects += 1;
+                    return Ok(self.inner.insert(t));
+                }
+                Ok((false, _)) => {
+                    self.pending.clear();
+                    last = io::Error::other("a different device is on the re-opened port");
+                }
+                Err(e) => last = e,
+            }
+            event!(debug, attempt, error = %last, "reconnect failed");
+        }
+        Err(last)
+    }
+
+    // whether the device on `t` is the one that was there before, keeping whatever it sends
+    fn same_device(&mut self, t: &mut T) -> io::Result<bool> {
+        let Some(expected) = self.device.clone().filter(|_| self.match_device) else {
+            return Ok(true);
+        };
+        t.send_frame(&REQUEST_DEVICE_INFORMATION)?;
+        let deadline = Instant::now() + MATCH_TIMEOUT;
+        while let Some(frame) = t.recv_frame(deadline.saturating_duration_since(Instant::now()))? {
+            let info = device_info(&frame);
+            self.pending.push_back(frame);
+            if let Some(info) = info {
+                return Ok(info == expected);
+            }
+        }
+        Ok(false)
+    }
+
+    fn remember(&mut self, frame: &[u8]) {
+        if self.match_device {
+            if let Some(info) = device_info(frame) {
+                self.device = Some(info);
+            }
+        }
+    }
+}
+
+fn device_info(frame: &[u8]) -> Option<DeviceInfo> {
+    client::device_info(decode_frame(frame).ok()?).ok()?
+}
+
+impl<T, F> Transport for Reconnecting<T, F>
+where
+    T: Transport,
+    F: FnMut() -> io::Result<T>,
+{
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some(t) = &mut self.inner {
+            match t.send_frame(frame) {
+                Ok(()) => return Ok(()),
+                Err(_e) => {
+                    event!(info, error = %_e, "link dropped while sending");
+                }
+            }
+        }
+        self.reconnect()?.send_frame(frame)
+    }
+
+    fn recv_frame(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                self.remember(&frame);
+                return Ok(Some(frame));
+            }
+            let t = match &mut self.inner {
+                Some(t) => t,
+                None => self.reconnect()?,
+            };
+            match t.recv_frame(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Some(frame)) => {
+                    self.remember(&frame);
+                    return Ok(Some(frame));
+                }
+                Ok(None) => return Ok(None),
+                Err(_e) => {
+                    event!(info, error = %_e, "link dropped while receiving");
+                    self.reconnect()?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CurrentState, ErrorState, ImprovErr, ImprovPacket};
+    use std::io::Cursor;
+
+    #[test]
+    fn traced() {
+        struct Loopback(Cursor<Vec<u8>>);
+
+        impl Read for Loopback {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+
+        impl Write for Loopback {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.get_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let frame = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        let mut log = Vec::new();
+        let mut t = Traced::new(
+            IoTransport::new(Loopback(Cursor::new(Vec::new()))),
+            |d: Direction, f: &[u8]| log.push((d, f.to_vec())),
+        );
+        t.send_frame(&frame).unwrap();
+        assert_eq!(
+            t.recv_frame(Duration::from_secs(1)).unwrap(),
+            Some(frame.clone())
+        );
+        drop(t);
+        assert_eq!(
+            log,
+            [
+                (Direction::Sent, frame.clone()),
+                (Direction::Received, frame)
+            ]
+        );
+    }
+
+    #[test]
+    fn terminator() {
+        let mut t = IoTransport::new(Cursor::new(Vec::new()));
+        t.set_terminator(Some(FRAME_TERMINATOR));
+        t.send_frame(&crate::REQUEST_CURRENT_STATE).unwrap();
+        assert_eq!(
+            t.into_inner().into_inner(),
+            [&crate::REQUEST_CURRENT_STATE[..], b"\n"].concat()
+        );
+    }
+
+    #[test]
+    fn chunking() {
+        // records the size of each write, and a 0 for each flush
+        struct Writes(Vec<usize>);
+
+        impl Read for Writes {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+        }
+
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.push(0);
+                Ok(())
+            }
+        }
+
+        let mut t = IoTransport::new(Writes(Vec::new()));
+        t.set_terminator(Some(FRAME_TERMINATOR));
+        t.send_frame(&crate::REQUEST_CURRENT_STATE).unwrap();
+        assert_eq!(t.get_mut().0, [13, 0]);
+
+        t.get_mut().0.clear();
+        t.set_chunking(Some(5), Duration::from_millis(1));
+        t.send_frame(&crate::REQUEST_CURRENT_STATE).unwrap();
+        assert_eq!(t.get_mut().0, [5, 0, 5, 0, 3, 0]);
+
+        t.get_mut().0.clear();
+        t.set_chunking(Some(0), Duration::ZERO);
+        t.send_frame(&crate::REQUEST_CURRENT_STATE).unwrap();
+        assert_eq!(t.get_mut().0, [13, 0]);
+
+        let gap = Duration::from_millis(20);
+        t.set_frame_gap(gap);
+        let start = Instant::now();
+        t.send_frame(&crate::REQUEST_CURRENT_STATE).unwrap();
+        t.send_frame(&crate::REQUEST_CURRENT_STATE).unwrap();
+        assert!(start.elapsed() >= gap);
+    }
+
+    #[test]
+    fn would_block_waits() {
+        // a non-blocking stream which never has anything, counting the reads
+        struct Idle(usize);
+
+        impl Read for Idle {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                self.0 += 1;
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+
+        impl Write for Idle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut t = IoTransport::new(Idle(0));
+        assert_eq!(t.recv_frame(Duration::from_millis(100)).unwrap(), None);
+        assert!(t.into_inner().0 <= 25);
+    }
+
+    #[test]
+    fn packet_reader() {
+        let mut v2 = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        v2[6] = 2;
+        v2[10] = crate::checksum(&v2[..10]);
+        let mut rx = b"[I][wifi]: starting\r\n".to_vec();
+        rx.extend(Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap());
+        rx.extend(v2);
+        rx.extend(b"IMPROV\x01\x02");
+        rx.extend(Vec::try_from(ImprovPacket::ErrorState(ErrorState::UnableToConnect)).unwrap());
+        rx.extend(b"IMPR");
+
+        let mut r = PacketReader::new(Cursor::new(rx));
+        assert_eq!(
+            r.next().unwrap().unwrap(),
+            ImprovPacket::CurrentState(CurrentState::Ready)
+        );
+        assert!(matches!(
+            r.next(),
+            Some(Err(ClientErr::Improv(ImprovErr::UnsupportedVersion(2))))
+        ));
+        assert_eq!(
+            r.next().unwrap().unwrap(),
+            ImprovPacket::ErrorState(ErrorState::UnableToConnect)
+        );
+        assert!(r.next().is_none());
+    }
+
+    #[test]
+    fn packet_reader_would_block() {
+        // a non-blocking port with half a frame waiting
+        struct NonBlocking(Cursor<Vec<u8>>);
+
+        impl Read for NonBlocking {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.read(buf)? {
+                    0 => Err(io::ErrorKind::WouldBlock.into()),
+                    n => Ok(n),
+                }
+            }
+        }
+
+        let frame = Vec::try_from(ImprovPacket::CurrentState(CurrentState::Ready)).unwrap();
+        let (first, rest) = frame.split_at(5);
+        let mut r = PacketReader::new(NonBlocking(Cursor::new(first.to_vec())));
+        match r.next() {
+            Some(Err(ClientErr::Io(e))) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+            p => panic!("unexpected {:?}", p),
+        }
+        // what was read before is kept for when the rest arrives
+        *r.get_mut() = NonBlocking(Cursor::new(rest.to_vec()));
+        assert_eq!(
+            r.next().unwrap().unwrap(),
+            ImprovPacket::CurrentState(CurrentState::Ready)
+        );
+    }
+
+    // a device whose link drops after `frames` frames have been received
+    struct Flaky {
+        device: crate::MockImprovDevice,
+        frames: usize,
+    }
+
+    impl Transport for Flaky {
+        fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+            match self.frames {
+                0 => Err(io::ErrorKind::BrokenPipe.into()),
+                _ => self.device.send_frame(frame),
+            }
+        }
+
+        fn recv_frame(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+            match self.frames {
+                0 => Err(io::ErrorKind::UnexpectedEof.into()),
+                _ => {
+                    self.frames -= 1;
+                    self.device.recv_frame(timeout)
+                }
+            }
+        }
+    }
+
+    fn reconnecting(
+        frames: usize,
+        mut reopen: impl FnMut() -> crate::MockImprovDevice,
+    ) -> crate::ImprovClient<Reconnecting<Flaky, impl FnMut() -> io::Result<Flaky>>> {
+        let flaky = Flaky {
+            device: crate::MockImprovDevice::new(),
+            frames,
+        };
+        let mut t = Reconnecting::new(flaky, move || {
+            Ok(Flaky {
+                device: reopen(),
+                frames: usize::MAX,
+            })
+        });
+        t.set_backoff(Backoff::Fixed(Duration::ZERO), 2);
+        t.match_device(true);
+        crate::ImprovClient::new(t)
+    }
+
+    #[test]
+    fn reconnects() {
+        let mut client = reconnecting(1, crate::MockImprovDevice::new);
+        assert_eq!(client.device_info().unwrap().device_name, "mock-device");
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        assert_eq!(client.current_state().unwrap(), CurrentState::Ready);
+        let t = client.into_inner();
+        assert_eq!(t.reconnects(), 1);
+        assert_eq!(t.device().unwrap().device_name, "mock-device");
+    }
+
+    #[test]
+    fn refuses_another_device() {
+        let mut client = reconnecting(1, || {
+            crate::MockImprovDevice::new().with_device_info(crate::DeviceInfo {
+                firmware_name: "other".into(),
+                firmware_version: "1.0".into(),
+                hardware: "esp32".into(),
+                device_name: "someone-else".into(),
+            })
+        });
+        client.device_info().unwrap();
+        assert!(matches!(client.current_state(), Err(ClientErr::Io(_))));
+        assert_eq!(client.into_inner().reconnects(), 0);
+    }
+}
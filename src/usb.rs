@@ -0,0 +1,159 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Running `ImprovServer` over USB CDC-ACM, e.g. `usbd-serial` on top of `usb-device`, for boards
+//! whose console is native USB rather than a UART bridge.
+//!
+//! USB stacks are polled rather than blocked on: once `UsbDevice::poll` has run, reads return
+//! whatever arrived and writes take as much as the endpoint has room for, so a response may go out
+//! over several polls. `CdcServer` keeps what hasn't been sent yet until there's room for it.
+
+use alloc::vec::Vec;
+
+use crate::{CredentialStore, ImprovServer, WifiProvisioner};
+
+/// A link which never blocks, such as `usbd_serial::SerialPort`. Both methods return 0 rather
+/// than waiting, i.e. where `usbd-serial` reports `UsbError::WouldBlock`.
+pub trait PollIo {
+    type Error;
+
+    /// Read what has arrived, if anything.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    /// Write as much of `buf` as there's room for.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error>;
+}
+
+/// Runs an `ImprovServer` over a `PollIo`, a poll at a time.
+pub struct CdcServer<P, S = ()> {
+    server: ImprovServer<P, S>,
+    // the part of the server's responses the link hasn't taken yet
+    tx: Vec<u8>,
+}
+
+impl<P: WifiProvisioner, S: CredentialStore> CdcServer<P, S> {
+    pub fn new(server: ImprovServer<P, S>) -> CdcServer<P, S> {
+        CdcServer {
+            server,
+            tx: Vec::new(),
+        }
+    }
+
+    pub fn server(&mut self) -> &mut ImprovServer<P, S> {
+        &mut self.server
+    }
+
+    pub fn into_inner(self) -> ImprovServer<P, S> {
+        self.server
+    }
+
+    /// Queue bytes to send, such as those `ImprovServer::set_state` or `resume` return.
+    pub fn queue(&mut self, bytes: &[u8]) {
+        self.tx.extend_from_slice(bytes);
+    }
+
+    /// Whether there are bytes still waiting for room on the link.
+    pub fn is_sending(&self) -> bool {
+        !self.tx.is_empty()
+    }
+
+    /// Call after each `UsbDevice::poll` (or whenever the link may have moved): hand everything
+    /// that arrived to the server, and send as much of what's queued as the link will take.
+    pub fn poll<T: PollIo>(&mut self, io: &mut T) -> Result<(), T::Error> {
+        let mut chunk = [0u8; 64];
+        loop {
+            let n = io.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            let out = self.server.feed(&chunk[..n]);
+            self.tx.extend_from_slice(&out);
+        }
+        while !self.tx.is_empty() {
+            let n = io.write(&self.tx)?;
+            if n == 0 {
+                break;
+            }
+            self.tx.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{packets, result};
+    use crate::{CurrentState, DeviceInfo, ErrorState, ImprovPacket, RPCCommand, WifiNetwork};
+    use alloc::string::String;
+
+    struct Device;
+
+    impl WifiProvisioner for Device {
+        fn connect(&mut self, _: &[u8], _: &str) -> Result<Option<String>, ErrorState> {
+            Ok(None)
+        }
+
+        fn scan(&mut self) -> Vec<WifiNetwork> {
+            Vec::new()
+        }
+
+        fn device_info(&mut self) -> DeviceInfo {
+            DeviceInfo {
+                firmware_name: "improv-rs".into(),
+                firmware_version: "0.1.0".into(),
+                hardware: "usb".into(),
+                device_name: "cdc".into(),
+            }
+        }
+    }
+
+    // an endpoint which takes at most `room` bytes a poll
+    struct Endpoint {
+        rx: Vec<u8>,
+        tx: Vec<u8>,
+        room: usize,
+    }
+
+    impl PollIo for Endpoint {
+        type Error = ();
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+            let n = buf.len().min(self.rx.len());
+            buf[..n].copy_from_slice(&self.rx[..n]);
+            self.rx.drain(..n);
+            Ok(n)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> Result<usize, ()> {
+            let n = buf.len().min(self.room);
+            self.tx.extend_from_slice(&buf[..n]);
+            self.room -= n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn responses_span_polls() {
+        let mut endpoint = Endpoint {
+            rx: Vec::try_from(ImprovPacket::RPCCommand(
+                RPCCommand::RequestDeviceInformation,
+            ))
+            .unwrap(),
+            tx: Vec::new(),
+            room: 16,
+        };
+        let mut server = CdcServer::new(ImprovServer::new(Device));
+        server.poll(&mut endpoint).unwrap();
+        assert!(server.is_sending());
+        while server.is_sending() {
+            endpoint.room = 16;
+            server.poll(&mut endpoint).unwrap();
+        }
+
+        assert_eq!(
+            packets(&endpoint.tx),
+            [result(0x03, ["improv-rs", "0.1.0", "usb", "cdc"])]
+        );
+        assert_eq!(server.server().state(), CurrentState::Ready);
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! Checking that a device actually came online after being provisioned. A device reporting
+//! `Provisioned` only means it joined the network; it may still fail to get an address, or be on
+//! a network the host can't reach.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how long to wait between attempts while the device is still coming up
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the device was found on the network.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Verified {
+    /// Its redirect URL answered with this HTTP status.
+    Http(u16),
+    /// Its device name resolved under `.local` to this address.
+    Resolved(IpAddr),
+}
+
+/// Fetch `url` and return the HTTP status. Only plain `http://` URLs are supported.
+pub fn http_get(url: &str, timeout: Duration) -> io::Result<u16> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid(format!("only http:// URLs can be checked, not {}", url)))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let addr = match authority.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_owned(),
+        _ => format!("{}:80", authority),
+    };
+
+    let mut last = unresolved(authority);
+    for a in addr.to_socket_addrs()? {
+        let mut stream = match TcpStream::connect_timeout(&a, timeout) {
+            Ok(stream) => stream,
+            Err(e) => {
+                last = e;
+                continue;
+            }
+        };
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        write!(
+            stream,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, authority
+        )?;
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        return status
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("not an HTTP response: {:?}", status.trim_end()),
+                )
+            });
+    }
+    Err(last)
+}
+
+/// Resolve a device's name as an mDNS `.local` host. This goes through the system resolver, so
+/// it needs mDNS support there (Bonjour, or nss-mdns on Linux).
+pub fn resolve_local(device_name: &str) -> io::Result<IpAddr> {
+    let host = format!("{}.local", hostname(device_name));
+    (host.as_str(), 0)
+        .to_socket_addrs()?
+        .next()
+        .map(|a| a.ip())
+        .ok_or_else(|| unresolved(&host))
+}
+
+// devices usually derive their hostname from their name this way
+fn hostname(device_name: &str) -> String {
+    device_name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Keep trying to reach the device until `timeout` elapses: by fetching `redirect_url` if there
+/// is one, and otherwise by resolving `device_name`. The last error is returned if neither works
+/// in time.
+pub fn verify_online(
+    redirect_url: Option<&str>,
+    device_name: Option<&str>,
+    timeout: Duration,
+) -> io::Result<Verified> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let attempt = match (redirect_url, device_name) {
+            (Some(url), _) => http_get(url, ATTEMPT_TIMEOUT).map(Verified::Http),
+            (None, Some(name)) => resolve_local(name).map(Verified::Resolved),
+            (None, None) => {
+                return Err(invalid(String::from(
+                    "the device gave neither a URL nor a name to check",
+                )))
+            }
+        };
+        match attempt {
+            Ok(v) => return Ok(v),
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Err(e),
+            Err(e) if Instant::now() + RETRY_INTERVAL >= deadline => return Err(e),
+            Err(_) => thread::sleep(RETRY_INTERVAL),
+        }
+    }
+}
+
+// a request that can never succeed, so isn't retried
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn unresolved(host: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} didn't resolve to any address", host),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/setup", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut req = [0u8; 256];
+            let n = stream.read(&mut req).unwrap();
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&req[..n]).into_owned()
+        });
+
+        assert_eq!(
+            verify_online(Some(&url), None, Duration::from_secs(5)).unwrap(),
+            Verified::Http(200)
+        );
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("GET /setup HTTP/1.0\r\n"));
+
+        let e = http_get("https://10.0.0.2", Duration::from_secs(1)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn hostnames() {
+        assert_eq!(hostname("Living Room Lamp"), "living-room-lamp");
+        assert_eq!(hostname("esp32-c3"), "esp32-c3");
+    }
+}
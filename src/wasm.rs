@@ -0,0 +1,131 @@
+// Copyright 2024 Brandon Matthews <thenewwazoo@optimaltour.us>
+
+//! `wasm-bindgen` wrappers for the codec, e.g. for a browser installer using Web Serial.
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use wasm_bindgen::prelude::*;
+
+use crate::{Chunk, CommandId, Demux, ImprovErr, ImprovPacket, RPCCommand, WifiSettings};
+
+fn js_err(e: ImprovErr) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+/// Encode a request which takes no arguments: 0x02 (current state), 0x03 (device information), or
+/// 0x04 (scan).
+#[wasm_bindgen(js_name = encodeRequest)]
+pub fn encode_request(command: u8) -> Result<Vec<u8>, JsError> {
+    let cmd = match CommandId::try_from(command) {
+        Ok(CommandId::RequestCurrentState) => RPCCommand::RequestCurrentState,
+        Ok(CommandId::RequestDeviceInformation) => RPCCommand::RequestDeviceInformation,
+        Ok(CommandId::RequestScannedWifiNetworks) => RPCCommand::RequestScannedWifiNetworks,
+        _ => return Err(js_err(ImprovErr::InvalidRPCCommand(command))),
+    };
+    Vec::try_from(ImprovPacket::RPCCommand(cmd)).map_err(js_err)
+}
+
+#[wasm_bindgen(js_name = encodeWifiSettings)]
+pub fn encode_wifi_settings(ssid: String, psk: String) -> Result<Vec<u8>, JsError> {
+    let settings = WifiSettings {
+        ssid: ssid.into(),
+        psk,
+    };
+    Vec::try_from(ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(
+        settings,
+    )))
+    .map_err(js_err)
+}
+
+/// A decoded packet, flattened for JavaScript.
+#[wasm_bindgen(js_name = ImprovPacket, getter_with_clone)]
+pub struct WasmPacket {
+    /// 0x01 current state, 0x02 error state, 0x03 RPC command, 0x04 RPC result, or the type of a
+    /// packet this library doesn't know
+    #[wasm_bindgen(js_name = packetType)]
+    pub pkt_type: u8,
+    /// The state or error byte, or the RPC command id
+    pub value: u8,
+    /// The strings of an RPC result, or the SSID and PSK of Wi-Fi settings
+    pub strings: Vec<String>,
+}
+
+impl From<ImprovPacket> for WasmPacket {
+    fn from(p: ImprovPacket) -> WasmPacket {
+        let (pkt_type, value, strings) = match p {
+            ImprovPacket::CurrentState(s) => (0x01, s.into(), Vec::new()),
+            ImprovPacket::ErrorState(e) => (0x02, e.into(), Vec::new()),
+            ImprovPacket::RPCCommand(RPCCommand::SendWifiSettings(s)) => {
+                (0x03, 0x01, vec![s.ssid.to_string(), s.psk.clone()])
+            }
+            ImprovPacket::RPCCommand(c) => (0x03, c.id(), Vec::new()),
+            ImprovPacket::RPCResult(r) => (
+                0x04,
+                r.command,
+                r.data
+                    .iter()
+                    .map(|s| String::from_utf8_lossy(s).into_owned())
+                    .collect(),
+            ),
+            ImprovPacket::Raw { pkt_type, .. } => (pkt_type, 0, Vec::new()),
+        };
+        WasmPacket {
+            pkt_type,
+            value,
+            strings,
+        }
+    }
+}
+
+/// Decode one complete frame.
+#[wasm_bindgen(js_name = decodeFrame)]
+pub fn decode_frame(frame: &[u8]) -> Result<WasmPacket, JsError> {
+    ImprovPacket::decode_lenient(frame.to_vec())
+        .map(WasmPacket::from)
+        .map_err(js_err)
+}
+
+/// Picks packets out of a serial stream as it arrives, discarding the log output around them.
+#[wasm_bindgen(js_name = FrameDecoder)]
+#[derive(Default)]
+pub struct WasmDecoder {
+    demux: Demux,
+}
+
+#[wasm_bindgen(js_class = FrameDecoder)]
+impl WasmDecoder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmDecoder {
+        WasmDecoder::default()
+    }
+
+    /// Add bytes read from the port, returning any packets they completed.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<WasmPacket> {
+        self.demux
+            .feed(bytes)
+            .into_iter()
+            .filter_map(|c| match c {
+                Chunk::Packet(p) => Some(p.into()),
+                Chunk::Log(_) => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_in_pieces() {
+        let frame = encode_wifi_settings("anthill".into(), "hunter2".into()).unwrap();
+        let mut decoder = WasmDecoder::new();
+        assert!(decoder.push(&frame[..5]).is_empty());
+        let packets = decoder.push(&frame[5..]);
+        assert_eq!(packets.len(), 1);
+        assert_eq!((packets[0].pkt_type, packets[0].value), (0x03, 0x01));
+        assert_eq!(packets[0].strings, ["anthill", "hunter2"]);
+    }
+}